@@ -0,0 +1,235 @@
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures_util::Stream;
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Response, StatusCode, Version};
+use tracing::warn;
+
+use crate::cache::BlockUsage;
+use crate::metrics::Operation;
+
+/// Formats of access log lines.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// Apache combined log format, followed by the organization, cache status and time taken
+    Combined,
+    /// Fields of S3 server access logs, followed by the cache status
+    S3,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct AccessLogConfig {
+    /// File that a line per request is appended to, or - for stdout; requests are not logged if unset
+    #[arg(long, env)]
+    pub access_log: Option<PathBuf>,
+    /// Format of access log lines
+    #[arg(long, value_enum, default_value = "combined", env)]
+    pub access_log_format: AccessLogFormat,
+}
+
+impl AccessLogConfig {
+    /// Opens the access log, or returns `None` if it is disabled.
+    pub fn open(&self) -> std::io::Result<Option<AccessLog>> {
+        let Some(path) = &self.access_log else {
+            return Ok(None);
+        };
+        let out: Box<dyn Write + Send> = match path.to_str() {
+            Some("-") => Box::new(std::io::stdout()),
+            _ => Box::new(LineWriter::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+        };
+        Ok(Some(AccessLog {
+            format: self.access_log_format,
+            out: Mutex::new(out),
+        }))
+    }
+}
+
+/// What is known about a request when its response headers are sent.
+pub struct AccessLogEntry {
+    pub request_id: String,
+    pub time: DateTime<Utc>,
+    /// Address of the client, unless it connected over a Unix socket and
+    /// no trusted proxy forwarded it.
+    pub client_ip: Option<IpAddr>,
+    pub method: Method,
+    pub path: String,
+    pub version: Version,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    pub operation: Operation,
+    pub status: StatusCode,
+    pub user: Option<String>,
+    pub organization: Option<String>,
+    pub bucket: Option<String>,
+    pub key: Option<String>,
+    /// Time until the response headers were sent.
+    pub turnaround: Duration,
+}
+
+/// Writes a line per request once its response body has been sent or the
+/// client went away.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+fn or_dash(value: Option<&str>) -> &str {
+    value.filter(|value| !value.is_empty()).unwrap_or("-")
+}
+
+/// Quotes a header value, escaping quotes and control characters.
+fn quoted(value: Option<&str>) -> String {
+    let mut quoted = String::from("\"");
+    for c in or_dash(value).chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push_str(&c.escape_default().to_string()),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns the name of an operation as in S3 server access logs.
+fn s3_operation(operation: Operation) -> &'static str {
+    match operation {
+        Operation::Get => "REST.GET.OBJECT",
+        Operation::Head => "REST.HEAD.OBJECT",
+        Operation::List => "REST.GET.BUCKET",
+        Operation::Put => "REST.PUT.OBJECT",
+        Operation::Delete => "REST.DELETE.OBJECT",
+        Operation::Other => "-",
+    }
+}
+
+impl AccessLog {
+    fn format(
+        &self,
+        entry: &AccessLogEntry,
+        bytes: u64,
+        elapsed: Duration,
+        cache: Option<&str>,
+    ) -> String {
+        let time = entry.time.format("%d/%b/%Y:%H:%M:%S %z");
+        let remote_ip = match entry.client_ip {
+            Some(ip) => ip.to_string(),
+            None => "-".to_string(),
+        };
+        let request = format!("{} {} {:?}", entry.method, entry.path, entry.version);
+        let bytes = match bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        match self.format {
+            AccessLogFormat::Combined => format!(
+                "{} - {} [{}] {} {} {} {} {} {} {} {:.3}",
+                remote_ip,
+                or_dash(entry.user.as_deref()),
+                time,
+                quoted(Some(&request)),
+                entry.status.as_u16(),
+                bytes,
+                quoted(entry.referer.as_deref()),
+                quoted(entry.user_agent.as_deref()),
+                or_dash(entry.organization.as_deref()),
+                or_dash(cache),
+                elapsed.as_secs_f64() * 1000.0,
+            ),
+            AccessLogFormat::S3 => format!(
+                "{} {} [{}] {} {} {} {} {} {} {} - {} - {} {} {} {} - {}",
+                or_dash(entry.organization.as_deref()),
+                or_dash(entry.bucket.as_deref()),
+                time,
+                remote_ip,
+                or_dash(entry.user.as_deref()),
+                entry.request_id,
+                s3_operation(entry.operation),
+                or_dash(entry.key.as_deref()),
+                quoted(Some(&request)),
+                entry.status.as_u16(),
+                bytes,
+                elapsed.as_millis(),
+                entry.turnaround.as_millis(),
+                quoted(entry.referer.as_deref()),
+                quoted(entry.user_agent.as_deref()),
+                or_dash(cache),
+            ),
+        }
+    }
+
+    fn write(&self, line: &str) {
+        let mut out = self.out.lock().unwrap();
+        if let Err(e) = writeln!(out, "{}", line) {
+            warn!("Failed to write access log: {}", e);
+        }
+    }
+
+    /// Wraps the body of a response so that the request is logged with the
+    /// bytes sent and the cache status once the body is done.
+    pub fn wrap(
+        self: &Arc<Self>,
+        entry: AccessLogEntry,
+        start: Instant,
+        res: Response<Body>,
+    ) -> Response<Body> {
+        let usage = res.extensions().get::<Arc<BlockUsage>>().cloned();
+        let (parts, body) = res.into_parts();
+        let body = LoggedBody {
+            body,
+            bytes: 0,
+            log: self.clone(),
+            entry,
+            start,
+            usage,
+        };
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+}
+
+/// A response body that writes the access log line of its request when it
+/// is dropped.
+struct LoggedBody {
+    body: Body,
+    bytes: u64,
+    log: Arc<AccessLog>,
+    entry: AccessLogEntry,
+    start: Instant,
+    usage: Option<Arc<BlockUsage>>,
+}
+
+impl Stream for LoggedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.body).poll_data(cx);
+        if let Poll::Ready(Some(Ok(data))) = &polled {
+            self.bytes += data.len() as u64;
+        }
+        polled
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        let cache = self.usage.as_ref().and_then(|usage| usage.status());
+        let line = self
+            .log
+            .format(&self.entry, self.bytes, self.start.elapsed(), cache);
+        self.log.write(&line);
+    }
+}
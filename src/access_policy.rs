@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use hyper::Method;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::credentials::{CredentialsError, UserInfo};
+use crate::key_prefix;
+use crate::router::wildcard_match;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum Access {
+    /// GET and HEAD requests, including listings.
+    #[default]
+    Read,
+    /// All requests.
+    ReadWrite,
+}
+
+/// Grants access to the keys below a prefix of the buckets matching a
+/// pattern, optionally only to users with certain attributes.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    /// Bucket name or pattern with `*` wildcards.
+    bucket: String,
+    /// Key prefix, possibly with the placeholders of `--key-prefix`.
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    access: Access,
+    /// Attribute values of which a user must have at least one, for each
+    /// attribute.
+    #[serde(default)]
+    attributes: HashMap<String, Vec<String>>,
+}
+
+impl Rule {
+    /// Returns the prefix for `user`, or `None` if the rule doesn't apply to
+    /// them.
+    fn prefix(&self, user: Option<&UserInfo>) -> Option<String> {
+        if !key_prefix::is_templated(&self.prefix) && self.attributes.is_empty() {
+            return Some(self.prefix.clone());
+        }
+        let user = user?;
+        let matches = self.attributes.iter().all(|(name, values)| {
+            user.attribute(name)
+                .iter()
+                .any(|value| values.iter().any(|allowed| allowed == value))
+        });
+        if !matches {
+            return None;
+        }
+        key_prefix::render(&self.prefix, Some(user))
+    }
+}
+
+/// Per-prefix access rules of shared buckets, evaluated before requests are
+/// forwarded. Buckets that no rule matches are not restricted.
+pub struct AccessPolicy {
+    rules: Vec<Rule>,
+}
+
+impl AccessPolicy {
+    /// Reads a JSON array of rules.
+    pub fn from_file(path: &Path) -> Result<AccessPolicy, CredentialsError> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            CredentialsError::Configuration(format!("{}: {}", path.display(), e))
+        };
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+        Ok(AccessPolicy {
+            rules: serde_json::from_str(&text).map_err(|e| invalid(&e))?,
+        })
+    }
+
+    /// Returns true if `user` may make a `method` request for `path`, the key
+    /// or, for listings, the prefix of a request for `bucket`. Requests
+    /// without a user only match rules without attributes or placeholders.
+    pub fn allows(
+        &self,
+        user: Option<&UserInfo>,
+        method: &Method,
+        bucket: &str,
+        path: &str,
+    ) -> bool {
+        let write = !matches!(*method, Method::GET | Method::HEAD);
+        let mut rules = self
+            .rules
+            .iter()
+            .filter(|rule| wildcard_match(&rule.bucket, bucket))
+            .peekable();
+        if rules.peek().is_none() {
+            return true;
+        }
+        let allowed = rules
+            .filter(|rule| !write || rule.access == Access::ReadWrite)
+            .filter_map(|rule| rule.prefix(user))
+            .any(|prefix| path.starts_with(&prefix));
+        debug!(bucket, path, allowed, "Evaluated access policy");
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str) -> UserInfo {
+        serde_json::from_value(serde_json::json!({ "username": username, "id": "id-1" })).unwrap()
+    }
+
+    fn policy(rules: serde_json::Value) -> AccessPolicy {
+        AccessPolicy {
+            rules: serde_json::from_value(rules).unwrap(),
+        }
+    }
+
+    #[test]
+    fn user_prefix() {
+        let policy = policy(serde_json::json!([
+            { "bucket": "home", "prefix": "users/{{username}}/", "access": "read-write" },
+        ]));
+        let alice = user("alice");
+        assert!(policy.allows(Some(&alice), &Method::PUT, "home", "users/alice/k"));
+        assert!(!policy.allows(Some(&alice), &Method::GET, "home", "users/bob/k"));
+        assert!(!policy.allows(None, &Method::GET, "home", "users/alice/k"));
+        // Other buckets aren't restricted.
+        assert!(policy.allows(None, &Method::GET, "other", "k"));
+    }
+
+    #[test]
+    fn attributes_with_slashes() {
+        let policy = policy(serde_json::json!([
+            { "bucket": "home", "prefix": "users/{{username}}/" },
+        ]));
+        // `alice/x` must not get the prefix `users/alice/x/` in alice's
+        // namespace, nor any prefix at all.
+        let user = user("alice/x");
+        assert!(!policy.allows(Some(&user), &Method::GET, "home", "users/alice/x/k"));
+        assert!(!policy.allows(Some(&user), &Method::GET, "home", "users/k"));
+        assert!(!policy.allows(Some(&self::user("")), &Method::GET, "home", "users//k"));
+    }
+}
@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use hyper::http::request::Parts;
+use hyper::{Body, Method, Response, StatusCode};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::credentials::{Credentials, TokenSource};
+use crate::profiling::{self, ProfileError};
+use crate::s3_handler::S3Handler;
+
+/// Path prefix of the admin API. Underscores are not valid in bucket names,
+/// so admin routes cannot collide with S3 requests.
+pub const ADMIN_PREFIX: &str = "/_admin/";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PurgeParameters {
+    bucket: Option<String>,
+    prefix: Option<String>,
+}
+
+/// Seconds that CPU profiles are captured for unless asked otherwise, and at
+/// most.
+const PROFILE_SECONDS: u64 = 30;
+const PROFILE_MAX_SECONDS: u64 = 300;
+
+/// Samples per second of CPU profiles unless asked otherwise, slightly off
+/// 100 so that samples don't line up with work done on a 10ms tick.
+const PROFILE_FREQUENCY: i32 = 99;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileParameters {
+    seconds: Option<u64>,
+    frequency: Option<i32>,
+}
+
+pub fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    let body = body.to_string();
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .header("content-length", body.len())
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Handles requests below `ADMIN_PREFIX`. The admin API is only enabled when an
+/// admin token is configured and every request must present it as a bearer token.
+pub async fn route_admin(
+    parts: &Parts,
+    s3: &S3Handler,
+    admin_token: Option<&str>,
+) -> Result<Response<Body>, hyper::Error> {
+    let token = Credentials::token_from_headers(&parts.headers, &TokenSource::defaults());
+    let authorized = match (admin_token, token) {
+        (Some(expected), Ok(token)) => {
+            blake3::hash(token.as_bytes()) == blake3::hash(expected.as_bytes())
+        }
+        _ => false,
+    };
+    if !authorized {
+        warn!("Rejected unauthorized admin request");
+        return Ok(json_response(
+            StatusCode::FORBIDDEN,
+            serde_json::json!({ "error": "Forbidden" }),
+        ));
+    }
+
+    match (&parts.method, parts.uri.path()) {
+        (&Method::DELETE, "/_admin/cache") => {
+            let query = match serde_urlencoded::from_str::<PurgeParameters>(
+                parts.uri.query().unwrap_or_default(),
+            ) {
+                Ok(q) => q,
+                Err(e) => {
+                    return Ok(json_response(
+                        StatusCode::BAD_REQUEST,
+                        serde_json::json!({ "error": format!("Failed to parse query string: {}", e) }),
+                    ));
+                }
+            };
+            let prefix = query.prefix.unwrap_or_default();
+            match s3.purge_cache(query.bucket.as_deref(), &prefix).await {
+                Ok(removed) => {
+                    info!(bucket = query.bucket, prefix, removed, "Purged cache");
+                    Ok(json_response(
+                        StatusCode::OK,
+                        serde_json::json!({ "removed": removed }),
+                    ))
+                }
+                Err(e) => Ok(json_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    serde_json::json!({ "error": e.to_string() }),
+                )),
+            }
+        }
+        (&Method::GET, "/_admin/cache/stats") => match s3.cache_stats().await {
+            Ok(stats) => Ok(json_response(
+                StatusCode::OK,
+                serde_json::to_value(stats).unwrap(),
+            )),
+            Err(e) => Ok(json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                serde_json::json!({ "error": e.to_string() }),
+            )),
+        },
+        (&Method::GET, "/_admin/credentials/stats") => Ok(json_response(
+            StatusCode::OK,
+            serde_json::to_value(s3.credentials_stats()).unwrap(),
+        )),
+        (&Method::GET, "/_admin/debug/pprof/profile") => Ok(profile(parts).await),
+        _ => Ok(json_response(
+            StatusCode::NOT_FOUND,
+            serde_json::json!({ "error": "Not found" }),
+        )),
+    }
+}
+
+/// Captures a CPU profile of the whole process for `seconds` and answers
+/// with its flame graph.
+async fn profile(parts: &Parts) -> Response<Body> {
+    let query = match serde_urlencoded::from_str::<ProfileParameters>(
+        parts.uri.query().unwrap_or_default(),
+    ) {
+        Ok(q) => q,
+        Err(e) => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                serde_json::json!({ "error": format!("Failed to parse query string: {}", e) }),
+            );
+        }
+    };
+    let seconds = query.seconds.unwrap_or(PROFILE_SECONDS);
+    let frequency = query.frequency.unwrap_or(PROFILE_FREQUENCY);
+    if !(1..=PROFILE_MAX_SECONDS).contains(&seconds) || !(1..=1000).contains(&frequency) {
+        return json_response(
+            StatusCode::BAD_REQUEST,
+            serde_json::json!({
+                "error": format!(
+                    "seconds must be between 1 and {} and frequency between 1 and 1000",
+                    PROFILE_MAX_SECONDS
+                )
+            }),
+        );
+    }
+    info!(seconds, frequency, "Capturing CPU profile");
+    match profiling::flamegraph(Duration::from_secs(seconds), frequency).await {
+        Ok(svg) => Response::builder()
+            .header("content-type", "image/svg+xml")
+            .header("content-length", svg.len())
+            .body(Body::from(svg))
+            .unwrap(),
+        Err(e) => {
+            let status = match e {
+                ProfileError::Unsupported => StatusCode::NOT_IMPLEMENTED,
+                ProfileError::Busy => StatusCode::CONFLICT,
+                ProfileError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            warn!("CPU profile not captured: {}", e);
+            json_response(status, serde_json::json!({ "error": e.to_string() }))
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::credentials::{Credentials, CredentialsError};
+
+/// Header that clients pass their API key in.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// How long before their expiration access tokens obtained with a refresh
+/// token are renewed.
+const ACCESS_TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// An API key as configured, mapped to either static credentials or a
+/// refresh token.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApiKeyEntry {
+    key: String,
+    access_key_id: Option<String>,
+    secret_access_key: Option<String>,
+    session_token: Option<String>,
+    refresh_token: Option<String>,
+}
+
+/// The upstream identity of an API key.
+pub enum Identity {
+    /// Credentials that requests are signed with as they are.
+    Static(Credentials),
+    /// A refresh token whose access tokens are exchanged for credentials like
+    /// the tokens of other clients.
+    RefreshToken(Mutex<RefreshState>),
+}
+
+pub struct RefreshState {
+    refresh_token: String,
+    /// The current access token and when it expires.
+    access_token: Option<(String, Instant)>,
+}
+
+pub struct ApiKey {
+    /// Name of the key in the configuration, used in logs and as its tenant.
+    pub name: String,
+    pub identity: Identity,
+}
+
+/// OAuth client that renews the access tokens of refresh-token API keys.
+pub struct OAuthClient {
+    pub token_endpoint: String,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+    /// A new refresh token, if the server rotates them.
+    refresh_token: Option<String>,
+}
+
+/// API keys that non-interactive clients authenticate with instead of
+/// tokens.
+pub struct ApiKeys {
+    /// Keys by the hash of their secret.
+    keys: HashMap<blake3::Hash, ApiKey>,
+    oauth: Option<OAuthClient>,
+}
+
+impl ApiKeys {
+    /// Parses a JSON object of API keys by name. Each key has either
+    /// `access_key_id` and `secret_access_key` (and optionally
+    /// `session_token`), or `refresh_token`.
+    pub fn parse(
+        json: &str,
+        source: &str,
+        oauth: Option<OAuthClient>,
+    ) -> Result<ApiKeys, CredentialsError> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            CredentialsError::Configuration(format!("{}: {}", source, e))
+        };
+        let entries: HashMap<String, ApiKeyEntry> =
+            serde_json::from_str(json).map_err(|e| invalid(&e))?;
+        let mut keys = HashMap::new();
+        for (name, entry) in entries {
+            let hash = blake3::hash(entry.key.as_bytes());
+            if keys.contains_key(&hash) {
+                return Err(invalid(&format!("{}: duplicate key", name)));
+            }
+            let identity = match entry {
+                ApiKeyEntry {
+                    access_key_id: Some(access_key_id),
+                    secret_access_key: Some(secret_access_key),
+                    session_token,
+                    refresh_token: None,
+                    ..
+                } => Identity::Static(Credentials {
+                    access_key_id,
+                    secret_access_key,
+                    session_token,
+                    expiration: DateTime::<Utc>::MAX_UTC,
+                }),
+                ApiKeyEntry {
+                    access_key_id: None,
+                    secret_access_key: None,
+                    session_token: None,
+                    refresh_token: Some(refresh_token),
+                    ..
+                } if oauth.is_some() => Identity::RefreshToken(Mutex::new(RefreshState {
+                    refresh_token,
+                    access_token: None,
+                })),
+                ApiKeyEntry {
+                    refresh_token: Some(_),
+                    ..
+                } if oauth.is_none() => {
+                    return Err(invalid(&format!(
+                        "{}: refresh tokens require --oauth-token-endpoint",
+                        name
+                    )))
+                }
+                _ => {
+                    return Err(invalid(&format!(
+                    "{}: either access_key_id and secret_access_key or refresh_token is required",
+                    name
+                )))
+                }
+            };
+            keys.insert(hash, ApiKey { name, identity });
+        }
+        Ok(ApiKeys { keys, oauth })
+    }
+
+    /// Returns the API key with secret `key`.
+    pub fn get(&self, key: &str) -> Option<&ApiKey> {
+        self.keys.get(&blake3::hash(key.as_bytes()))
+    }
+
+    /// Returns a current access token of a refresh-token key, renewing it
+    /// when it is about to expire.
+    pub async fn access_token(
+        &self,
+        state: &Mutex<RefreshState>,
+    ) -> Result<String, CredentialsError> {
+        // Holding the lock while renewing makes concurrent requests wait for
+        // a single renewal.
+        let mut state = state.lock().await;
+        if let Some((access_token, expires)) = &state.access_token {
+            if Instant::now() + ACCESS_TOKEN_REFRESH_MARGIN < *expires {
+                return Ok(access_token.clone());
+            }
+        }
+        let oauth = self
+            .oauth
+            .as_ref()
+            .ok_or_else(|| CredentialsError::Configuration("no OAuth client".to_string()))?;
+        info!("Renewing access token of API key");
+        let mut form = vec![
+            ("grant_type", "refresh_token"),
+            ("refresh_token", state.refresh_token.as_str()),
+        ];
+        if let Some(client_id) = &oauth.client_id {
+            form.push(("client_id", client_id));
+        }
+        if let Some(client_secret) = &oauth.client_secret {
+            form.push(("client_secret", client_secret));
+        }
+        let res = reqwest::Client::new()
+            .post(&oauth.token_endpoint)
+            .form(&form)
+            .send()
+            .await?;
+        let text = res.error_for_status()?.text().await?;
+        let res: TokenResponse =
+            serde_json::from_str(&text).map_err(|_| CredentialsError::CredentialsParse())?;
+        let expires = Instant::now() + Duration::from_secs(res.expires_in.unwrap_or(3600));
+        if let Some(refresh_token) = res.refresh_token {
+            state.refresh_token = refresh_token;
+        }
+        state.access_token = Some((res.access_token.clone(), expires));
+        Ok(res.access_token)
+    }
+}
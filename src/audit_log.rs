@@ -0,0 +1,300 @@
+use std::fs::OpenOptions;
+use std::io::{LineWriter, Write};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::Stream;
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Response, StatusCode};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::metrics::Operation;
+
+/// Records sent to an HTTP sink in one request at most.
+const BATCH_SIZE: usize = 500;
+
+/// Time that records wait for more to be batched with at most.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Records kept while an HTTP sink fails before the oldest are dropped.
+const MAX_PENDING: usize = 100_000;
+
+/// Timeout of requests to an HTTP sink.
+const SINK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct AuditLogConfig {
+    /// File that a JSON record per S3 request is appended to, - for stdout, or an http(s) URL that records are posted to in batches; requests are not audited if unset
+    #[arg(long, env)]
+    pub audit_log: Option<String>,
+}
+
+impl AuditLogConfig {
+    /// Returns the URL of the HTTP sink, if records are posted to one.
+    fn url(&self) -> Result<Option<reqwest::Url>, String> {
+        match &self.audit_log {
+            Some(sink) if sink.starts_with("http://") || sink.starts_with("https://") => {
+                reqwest::Url::parse(sink)
+                    .map(Some)
+                    .map_err(|e| format!("invalid audit log URL {}: {}", sink, e))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Checks the sink without opening it.
+    pub fn check(&self) -> Result<(), String> {
+        if self.url()?.is_some() {
+            return Ok(());
+        }
+        let dir = self
+            .audit_log
+            .as_deref()
+            .filter(|sink| *sink != "-")
+            .and_then(|sink| std::path::Path::new(sink).parent())
+            .filter(|dir| !dir.as_os_str().is_empty() && !dir.is_dir());
+        match dir {
+            Some(dir) => Err(format!(
+                "failed to open audit log: {} is not a directory",
+                dir.display()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Opens the audit log, or returns `None` if it is disabled. Records for
+    /// an HTTP sink are posted by a background task.
+    pub fn open(&self) -> Result<Option<AuditLog>, String> {
+        let Some(sink) = &self.audit_log else {
+            return Ok(None);
+        };
+        if let Some(url) = self.url()? {
+            let client = reqwest::Client::builder()
+                .timeout(SINK_TIMEOUT)
+                .build()
+                .map_err(|e| format!("failed to set up audit log client: {}", e))?;
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(post_records(client, url, receiver));
+            return Ok(Some(AuditLog {
+                sink: Sink::Http(sender),
+            }));
+        }
+        let out: Box<dyn Write + Send> = match sink.as_str() {
+            "-" => Box::new(std::io::stdout()),
+            path => Box::new(LineWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("failed to open audit log: {}", e))?,
+            )),
+        };
+        Ok(Some(AuditLog {
+            sink: Sink::Writer(Mutex::new(out)),
+        }))
+    }
+}
+
+/// What is known about a request when its response headers are sent.
+pub struct AuditEntry {
+    pub request_id: String,
+    pub time: DateTime<Utc>,
+    pub client_ip: Option<IpAddr>,
+    pub method: Method,
+    pub operation: Operation,
+    pub status: StatusCode,
+    pub user: Option<String>,
+    pub username: Option<String>,
+    pub organization: Option<String>,
+    pub bucket: Option<String>,
+    pub key: Option<String>,
+    /// The `Range` header of the request.
+    pub range: Option<String>,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: String,
+    request_id: &'a str,
+    client_ip: Option<String>,
+    user: Option<&'a str>,
+    username: Option<&'a str>,
+    organization: Option<&'a str>,
+    method: &'a str,
+    operation: &'a str,
+    bucket: Option<&'a str>,
+    key: Option<&'a str>,
+    range: Option<&'a str>,
+    status: u16,
+    bytes: u64,
+    /// Whether the whole response body was sent, rather than the client
+    /// going away first.
+    completed: bool,
+}
+
+enum Sink {
+    Writer(Mutex<Box<dyn Write + Send>>),
+    Http(mpsc::UnboundedSender<String>),
+}
+
+/// Writes a JSON record per S3 request, with the identity of its user and
+/// the bytes served, once its response body has been sent or the client
+/// went away.
+pub struct AuditLog {
+    sink: Sink,
+}
+
+impl AuditLog {
+    fn write(&self, record: String) {
+        match &self.sink {
+            Sink::Writer(out) => {
+                let mut out = out.lock().unwrap();
+                if let Err(e) = writeln!(out, "{}", record) {
+                    warn!("Failed to write audit log: {}", e);
+                }
+            }
+            Sink::Http(sender) => {
+                if sender.send(record).is_err() {
+                    warn!("Failed to queue audit record, the audit log task has stopped");
+                }
+            }
+        }
+    }
+
+    /// Wraps the body of a response so that the request is audited with the
+    /// bytes sent once the body is done.
+    pub fn wrap(self: &Arc<Self>, entry: AuditEntry, res: Response<Body>) -> Response<Body> {
+        // Bodies are not polled to their end once their length is sent.
+        let length = match (&entry.method, res.status()) {
+            (&Method::HEAD, _) | (_, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED) => Some(0),
+            _ => res
+                .headers()
+                .get("content-length")
+                .and_then(|value| value.to_str().ok()?.parse().ok()),
+        };
+        let (parts, body) = res.into_parts();
+        let body = AuditedBody {
+            body,
+            bytes: 0,
+            length,
+            completed: false,
+            log: self.clone(),
+            entry,
+        };
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+}
+
+/// Posts records to `url` as newline-delimited JSON, in batches of up to
+/// `BATCH_SIZE` at least every `FLUSH_INTERVAL`. Batches that fail are sent
+/// again with the next one.
+async fn post_records(
+    client: reqwest::Client,
+    url: reqwest::Url,
+    mut receiver: mpsc::UnboundedReceiver<String>,
+) {
+    let mut pending: Vec<String> = Vec::new();
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    let mut open = true;
+    while open || !pending.is_empty() {
+        tokio::select! {
+            record = receiver.recv(), if open => match record {
+                Some(record) => {
+                    pending.push(record);
+                    // Full batches are posted right away, the rest on the
+                    // next tick.
+                    if !pending.len().is_multiple_of(BATCH_SIZE) {
+                        continue;
+                    }
+                }
+                None => open = false,
+            },
+            _ = interval.tick() => {}
+        }
+        while !pending.is_empty() {
+            let batch = pending.len().min(BATCH_SIZE);
+            let mut body = pending[..batch].join("\n");
+            body.push('\n');
+            let posted = client
+                .post(url.clone())
+                .header("content-type", "application/x-ndjson")
+                .body(body)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            if let Err(e) = posted {
+                warn!(
+                    pending = pending.len(),
+                    "Failed to post audit records: {}", e
+                );
+                if pending.len() > MAX_PENDING {
+                    let dropped = pending.len() - MAX_PENDING;
+                    pending.drain(..dropped);
+                    warn!(dropped, "Dropped audit records that could not be posted");
+                }
+                // Without a sink, records that are left would be lost anyway.
+                if !open {
+                    return;
+                }
+                break;
+            }
+            pending.drain(..batch);
+        }
+    }
+}
+
+/// A response body that writes the audit record of its request when it is
+/// dropped.
+struct AuditedBody {
+    body: Body,
+    bytes: u64,
+    /// Length of the body, if known in advance.
+    length: Option<u64>,
+    completed: bool,
+    log: Arc<AuditLog>,
+    entry: AuditEntry,
+}
+
+impl Stream for AuditedBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.body).poll_data(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(data))) => self.bytes += data.len() as u64,
+            Poll::Ready(None) => self.completed = true,
+            _ => {}
+        }
+        polled
+    }
+}
+
+impl Drop for AuditedBody {
+    fn drop(&mut self) {
+        let entry = &self.entry;
+        let record = AuditRecord {
+            time: entry.time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            request_id: &entry.request_id,
+            client_ip: entry.client_ip.map(|ip| ip.to_string()),
+            user: entry.user.as_deref(),
+            username: entry.username.as_deref(),
+            organization: entry.organization.as_deref(),
+            method: entry.method.as_str(),
+            operation: entry.operation.as_str(),
+            bucket: entry.bucket.as_deref(),
+            key: entry.key.as_deref(),
+            range: entry.range.as_deref(),
+            status: entry.status.as_u16(),
+            bytes: self.bytes,
+            completed: self.completed || self.length.is_some_and(|length| self.bytes >= length),
+        };
+        self.log.write(serde_json::to_string(&record).unwrap());
+    }
+}
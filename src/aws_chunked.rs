@@ -0,0 +1,387 @@
+use bytes::{Buf, Bytes, BytesMut};
+use futures_util::{stream, Stream, StreamExt};
+use hyper::header::HeaderMap;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::sigv4::ChunkSignatures;
+
+/// Prefix of the `x-amz-content-sha256` values used by streaming SigV4 uploads,
+/// e.g. `STREAMING-AWS4-HMAC-SHA256-PAYLOAD` or `STREAMING-UNSIGNED-PAYLOAD-TRAILER`.
+const STREAMING_PAYLOAD_PREFIX: &str = "STREAMING-";
+
+/// Upper bound for a single chunk header or trailer line.
+const MAX_LINE_LENGTH: usize = 4096;
+
+#[derive(Error, Debug)]
+pub enum AwsChunkedError {
+    #[error("Invalid aws-chunked chunk header")]
+    InvalidChunkHeader(),
+    #[error("Missing CRLF after aws-chunked chunk data")]
+    MissingChunkTerminator(),
+    #[error("Unexpected end of aws-chunked body")]
+    UnexpectedEof(),
+    #[error("Invalid aws-chunked chunk or trailer signature")]
+    SignatureMismatch(),
+}
+
+/// Returns true if the request body is encoded with `aws-chunked`, either
+/// announced through `Content-Encoding` or a `STREAMING-*` payload hash.
+pub fn is_aws_chunked(headers: &HeaderMap) -> bool {
+    let encoded = headers
+        .get_all("content-encoding")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|v| v.trim().eq_ignore_ascii_case("aws-chunked"));
+    let streaming = headers
+        .get("x-amz-content-sha256")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.starts_with(STREAMING_PAYLOAD_PREFIX))
+        .unwrap_or(false);
+    encoded || streaming
+}
+
+/// Returns the length of the payload of an upload: the
+/// `x-amz-decoded-content-length` of `aws-chunked` bodies, otherwise the
+/// `Content-Length`.
+pub fn payload_length(headers: &HeaderMap, chunked: bool) -> Option<u64> {
+    let name = match chunked {
+        true => "x-amz-decoded-content-length",
+        false => "content-length",
+    };
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+/// Removes `aws-chunked` from a `Content-Encoding` value, returning `None` if
+/// no other encodings remain.
+pub fn strip_content_encoding(value: &str) -> Option<String> {
+    let remaining: Vec<&str> = value
+        .split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty() && !v.eq_ignore_ascii_case("aws-chunked"))
+        .collect();
+    if remaining.is_empty() {
+        None
+    } else {
+        Some(remaining.join(", "))
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    Header,
+    Data(usize),
+    DataEnd,
+    Trailer,
+    Done,
+}
+
+/// Incremental decoder for `aws-chunked` bodies.
+///
+/// Each chunk is framed as `<hex-size>[;chunk-signature=<sig>]\r\n<data>\r\n`
+/// and the body ends with a zero-sized chunk followed by optional trailing
+/// headers (e.g. `x-amz-checksum-crc32`) and an empty line. Signatures are
+/// checked if the decoder is given the chain they belong to, and trailers
+/// are discarded; only the payload is returned.
+#[derive(Debug)]
+pub struct AwsChunkedDecoder {
+    buf: BytesMut,
+    state: State,
+    signatures: Option<ChunkSignatures>,
+    /// Signature and hash of the data of the current chunk.
+    signature: Option<String>,
+    chunk_hash: Sha256,
+    /// Trailers as signed: `name:value` lines each ending with a newline.
+    trailers: BytesMut,
+    trailer_signature: Option<String>,
+}
+
+impl AwsChunkedDecoder {
+    pub fn new() -> Self {
+        AwsChunkedDecoder {
+            buf: BytesMut::new(),
+            state: State::Header,
+            signatures: None,
+            signature: None,
+            chunk_hash: Sha256::new(),
+            trailers: BytesMut::new(),
+            trailer_signature: None,
+        }
+    }
+
+    /// Returns a decoder that fails unless every chunk, and the trailers if
+    /// they are signed, carry the next signature of `signatures`.
+    pub fn with_signatures(signatures: ChunkSignatures) -> Self {
+        AwsChunkedDecoder {
+            signatures: Some(signatures),
+            ..AwsChunkedDecoder::new()
+        }
+    }
+
+    /// Feeds raw body bytes into the decoder and returns the payload bytes
+    /// that could be decoded so far.
+    pub fn push(&mut self, input: &[u8]) -> Result<Bytes, AwsChunkedError> {
+        self.buf.extend_from_slice(input);
+        let mut out = BytesMut::new();
+        loop {
+            match self.state {
+                State::Header => {
+                    let Some(line) = self.take_line()? else { break };
+                    let line = std::str::from_utf8(&line)
+                        .map_err(|_| AwsChunkedError::InvalidChunkHeader())?;
+                    let mut fields = line.split(';');
+                    let size = fields.next().unwrap_or_default();
+                    let size = usize::from_str_radix(size.trim(), 16)
+                        .map_err(|_| AwsChunkedError::InvalidChunkHeader())?;
+                    self.signature = fields
+                        .find_map(|field| field.trim().strip_prefix("chunk-signature="))
+                        .map(str::to_string);
+                    self.state = match size {
+                        0 => {
+                            self.verify_chunk()?;
+                            State::Trailer
+                        }
+                        n => State::Data(n),
+                    };
+                }
+                State::Data(remaining) => {
+                    if self.buf.is_empty() {
+                        break;
+                    }
+                    let n = remaining.min(self.buf.len());
+                    let data = self.buf.split_to(n);
+                    if self.signatures.is_some() {
+                        self.chunk_hash.update(&data);
+                    }
+                    out.extend_from_slice(&data);
+                    self.state = match remaining - n {
+                        0 => State::DataEnd,
+                        n => State::Data(n),
+                    };
+                }
+                State::DataEnd => {
+                    if self.buf.len() < 2 {
+                        break;
+                    }
+                    if &self.buf[..2] != b"\r\n" {
+                        return Err(AwsChunkedError::MissingChunkTerminator());
+                    }
+                    self.buf.advance(2);
+                    self.verify_chunk()?;
+                    self.state = State::Header;
+                }
+                State::Trailer => {
+                    let Some(line) = self.take_line()? else { break };
+                    if line.is_empty() {
+                        self.verify_trailer()?;
+                        self.state = State::Done;
+                    } else if let Some(signature) = line.strip_prefix(b"x-amz-trailer-signature:") {
+                        self.trailer_signature =
+                            Some(String::from_utf8_lossy(signature).trim().to_string());
+                    } else {
+                        self.trailers.extend_from_slice(&line);
+                        self.trailers.extend_from_slice(b"\n");
+                    }
+                }
+                State::Done => {
+                    self.buf.clear();
+                    break;
+                }
+            }
+        }
+        Ok(out.freeze())
+    }
+
+    /// Verifies that the terminating chunk has been seen.
+    pub fn finish(&self) -> Result<(), AwsChunkedError> {
+        let signed_trailer = self
+            .signatures
+            .as_ref()
+            .is_some_and(ChunkSignatures::signs_trailer);
+        match self.state {
+            State::Done => Ok(()),
+            // Some clients omit the final empty line when there are no trailers.
+            State::Trailer
+                if self.buf.is_empty() && self.trailers.is_empty() && !signed_trailer =>
+            {
+                Ok(())
+            }
+            _ => Err(AwsChunkedError::UnexpectedEof()),
+        }
+    }
+
+    /// Checks the signature of the chunk whose data has just been read.
+    fn verify_chunk(&mut self) -> Result<(), AwsChunkedError> {
+        let Some(signatures) = &mut self.signatures else {
+            return Ok(());
+        };
+        let hash = self.chunk_hash.finalize_reset();
+        match self.signature.take() {
+            Some(signature) if signatures.verify_chunk(&signature, &hash) => Ok(()),
+            _ => Err(AwsChunkedError::SignatureMismatch()),
+        }
+    }
+
+    /// Checks the signature of the trailers, if they are signed.
+    fn verify_trailer(&mut self) -> Result<(), AwsChunkedError> {
+        let Some(signatures) = self.signatures.as_mut().filter(|s| s.signs_trailer()) else {
+            return Ok(());
+        };
+        match self.trailer_signature.take() {
+            Some(signature) if signatures.verify_trailer(&signature, &self.trailers) => Ok(()),
+            _ => Err(AwsChunkedError::SignatureMismatch()),
+        }
+    }
+
+    fn take_line(&mut self) -> Result<Option<Bytes>, AwsChunkedError> {
+        match self.buf.windows(2).position(|w| w == b"\r\n") {
+            Some(pos) => {
+                let line = self.buf.split_to(pos).freeze();
+                self.buf.advance(2);
+                Ok(Some(line))
+            }
+            None if self.buf.len() > MAX_LINE_LENGTH => Err(AwsChunkedError::InvalidChunkHeader()),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Default for AwsChunkedDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decodes an `aws-chunked` body as it streams in, failing at the end if the
+/// terminating chunk is missing, and at the first chunk that doesn't carry
+/// the next signature of `signatures` if given.
+pub fn decode_stream<S, E>(
+    body: S,
+    signatures: Option<ChunkSignatures>,
+) -> impl Stream<Item = std::io::Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let decoder = match signatures {
+        Some(signatures) => AwsChunkedDecoder::with_signatures(signatures),
+        None => AwsChunkedDecoder::new(),
+    };
+    stream::unfold((body, Some(decoder)), |(mut body, decoder)| async move {
+        let mut decoder = decoder?;
+        let item = match body.next().await {
+            Some(Ok(bytes)) => decoder.push(&bytes).map_err(std::io::Error::other),
+            Some(Err(e)) => Err(std::io::Error::other(e)),
+            None => {
+                let e = decoder.finish().err()?;
+                return Some((Err(std::io::Error::other(e)), (body, None)));
+            }
+        };
+        Some((item, (body, Some(decoder))))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNATURE: &str =
+        "chunk-signature=ad80c730a21e5b8d04586a2213dd63b9a0e99e0e2307b0ade35a65485a288648";
+
+    /// Decodes `body` fed to the decoder in pieces of `step` bytes.
+    fn decode(body: &[u8], step: usize) -> Result<Bytes, AwsChunkedError> {
+        let mut decoder = AwsChunkedDecoder::new();
+        let mut payload = BytesMut::new();
+        for piece in body.chunks(step) {
+            payload.extend_from_slice(&decoder.push(piece)?);
+        }
+        decoder.finish()?;
+        Ok(payload.freeze())
+    }
+
+    #[test]
+    fn multiple_chunks() {
+        let body =
+            format!("5;{SIGNATURE}\r\nhello\r\n7;{SIGNATURE}\r\n, world\r\n0;{SIGNATURE}\r\n\r\n");
+        for step in [1, 3, body.len()] {
+            assert_eq!(decode(body.as_bytes(), step).unwrap(), "hello, world");
+        }
+    }
+
+    #[test]
+    fn zero_length_final_chunk() {
+        assert_eq!(decode(b"3\r\nabc\r\n0\r\n\r\n", 1).unwrap(), "abc");
+        // Without trailers, some clients end the body right after it.
+        assert_eq!(decode(b"3\r\nabc\r\n0\r\n", 1).unwrap(), "abc");
+        assert_eq!(decode(b"0\r\n\r\n", 1).unwrap(), "");
+    }
+
+    #[test]
+    fn bad_chunk_size() {
+        for body in [&b"xyz\r\nabc\r\n0\r\n\r\n"[..], b";sig\r\n", b"-1\r\n"] {
+            assert!(matches!(
+                decode(body, 1),
+                Err(AwsChunkedError::InvalidChunkHeader())
+            ));
+        }
+        // A header line that never ends.
+        let body = vec![b'1'; MAX_LINE_LENGTH + 1];
+        assert!(matches!(
+            decode(&body, 1),
+            Err(AwsChunkedError::InvalidChunkHeader())
+        ));
+        // Data running past the size of its chunk.
+        assert!(matches!(
+            decode(b"3\r\nabcd\r\n0\r\n\r\n", 1),
+            Err(AwsChunkedError::MissingChunkTerminator())
+        ));
+    }
+
+    #[test]
+    fn truncated_body() {
+        for body in [
+            &b""[..],
+            b"5\r\nhel",
+            b"5\r\nhello",
+            b"5\r\nhello\r\n",
+            b"5\r\nhello\r\n0",
+        ] {
+            assert!(matches!(
+                decode(body, 1),
+                Err(AwsChunkedError::UnexpectedEof())
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_stream() {
+        let body = stream::iter([Ok::<_, std::io::Error>(Bytes::from("5\r\nhel"))]);
+        let decoded: Vec<_> = decode_stream(body, None).collect().await;
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].as_ref().unwrap(), "hel");
+        assert!(decoded[1].is_err());
+    }
+
+    #[test]
+    fn trailers() {
+        let body = b"3\r\nabc\r\n0\r\nx-amz-checksum-crc32:NSRBwg==\r\nx-amz-trailer-signature:0000\r\n\r\n";
+        for step in [1, body.len()] {
+            assert_eq!(decode(body, step).unwrap(), "abc");
+        }
+        // Bytes after the final empty line are ignored.
+        let mut decoder = AwsChunkedDecoder::new();
+        decoder.push(b"0\r\n\r\n").unwrap();
+        assert_eq!(decoder.push(b"junk").unwrap(), "");
+        decoder.finish().unwrap();
+    }
+
+    #[test]
+    fn content_encoding() {
+        assert_eq!(strip_content_encoding("aws-chunked"), None);
+        assert_eq!(
+            strip_content_encoding("aws-chunked, gzip").as_deref(),
+            Some("gzip")
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use bytes::Bytes;
+use futures_util::future::BoxFuture;
+use serde::Deserialize;
+
+use crate::gcs::GcsBackend;
+use crate::local_fs::LocalFsBackend;
+
+/// Storage services that upstreams are accessed as.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum BackendKind {
+    /// The S3 API, with requests signed with SigV4.
+    S3,
+    /// The XML API of Google Cloud Storage, with requests authorized by
+    /// OAuth.
+    Gcs,
+    /// A directory on the local filesystem.
+    Fs(PathBuf),
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s3" => Ok(BackendKind::S3),
+            "gcs" => Ok(BackendKind::Gcs),
+            _ => match s.strip_prefix("fs:") {
+                Some(dir) if !dir.is_empty() => Ok(BackendKind::Fs(dir.into())),
+                _ => Err(format!("expected `s3`, `gcs` or `fs:<dir>`, got {:?}", s)),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for BackendKind {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Body of an upstream request, and how it is covered by the signature.
+pub enum Payload {
+    Empty,
+    /// A buffered body, signed with its SHA-256 hash.
+    Bytes(Bytes),
+    /// A streamed body, sent as it arrives and signed as `UNSIGNED-PAYLOAD`
+    /// since its hash isn't known up front.
+    Unsigned(hyper::Body),
+}
+
+impl Payload {
+    pub fn into_body(self) -> Option<reqwest::Body> {
+        match self {
+            Payload::Empty => None,
+            Payload::Bytes(body) => Some(body.into()),
+            Payload::Unsigned(body) => Some(body.into()),
+        }
+    }
+}
+
+/// The storage service behind an upstream, which authorizes the requests
+/// sent to it. Requests are made with the S3 API; backends of services with
+/// another API must accept its requests, like the XML API of GCS does, or
+/// answer them themselves.
+pub trait Backend: Send + Sync {
+    /// Authorizes `request` made for a client with `credentials`, before
+    /// `payload` is attached to it as its body.
+    fn authorize<'a>(
+        &'a self,
+        request: &'a mut reqwest::Request,
+        credentials: &'a aws_credential_types::Credentials,
+        payload: &'a Payload,
+    ) -> BoxFuture<'a, Result<(), String>>;
+
+    /// Sends an authorized request with `payload` as its body.
+    fn send<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+        mut request: reqwest::Request,
+        payload: Payload,
+    ) -> BoxFuture<'a, Result<reqwest::Response, reqwest::Error>> {
+        *request.body_mut() = payload.into_body();
+        Box::pin(client.execute(request))
+    }
+}
+
+/// An S3-compatible upstream, whose requests are signed with the credentials
+/// of the client.
+pub struct S3Backend {
+    /// Region that requests are signed for.
+    region: String,
+}
+
+impl S3Backend {
+    pub fn new(region: &str) -> Self {
+        S3Backend {
+            region: region.to_string(),
+        }
+    }
+
+    fn sign(
+        &self,
+        request: &mut reqwest::Request,
+        credentials: &aws_credential_types::Credentials,
+        payload: &Payload,
+    ) -> Result<(), String> {
+        use aws_sigv4::http_request::{
+            PayloadChecksumKind, PercentEncodingMode, SignableBody, SignableRequest,
+            SigningSettings, UriPathNormalizationMode,
+        };
+        use aws_sigv4::sign::v4;
+        use hyper::header::HeaderValue;
+
+        // Anonymous requests for public buckets are sent unsigned.
+        if credentials.access_key_id().is_empty() {
+            return Ok(());
+        }
+        let mut signing_settings = SigningSettings::default();
+        signing_settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+        // S3 signs keys as they are sent, already encoded by `object_url`.
+        signing_settings.percent_encoding_mode = PercentEncodingMode::Single;
+        signing_settings.uri_path_normalization_mode = UriPathNormalizationMode::Disabled;
+        let identity = credentials.clone().into();
+        let signer = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name("s3")
+            .settings(signing_settings)
+            .time(SystemTime::now())
+            .build()
+            .map_err(|e| e.to_string())?;
+        let headers = request
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)));
+        let signable_request = SignableRequest::new(
+            request.method().as_str(),
+            request.url().as_str(),
+            headers,
+            match payload {
+                Payload::Empty => SignableBody::Bytes(&[]),
+                Payload::Bytes(body) => SignableBody::Bytes(body),
+                Payload::Unsigned(_) => SignableBody::UnsignedPayload,
+            },
+        )
+        .map_err(|e| e.to_string())?;
+        let signed = aws_sigv4::http_request::sign(signable_request, &signer.into())
+            .map_err(|e| e.to_string())?;
+        let (instructions, _) = signed.into_parts();
+        let (signed_headers, _) = instructions.into_parts();
+        for header in signed_headers {
+            let value = HeaderValue::from_str(header.value()).map_err(|e| e.to_string())?;
+            request.headers_mut().insert(header.name(), value);
+        }
+        Ok(())
+    }
+}
+
+impl Backend for S3Backend {
+    fn authorize<'a>(
+        &'a self,
+        request: &'a mut reqwest::Request,
+        credentials: &'a aws_credential_types::Credentials,
+        payload: &'a Payload,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move { self.sign(request, credentials, payload) })
+    }
+}
+
+/// Sets up the backends of upstreams. Upstreams on GCS share a backend, so
+/// that they share its access tokens.
+pub struct Backends {
+    kind: BackendKind,
+    region: String,
+    gcs_service_account_file: Option<PathBuf>,
+    gcs: Option<Arc<GcsBackend>>,
+    /// Bytes read at a time from the files of fs backends.
+    read_buffer_size: usize,
+}
+
+impl Backends {
+    /// Sets up backends of `kind` by default, signing S3 requests for
+    /// `region` unless an upstream has a region of its own.
+    pub fn new(
+        kind: BackendKind,
+        region: &str,
+        gcs_service_account_file: Option<PathBuf>,
+        read_buffer_size: usize,
+    ) -> Self {
+        Backends {
+            kind,
+            region: region.to_string(),
+            gcs_service_account_file,
+            gcs: None,
+            read_buffer_size,
+        }
+    }
+
+    /// Returns a backend of `kind`, or of the default kind if unset.
+    pub fn get(
+        &mut self,
+        kind: Option<&BackendKind>,
+        region: Option<&str>,
+    ) -> Result<Arc<dyn Backend>, String> {
+        match kind.unwrap_or(&self.kind).clone() {
+            BackendKind::S3 => Ok(Arc::new(S3Backend::new(region.unwrap_or(&self.region)))),
+            BackendKind::Fs(root) => {
+                Ok(Arc::new(LocalFsBackend::new(&root, self.read_buffer_size)?))
+            }
+            BackendKind::Gcs => {
+                if self.gcs.is_none() {
+                    let gcs = GcsBackend::new(self.gcs_service_account_file.as_deref())?;
+                    self.gcs = Some(Arc::new(gcs));
+                }
+                Ok(self.gcs.clone().unwrap())
+            }
+        }
+    }
+}
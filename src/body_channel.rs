@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use hyper::Body;
+use tokio::sync::mpsc;
+
+/// Returns a response body fed by the returned sender, which can queue up
+/// to `capacity` chunks before it waits for the client to take them.
+/// Unlike hyper's body channel, which holds a single chunk, a deeper queue
+/// lets reads from the cache or upstream run ahead of a fast connection.
+pub fn channel(capacity: usize) -> (Sender, Body) {
+    let (chunks, mut receiver) = mpsc::channel(capacity);
+    let aborted = Arc::new(AtomicBool::new(false));
+    let sender = Sender {
+        chunks,
+        aborted: aborted.clone(),
+    };
+    let body = futures_util::stream::poll_fn(move |cx| {
+        receiver.poll_recv(cx).map(|chunk| match chunk {
+            Some(chunk) => Some(Ok(chunk)),
+            None if aborted.load(Ordering::Acquire) => {
+                Some(Err(std::io::Error::other("response body aborted")))
+            }
+            None => None,
+        })
+    });
+    (sender, Body::wrap_stream(body))
+}
+
+/// The sending half of a response body.
+pub struct Sender {
+    chunks: mpsc::Sender<Bytes>,
+    aborted: Arc<AtomicBool>,
+}
+
+impl Sender {
+    /// Queues `chunk`, waiting while the queue is full. Fails once the body
+    /// has been dropped, e.g. because the client went away.
+    pub async fn send_data(&mut self, chunk: Bytes) -> Result<(), mpsc::error::SendError<Bytes>> {
+        self.chunks.send(chunk).await
+    }
+
+    /// Ends the body with an error once the queued chunks are sent, so that
+    /// the connection is closed instead of the response looking complete.
+    pub fn abort(self) {
+        self.aborted.store(true, Ordering::Release);
+    }
+}
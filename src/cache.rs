@@ -0,0 +1,1680 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::{Bytes, BytesMut};
+use hyper::header::HeaderMap;
+use hyper::http::response::Builder;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{watch, Semaphore, SemaphorePermit};
+use tracing::{debug, warn};
+
+use crate::cache_io::{CacheFile, CacheIo, CacheIoBackend};
+use crate::metrics::{CacheEvent, CacheEvents};
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct CacheConfig {
+    /// Directory used to store cached objects; repeat to shard the cache across several directories
+    #[arg(long, default_value = "data", env, value_delimiter = ',')]
+    pub cache_dir: Vec<PathBuf>,
+    /// Maximum age in seconds of a cached object before it is refetched
+    #[arg(long, env)]
+    pub cache_max_age: Option<u64>,
+    /// Revalidate cached objects on every request with a GET of their first block conditional on the cached ETag
+    #[arg(long, env)]
+    pub cache_revalidate: bool,
+    /// Size in bytes of the blocks objects are cached in
+    #[arg(long, default_value = "8388608", env)]
+    pub cache_block_size: u64,
+    /// Number of blocks to prefetch after sequential range reads of an object (0 disables readahead)
+    #[arg(long, default_value = "0", env)]
+    pub cache_readahead: u64,
+    /// Age in seconds after which temporary files of interrupted fills are deleted at startup
+    #[arg(long, default_value = "3600", env)]
+    pub cache_temp_max_age: u64,
+    /// Verify the checksum of cached blocks before serving them
+    #[arg(long, env)]
+    pub cache_verify_on_serve: bool,
+    /// Interval in seconds at which all cached blocks are verified in the background
+    #[arg(long, env)]
+    pub cache_verify_interval: Option<u64>,
+    /// Store cached blocks zstd-compressed
+    #[arg(long, env)]
+    pub cache_compress: bool,
+    /// zstd compression level used with --cache-compress
+    #[arg(long, env, default_value_t = 3)]
+    pub cache_compress_level: i32,
+    /// Scope cached objects to the organization of the requesting user
+    #[arg(long, env)]
+    pub cache_tenant_isolation: bool,
+    /// Maximum bytes cached per organization with --cache-tenant-isolation; the oldest entries are evicted beyond it
+    #[arg(long, env)]
+    pub cache_tenant_quota: Option<u64>,
+    /// Maximum age in seconds of a cached object size, including sizes restored at startup
+    #[arg(long, default_value = "86400", env)]
+    pub size_cache_max_age: u64,
+    /// Maximum number of object sizes kept in memory
+    #[arg(long, default_value = "100000", env)]
+    pub size_cache_capacity: usize,
+    /// Interval in seconds at which object sizes are saved to the cache directory and restored from at startup (0 disables persistence)
+    #[arg(long, default_value = "60", env)]
+    pub size_cache_snapshot_interval: u64,
+    /// Free bytes below which no new entries are written to a cache directory's disk and its oldest entries are evicted (0 disables the check)
+    #[arg(long, default_value = "1073741824", env)]
+    pub cache_min_free_space: u64,
+    /// Free bytes that eviction restores on a disk that fell below --cache-min-free-space [default: twice the minimum]
+    #[arg(long, env)]
+    pub cache_target_free_space: Option<u64>,
+    /// Seconds past their max age during which expired cached objects are served when the upstream fails with a 5xx or connection error
+    #[arg(long, env)]
+    pub cache_stale_if_error: Option<u64>,
+    /// Seconds past their max age during which expired cached objects are served immediately while they are revalidated in the background
+    #[arg(long, env)]
+    pub cache_stale_while_revalidate: Option<u64>,
+    /// Objects smaller than this many bytes are streamed through without being cached
+    #[arg(long, default_value = "0", env)]
+    pub cache_min_size: u64,
+    /// Objects larger than this many bytes are streamed through without being cached
+    #[arg(long, env)]
+    pub cache_max_size_per_object: Option<u64>,
+    /// Cached objects of at most this many bytes are read into memory in one go and sent in a single write (0 disables)
+    #[arg(long, default_value = "262144", env)]
+    pub cache_small_object_size: u64,
+    /// Maximum number of blocks fetched from the upstream into the cache at once; further misses wait and prefetches are skipped
+    #[arg(long, env, value_parser = clap::value_parser!(u32).range(1..))]
+    pub cache_max_concurrent_fills: Option<u32>,
+    /// How cached blocks are read and written
+    #[arg(long, value_enum, default_value = "blocking", env)]
+    pub cache_io: CacheIoBackend,
+    /// Bytes of upstream data collected before they are written to the file of a block being filled (0 writes every chunk as it arrives)
+    #[arg(long, default_value = "262144", env)]
+    pub cache_write_buffer_size: u64,
+    /// Stream all objects through without caching them, leaving the filesystem untouched
+    #[arg(long, env)]
+    pub no_cache: bool,
+    /// Don't keep object sizes in memory to answer HEAD requests
+    #[arg(long, env)]
+    pub no_size_cache: bool,
+}
+
+/// File in the first cache directory that the size cache is saved to.
+const SIZE_SNAPSHOT: &str = ".size-cache.json";
+
+/// File written and removed in each cache directory to check that it is
+/// writable.
+const WRITE_PROBE: &str = ".write-probe";
+
+/// Upstream metadata stored in a sidecar file next to each cached object.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct CacheMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Organization the entry is scoped to with tenant isolation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_size: Option<u64>,
+    /// Length of the cached data, recorded when the fill is committed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub len: Option<u64>,
+    /// blake3 checksum of the cached data, recorded when the fill is committed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Encoding of the cached data, `zstd` if it is stored compressed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+    /// Other upstream headers relayed with the object.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<(String, String)>,
+}
+
+impl CacheMetadata {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        CacheMetadata {
+            bucket: None,
+            key: None,
+            tenant: None,
+            etag: get("etag"),
+            content_type: get("content-type"),
+            last_modified: get("last-modified"),
+            object_size: None,
+            len: None,
+            checksum: None,
+            compression: None,
+            headers: Vec::new(),
+        }
+    }
+
+    /// Returns true if the blocks of the object are stored compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.compression.as_deref() == Some(ZSTD)
+    }
+
+    /// Returns the recorded headers, as `apply` adds them to a response.
+    pub fn response_headers(&self) -> Vec<(String, String)> {
+        let headers = [
+            ("etag", &self.etag),
+            ("content-type", &self.content_type),
+            ("last-modified", &self.last_modified),
+        ];
+        headers
+            .into_iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.clone()?)))
+            .chain(self.headers.iter().cloned())
+            .collect()
+    }
+
+    /// Adds the recorded headers to a response.
+    pub fn apply(&self, mut builder: Builder) -> Builder {
+        for (name, value) in self.response_headers() {
+            builder = builder.header(name, value);
+        }
+        builder
+    }
+}
+
+/// A cached block ready to be served.
+pub struct CacheEntry {
+    pub file: CacheFile,
+    pub len: u64,
+    pub metadata: CacheMetadata,
+}
+
+impl CacheEntry {
+    /// Reads and decompresses the whole block of a compressed entry.
+    pub async fn decompress(self) -> std::io::Result<Bytes> {
+        let len = self.file.len().await?;
+        let data = self.file.read_at(0, len as usize).await?;
+        decompress(data.into()).await.map(Bytes::from)
+    }
+}
+
+const ZSTD: &str = "zstd";
+
+async fn compress(data: Vec<u8>, level: i32) -> std::io::Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || zstd::encode_all(&data[..], level)).await?
+}
+
+async fn decompress(data: Vec<u8>) -> std::io::Result<Vec<u8>> {
+    tokio::task::spawn_blocking(move || zstd::decode_all(&data[..])).await?
+}
+
+/// Removes the data and metadata sidecar of the entry at `path`, whose
+/// metadata is `metadata`, from the disk and the counters.
+async fn remove_entry(
+    counters: &CacheCounters,
+    path: &std::path::Path,
+    metadata: &CacheMetadata,
+) -> std::io::Result<()> {
+    let usage = DiskUsage::of(path).await;
+    tokio::fs::remove_file(path).await?;
+    let _ = tokio::fs::remove_file(DiskCache::metadata_path(path)).await;
+    counters.remove_usage(usage);
+    counters.remove_tenant_usage(metadata.tenant.as_deref(), usage.bytes);
+    counters.remove_object_entry(metadata);
+    Ok(())
+}
+
+/// An in-progress cache fill. Data is written to a temporary file that is
+/// atomically renamed into place by `commit`, replacing any expired entry.
+/// A fill that is dropped before being committed leaves its temporary file
+/// and metadata behind so that a later fill can resume it.
+pub struct CacheFill {
+    file: CacheFile,
+    temp_path: PathBuf,
+    path: PathBuf,
+    metadata: CacheMetadata,
+    written: u64,
+    /// Data written but not yet in the file, up to `write_buffer_size`.
+    buffer: BytesMut,
+    write_buffer_size: usize,
+    hasher: blake3::Hasher,
+    compress_level: Option<i32>,
+    guard: FillGuard,
+    counters: Arc<CacheCounters>,
+    started: Instant,
+    /// Bucket of the entry, which a failed fill is counted for.
+    bucket: String,
+    committed: bool,
+}
+
+impl Drop for CacheFill {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.counters
+                .events
+                .record(&self.bucket, CacheEvent::FillFailure);
+        }
+    }
+}
+
+#[derive(Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    corrupt: AtomicU64,
+    fills: AtomicU64,
+    fill_micros_total: AtomicU64,
+    fill_micros_max: AtomicU64,
+    events: CacheEvents,
+    /// Usage of the cache directories, seeded by `recover` and kept up to
+    /// date as entries are committed and removed.
+    entries: AtomicU64,
+    files: AtomicU64,
+    bytes: AtomicU64,
+    /// Bytes of the entries of each tenant, data and metadata sidecars,
+    /// tracked like the usage above so that quotas need no scan.
+    tenants: Mutex<HashMap<String, u64>>,
+    /// Entries of each object by bucket and key, tracked like the usage
+    /// above so that the blocks of an object can be found by name.
+    objects: Mutex<HashMap<(String, String), ObjectEntries>>,
+}
+
+/// Cached blocks of an object, for all tenants.
+#[derive(Clone, Copy, Debug, Default)]
+struct ObjectEntries {
+    entries: u64,
+    /// Largest size of the object among its blocks, which bounds their
+    /// indices.
+    size: u64,
+}
+
+impl CacheCounters {
+    fn record_eviction(&self, bucket: Option<&str>) {
+        self.evictions.fetch_add(1, Ordering::Relaxed);
+        self.events
+            .record(bucket.unwrap_or_default(), CacheEvent::Eviction);
+    }
+
+    fn add_usage(&self, usage: DiskUsage) {
+        self.entries.fetch_add(usage.entries, Ordering::Relaxed);
+        self.files.fetch_add(usage.files, Ordering::Relaxed);
+        self.bytes.fetch_add(usage.bytes, Ordering::Relaxed);
+    }
+
+    /// Subtracts `usage`, stopping at zero in case an entry was removed by
+    /// two tasks at once.
+    fn remove_usage(&self, usage: DiskUsage) {
+        for (counter, value) in [
+            (&self.entries, usage.entries),
+            (&self.files, usage.files),
+            (&self.bytes, usage.bytes),
+        ] {
+            let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                Some(current.saturating_sub(value))
+            });
+        }
+    }
+
+    fn usage(&self) -> DiskUsage {
+        DiskUsage {
+            entries: self.entries.load(Ordering::Relaxed),
+            files: self.files.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add_tenant_usage(&self, tenant: Option<&str>, bytes: u64) {
+        if let Some(tenant) = tenant {
+            *self
+                .tenants
+                .lock()
+                .unwrap()
+                .entry(tenant.to_string())
+                .or_default() += bytes;
+        }
+    }
+
+    /// Subtracts `bytes` from the usage of `tenant`, forgetting tenants that
+    /// have no entries left.
+    fn remove_tenant_usage(&self, tenant: Option<&str>, bytes: u64) {
+        let Some(tenant) = tenant else {
+            return;
+        };
+        let mut tenants = self.tenants.lock().unwrap();
+        if let Some(used) = tenants.get_mut(tenant) {
+            *used = used.saturating_sub(bytes);
+            if *used == 0 {
+                tenants.remove(tenant);
+            }
+        }
+    }
+
+    /// Returns the tenants that have entries.
+    fn tenants(&self) -> Vec<String> {
+        self.tenants.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Counts an entry of the object of `metadata`, which replaces one of
+    /// the same name unless `added`.
+    fn add_object_entry(&self, metadata: &CacheMetadata, added: bool) {
+        let (Some(bucket), Some(key)) = (&metadata.bucket, &metadata.key) else {
+            return;
+        };
+        let mut objects = self.objects.lock().unwrap();
+        let object = objects.entry((bucket.clone(), key.clone())).or_default();
+        object.entries += added as u64;
+        object.size = object.size.max(metadata.object_size.unwrap_or_default());
+    }
+
+    /// Uncounts an entry of the object of `metadata`, forgetting objects that
+    /// have no entries left.
+    fn remove_object_entry(&self, metadata: &CacheMetadata) {
+        let (Some(bucket), Some(key)) = (&metadata.bucket, &metadata.key) else {
+            return;
+        };
+        let mut objects = self.objects.lock().unwrap();
+        let object_key = (bucket.clone(), key.clone());
+        if let Some(object) = objects.get_mut(&object_key) {
+            object.entries = object.entries.saturating_sub(1);
+            if object.entries == 0 {
+                objects.remove(&object_key);
+            }
+        }
+    }
+
+    fn object_size(&self, bucket: &str, key: &str) -> Option<u64> {
+        let objects = self.objects.lock().unwrap();
+        objects
+            .get(&(bucket.to_string(), key.to_string()))
+            .map(|object| object.size)
+    }
+
+    fn tenant_usage(&self, tenant: &str) -> u64 {
+        self.tenants
+            .lock()
+            .unwrap()
+            .get(tenant)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Entries of the cache directories, the files they consist of (data and
+/// metadata sidecars) and their bytes. Temporary files of fills in progress
+/// aren't included.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskUsage {
+    pub entries: u64,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+impl DiskUsage {
+    /// Returns the usage of the entry at `path`, counting only the files
+    /// that exist.
+    async fn of(path: &std::path::Path) -> Self {
+        let mut usage = DiskUsage::default();
+        if let Ok(stat) = tokio::fs::metadata(path).await {
+            usage.entries = 1;
+            usage.files += 1;
+            usage.bytes += stat.len();
+        }
+        if let Ok(stat) = tokio::fs::metadata(DiskCache::metadata_path(path)).await {
+            usage.files += 1;
+            usage.bytes += stat.len();
+        }
+        usage
+    }
+}
+
+/// Blocks of one response served from the cache and fetched upstream,
+/// attached to `GET` responses as an extension.
+#[derive(Debug, Default)]
+pub struct BlockUsage {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockUsage {
+    pub fn record(&self, hit: bool) {
+        match hit {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// Returns `hit` if all blocks were served from the cache, `miss` if none
+    /// were, `partial` otherwise, or `None` if the response used no blocks.
+    pub fn status(&self) -> Option<&'static str> {
+        match (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        ) {
+            (0, 0) => None,
+            (_, 0) => Some("hit"),
+            (0, _) => Some("miss"),
+            _ => Some("partial"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RecoveryReport {
+    pub entries: usize,
+    pub bytes: u64,
+    pub removed_temp: usize,
+    pub removed_corrupt: usize,
+    pub removed_misplaced: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CacheStats {
+    pub entries: u64,
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub corrupt: u64,
+    pub fills: u64,
+    pub fill_ms_avg: f64,
+    pub fill_ms_max: f64,
+}
+
+type InFlight = Arc<Mutex<HashMap<String, InFlightFill>>>;
+
+/// A fill in progress, by the name of its entry.
+struct InFlightFill {
+    receiver: watch::Receiver<bool>,
+    bucket: String,
+    key: String,
+    /// Set when the object is replaced or removed while the fill is running,
+    /// so that the fill doesn't commit data of the earlier version.
+    stale: Arc<AtomicBool>,
+}
+
+/// Exclusive right to fill a cache entry. Waiters are woken when the guard is
+/// dropped, with `true` if the fill was committed.
+pub struct FillGuard {
+    name: String,
+    sender: watch::Sender<bool>,
+    inflight: InFlight,
+    stale: Arc<AtomicBool>,
+}
+
+impl Drop for FillGuard {
+    fn drop(&mut self) {
+        self.inflight.lock().unwrap().remove(&self.name);
+    }
+}
+
+/// Permission to fetch a block from the upstream into the cache, given back
+/// when dropped.
+pub struct FillPermit<'a> {
+    _permit: Option<SemaphorePermit<'a>>,
+}
+
+pub enum FillSlot {
+    /// No fill is in progress; the caller must fetch the object.
+    Leader(FillGuard),
+    /// Another request is filling the entry; resolves to `true` once it is cached.
+    Follower(watch::Receiver<bool>),
+}
+
+impl CacheFill {
+    /// Number of bytes written so far, including those of a resumed fill.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn metadata(&self) -> &CacheMetadata {
+        &self.metadata
+    }
+
+    /// Returns the file of the fill. Data that is still buffered isn't in it
+    /// yet, but none is when the fill has just been opened.
+    pub fn file(&self) -> &CacheFile {
+        &self.file
+    }
+
+    /// Discards any data and metadata of a previous attempt.
+    pub async fn reset(&mut self) -> std::io::Result<()> {
+        self.buffer.clear();
+        self.file.set_len(0).await?;
+        self.written = 0;
+        self.hasher.reset();
+        self.metadata = CacheMetadata::default();
+        match tokio::fs::remove_file(DiskCache::metadata_path(&self.temp_path)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the metadata of the object being filled, which identifies the
+    /// object version when the fill is resumed.
+    pub async fn set_metadata(&mut self, metadata: CacheMetadata) -> std::io::Result<()> {
+        let meta_temp_path = DiskCache::metadata_path(&self.temp_path);
+        tokio::fs::write(&meta_temp_path, serde_json::to_vec(&metadata)?).await?;
+        self.metadata = metadata;
+        Ok(())
+    }
+
+    /// Appends `bytes` to the fill. They are collected until the write
+    /// buffer is full, so that the file gets fewer, larger writes.
+    pub async fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.buffer.extend_from_slice(bytes);
+        self.hasher.update(bytes);
+        self.written += bytes.len() as u64;
+        if self.buffer.len() >= self.write_buffer_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered data to the file.
+    async fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let data = self.buffer.split().freeze();
+        self.buffer.reserve(self.write_buffer_size);
+        let offset = self.written - data.len() as u64;
+        self.file.write_all_at(data, offset).await
+    }
+
+    /// Compresses the written data into a second temporary file, returning
+    /// its path and length, or `None` if compression doesn't make the block
+    /// smaller.
+    async fn compress(&self, level: i32) -> std::io::Result<Option<(PathBuf, u64)>> {
+        let data = tokio::fs::read(&self.temp_path).await?;
+        let len = data.len();
+        let compressed = compress(data, level).await?;
+        if compressed.len() >= len {
+            return Ok(None);
+        }
+        let mut path = self.temp_path.as_os_str().to_owned();
+        path.push(".zst");
+        let path = PathBuf::from(path);
+        let len = compressed.len() as u64;
+        tokio::fs::write(&path, compressed).await?;
+        Ok(Some((path, len)))
+    }
+
+    /// Moves the data and metadata sidecar into place. Returns false, leaving
+    /// nothing behind, if the object was removed from the cache while it was
+    /// being filled.
+    pub async fn commit(mut self) -> std::io::Result<bool> {
+        if self.guard.stale.load(Ordering::Acquire) {
+            self.discard().await;
+            return Ok(false);
+        }
+        self.flush().await?;
+        self.metadata.len = Some(self.written);
+        self.metadata.checksum = Some(self.hasher.finalize().to_hex().to_string());
+        let compressed = match self.compress_level {
+            Some(level) => self.compress(level).await?,
+            None => None,
+        };
+        self.metadata.compression = compressed.as_ref().map(|_| ZSTD.to_string());
+        let meta = serde_json::to_vec(&self.metadata)?;
+        let replaced = DiskUsage::of(&self.path).await;
+        let meta_temp_path = DiskCache::metadata_path(&self.temp_path);
+        tokio::fs::write(&meta_temp_path, &meta).await?;
+        tokio::fs::rename(&meta_temp_path, DiskCache::metadata_path(&self.path)).await?;
+        if replaced.entries > 0 {
+            self.counters
+                .record_eviction(self.metadata.bucket.as_deref());
+        }
+        let len = match &compressed {
+            Some((compressed, len)) => {
+                tokio::fs::rename(compressed, &self.path).await?;
+                tokio::fs::remove_file(&self.temp_path).await?;
+                *len
+            }
+            None => {
+                tokio::fs::rename(&self.temp_path, &self.path).await?;
+                self.written
+            }
+        };
+        let tenant = self.metadata.tenant.as_deref();
+        self.counters.remove_usage(replaced);
+        self.counters.remove_tenant_usage(tenant, replaced.bytes);
+        self.counters.add_usage(DiskUsage {
+            entries: 1,
+            files: 2,
+            bytes: len + meta.len() as u64,
+        });
+        self.counters
+            .add_tenant_usage(tenant, len + meta.len() as u64);
+        self.counters
+            .add_object_entry(&self.metadata, replaced.entries == 0);
+        self.committed = true;
+        // Removed while being moved into place, after `remove_object` looked
+        // for the entry.
+        if self.guard.stale.load(Ordering::Acquire) {
+            match remove_entry(&self.counters, &self.path, &self.metadata).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            return Ok(false);
+        }
+        self.guard.sender.send_replace(true);
+
+        let elapsed = self.started.elapsed().as_micros() as u64;
+        self.counters.fills.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .fill_micros_total
+            .fetch_add(elapsed, Ordering::Relaxed);
+        self.counters
+            .fill_micros_max
+            .fetch_max(elapsed, Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Removes the temporary data and metadata, so that the fill isn't
+    /// resumed either.
+    async fn discard(&mut self) {
+        self.committed = true;
+        let _ = tokio::fs::remove_file(&self.temp_path).await;
+        let _ = tokio::fs::remove_file(DiskCache::metadata_path(&self.temp_path)).await;
+    }
+}
+
+/// An upload staged in a temporary file of the cache directories while it is
+/// streamed upstream, so that it can be cached once the upstream accepted it
+/// without being held in memory. The file is removed when dropped.
+pub struct UploadStage {
+    file: CacheFile,
+    path: PathBuf,
+    written: u64,
+    /// Data written but not yet in the file, up to `write_buffer_size`.
+    buffer: BytesMut,
+    write_buffer_size: usize,
+}
+
+impl UploadStage {
+    /// Number of bytes staged so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Appends `bytes` to the staged upload.
+    pub async fn write(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.buffer.extend_from_slice(bytes);
+        self.written += bytes.len() as u64;
+        if self.buffer.len() >= self.write_buffer_size {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let data = self.buffer.split().freeze();
+        let offset = self.written - data.len() as u64;
+        self.file.write_all_at(data, offset).await
+    }
+
+    /// Reads `len` staged bytes at `offset`.
+    pub async fn read(&mut self, offset: u64, len: usize) -> std::io::Result<Bytes> {
+        self.flush().await?;
+        self.file.read_at(offset, len).await
+    }
+}
+
+impl Drop for UploadStage {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            warn!(path = %self.path.display(), "Failed to remove staged upload: {}", e);
+        }
+    }
+}
+
+/// Returns the number of bytes available to unprivileged users on the file
+/// system holding `path`.
+fn available_space(path: &std::path::Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string and `stat` is only read after
+    // statvfs reports success, which means it has been initialized.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+pub struct DiskCache {
+    config: CacheConfig,
+    io: CacheIo,
+    inflight: InFlight,
+    counters: Arc<CacheCounters>,
+    evicting: AtomicBool,
+    /// Permits of concurrent fills, if they are limited.
+    fills: Option<Semaphore>,
+    /// Number of uploads staged so far, which names their files.
+    uploads: AtomicU64,
+    /// Tenants whose entries are being evicted for their quota.
+    quota_evictions: Mutex<HashSet<String>>,
+}
+
+impl DiskCache {
+    pub fn new(config: CacheConfig) -> Self {
+        let fills = config
+            .cache_max_concurrent_fills
+            .map(|max| Semaphore::new(max as usize));
+        // Nothing is read or written without a cache.
+        let io = CacheIo::new(match config.no_cache {
+            true => CacheIoBackend::Blocking,
+            false => config.cache_io,
+        });
+        DiskCache {
+            config,
+            io,
+            inflight: Arc::new(Mutex::new(HashMap::new())),
+            counters: Arc::new(CacheCounters::default()),
+            evicting: AtomicBool::new(false),
+            fills,
+            uploads: AtomicU64::new(0),
+            quota_evictions: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn record_hit(&self, bucket: &str) {
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        self.counters.events.record(bucket, CacheEvent::Hit);
+    }
+
+    pub fn record_miss(&self, bucket: &str) {
+        self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        self.counters.events.record(bucket, CacheEvent::Miss);
+    }
+
+    /// Returns the hits, misses, evictions and failed fills of blocks by
+    /// bucket since startup.
+    pub fn events(&self) -> &CacheEvents {
+        &self.counters.events
+    }
+
+    /// Returns the usage of the cache directories, as far as this process
+    /// has seen entries being added and removed since `recover`.
+    pub fn usage(&self) -> DiskUsage {
+        self.counters.usage()
+    }
+
+    /// Returns the numbers of hits and misses since startup.
+    pub fn hit_counts(&self) -> (u64, u64) {
+        (
+            self.counters.hits.load(Ordering::Relaxed),
+            self.counters.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Checks that every cache directory is writable by writing and removing
+    /// a small file. Callers must not check concurrently.
+    pub async fn check_writable(&self) -> std::io::Result<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        for dir in &self.config.cache_dir {
+            let path = dir.join(WRITE_PROBE);
+            tokio::fs::write(&path, b"ok").await?;
+            tokio::fs::remove_file(&path).await?;
+        }
+        Ok(())
+    }
+
+    /// Scans the cache directory for its current size and combines it with
+    /// the counters collected since startup.
+    pub async fn stats(&self) -> std::io::Result<CacheStats> {
+        let (mut entries, mut bytes) = (0, 0);
+        for entry in self.entries().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || name.ends_with(".meta") {
+                continue;
+            }
+            // Entries may be removed concurrently while the cache is scanned.
+            let Ok(stat) = entry.metadata().await else {
+                continue;
+            };
+            entries += 1;
+            bytes += stat.len();
+        }
+        let counters = &self.counters;
+        let fills = counters.fills.load(Ordering::Relaxed);
+        let fill_ms_total = counters.fill_micros_total.load(Ordering::Relaxed) as f64 / 1000.0;
+        Ok(CacheStats {
+            entries,
+            bytes,
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            evictions: counters.evictions.load(Ordering::Relaxed),
+            corrupt: counters.corrupt.load(Ordering::Relaxed),
+            fills,
+            fill_ms_avg: if fills > 0 {
+                fill_ms_total / fills as f64
+            } else {
+                0.0
+            },
+            fill_ms_max: counters.fill_micros_max.load(Ordering::Relaxed) as f64 / 1000.0,
+        })
+    }
+
+    fn hash_filename(bucket: &str, key: &str, range: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(format!("{}/{}/{}", bucket, key, range));
+        let result = hasher.finalize();
+        format!("{:x}", result)
+    }
+
+    /// Objects are cached in fixed-size blocks so that any byte range can be
+    /// assembled from, and shares storage with, previously fetched ranges.
+    /// Blocks cached for a tenant are only shared within that tenant.
+    pub fn block_filename(tenant: Option<&str>, bucket: &str, key: &str, index: u64) -> String {
+        match tenant {
+            Some(tenant) => {
+                DiskCache::hash_filename(bucket, key, &format!("block-{}@{}", index, tenant))
+            }
+            None => DiskCache::hash_filename(bucket, key, &format!("block-{}", index)),
+        }
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.config.cache_block_size
+    }
+
+    pub fn readahead(&self) -> u64 {
+        self.config.cache_readahead
+    }
+
+    /// Returns whether an object of `size` bytes is sent from memory when it
+    /// is cached, which requires it to fit in a single block.
+    pub fn is_small(&self, size: u64) -> bool {
+        size <= self.config.cache_small_object_size
+            && size <= self.config.cache_block_size
+            && self.is_cacheable(size)
+    }
+
+    /// Returns false if the disk cache is disabled with `no_cache`.
+    pub fn enabled(&self) -> bool {
+        !self.config.no_cache
+    }
+
+    /// Returns whether objects of `size` bytes are cached, according to
+    /// `cache_min_size` and `cache_max_size_per_object`.
+    pub fn is_cacheable(&self, size: u64) -> bool {
+        self.enabled()
+            && size >= self.config.cache_min_size
+            && self
+                .config
+                .cache_max_size_per_object
+                .is_none_or(|max| size <= max)
+    }
+
+    /// Picks the cache directory of an entry from its (hex-encoded) hash.
+    fn shard(&self, name: &str) -> &std::path::Path {
+        let dirs = &self.config.cache_dir;
+        let hash = name
+            .get(..16)
+            .and_then(|prefix| u64::from_str_radix(prefix, 16).ok())
+            .unwrap_or_default();
+        &dirs[(hash % dirs.len() as u64) as usize]
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.shard(name).join(name)
+    }
+
+    fn temp_path(&self, name: &str) -> PathBuf {
+        self.shard(name).join(format!(".{}", name))
+    }
+
+    /// Lists the files of all cache directories, skipping missing ones.
+    async fn entries(&self) -> std::io::Result<Vec<tokio::fs::DirEntry>> {
+        let mut entries = Vec::new();
+        if !self.enabled() {
+            return Ok(entries);
+        }
+        for dir in &self.config.cache_dir {
+            let mut dir = match tokio::fs::read_dir(dir).await {
+                Ok(dir) => dir,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            while let Some(entry) = dir.next_entry().await? {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn metadata_path(path: &std::path::Path) -> PathBuf {
+        let mut path = path.as_os_str().to_owned();
+        path.push(".meta");
+        PathBuf::from(path)
+    }
+
+    pub fn size_cache_max_age(&self) -> Duration {
+        Duration::from_secs(self.config.size_cache_max_age)
+    }
+
+    /// Capacity of the size cache, zero if it is disabled.
+    pub fn size_cache_capacity(&self) -> usize {
+        match self.config.no_size_cache {
+            true => 0,
+            false => self.config.size_cache_capacity,
+        }
+    }
+
+    /// Location and interval of size cache snapshots, if enabled.
+    pub fn size_snapshot(&self) -> Option<(PathBuf, Duration)> {
+        let interval = self.config.size_cache_snapshot_interval;
+        let enabled = interval > 0 && !self.config.no_cache && !self.config.no_size_cache;
+        enabled.then(|| {
+            (
+                self.config.cache_dir[0].join(SIZE_SNAPSHOT),
+                Duration::from_secs(interval),
+            )
+        })
+    }
+
+    pub fn tenant_isolation(&self) -> bool {
+        self.config.cache_tenant_isolation
+    }
+
+    pub fn revalidate(&self) -> bool {
+        self.config.cache_revalidate
+    }
+
+    pub fn stale_if_error(&self) -> Option<Duration> {
+        self.config.cache_stale_if_error.map(Duration::from_secs)
+    }
+
+    pub fn stale_while_revalidate(&self) -> Option<Duration> {
+        self.config
+            .cache_stale_while_revalidate
+            .map(Duration::from_secs)
+    }
+
+    /// Returns true if an entry last modified at `modified` is older than the
+    /// max age plus `grace`.
+    fn is_expired(&self, modified: SystemTime, grace: Duration) -> bool {
+        match self.config.cache_max_age {
+            Some(max_age) => {
+                let age = SystemTime::now()
+                    .duration_since(modified)
+                    .unwrap_or_default();
+                age > Duration::from_secs(max_age) + grace
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the size and metadata of a cached block without opening it,
+    /// treating expired entries as misses. The size of compressed blocks is
+    /// their uncompressed length.
+    pub async fn head(&self, name: &str) -> Option<(u64, CacheMetadata)> {
+        self.lookup(name, Duration::ZERO).await
+    }
+
+    /// Like `head`, but also returns entries that expired less than `grace`
+    /// ago.
+    pub async fn head_stale(&self, name: &str, grace: Duration) -> Option<(u64, CacheMetadata)> {
+        self.lookup(name, grace).await
+    }
+
+    async fn lookup(&self, name: &str, grace: Duration) -> Option<(u64, CacheMetadata)> {
+        if !self.enabled() {
+            return None;
+        }
+        let path = self.path(name);
+        let stat = tokio::fs::metadata(&path).await.ok()?;
+        if self.is_expired(stat.modified().ok()?, grace) {
+            debug!(name, "Cache entry expired");
+            return None;
+        }
+        let metadata = DiskCache::read_metadata(&path).await;
+        let len = if metadata.is_compressed() {
+            metadata.len?
+        } else {
+            stat.len()
+        };
+        Some((len, metadata))
+    }
+
+    async fn read_metadata(path: &std::path::Path) -> CacheMetadata {
+        // Entries written before metadata was recorded have no sidecar.
+        tokio::fs::read(DiskCache::metadata_path(path))
+            .await
+            .ok()
+            .and_then(|m| serde_json::from_slice(&m).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn verify_interval(&self) -> Option<Duration> {
+        self.config
+            .cache_verify_interval
+            .filter(|_| self.enabled())
+            .map(Duration::from_secs)
+    }
+
+    /// Checks cached data against its recorded checksum, removing the entry if
+    /// it doesn't match. Entries without a checksum are assumed to be intact.
+    async fn verify(&self, path: &std::path::Path, metadata: &CacheMetadata) -> bool {
+        let Some(expected) = &metadata.checksum else {
+            return true;
+        };
+        let data = match tokio::fs::read(path).await {
+            Ok(data) if metadata.is_compressed() => decompress(data).await,
+            data => data,
+        };
+        let intact = match data {
+            Ok(data) => blake3::hash(&data).to_hex().as_str() == expected,
+            Err(_) => false,
+        };
+        if !intact {
+            warn!(path = %path.display(), "Removing cache entry with checksum mismatch");
+            let _ = self.remove_entry(path, metadata).await;
+            self.counters.corrupt.fetch_add(1, Ordering::Relaxed);
+        }
+        intact
+    }
+
+    /// Verifies every cached entry, returning the number of corrupt entries
+    /// that were removed.
+    pub async fn scrub(&self) -> std::io::Result<usize> {
+        let mut removed = 0;
+        for entry in self.entries().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || name.ends_with(".meta") {
+                continue;
+            }
+            let path = entry.path();
+            let metadata = DiskCache::read_metadata(&path).await;
+            if !self.verify(&path, &metadata).await {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Prepares the cache directories at startup: creates them if needed,
+    /// deletes temporary files of fills interrupted longer than
+    /// `cache_temp_max_age` ago and drops entries whose data and metadata don't
+    /// match or that belong to another directory after the set of directories
+    /// changed.
+    pub async fn recover(&self) -> std::io::Result<RecoveryReport> {
+        for dir in &self.config.cache_dir {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let temp_max_age = Duration::from_secs(self.config.cache_temp_max_age);
+        let mut report = RecoveryReport::default();
+        let mut tenants = HashMap::<String, u64>::new();
+        let mut objects = HashMap::<(String, String), ObjectEntries>::new();
+        for entry in self.entries().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+            // Sidecars of entries removed earlier in the scan are gone.
+            let stat = match entry.metadata().await {
+                Ok(stat) => stat,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if name == SIZE_SNAPSHOT {
+                continue;
+            }
+            if name.starts_with('.') {
+                let age = stat
+                    .modified()
+                    .ok()
+                    .and_then(|m| SystemTime::now().duration_since(m).ok())
+                    .unwrap_or_default();
+                if age > temp_max_age {
+                    debug!(name, "Removing stale temporary cache file");
+                    tokio::fs::remove_file(&path).await?;
+                    report.removed_temp += 1;
+                }
+                continue;
+            }
+            if let Some(data) = name.strip_suffix(".meta") {
+                if !tokio::fs::try_exists(path.with_file_name(data)).await? {
+                    debug!(name, "Removing metadata without cached data");
+                    tokio::fs::remove_file(&path).await?;
+                }
+                continue;
+            }
+            let meta = tokio::fs::read(DiskCache::metadata_path(&path)).await.ok();
+            let meta_len = meta.as_ref().map_or(0, |meta| meta.len() as u64);
+            let metadata = meta.and_then(|m| serde_json::from_slice::<CacheMetadata>(&m).ok());
+            // The length of compressed entries is checked by verification.
+            let valid = metadata.as_ref().is_some_and(|metadata| {
+                metadata.is_compressed() || metadata.len.is_none_or(|len| len == stat.len())
+            });
+            if path != self.path(&name) {
+                debug!(name, "Removing cache entry of another cache directory");
+                tokio::fs::remove_file(&path).await?;
+                let _ = tokio::fs::remove_file(DiskCache::metadata_path(&path)).await;
+                report.removed_misplaced += 1;
+            } else if let Some(metadata) = metadata.filter(|_| valid) {
+                report.entries += 1;
+                report.bytes += stat.len() + meta_len;
+                if let (Some(bucket), Some(key)) = (metadata.bucket, metadata.key) {
+                    let object = objects.entry((bucket, key)).or_default();
+                    object.entries += 1;
+                    object.size = object.size.max(metadata.object_size.unwrap_or_default());
+                }
+                if let Some(tenant) = metadata.tenant {
+                    *tenants.entry(tenant).or_default() += stat.len() + meta_len;
+                }
+            } else {
+                warn!(name, "Removing corrupt cache entry");
+                tokio::fs::remove_file(&path).await?;
+                let _ = tokio::fs::remove_file(DiskCache::metadata_path(&path)).await;
+                report.removed_corrupt += 1;
+            }
+        }
+        // Every entry left has its data and metadata sidecar.
+        self.counters.remove_usage(self.counters.usage());
+        self.counters.add_usage(DiskUsage {
+            entries: report.entries as u64,
+            files: report.entries as u64 * 2,
+            bytes: report.bytes,
+        });
+        *self.counters.tenants.lock().unwrap() = tenants;
+        *self.counters.objects.lock().unwrap() = objects;
+        Ok(report)
+    }
+
+    /// Removes cached objects in `bucket` (or any bucket) whose key starts with
+    /// `prefix`, returning the number of removed entries.
+    pub async fn purge(&self, bucket: Option<&str>, prefix: &str) -> std::io::Result<usize> {
+        self.remove_where(|metadata| match (&metadata.bucket, &metadata.key) {
+            (Some(b), Some(k)) => bucket.is_none_or(|bucket| bucket == b) && k.starts_with(prefix),
+            // Entries without a recorded location only match a full purge.
+            _ => bucket.is_none() && prefix.is_empty(),
+        })
+        .await
+    }
+
+    /// Removes the cached blocks of an object for all tenants, returning the
+    /// number of removed entries. The blocks are looked up by name, up to the
+    /// largest size of the object that is cached, for each tenant with
+    /// entries.
+    pub async fn remove_object(&self, bucket: &str, key: &str) -> std::io::Result<usize> {
+        self.invalidate_fills(bucket, key);
+        let Some(size) = self.counters.object_size(bucket, key) else {
+            return Ok(0);
+        };
+        let blocks = size.div_ceil(self.config.cache_block_size).max(1);
+        let tenants = self.counters.tenants();
+        let tenants = std::iter::once(None).chain(tenants.iter().map(|t| Some(t.as_str())));
+        let mut removed = 0;
+        for tenant in tenants {
+            for index in 0..blocks {
+                let path = self.path(&DiskCache::block_filename(tenant, bucket, key, index));
+                if !tokio::fs::try_exists(&path).await? {
+                    continue;
+                }
+                let metadata = DiskCache::read_metadata(&path).await;
+                match self.remove_entry(&path, &metadata).await {
+                    Ok(()) => {}
+                    // Removed concurrently.
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                }
+                self.counters.record_eviction(Some(bucket));
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    async fn remove_where(
+        &self,
+        predicate: impl Fn(&CacheMetadata) -> bool,
+    ) -> std::io::Result<usize> {
+        let mut removed = 0;
+        for entry in self.entries().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || name.ends_with(".meta") {
+                continue;
+            }
+            let path = entry.path();
+            let metadata = DiskCache::read_metadata(&path).await;
+            if predicate(&metadata) {
+                self.remove_entry(&path, &metadata).await?;
+                self.counters.record_eviction(metadata.bucket.as_deref());
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Returns whether the disk holding the cache directory of `name` has room
+    /// for new entries. When it doesn't, the oldest entries of the directory
+    /// are evicted in the background.
+    pub fn has_space(self: &Arc<Self>, name: &str) -> bool {
+        let min_free = self.config.cache_min_free_space;
+        if min_free == 0 {
+            return true;
+        }
+        match available_space(self.shard(name)) {
+            Ok(free) if free >= min_free => true,
+            Ok(_) => {
+                self.reclaim_space(name);
+                false
+            }
+            Err(e) => {
+                warn!("Failed to check free disk space: {}", e);
+                true
+            }
+        }
+    }
+
+    /// Evicts the oldest entries of the cache directory of `name` in the
+    /// background until the target free space is reached, unless an eviction
+    /// is already running.
+    pub fn reclaim_space(self: &Arc<Self>, name: &str) {
+        if self.evicting.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let cache = self.clone();
+        let dir = self.shard(name).to_path_buf();
+        tokio::spawn(async move {
+            match cache.evict_for_space(&dir).await {
+                Ok(0) => debug!(dir = %dir.display(), "No cache entries left to free disk space"),
+                Ok(evicted) => warn!(
+                    dir = %dir.display(),
+                    evicted,
+                    "Evicted cache entries to free disk space"
+                ),
+                Err(e) => warn!(dir = %dir.display(), "Failed to free disk space: {}", e),
+            }
+            cache.evicting.store(false, Ordering::Release);
+        });
+    }
+
+    async fn evict_for_space(&self, dir: &std::path::Path) -> std::io::Result<usize> {
+        let target = self
+            .config
+            .cache_target_free_space
+            .unwrap_or(self.config.cache_min_free_space.saturating_mul(2));
+        let mut candidates = Vec::new();
+        let mut entries = tokio::fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || name.ends_with(".meta") {
+                continue;
+            }
+            if let Ok(stat) = entry.metadata().await {
+                candidates.push((stat.modified()?, entry.path()));
+            }
+        }
+        candidates.sort();
+        let mut evicted = 0;
+        for (_, path) in candidates {
+            if available_space(dir)? >= target {
+                break;
+            }
+            let metadata = DiskCache::read_metadata(&path).await;
+            self.remove_entry(&path, &metadata).await?;
+            self.counters.record_eviction(metadata.bucket.as_deref());
+            evicted += 1;
+        }
+        Ok(evicted)
+    }
+
+    /// Checks the usage of `tenant` against the configured quota. When it is
+    /// exceeded, the oldest entries of the tenant are evicted in the
+    /// background, unless an eviction for the tenant is already running.
+    pub fn enforce_quota(self: &Arc<Self>, tenant: &str) {
+        let Some(quota) = self.config.cache_tenant_quota else {
+            return;
+        };
+        if self.counters.tenant_usage(tenant) <= quota {
+            return;
+        }
+        if !self
+            .quota_evictions
+            .lock()
+            .unwrap()
+            .insert(tenant.to_string())
+        {
+            return;
+        }
+        let cache = self.clone();
+        let tenant = tenant.to_string();
+        tokio::spawn(async move {
+            match cache.evict_over_quota(&tenant, quota).await {
+                Ok(evicted) => debug!(tenant, evicted, "Evicted cache entries over tenant quota"),
+                Err(e) => warn!(tenant, "Failed to enforce tenant quota: {}", e),
+            }
+            cache.quota_evictions.lock().unwrap().remove(&tenant);
+        });
+    }
+
+    /// Evicts the oldest entries of `tenant` until it uses no more than
+    /// `quota`, returning the number of evicted entries.
+    async fn evict_over_quota(&self, tenant: &str, quota: u64) -> std::io::Result<usize> {
+        let mut owned = Vec::new();
+        for entry in self.entries().await? {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.starts_with('.') || name.ends_with(".meta") {
+                continue;
+            }
+            let path = entry.path();
+            let metadata = DiskCache::read_metadata(&path).await;
+            if metadata.tenant.as_deref() != Some(tenant) {
+                continue;
+            }
+            let Ok(stat) = entry.metadata().await else {
+                continue;
+            };
+            owned.push((stat.modified()?, path, metadata));
+        }
+        owned.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        let mut evicted = 0;
+        for (_, path, metadata) in owned {
+            if self.counters.tenant_usage(tenant) <= quota {
+                break;
+            }
+            debug!(tenant, path = %path.display(), "Evicting cache entry over tenant quota");
+            self.remove_entry(&path, &metadata).await?;
+            self.counters.record_eviction(metadata.bucket.as_deref());
+            evicted += 1;
+        }
+        Ok(evicted)
+    }
+
+    /// Removes the data and metadata sidecar of the entry at `path`, whose
+    /// metadata is `metadata`.
+    async fn remove_entry(
+        &self,
+        path: &std::path::Path,
+        metadata: &CacheMetadata,
+    ) -> std::io::Result<()> {
+        remove_entry(&self.counters, path, metadata).await
+    }
+
+    /// Keeps the fills of an object that are in progress from committing,
+    /// since they may have fetched an earlier version of it.
+    fn invalidate_fills(&self, bucket: &str, key: &str) {
+        for fill in self.inflight.lock().unwrap().values() {
+            if fill.bucket == bucket && fill.key == key {
+                fill.stale.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Opens the cached block, treating entries that expired more than `grace`
+    /// ago as misses.
+    pub async fn get_stale(&self, name: &str, grace: Duration) -> Option<CacheEntry> {
+        let found = self.head_stale(name, grace).await;
+        self.open(name, found).await
+    }
+
+    /// Marks an entry as freshly fetched, restarting its max age. Returns
+    /// false if there is no such entry.
+    pub async fn touch(&self, name: &str) -> std::io::Result<bool> {
+        let path = self.path(name);
+        let touched = tokio::task::spawn_blocking(move || {
+            std::fs::File::options()
+                .write(true)
+                .open(path)?
+                .set_modified(SystemTime::now())
+        })
+        .await?;
+        match touched {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn open(&self, name: &str, found: Option<(u64, CacheMetadata)>) -> Option<CacheEntry> {
+        let (len, metadata) = found?;
+        if self.config.cache_verify_on_serve && !self.verify(&self.path(name), &metadata).await {
+            return None;
+        }
+        let file = self.io.open(&self.path(name)).await.ok()?;
+        Some(CacheEntry {
+            file,
+            len,
+            metadata,
+        })
+    }
+
+    /// Claims the fill of an entry of `bucket/key` so that concurrent misses
+    /// for the same object result in a single upstream download.
+    pub fn claim(&self, name: &str, bucket: &str, key: &str) -> FillSlot {
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(fill) = inflight.get(name) {
+            return FillSlot::Follower(fill.receiver.clone());
+        }
+        let (sender, receiver) = watch::channel(false);
+        let stale = Arc::new(AtomicBool::new(false));
+        inflight.insert(
+            name.to_string(),
+            InFlightFill {
+                receiver,
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                stale: stale.clone(),
+            },
+        );
+        FillSlot::Leader(FillGuard {
+            name: name.to_string(),
+            sender,
+            inflight: self.inflight.clone(),
+            stale,
+        })
+    }
+
+    /// Waits until a fill from the upstream may start.
+    pub async fn acquire_fill(&self) -> FillPermit<'_> {
+        let Some(fills) = &self.fills else {
+            return FillPermit { _permit: None };
+        };
+        if fills.available_permits() == 0 {
+            debug!("Waiting for other cache fills to finish");
+        }
+        // The semaphore is never closed.
+        FillPermit {
+            _permit: fills.acquire().await.ok(),
+        }
+    }
+
+    /// Returns a permit to start a fill from the upstream, or `None` if as
+    /// many fills as allowed are running.
+    pub fn try_acquire_fill(&self) -> Option<FillPermit<'_>> {
+        match &self.fills {
+            Some(fills) => fills.try_acquire().ok().map(|permit| FillPermit {
+                _permit: Some(permit),
+            }),
+            None => Some(FillPermit { _permit: None }),
+        }
+    }
+
+    /// Stores `data` as a complete entry, unless the entry is currently being
+    /// filled. Returns whether the entry was written.
+    pub async fn insert(
+        &self,
+        name: &str,
+        metadata: CacheMetadata,
+        data: &[u8],
+    ) -> std::io::Result<bool> {
+        let bucket = metadata.bucket.clone().unwrap_or_default();
+        let key = metadata.key.clone().unwrap_or_default();
+        let FillSlot::Leader(guard) = self.claim(name, &bucket, &key) else {
+            return Ok(false);
+        };
+        let mut fill = self.open_fill(&bucket, guard).await?;
+        fill.reset().await?;
+        fill.set_metadata(metadata).await?;
+        fill.write(data).await?;
+        fill.commit().await
+    }
+
+    /// Creates a temporary file to stage an upload of `bucket/key` in. Like
+    /// those of fills, it is removed at startup once it is older than
+    /// `cache_temp_max_age`.
+    pub async fn stage_upload(&self, bucket: &str, key: &str) -> std::io::Result<UploadStage> {
+        let upload = self.uploads.fetch_add(1, Ordering::Relaxed);
+        let name = DiskCache::hash_filename(
+            bucket,
+            key,
+            &format!("upload-{}-{}", std::process::id(), upload),
+        );
+        let path = self.temp_path(&name);
+        let file = self.io.open_writable(&path).await?;
+        file.set_len(0).await?;
+        Ok(UploadStage {
+            file,
+            path,
+            written: 0,
+            buffer: BytesMut::new(),
+            write_buffer_size: self.config.cache_write_buffer_size as usize,
+        })
+    }
+
+    /// Opens the temporary file of an entry for filling. Data and metadata
+    /// left behind by an interrupted fill are kept, so callers can either
+    /// resume from `written()` or `reset()` the fill.
+    pub async fn open_fill(&self, bucket: &str, guard: FillGuard) -> std::io::Result<CacheFill> {
+        let temp_path = self.temp_path(&guard.name);
+        let file = self.io.open_writable(&temp_path).await?;
+        let written = file.len().await?;
+        let metadata = DiskCache::read_metadata(&temp_path).await;
+        let mut hasher = blake3::Hasher::new();
+        if written > 0 {
+            hasher.update(&tokio::fs::read(&temp_path).await?);
+        }
+        Ok(CacheFill {
+            file,
+            temp_path,
+            path: self.path(&guard.name),
+            metadata,
+            written,
+            buffer: BytesMut::new(),
+            write_buffer_size: self.config.cache_write_buffer_size as usize,
+            hasher,
+            compress_level: self
+                .config
+                .cache_compress
+                .then_some(self.config.cache_compress_level),
+            guard,
+            counters: self.counters.clone(),
+            started: Instant::now(),
+            bucket: bucket.to_string(),
+            committed: false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        config: CacheConfig,
+    }
+
+    /// Returns a recovered cache in a fresh directory, which is returned for
+    /// the test to remove.
+    async fn cache(name: &str, args: &[&str]) -> (Arc<DiskCache>, PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("s3proxy-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cli = Cli::parse_from(
+            ["s3proxy", "--cache-dir", dir.to_str().unwrap()]
+                .iter()
+                .chain(args),
+        );
+        let cache = Arc::new(DiskCache::new(cli.config));
+        cache.recover().await.unwrap();
+        (cache, dir)
+    }
+
+    /// Caches block `index` of a 3000 byte object.
+    async fn insert(cache: &DiskCache, tenant: Option<&str>, key: &str, index: u64) -> String {
+        let name = DiskCache::block_filename(tenant, "b", key, index);
+        let metadata = CacheMetadata {
+            bucket: Some("b".to_string()),
+            key: Some(key.to_string()),
+            tenant: tenant.map(str::to_string),
+            object_size: Some(3000),
+            ..CacheMetadata::default()
+        };
+        assert!(cache.insert(&name, metadata, &[0; 1000]).await.unwrap());
+        name
+    }
+
+    #[tokio::test]
+    async fn tenant_quota() {
+        let (cache, dir) = cache("quota", &["--cache-tenant-quota", "2500"]).await;
+        let mut names = Vec::new();
+        for key in ["k1", "k2", "k3"] {
+            names.push(insert(&cache, Some("t"), key, 0).await);
+        }
+        let other = insert(&cache, Some("u"), "k1", 0).await;
+        let used = cache.counters.tenant_usage("t");
+        assert!(used > 2500);
+
+        // Usage is seeded from the entries on disk at startup.
+        let recovered = DiskCache::new(cache.config.clone());
+        recovered.recover().await.unwrap();
+        assert_eq!(recovered.counters.tenant_usage("t"), used);
+
+        cache.enforce_quota("t");
+        while !cache.quota_evictions.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(cache.counters.tenant_usage("t") <= 2500);
+        assert!(cache.head(&names[0]).await.is_none());
+        assert!(cache.head(&names[2]).await.is_some());
+        assert!(cache.head(&other).await.is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn remove_object() {
+        let (cache, dir) = cache("remove", &["--cache-block-size", "1000"]).await;
+        // Blocks are cached sparsely, by range requests of several tenants.
+        let mut names = Vec::new();
+        for (tenant, index) in [(None, 2), (Some("t"), 0), (Some("t"), 2), (Some("u"), 1)] {
+            names.push(insert(&cache, tenant, "k", index).await);
+        }
+        let other = insert(&cache, None, "other", 0).await;
+        assert_eq!(cache.usage().entries, 5);
+
+        assert_eq!(cache.remove_object("b", "k").await.unwrap(), 4);
+        for name in &names {
+            assert!(cache.head(name).await.is_none());
+        }
+        assert!(cache.head(&other).await.is_some());
+        assert_eq!(cache.usage().entries, 1);
+        assert_eq!(cache.remove_object("b", "k").await.unwrap(), 0);
+        assert!(cache.counters.tenants().is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,469 @@
+use std::future::Future;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// How cache files are opened, read and written.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheIoBackend {
+    /// System calls on Tokio's blocking thread pool
+    Blocking,
+    /// Submissions to an io_uring from a dedicated thread; Linux only, requires the io-uring feature
+    IoUring,
+}
+
+impl CacheIoBackend {
+    /// Returns whether this build can use the backend.
+    pub fn supported(self) -> bool {
+        match self {
+            CacheIoBackend::Blocking => true,
+            CacheIoBackend::IoUring => ring::SUPPORTED,
+        }
+    }
+}
+
+/// Opens cache files with the configured backend.
+pub struct CacheIo {
+    ring: Option<ring::Ring>,
+}
+
+impl CacheIo {
+    /// Sets up `backend`. If the kernel doesn't allow io_uring, e.g. in
+    /// containers whose seccomp profile blocks it, the blocking thread pool
+    /// is used instead.
+    pub fn new(backend: CacheIoBackend) -> Self {
+        let ring = match backend {
+            CacheIoBackend::Blocking => None,
+            CacheIoBackend::IoUring => match ring::Ring::start() {
+                Ok(ring) => {
+                    info!("Cache I/O uses io_uring");
+                    Some(ring)
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to set up io_uring, cache I/O uses the blocking thread pool: {}",
+                        e
+                    );
+                    None
+                }
+            },
+        };
+        CacheIo { ring }
+    }
+
+    /// Opens a file for reading.
+    pub async fn open(&self, path: &Path) -> io::Result<CacheFile> {
+        self.open_with(path, false).await
+    }
+
+    /// Opens a file for reading and writing, creating it if it doesn't exist.
+    pub async fn open_writable(&self, path: &Path) -> io::Result<CacheFile> {
+        self.open_with(path, true).await
+    }
+
+    async fn open_with(&self, path: &Path, write: bool) -> io::Result<CacheFile> {
+        let path = path.to_path_buf();
+        if let Some(ring) = &self.ring {
+            let (file, ring) = ring.open(path, write).await?;
+            return Ok(CacheFile {
+                file: Arc::new(file),
+                ring: Some(ring),
+            });
+        }
+        let file = tokio::task::spawn_blocking(move || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(write)
+                .create(write)
+                .open(path)
+        })
+        .await??;
+        Ok(CacheFile {
+            file: Arc::new(file),
+            ring: None,
+        })
+    }
+}
+
+/// A file in the cache directories. Reads and writes are positional and
+/// start as soon as they are requested, so that the next chunk of a file can
+/// be read while the previous one is sent.
+pub struct CacheFile {
+    file: Arc<std::fs::File>,
+    ring: Option<ring::RingFile>,
+}
+
+impl CacheFile {
+    /// Reads exactly `len` bytes at `offset`.
+    pub fn read_at(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> impl Future<Output = io::Result<Bytes>> + Send + 'static {
+        let pending = match &self.ring {
+            Some(ring) => Pending::Ring(ring.read(offset, len)),
+            None => {
+                let file = self.file.clone();
+                Pending::Blocking(tokio::task::spawn_blocking(move || {
+                    let mut buf = vec![0; len];
+                    file.read_exact_at(&mut buf, offset)?;
+                    Ok(Bytes::from(buf))
+                }))
+            }
+        };
+        pending.wait()
+    }
+
+    /// Writes all of `data` at `offset`.
+    pub fn write_all_at(
+        &self,
+        data: Bytes,
+        offset: u64,
+    ) -> impl Future<Output = io::Result<()>> + Send + 'static {
+        let pending = match &self.ring {
+            Some(ring) => Pending::Ring(ring.write(data, offset)),
+            None => {
+                let file = self.file.clone();
+                Pending::Blocking(tokio::task::spawn_blocking(move || {
+                    file.write_all_at(&data, offset)
+                }))
+            }
+        };
+        pending.wait()
+    }
+
+    /// Returns the length of the file.
+    pub async fn len(&self) -> io::Result<u64> {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || file.metadata().map(|metadata| metadata.len())).await?
+    }
+
+    /// Truncates or extends the file to `len` bytes.
+    pub async fn set_len(&self, len: u64) -> io::Result<()> {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || file.set_len(len)).await?
+    }
+}
+
+/// An operation running on the blocking thread pool or the io_uring thread.
+enum Pending<T> {
+    Blocking(JoinHandle<io::Result<T>>),
+    Ring(oneshot::Receiver<io::Result<T>>),
+}
+
+impl<T> Pending<T> {
+    async fn wait(self) -> io::Result<T> {
+        match self {
+            Pending::Blocking(task) => task.await?,
+            Pending::Ring(reply) => reply
+                .await
+                .map_err(|_| io::Error::other("the io_uring thread has stopped"))?,
+        }
+    }
+}
+
+/// The io_uring backend. A dedicated thread runs a tokio-uring runtime,
+/// which owns the files opened through it, and submits the operations sent
+/// to it from the worker threads.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod ring {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::io;
+    use std::os::fd::{AsRawFd, BorrowedFd};
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use bytes::Bytes;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio_uring::buf::IoBuf;
+
+    pub const SUPPORTED: bool = true;
+
+    /// Submission queue entries of the io_uring, which bounds the operations
+    /// submitted at once; further ones wait for a free entry.
+    const RING_ENTRIES: u32 = 1024;
+
+    type Files = Rc<RefCell<HashMap<u64, Rc<tokio_uring::fs::File>>>>;
+
+    enum Op {
+        Open {
+            id: u64,
+            path: PathBuf,
+            write: bool,
+            reply: oneshot::Sender<io::Result<std::fs::File>>,
+        },
+        Close(u64),
+        Read {
+            id: u64,
+            offset: u64,
+            len: usize,
+            reply: oneshot::Sender<io::Result<Bytes>>,
+        },
+        Write {
+            id: u64,
+            offset: u64,
+            data: Bytes,
+            reply: oneshot::Sender<io::Result<()>>,
+        },
+    }
+
+    pub struct Ring {
+        ops: mpsc::UnboundedSender<Op>,
+        next_id: AtomicU64,
+    }
+
+    impl Ring {
+        /// Starts the io_uring thread, failing if the kernel doesn't allow
+        /// setting up an io_uring.
+        pub fn start() -> io::Result<Ring> {
+            let (ops, receiver) = mpsc::unbounded_channel();
+            let (started, result) = std::sync::mpsc::channel();
+            std::thread::Builder::new()
+                .name("cache-io-uring".to_string())
+                .spawn(move || {
+                    let mut builder = tokio_uring::builder();
+                    builder.entries(RING_ENTRIES);
+                    match tokio_uring::Runtime::new(&builder) {
+                        Ok(runtime) => {
+                            let _ = started.send(Ok(()));
+                            runtime.block_on(run(receiver));
+                        }
+                        Err(e) => {
+                            let _ = started.send(Err(e));
+                        }
+                    }
+                })?;
+            result
+                .recv()
+                .map_err(|_| io::Error::other("the io_uring thread has stopped"))??;
+            Ok(Ring {
+                ops,
+                next_id: AtomicU64::new(0),
+            })
+        }
+
+        /// Opens a file on the io_uring thread. Alongside the handle for
+        /// operations on the io_uring, it returns a duplicate of the file
+        /// for the rare operations that io_uring doesn't support.
+        pub async fn open(
+            &self,
+            path: PathBuf,
+            write: bool,
+        ) -> io::Result<(std::fs::File, RingFile)> {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let (reply, opened) = oneshot::channel();
+            self.submit(Op::Open {
+                id,
+                path,
+                write,
+                reply,
+            });
+            let file = opened
+                .await
+                .map_err(|_| io::Error::other("the io_uring thread has stopped"))??;
+            Ok((
+                file,
+                RingFile {
+                    id,
+                    ops: self.ops.clone(),
+                },
+            ))
+        }
+
+        fn submit(&self, op: Op) {
+            // If the thread stopped, the reply sender is dropped with the op.
+            let _ = self.ops.send(op);
+        }
+    }
+
+    /// A file opened on the io_uring thread, which closes it once it is
+    /// dropped and the operations on it are done.
+    pub struct RingFile {
+        id: u64,
+        ops: mpsc::UnboundedSender<Op>,
+    }
+
+    impl RingFile {
+        pub fn read(&self, offset: u64, len: usize) -> oneshot::Receiver<io::Result<Bytes>> {
+            let (reply, receiver) = oneshot::channel();
+            let _ = self.ops.send(Op::Read {
+                id: self.id,
+                offset,
+                len,
+                reply,
+            });
+            receiver
+        }
+
+        pub fn write(&self, data: Bytes, offset: u64) -> oneshot::Receiver<io::Result<()>> {
+            let (reply, receiver) = oneshot::channel();
+            let _ = self.ops.send(Op::Write {
+                id: self.id,
+                offset,
+                data,
+                reply,
+            });
+            receiver
+        }
+    }
+
+    impl Drop for RingFile {
+        fn drop(&mut self) {
+            let _ = self.ops.send(Op::Close(self.id));
+        }
+    }
+
+    /// Runs the operations sent to the thread until all senders are gone.
+    async fn run(mut ops: mpsc::UnboundedReceiver<Op>) {
+        let files: Files = Rc::default();
+        while let Some(op) = ops.recv().await {
+            match op {
+                Op::Open {
+                    id,
+                    path,
+                    write,
+                    reply,
+                } => {
+                    let files = files.clone();
+                    tokio_uring::spawn(async move {
+                        let opened = open(&files, id, path, write).await;
+                        // Nobody holds a handle if the open was abandoned.
+                        if reply.send(opened).is_err() {
+                            files.borrow_mut().remove(&id);
+                        }
+                    });
+                }
+                Op::Close(id) => {
+                    files.borrow_mut().remove(&id);
+                }
+                Op::Read {
+                    id,
+                    offset,
+                    len,
+                    reply,
+                } => {
+                    let Some(file) = files.borrow().get(&id).cloned() else {
+                        continue;
+                    };
+                    tokio_uring::spawn(async move {
+                        let _ = reply.send(read_exact_at(&file, offset, len).await);
+                    });
+                }
+                Op::Write {
+                    id,
+                    offset,
+                    data,
+                    reply,
+                } => {
+                    let Some(file) = files.borrow().get(&id).cloned() else {
+                        continue;
+                    };
+                    tokio_uring::spawn(async move {
+                        let _ = reply.send(write_all_at(&file, data, offset).await);
+                    });
+                }
+            }
+        }
+    }
+
+    async fn open(files: &Files, id: u64, path: PathBuf, write: bool) -> io::Result<std::fs::File> {
+        let file = tokio_uring::fs::OpenOptions::new()
+            .read(true)
+            .write(write)
+            .create(write)
+            .open(path)
+            .await?;
+        // SAFETY: the descriptor belongs to `file`, which is open.
+        let duplicate = unsafe { BorrowedFd::borrow_raw(file.as_raw_fd()) }.try_clone_to_owned()?;
+        files.borrow_mut().insert(id, Rc::new(file));
+        Ok(duplicate.into())
+    }
+
+    async fn read_exact_at(
+        file: &tokio_uring::fs::File,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<Bytes> {
+        let mut buf = Vec::with_capacity(len);
+        while buf.len() < len {
+            let filled = buf.len();
+            let (read, slice) = file
+                .read_at(buf.slice(filled..), offset + filled as u64)
+                .await;
+            buf = slice.into_inner();
+            if read? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        Ok(Bytes::from(buf))
+    }
+
+    async fn write_all_at(
+        file: &tokio_uring::fs::File,
+        mut data: Bytes,
+        mut offset: u64,
+    ) -> io::Result<()> {
+        while !data.is_empty() {
+            let (written, buf) = file.write_at(data, offset).await;
+            let written = written?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            data = Bytes::slice(&buf, written..);
+            offset += written as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Stand-in for the io_uring backend in builds without it.
+#[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+mod ring {
+    use std::convert::Infallible;
+    use std::io;
+    use std::path::PathBuf;
+
+    use bytes::Bytes;
+    use tokio::sync::oneshot;
+
+    pub const SUPPORTED: bool = false;
+
+    pub struct Ring(Infallible);
+
+    impl Ring {
+        pub fn start() -> io::Result<Ring> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the proxy was built without the io-uring feature",
+            ))
+        }
+
+        pub async fn open(
+            &self,
+            _path: PathBuf,
+            _write: bool,
+        ) -> io::Result<(std::fs::File, RingFile)> {
+            match self.0 {}
+        }
+    }
+
+    pub struct RingFile(Infallible);
+
+    impl RingFile {
+        pub fn read(&self, _offset: u64, _len: usize) -> oneshot::Receiver<io::Result<Bytes>> {
+            match self.0 {}
+        }
+
+        pub fn write(&self, _data: Bytes, _offset: u64) -> oneshot::Receiver<io::Result<()>> {
+            match self.0 {}
+        }
+    }
+}
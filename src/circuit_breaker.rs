@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::{info, warn};
+
+/// Lets upstream requests fail fast after consecutive upstream failures.
+///
+/// Once `threshold` upstream requests in a row failed, the circuit opens and
+/// requests are rejected for `cooldown`. After that, a single probe request
+/// is let through: if it succeeds, the circuit closes again, otherwise it
+/// stays open for another `cooldown`.
+pub struct CircuitBreaker {
+    /// Consecutive failures that open the circuit; 0 never opens it.
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+    rejected: AtomicU64,
+    /// Times the circuit opened, let a probe through and closed again.
+    opened: AtomicU64,
+    probed: AtomicU64,
+    closed: AtomicU64,
+}
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    /// Until when requests are rejected, while the circuit is open.
+    open_until: Option<Instant>,
+    /// When the probe of an open circuit was let through, if any. A probe
+    /// that never reports back is replaced after `cooldown`.
+    probe_started: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(State::default()),
+            rejected: AtomicU64::new(0),
+            opened: AtomicU64::new(0),
+            probed: AtomicU64::new(0),
+            closed: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns whether an upstream request may be made now. A request that
+    /// is let through must report its outcome with `record`.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(open_until) = state.open_until else {
+            return true;
+        };
+        let now = Instant::now();
+        let probing = state
+            .probe_started
+            .is_some_and(|started| now < started + self.cooldown);
+        if now < open_until || probing {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        state.probe_started = Some(now);
+        self.probed.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Records the outcome of an upstream request.
+    pub fn record(&self, success: bool) {
+        if self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if success {
+            if state.open_until.is_some() {
+                info!("Upstream recovered, closing circuit");
+                self.closed.fetch_add(1, Ordering::Relaxed);
+            }
+            *state = State::default();
+            return;
+        }
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= self.threshold {
+            if state.open_until.is_none() {
+                warn!(
+                    failures = state.failures,
+                    "Upstream failing, rejecting upstream requests for {:?}", self.cooldown
+                );
+            }
+            // A failed probe opens the circuit again.
+            if state.open_until.is_none() || state.probe_started.is_some() {
+                self.opened.fetch_add(1, Ordering::Relaxed);
+            }
+            state.open_until = Some(Instant::now() + self.cooldown);
+            state.probe_started = None;
+        }
+    }
+
+    /// Returns whether upstream requests are being rejected.
+    pub fn is_open(&self) -> bool {
+        self.state.lock().unwrap().open_until.is_some()
+    }
+
+    /// Returns the number of upstream requests rejected so far.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Returns how often the circuit changed to each state so far: `open`,
+    /// `half_open` when a probe was let through, and `closed`.
+    pub fn transitions(&self) -> [(&'static str, u64); 3] {
+        [
+            ("open", self.opened.load(Ordering::Relaxed)),
+            ("half_open", self.probed.load(Ordering::Relaxed)),
+            ("closed", self.closed.load(Ordering::Relaxed)),
+        ]
+    }
+}
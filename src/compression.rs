@@ -0,0 +1,130 @@
+use std::io::Write;
+
+use hyper::body::HttpBody;
+use hyper::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY};
+use hyper::{Body, Response};
+
+use crate::metrics::Operation;
+
+/// Responses smaller than this aren't worth compressing.
+const MIN_SIZE: u64 = 1024;
+
+/// Content codings that responses can be compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Returns the coding with the highest weight in an `Accept-Encoding`
+/// header, preferring zstd on ties.
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let weight = |encoding: Encoding| {
+        let mut wildcard = None;
+        for coding in accept_encoding.split(',') {
+            let mut params = coding.split(';').map(str::trim);
+            let name = params.next().unwrap_or_default();
+            let q = params
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                .unwrap_or(0.0);
+            if name.eq_ignore_ascii_case(encoding.as_str()) {
+                return q;
+            }
+            if name == "*" {
+                wildcard = Some(q);
+            }
+        }
+        wildcard.unwrap_or(0.0)
+    };
+    [Encoding::Zstd, Encoding::Gzip]
+        .into_iter()
+        .map(|encoding| (encoding, weight(encoding)))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(
+            None,
+            |best: Option<(Encoding, f32)>, (encoding, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((encoding, q)),
+            },
+        )
+        .map(|(encoding, _)| encoding)
+}
+
+/// Returns whether `res` is a listing or an error document generated in
+/// memory, as opposed to an object body, which is never compressed.
+fn compressible(res: &Response<Body>, operation: Operation) -> bool {
+    let document = match operation {
+        Operation::List => true,
+        _ => {
+            let content_type = res
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+            (res.status().is_client_error() || res.status().is_server_error())
+                && (content_type.starts_with("application/xml")
+                    || content_type.starts_with("application/json"))
+        }
+    };
+    document
+        && !res.headers().contains_key(CONTENT_ENCODING)
+        && res
+            .body()
+            .size_hint()
+            .exact()
+            .is_some_and(|size| size >= MIN_SIZE)
+}
+
+/// Compresses list and error responses with the best coding that the
+/// client accepts, if any.
+pub async fn compress(
+    mut res: Response<Body>,
+    operation: Operation,
+    accept_encoding: Option<&str>,
+) -> Result<Response<Body>, hyper::Error> {
+    if !compressible(&res, operation) {
+        return Ok(res);
+    }
+    res.headers_mut()
+        .append(VARY, "accept-encoding".parse().unwrap());
+    let Some(encoding) = accept_encoding.and_then(negotiate) else {
+        return Ok(res);
+    };
+    let (mut parts, body) = res.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let data = body.clone();
+    let compressed = tokio::task::spawn_blocking(move || match encoding {
+        Encoding::Zstd => zstd::encode_all(&data[..], 3),
+        Encoding::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            encoder.write_all(&data)?;
+            encoder.finish()
+        }
+    })
+    .await
+    .map_err(std::io::Error::from)
+    .and_then(|compressed| compressed);
+    match compressed {
+        Ok(compressed) if compressed.len() < body.len() => {
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, encoding.as_str().parse().unwrap());
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, compressed.len().into());
+            Ok(Response::from_parts(parts, Body::from(compressed)))
+        }
+        _ => Ok(Response::from_parts(parts, Body::from(body))),
+    }
+}
@@ -0,0 +1,86 @@
+use std::path::Path;
+
+use serde_json::Value;
+
+/// Reads the TOML or YAML file at `path`, by extension, and sets the
+/// environment variable of each setting in it that isn't set already, so
+/// that flags and the environment take precedence over the file.
+///
+/// Settings are named like the flags of `command`, with `-` or `_`, either
+/// at the top level or in tables whose names prefix them: `cache-max-age`
+/// can also be set as `max-age` in a `[cache]` table. Lists are joined with
+/// commas.
+pub fn apply(path: &Path, command: &clap::Command) -> Result<(), String> {
+    let failed = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+    let text = std::fs::read_to_string(path).map_err(|e| failed(&e))?;
+    let settings: Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&text).map_err(|e| failed(&e))?,
+        Some("yaml" | "yml") => serde_yaml::from_str(&text).map_err(|e| failed(&e))?,
+        _ => return Err(failed(&"expected a .toml, .yaml or .yml file")),
+    };
+    let mut vars = Vec::new();
+    collect(command, "", &settings, &mut vars).map_err(|e| failed(&e))?;
+    for (name, value) in vars {
+        if std::env::var_os(&name).is_none() {
+            std::env::set_var(name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Collects the environment variables of the settings in `table`, whose
+/// keys are prefixed with `prefix`.
+fn collect(
+    command: &clap::Command,
+    prefix: &str,
+    table: &Value,
+    vars: &mut Vec<(String, String)>,
+) -> Result<(), String> {
+    let Value::Object(table) = table else {
+        return Err(match prefix {
+            "" => "expected a table of settings".to_string(),
+            _ => format!("`{}` must be a table", prefix),
+        });
+    };
+    for (key, value) in table {
+        let name = match prefix {
+            "" => key.replace('_', "-"),
+            _ => format!("{}-{}", prefix, key.replace('_', "-")),
+        };
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(name.as_str()));
+        let value = match (arg, value) {
+            (_, Value::Null) => continue,
+            (None, Value::Object(_)) => {
+                collect(command, &name, value, vars)?;
+                continue;
+            }
+            (None, _) => return Err(format!("unknown setting `{}`", name)),
+            (Some(_), Value::Object(_)) => return Err(format!("`{}` must not be a table", name)),
+            (Some(arg), Value::Array(items)) if arg.get_value_delimiter().is_some() => items
+                .iter()
+                .map(scalar)
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("`{}` must be a list of values", name))?
+                .join(","),
+            (Some(_), Value::Array(_)) => return Err(format!("`{}` must not be a list", name)),
+            (Some(_), value) => scalar(value).unwrap(),
+        };
+        let Some(env) = arg.and_then(|arg| arg.get_env()) else {
+            return Err(format!("`{}` can't be set in a configuration file", name));
+        };
+        vars.push((env.to_string_lossy().into_owned(), value));
+    }
+    Ok(())
+}
+
+/// Returns a string, number or boolean as the value of a flag.
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(value) => Some(value.clone()),
+        Value::Number(value) => Some(value.to_string()),
+        Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
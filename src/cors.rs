@@ -0,0 +1,83 @@
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Body, Response, StatusCode};
+
+use crate::router::wildcard_match;
+
+/// Methods of the S3 requests that the proxy serves.
+const METHODS: &str = "GET, HEAD, PUT, DELETE, OPTIONS";
+
+/// Methods of the S3 requests that the proxy serves with --read-only.
+const READ_ONLY_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Response headers that browsers let scripts read besides the safelisted
+/// ones.
+const EXPOSED_HEADERS: &str =
+    "ETag, Content-Length, Content-Range, Accept-Ranges, x-amz-request-id, x-amz-version-id";
+
+/// Seconds that browsers may reuse the result of a preflight request for.
+const MAX_AGE: &str = "3600";
+
+/// Returns the methods of the S3 requests that the proxy serves.
+pub fn allowed_methods(read_only: bool) -> &'static str {
+    match read_only {
+        true => READ_ONLY_METHODS,
+        false => METHODS,
+    }
+}
+
+/// Returns true if `origin` matches one of `allowed_origins`, origins or
+/// patterns with `*` wildcards.
+fn origin_allowed(allowed_origins: &[String], origin: &str) -> bool {
+    allowed_origins
+        .iter()
+        .any(|pattern| wildcard_match(pattern, origin))
+}
+
+/// Answers an OPTIONS request: capability probes get the allowed methods,
+/// and preflight requests from allowed origins also the methods and headers
+/// that the actual request may use.
+pub fn preflight(
+    allowed_origins: &[String],
+    read_only: bool,
+    headers: &HeaderMap,
+) -> Response<Body> {
+    let methods = allowed_methods(read_only);
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::ALLOW, methods);
+    let origin = headers
+        .get(header::ORIGIN)
+        .and_then(|value| value.to_str().ok());
+    let preflight = origin.is_some_and(|origin| origin_allowed(allowed_origins, origin))
+        && headers.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if preflight {
+        builder = builder
+            .header(header::ACCESS_CONTROL_ALLOW_METHODS, methods)
+            .header(header::ACCESS_CONTROL_MAX_AGE, MAX_AGE);
+        if let Some(requested) = headers.get(header::ACCESS_CONTROL_REQUEST_HEADERS) {
+            builder = builder.header(header::ACCESS_CONTROL_ALLOW_HEADERS, requested);
+        }
+    }
+    builder
+        .header(header::CONTENT_LENGTH, 0)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Lets browsers hand a response to scripts of `origin`, the origin of the
+/// request, if it is allowed.
+pub fn apply(allowed_origins: &[String], origin: Option<&str>, headers: &mut HeaderMap) {
+    let Some(origin) = origin.filter(|origin| origin_allowed(allowed_origins, origin)) else {
+        return;
+    };
+    let Ok(value) = HeaderValue::from_str(origin) else {
+        return;
+    };
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+    headers.insert(
+        header::ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(EXPOSED_HEADERS),
+    );
+    // The origin is echoed, so caches must tell the responses of origins apart.
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+}
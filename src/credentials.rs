@@ -1,26 +1,443 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
+use aws_config::default_provider::credentials::DefaultCredentialsChain;
+use aws_credential_types::provider::ProvideCredentials;
 use chrono::{DateTime, Utc};
-use hyper::header::{HeaderMap, HeaderValue};
-use quick_xml;
-use serde::Deserialize;
-use serde_json;
+use futures_util::future::BoxFuture;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, COOKIE};
+use hyper::http::request::Parts;
+use hyper::Method;
+use serde::{Deserialize, Serialize};
 
-use tracing::{info, instrument};
+use tracing::{debug, info, instrument, warn};
 
-use reqwest;
+use crate::access_policy::AccessPolicy;
+use crate::api_keys::{ApiKeys, Identity, OAuthClient, API_KEY_HEADER};
+use crate::jwt::{ClaimRequirements, JwtValidator};
+use crate::sigv4::{query_params, PayloadCheck, SigV4Verifier};
 
-#[derive(Debug, Deserialize, Clone)]
-struct UserAttributes {
-    #[serde(rename = "multipass:organization-rid")]
-    organization_rid: Vec<String>,
+/// Endpoint returning the `UserInfo` of a bearer token.
+pub const USER_INFO_ENDPOINT: &str = "https://ecosystem.athinia.com/multipass/api/me";
+
+/// How upstream requests are signed.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthMode {
+    /// Exchange the bearer token of each client for temporary credentials
+    Token,
+    /// Use the operator-provided --access-key-id and --secret-access-key, or --credentials-file, for all clients
+    Static,
+    /// Use the AWS default credential chain: environment, shared config, web identity (IRSA), ECS or IMDS
+    Chain,
+    /// Send upstream requests unsigned and require no token, e.g. for the local filesystem backend
+    None,
+}
+
+/// How long before their expiration credentials from the AWS default chain
+/// are refreshed.
+const CHAIN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// Lifetime of credentials from `AssumeRoleWithWebIdentity` when no session
+/// duration is requested.
+const DEFAULT_SESSION_DURATION: Duration = Duration::from_secs(900);
+
+/// Fraction of their lifetime before the expiration of exchanged credentials
+/// at which they are refreshed.
+const SESSION_REFRESH_DIVISOR: u32 = 10;
+
+/// Maximum duration of sessions assumed with role chaining.
+const MAX_CHAINED_SESSION_DURATION: Duration = Duration::from_secs(3600);
+
+/// Number of attempts at exchanging a token when the endpoint fails
+/// transiently.
+const EXCHANGE_ATTEMPTS: u32 = 3;
+
+/// Delay before the first retry of a failed exchange, doubled on each
+/// further retry.
+const EXCHANGE_BACKOFF: Duration = Duration::from_millis(200);
+
+/// How long the user info of a token is cached.
+const USER_INFO_MAX_AGE: Duration = Duration::from_secs(3600);
+
+/// Where in a request its token is looked for. Values of both headers and
+/// cookies may have a `Bearer ` prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenSource {
+    Header(HeaderName),
+    Cookie(String),
+}
+
+/// Token sources of requests when none are configured.
+const DEFAULT_TOKEN_SOURCES: &str = "header:x-amz-security-token,header:authorization";
+
+impl std::str::FromStr for TokenSource {
+    type Err = String;
+
+    /// Parses `header:<name>` or `cookie:<name>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("header", name)) => HeaderName::from_bytes(name.as_bytes())
+                .map(TokenSource::Header)
+                .map_err(|e| format!("{}: {}", name, e)),
+            Some(("cookie", name)) if !name.is_empty() => Ok(TokenSource::Cookie(name.to_string())),
+            _ => Err("expected header:<name> or cookie:<name>".to_string()),
+        }
+    }
+}
+
+impl TokenSource {
+    /// Returns the default token sources: the `x-amz-security-token` header,
+    /// then the `authorization` header.
+    pub fn defaults() -> Vec<TokenSource> {
+        DEFAULT_TOKEN_SOURCES
+            .split(',')
+            .map(|source| source.parse().unwrap())
+            .collect()
+    }
+
+    /// Returns the value of the source in `headers`, if any.
+    fn value<'a>(&self, headers: &'a HeaderMap<HeaderValue>) -> Option<&'a str> {
+        match self {
+            TokenSource::Header(name) => headers.get(name)?.to_str().ok(),
+            TokenSource::Cookie(name) => headers
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(';'))
+                .filter_map(|cookie| cookie.trim().split_once('='))
+                .find(|(cookie, _)| cookie == name)
+                .map(|(_, value)| value),
+        }
+    }
+}
+
+#[derive(clap::Args, Clone)]
+pub struct CredentialsConfig {
+    /// How upstream requests are signed
+    #[arg(long, value_enum, default_value = "token", env)]
+    pub auth_mode: AuthMode,
+    /// Access key id used with --auth-mode static
+    #[arg(long, env = "AWS_ACCESS_KEY_ID")]
+    pub access_key_id: Option<String>,
+    /// Secret access key used with --auth-mode static
+    #[arg(long, env = "AWS_SECRET_ACCESS_KEY")]
+    pub secret_access_key: Option<String>,
+    /// Session token used with --auth-mode static
+    #[arg(long, env = "AWS_SESSION_TOKEN")]
+    pub session_token: Option<String>,
+    /// AWS shared credentials file whose default profile is used with --auth-mode static, instead of the key flags
+    #[arg(long, env)]
+    pub credentials_file: Option<PathBuf>,
+    /// AWS shared credentials file whose default profile signs anonymous requests for --public-buckets; they are sent unsigned if unset
+    #[arg(long, env)]
+    pub anonymous_credentials_file: Option<PathBuf>,
+    /// AWS shared credentials file whose profiles hold access keys that clients may sign requests with; their SigV4 signatures are verified
+    #[arg(long, env)]
+    pub client_keys_file: Option<PathBuf>,
+    /// Secret access key that clients sign requests with when passing their token as the access key id; their SigV4 signatures are verified
+    #[arg(long, env)]
+    pub client_token_secret: Option<String>,
+    /// JWKS endpoint whose keys bearer tokens must be signed with; invalid tokens are rejected without calling the token exchange
+    #[arg(long, env)]
+    pub jwks_url: Option<String>,
+    /// Issuer that bearer tokens must have with --jwks-url
+    #[arg(long, env)]
+    pub jwt_issuer: Option<String>,
+    /// Audience that bearer tokens must have with --jwks-url
+    #[arg(long, env)]
+    pub jwt_audience: Option<String>,
+    /// Interval in seconds at which the keys of --jwks-url are refetched
+    #[arg(long, default_value = "3600", env)]
+    pub jwks_refresh_interval: u64,
+    /// Audiences of which tokens must have at least one to be exchanged; tokens minted for other services are rejected
+    #[arg(long, env, value_delimiter = ',')]
+    pub required_audiences: Vec<String>,
+    /// Scopes that tokens must all have to be exchanged, from their scope or scp claim
+    #[arg(long, env, value_delimiter = ',')]
+    pub required_scopes: Vec<String>,
+    /// Duration in seconds requested for the sessions of exchanged tokens (900 to 43200); the endpoint's default if unset
+    #[arg(long, env, value_parser = clap::value_parser!(u64).range(900..=43200))]
+    pub sts_session_duration: Option<u64>,
+    /// JSON file mapping organization RIDs to the role ARN that tokens of their users are exchanged for
+    #[arg(long, env)]
+    pub role_map_file: Option<PathBuf>,
+    /// JSON file of an IAM session policy that scopes down the credentials of exchanged tokens
+    #[arg(long, env)]
+    pub session_policy_file: Option<PathBuf>,
+    /// Role that the credentials of exchanged tokens assume with AssumeRole before they are used (role chaining)
+    #[arg(long, env)]
+    pub assume_role_arn: Option<String>,
+    /// STS endpoint for --assume-role-arn; the upstream endpoint if unset
+    #[arg(long, env)]
+    pub assume_role_endpoint: Option<String>,
+    /// Signing region of --assume-role-endpoint
+    #[arg(long, default_value = "us-east-1", env)]
+    pub assume_role_region: String,
+    /// JSON file of API keys by name, each mapped to access_key_id and secret_access_key or to a refresh_token
+    #[arg(long, env, conflicts_with = "api_keys")]
+    pub api_keys_file: Option<PathBuf>,
+    /// API keys as JSON, in the format of --api-keys-file
+    #[arg(long, env)]
+    pub api_keys: Option<String>,
+    /// OAuth token endpoint that the refresh tokens of API keys are redeemed at
+    #[arg(long, env)]
+    pub oauth_token_endpoint: Option<String>,
+    /// OAuth client id sent with refresh tokens
+    #[arg(long, env)]
+    pub oauth_client_id: Option<String>,
+    /// OAuth client secret sent with refresh tokens
+    #[arg(long, env)]
+    pub oauth_client_secret: Option<String>,
+    /// The endpoint used to look up the user of a token for --cache-tenant-isolation and --role-map-file
+    #[arg(long, default_value = USER_INFO_ENDPOINT, env)]
+    pub user_info_endpoint: String,
+    /// Ordered list of places that the token of a request is taken from, each header:<name> or cookie:<name>
+    #[arg(long, env, value_delimiter = ',', default_value = DEFAULT_TOKEN_SOURCES)]
+    pub token_sources: Vec<TokenSource>,
+    /// JSON file of rules granting users access to prefixes of shared buckets, by their attributes
+    #[arg(long, env)]
+    pub access_policy_file: Option<PathBuf>,
+    /// Maximum number of tokens whose credentials are kept in memory
+    #[arg(long, default_value = "10000", env)]
+    pub credentials_cache_capacity: usize,
+    /// Interval in seconds at which expired credentials are removed from memory (0 disables the sweep)
+    #[arg(long, default_value = "60", env)]
+    pub credentials_sweep_interval: u64,
+}
+
+impl std::fmt::Debug for CredentialsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CredentialsConfig")
+            .field("auth_mode", &self.auth_mode)
+            .field("access_key_id", &self.access_key_id)
+            .field(
+                "secret_access_key",
+                &self.secret_access_key.as_ref().map(|_| "<redacted>"),
+            )
+            .field(
+                "session_token",
+                &self.session_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("credentials_file", &self.credentials_file)
+            .field(
+                "anonymous_credentials_file",
+                &self.anonymous_credentials_file,
+            )
+            .field("client_keys_file", &self.client_keys_file)
+            .field(
+                "client_token_secret",
+                &self.client_token_secret.as_ref().map(|_| "<redacted>"),
+            )
+            .field("jwks_url", &self.jwks_url)
+            .field("jwt_issuer", &self.jwt_issuer)
+            .field("jwt_audience", &self.jwt_audience)
+            .field("jwks_refresh_interval", &self.jwks_refresh_interval)
+            .field("required_audiences", &self.required_audiences)
+            .field("required_scopes", &self.required_scopes)
+            .field("sts_session_duration", &self.sts_session_duration)
+            .field("role_map_file", &self.role_map_file)
+            .field("session_policy_file", &self.session_policy_file)
+            .field("assume_role_arn", &self.assume_role_arn)
+            .field("assume_role_endpoint", &self.assume_role_endpoint)
+            .field("assume_role_region", &self.assume_role_region)
+            .field("api_keys_file", &self.api_keys_file)
+            .field("api_keys", &self.api_keys.as_ref().map(|_| "<redacted>"))
+            .field("oauth_token_endpoint", &self.oauth_token_endpoint)
+            .field("oauth_client_id", &self.oauth_client_id)
+            .field(
+                "oauth_client_secret",
+                &self.oauth_client_secret.as_ref().map(|_| "<redacted>"),
+            )
+            .field("token_sources", &self.token_sources)
+            .field("access_policy_file", &self.access_policy_file)
+            .field("user_info_endpoint", &self.user_info_endpoint)
+            .field(
+                "credentials_cache_capacity",
+                &self.credentials_cache_capacity,
+            )
+            .field(
+                "credentials_sweep_interval",
+                &self.credentials_sweep_interval,
+            )
+            .finish()
+    }
+}
+
+impl CredentialsConfig {
+    /// Creates the verifier of client SigV4 signatures, if client keys or a
+    /// token secret are configured.
+    pub fn verifier(&self) -> Result<Option<SigV4Verifier>, CredentialsError> {
+        let keys = match &self.client_keys_file {
+            Some(path) => Credentials::read_profiles(path)?
+                .into_values()
+                .map(|creds| (creds.access_key_id, creds.secret_access_key))
+                .collect(),
+            None if self.client_token_secret.is_none() => return Ok(None),
+            None => HashMap::new(),
+        };
+        Ok(Some(SigV4Verifier::new(
+            keys,
+            self.client_token_secret.clone(),
+        )))
+    }
+
+    /// Creates the credentials provider selected with `--auth-mode`.
+    pub async fn provider(
+        &self,
+        endpoint: &str,
+    ) -> Result<Arc<dyn CredentialsProvider>, CredentialsError> {
+        Ok(match self.auth_mode {
+            AuthMode::Token => {
+                let validator = self.jwks_url.as_deref().map(|url| {
+                    JwtValidator::new(
+                        url,
+                        self.jwt_issuer.clone(),
+                        self.jwt_audience.clone(),
+                        Duration::from_secs(self.jwks_refresh_interval),
+                    )
+                });
+                let roles = match &self.role_map_file {
+                    Some(path) => Some(RoleMap::from_file(path)?),
+                    None => None,
+                };
+                let session_policy = match &self.session_policy_file {
+                    Some(path) => Some(SessionPolicy::from_file(path)?),
+                    None => None,
+                };
+                let chain = self.assume_role_arn.as_deref().map(|role_arn| {
+                    RoleChain::new(
+                        self.assume_role_endpoint.as_deref().unwrap_or(endpoint),
+                        &self.assume_role_region,
+                        role_arn,
+                    )
+                });
+                let claims = (!self.required_audiences.is_empty()
+                    || !self.required_scopes.is_empty())
+                .then(|| {
+                    ClaimRequirements::new(
+                        self.required_audiences.clone(),
+                        self.required_scopes.clone(),
+                    )
+                });
+                let options = TokenExchangeOptions {
+                    validator,
+                    claims,
+                    roles,
+                    session_policy,
+                    chain,
+                    session_duration: self.sts_session_duration.map(Duration::from_secs),
+                    user_info_endpoint: self.user_info_endpoint.clone(),
+                };
+                Arc::new(TokenExchangeProvider::new(
+                    endpoint,
+                    self.credentials_cache_capacity,
+                    options,
+                ))
+            }
+            AuthMode::Static => Arc::new(StaticProvider::new(self.static_credentials()?)),
+            AuthMode::Chain => Arc::new(ChainProvider::new().await),
+            AuthMode::None => Arc::new(StaticProvider::new(Credentials {
+                access_key_id: String::new(),
+                secret_access_key: String::new(),
+                session_token: None,
+                expiration: DateTime::<Utc>::MAX_UTC,
+            })),
+        })
+    }
+
+    /// Loads the API keys of `--api-keys-file` or `--api-keys`, if set.
+    pub fn api_keys(&self) -> Result<Option<ApiKeys>, CredentialsError> {
+        let (json, source) = match (&self.api_keys_file, &self.api_keys) {
+            (Some(path), _) => (
+                std::fs::read_to_string(path).map_err(|e| {
+                    CredentialsError::Configuration(format!("{}: {}", path.display(), e))
+                })?,
+                path.display().to_string(),
+            ),
+            (None, Some(json)) => (json.clone(), "--api-keys".to_string()),
+            (None, None) => return Ok(None),
+        };
+        let oauth = self
+            .oauth_token_endpoint
+            .as_ref()
+            .map(|token_endpoint| OAuthClient {
+                token_endpoint: token_endpoint.clone(),
+                client_id: self.oauth_client_id.clone(),
+                client_secret: self.oauth_client_secret.clone(),
+            });
+        ApiKeys::parse(&json, &source, oauth).map(Some)
+    }
+
+    /// Loads the access policy, if one is configured.
+    pub fn access_policy(&self) -> Result<Option<AccessPolicy>, CredentialsError> {
+        match &self.access_policy_file {
+            Some(path) => AccessPolicy::from_file(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the credentials that anonymous requests for public buckets are
+    /// signed with, from `--anonymous-credentials-file`, or `None` for
+    /// unsigned requests. The static credentials are never used, since they
+    /// may have been picked up from the environment of the deployment.
+    pub fn anonymous_credentials(&self) -> Result<Option<Credentials>, CredentialsError> {
+        match &self.anonymous_credentials_file {
+            Some(path) => Credentials::from_file(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the credentials of `--auth-mode static`, from the credentials
+    /// file if one is set.
+    fn static_credentials(&self) -> Result<Credentials, CredentialsError> {
+        if let Some(path) = &self.credentials_file {
+            return Credentials::from_file(path);
+        }
+        match (&self.access_key_id, &self.secret_access_key) {
+            (Some(access_key_id), Some(secret_access_key)) => Ok(Credentials {
+                access_key_id: access_key_id.clone(),
+                secret_access_key: secret_access_key.clone(),
+                session_token: self.session_token.clone(),
+                expiration: DateTime::<Utc>::MAX_UTC,
+            }),
+            (Some(_), None) => Err(CredentialsError::Configuration(
+                "--access-key-id (AWS_ACCESS_KEY_ID) is set without --secret-access-key (AWS_SECRET_ACCESS_KEY)"
+                    .to_string(),
+            )),
+            (None, Some(_)) => Err(CredentialsError::Configuration(
+                "--secret-access-key (AWS_SECRET_ACCESS_KEY) is set without --access-key-id (AWS_ACCESS_KEY_ID)"
+                    .to_string(),
+            )),
+            (None, None) => Err(CredentialsError::Configuration(
+                "--auth-mode static requires --access-key-id and --secret-access-key or --credentials-file"
+                    .to_string(),
+            )),
+        }
+    }
+}
+
+/// User attribute holding the RID of the user's organization.
+const ORGANIZATION_RID_ATTRIBUTE: &str = "multipass:organization-rid";
+
+/// The user presenting a token, as logged with their requests.
+#[derive(Debug, Clone)]
+pub struct UserIdentity {
+    /// Id of the user, or `api-key:<name>` for API keys.
+    pub user: String,
+    pub username: Option<String>,
+    pub organization: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct UserInfo {
     pub username: String,
     pub id: String,
-    attributes: UserAttributes,
+    /// Attribute values by name, usually lists of strings.
+    #[serde(default)]
+    attributes: HashMap<String, serde_json::Value>,
 }
 
 #[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
@@ -28,7 +445,7 @@ pub struct UserInfo {
 pub struct Credentials {
     pub access_key_id: String,
     pub secret_access_key: String,
-    pub session_token: String,
+    pub session_token: Option<String>,
     pub expiration: DateTime<Utc>,
 }
 
@@ -44,6 +461,30 @@ struct AssumeRoleWithWebIdentityResponse {
     assume_role_with_web_identity_result: AssumeRoleWithWebIdentityResult,
 }
 
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StsError {
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct StsErrorResponse {
+    error: StsError,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AssumeRoleResult {
+    credentials: Credentials,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AssumeRoleResponse {
+    assume_role_result: AssumeRoleResult,
+}
+
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -54,21 +495,146 @@ pub enum CredentialsError {
     TokenMissing(),
     #[error("Request failed with status code {:?}", .0.status())]
     RequestFailed(#[from] reqwest::Error),
+    #[error("Invalid credentials configuration: {0}")]
+    Configuration(String),
+    #[error("Invalid request signature: {0}")]
+    InvalidSignature(String),
+    #[error("Invalid token: {0}")]
+    InvalidToken(String),
+    #[error("Token expired")]
+    ExpiredToken(),
+    #[error("Token not accepted: {0}")]
+    TokenRejected(String),
+    #[error("Token exchange failed with status code {status}: {code}")]
+    ExchangeFailed {
+        status: reqwest::StatusCode,
+        code: String,
+    },
+    #[error("Failed to load credentials from the default chain: {0}")]
+    Chain(#[from] aws_credential_types::provider::error::CredentialsError),
+    /// The error of an exchange that other requests waited for.
+    #[error(transparent)]
+    Shared(Arc<CredentialsError>),
+}
+
+impl CredentialsError {
+    /// Returns true if the error may go away on retry: connection failures,
+    /// throttling and server errors.
+    pub fn is_transient(&self) -> bool {
+        let retryable = |status: reqwest::StatusCode| {
+            status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        };
+        match self {
+            CredentialsError::RequestFailed(e) => e.status().is_none_or(retryable),
+            CredentialsError::ExchangeFailed { status, code } => {
+                retryable(*status) || code == "Throttling"
+            }
+            CredentialsError::Shared(e) => e.is_transient(),
+            _ => false,
+        }
+    }
+
+    /// Builds the error of a failed STS call from its status and the code in
+    /// its `ErrorResponse` body, if any.
+    async fn exchange_failed(res: reqwest::Response) -> CredentialsError {
+        let status = res.status();
+        let text = res.text().await.unwrap_or_default();
+        let code = quick_xml::de::from_str::<StsErrorResponse>(&text)
+            .map(|res| res.error.code)
+            .unwrap_or_default();
+        CredentialsError::ExchangeFailed { status, code }
+    }
+
+    /// Returns the status code, S3 error code and message that the error is
+    /// reported to clients with.
+    pub fn s3_error(&self) -> (hyper::StatusCode, &'static str, &'static str) {
+        use hyper::StatusCode;
+        const ACCESS_DENIED: (StatusCode, &str, &str) =
+            (StatusCode::FORBIDDEN, "AccessDenied", "Access Denied");
+        const INVALID_TOKEN: (StatusCode, &str, &str) = (
+            StatusCode::BAD_REQUEST,
+            "InvalidToken",
+            "The provided token is malformed or otherwise invalid.",
+        );
+        const EXPIRED_TOKEN: (StatusCode, &str, &str) = (
+            StatusCode::BAD_REQUEST,
+            "ExpiredToken",
+            "The provided token has expired.",
+        );
+        const SERVICE_UNAVAILABLE: (StatusCode, &str, &str) = (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "ServiceUnavailable",
+            "Service is unable to handle request.",
+        );
+        const SLOW_DOWN: (StatusCode, &str, &str) = (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "SlowDown",
+            "Please reduce your request rate.",
+        );
+        const INTERNAL_ERROR: (StatusCode, &str, &str) = (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "InternalError",
+            "We encountered an internal error. Please try again.",
+        );
+        match self {
+            CredentialsError::TokenMissing() => ACCESS_DENIED,
+            CredentialsError::InvalidSignature(_) => (
+                StatusCode::FORBIDDEN,
+                "SignatureDoesNotMatch",
+                "The request signature we calculated does not match the signature you provided.",
+            ),
+            CredentialsError::InvalidToken(_) => INVALID_TOKEN,
+            CredentialsError::ExpiredToken() => EXPIRED_TOKEN,
+            CredentialsError::TokenRejected(_) => (
+                StatusCode::FORBIDDEN,
+                "AccessDenied",
+                "The provided token was not issued for this service.",
+            ),
+            CredentialsError::ExchangeFailed { status, code } => match code.as_str() {
+                "ExpiredTokenException" | "ExpiredToken" => EXPIRED_TOKEN,
+                "InvalidIdentityToken" | "IDPRejectedClaim" | "InvalidToken" => INVALID_TOKEN,
+                "AccessDenied" => ACCESS_DENIED,
+                "Throttling" => SLOW_DOWN,
+                _ if *status == reqwest::StatusCode::TOO_MANY_REQUESTS => SLOW_DOWN,
+                _ if status.is_server_error() => SERVICE_UNAVAILABLE,
+                _ if *status == reqwest::StatusCode::FORBIDDEN => ACCESS_DENIED,
+                _ => INVALID_TOKEN,
+            },
+            CredentialsError::RequestFailed(e) => match e.status() {
+                None => SERVICE_UNAVAILABLE,
+                Some(status) if status.is_server_error() => SERVICE_UNAVAILABLE,
+                Some(reqwest::StatusCode::FORBIDDEN) => ACCESS_DENIED,
+                Some(status) if status.is_client_error() => INVALID_TOKEN,
+                Some(_) => INTERNAL_ERROR,
+            },
+            CredentialsError::Shared(e) => e.s3_error(),
+            CredentialsError::CredentialsParse()
+            | CredentialsError::Configuration(_)
+            | CredentialsError::Chain(_) => INTERNAL_ERROR,
+        }
+    }
+}
+
+/// Returns the delay before retry `attempt` (from 0), jittered between half
+/// and all of the exponential backoff so that concurrent retries spread out.
+fn exchange_backoff(attempt: u32) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+    let backoff = EXCHANGE_BACKOFF * 2u32.pow(attempt);
+    let random = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    backoff / 2 + backoff.mul_f64((random % 1000) as f64 / 2000.0)
 }
 
 impl UserInfo {
-    pub async fn from_token(token: String) -> Result<UserInfo, CredentialsError> {
+    pub async fn from_token(endpoint: &str, token: &str) -> Result<UserInfo, CredentialsError> {
         let client = reqwest::Client::new();
         let mut headers = HeaderMap::new();
         headers.append(
             "Authorization",
             HeaderValue::from_str(format!("Bearer {}", token).as_str()).unwrap(),
         );
-        let res = client
-            .get("https://ecosystem.athinia.com/multipass/api/me")
-            .headers(headers)
-            .send()
-            .await?;
+        let res = client.get(endpoint).headers(headers).send().await?;
 
         if !res.status().is_success() {
             return Err(CredentialsError::RequestFailed(
@@ -77,12 +643,22 @@ impl UserInfo {
         }
 
         let text = res.text().await?;
-        let res: UserInfo = serde_json::from_str(&text).unwrap();
-        Ok(res)
+        serde_json::from_str(&text).map_err(|_| CredentialsError::CredentialsParse())
     }
 
-    pub fn organization_rid(&self) -> &str {
-        &self.attributes.organization_rid[0]
+    pub fn organization_rid(&self) -> Option<&str> {
+        self.attribute(ORGANIZATION_RID_ATTRIBUTE).first().copied()
+    }
+
+    /// Returns the string values of an attribute.
+    pub fn attribute(&self, name: &str) -> Vec<&str> {
+        match self.attributes.get(name) {
+            Some(serde_json::Value::String(value)) => vec![value.as_str()],
+            Some(serde_json::Value::Array(values)) => {
+                values.iter().filter_map(|value| value.as_str()).collect()
+            }
+            _ => vec![],
+        }
     }
 }
 
@@ -90,100 +666,762 @@ impl Credentials {
     #[instrument(skip_all)]
     pub fn token_from_headers(
         headers: &HeaderMap<HeaderValue>,
+        sources: &[TokenSource],
     ) -> Result<String, CredentialsError> {
-        let mut token = headers.get("x-amz-security-token");
-        if token.is_none() {
-            token = headers.get("authorization");
-        }
-        let mut token = token
-            .ok_or(CredentialsError::TokenMissing())?
-            .to_str()
-            .map_err(|_| CredentialsError::TokenMissing())?;
+        let mut token = sources
+            .iter()
+            .find_map(|source| source.value(headers))
+            .ok_or(CredentialsError::TokenMissing())?;
         if token.to_ascii_lowercase().starts_with("bearer ") {
             token = token.get(7..).unwrap();
         }
         Ok(token.to_string())
     }
 
+    /// Returns the token of a request from the headers and cookies of
+    /// `sources` or, as in presigned URLs, from the `X-Amz-Security-Token` or
+    /// `X-Amz-Credential` query parameters, where the access key id is taken
+    /// as the token.
+    pub fn token_from_request(
+        parts: &Parts,
+        sources: &[TokenSource],
+    ) -> Result<String, CredentialsError> {
+        let header_token = Credentials::token_from_headers(&parts.headers, sources);
+        if header_token.is_ok() {
+            return header_token;
+        }
+        let mut params = query_params(parts.uri.query().unwrap_or_default());
+        params
+            .remove("X-Amz-Security-Token")
+            .or_else(|| {
+                let credential = params.remove("X-Amz-Credential")?;
+                Some(credential.split('/').next()?.to_string())
+            })
+            .filter(|token| !token.is_empty())
+            .ok_or(CredentialsError::TokenMissing())
+    }
+
+    /// Exchanges a token for credentials of `role_arn`, or of the default role
+    /// of the endpoint, valid for `duration` or the endpoint's default and
+    /// scoped down by the session `policy`, if any.
     #[instrument(skip_all)]
     pub async fn from_token(
         endpoint: &str,
         token: &str,
+        role_arn: Option<&str>,
+        duration: Option<Duration>,
+        policy: Option<&str>,
     ) -> Result<Credentials, CredentialsError> {
         let client = reqwest::Client::new();
-        let res = client
-            .post(endpoint)
-            .query(&[
-                ("Action", "AssumeRoleWithWebIdentity"),
-                ("WebIdentityToken", token),
-            ])
-            .send()
-            .await?;
+        let duration = duration.map(|d| d.as_secs().to_string());
+        let mut query = vec![
+            ("Action", "AssumeRoleWithWebIdentity"),
+            ("WebIdentityToken", token),
+        ];
+        if let Some(role_arn) = role_arn {
+            query.push(("RoleArn", role_arn));
+        }
+        if let Some(duration) = &duration {
+            query.push(("DurationSeconds", duration));
+        }
+        if let Some(policy) = policy {
+            query.push(("Policy", policy));
+        }
+        let res = client.post(endpoint).query(&query).send().await?;
 
         if !res.status().is_success() {
-            return Err(CredentialsError::RequestFailed(
-                res.error_for_status().unwrap_err(),
-            ));
+            return Err(CredentialsError::exchange_failed(res).await);
         }
 
         let text = res.text().await?;
-        let res: AssumeRoleWithWebIdentityResponse = quick_xml::de::from_str(&text).unwrap();
+        let res: AssumeRoleWithWebIdentityResponse =
+            quick_xml::de::from_str(&text).map_err(|_| CredentialsError::CredentialsParse())?;
         Ok(res.assume_role_with_web_identity_result.credentials)
     }
 
-    pub fn is_expired(&self) -> bool {
-        self.expiration < Utc::now()
+    fn to_aws(&self) -> aws_credential_types::Credentials {
+        aws_credential_types::Credentials::new(
+            &self.access_key_id,
+            &self.secret_access_key,
+            self.session_token.clone(),
+            None,
+            "s3proxy",
+        )
+    }
+
+    /// Reads the default profile of an AWS shared credentials file. Static
+    /// credentials never expire.
+    pub fn from_file(path: &Path) -> Result<Credentials, CredentialsError> {
+        Credentials::read_profiles(path)?
+            .remove("default")
+            .ok_or_else(|| {
+                CredentialsError::Configuration(format!(
+                    "{}: no keys in the default profile",
+                    path.display()
+                ))
+            })
+    }
+
+    /// Reads the credentials of all profiles of an AWS shared credentials
+    /// file, by profile name. Profiles without keys are skipped.
+    pub fn read_profiles(path: &Path) -> Result<HashMap<String, Credentials>, CredentialsError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| CredentialsError::Configuration(format!("{}: {}", path.display(), e)))?;
+        let mut values: HashMap<&str, HashMap<&str, String>> = HashMap::new();
+        let mut profile = None;
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                profile = Some(name.trim());
+            } else if let (Some(profile), Some((key, value))) = (profile, line.split_once('=')) {
+                values
+                    .entry(profile)
+                    .or_default()
+                    .insert(key.trim(), value.trim().to_string());
+            }
+        }
+        let profiles = values.into_iter().filter_map(|(profile, mut values)| {
+            let credentials = Credentials {
+                access_key_id: values.remove("aws_access_key_id")?,
+                secret_access_key: values.remove("aws_secret_access_key")?,
+                session_token: values.remove("aws_session_token"),
+                expiration: DateTime::<Utc>::MAX_UTC,
+            };
+            Some((profile.to_string(), credentials))
+        });
+        Ok(profiles.collect())
+    }
+
+    fn from_chain(credentials: aws_credential_types::Credentials) -> Credentials {
+        Credentials {
+            access_key_id: credentials.access_key_id().to_string(),
+            secret_access_key: credentials.secret_access_key().to_string(),
+            session_token: credentials.session_token().map(str::to_string),
+            expiration: credentials
+                .expiry()
+                .map_or(DateTime::<Utc>::MAX_UTC, DateTime::from),
+        }
+    }
+
+    /// Returns true if the credentials expire in less than `margin`.
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        let margin =
+            chrono::Duration::from_std(margin).unwrap_or_else(|_| chrono::Duration::zero());
+        self.expiration - margin < Utc::now()
     }
 }
 
-struct CredentialsCacheValue(tokio::sync::watch::Receiver<Option<Credentials>>);
+/// A source of the credentials that upstream requests are signed with.
+/// Implementations are selected at startup with `--auth-mode`, and custom ones
+/// can be passed to `CredentialsManager::new` when embedding the proxy.
+pub trait CredentialsProvider: Send + Sync {
+    /// Returns the credentials to sign the requests of a client presenting
+    /// `token` with.
+    fn credentials<'a>(
+        &'a self,
+        token: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Credentials, CredentialsError>>;
 
-pub struct CredentialsManager {
+    /// Returns whether clients must present a token to be signed for.
+    fn requires_token(&self) -> bool {
+        false
+    }
+
+    /// Drops expired credentials held by the provider, returning their number.
+    fn sweep(&self) -> usize {
+        0
+    }
+
+    /// Returns the counters collected since startup by providers that cache
+    /// credentials per token.
+    fn stats(&self) -> CredentialsStats {
+        CredentialsStats::default()
+    }
+}
+
+#[derive(Default)]
+struct CredentialsCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    waits: AtomicU64,
+    waiting: AtomicU64,
+    exchanges: AtomicU64,
+    exchange_failures: AtomicU64,
+    refreshes: AtomicU64,
+    refresh_failures: AtomicU64,
+    exchange_micros_total: AtomicU64,
+    exchange_micros_max: AtomicU64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct CredentialsStats {
+    /// Tokens with cached or pending credentials.
+    pub identities: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Requests that waited for the exchange of another request.
+    pub waits: u64,
+    /// Requests currently waiting for the exchange of another request.
+    pub waiting: u64,
+    pub exchanges: u64,
+    pub exchange_failures: u64,
+    /// Exchanges of tokens whose cached credentials were about to expire.
+    pub refreshes: u64,
+    pub refresh_failures: u64,
+    pub exchange_ms_avg: f64,
+    pub exchange_ms_max: f64,
+}
+
+/// Counts a request as waiting for an exchange until it is dropped, so that
+/// cancelled requests are accounted for.
+struct WaitingGuard<'a>(&'a AtomicU64);
+
+impl<'a> WaitingGuard<'a> {
+    fn new(waiting: &'a AtomicU64) -> Self {
+        waiting.fetch_add(1, Ordering::Relaxed);
+        WaitingGuard(waiting)
+    }
+}
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The outcome of an exchange, shared with the requests waiting for it.
+type ExchangeResult = Result<Credentials, Arc<CredentialsError>>;
+
+struct CredentialsCacheValue(tokio::sync::watch::Receiver<Option<ExchangeResult>>);
+
+impl CredentialsCacheValue {
+    /// Expiration of the credentials, or `None` while they are being fetched
+    /// or if the exchange failed.
+    fn expiration(&self) -> Option<DateTime<Utc>> {
+        match &*self.0.borrow() {
+            Some(Ok(creds)) => Some(creds.expiration),
+            _ => None,
+        }
+    }
+}
+
+type CredentialsCache = HashMap<blake3::Hash, Arc<CredentialsCacheValue>>;
+
+/// Maps the organization of a token's user to the role that the token is
+/// exchanged for.
+pub struct RoleMap {
+    /// Role ARNs by organization RID.
+    roles: HashMap<String, String>,
+}
+
+impl RoleMap {
+    /// Reads a JSON object of role ARNs by organization RID.
+    pub fn from_file(path: &Path) -> Result<RoleMap, CredentialsError> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            CredentialsError::Configuration(format!("{}: {}", path.display(), e))
+        };
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+        Ok(RoleMap {
+            roles: serde_json::from_str(&text).map_err(|e| invalid(&e))?,
+        })
+    }
+
+    /// Returns the role to exchange a token of `user_info` for, or `None` if
+    /// its organization isn't mapped.
+    fn role_arn(&self, user_info: &UserInfo) -> Option<&str> {
+        let role_arn = user_info
+            .organization_rid()
+            .and_then(|rid| self.roles.get(rid))
+            .map(String::as_str);
+        debug!(
+            organization_rid = user_info.organization_rid(),
+            role_arn, "Resolved role for token"
+        );
+        role_arn
+    }
+}
+
+/// An IAM session policy passed with token exchanges, so that the credentials
+/// held by the proxy are scoped down to what the token's user may access.
+pub struct SessionPolicy {
+    /// The compact policy document, possibly with placeholders.
+    template: String,
+}
+
+impl SessionPolicy {
+    /// Placeholders replaced with attributes of the token's user.
+    const PLACEHOLDERS: [&'static str; 3] = ["{{organization_rid}}", "{{user_id}}", "{{username}}"];
+
+    /// Reads a policy document, which must be valid JSON with its
+    /// placeholders in place.
+    pub fn from_file(path: &Path) -> Result<SessionPolicy, CredentialsError> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            CredentialsError::Configuration(format!("{}: {}", path.display(), e))
+        };
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+        let policy: serde_json::Value = serde_json::from_str(&text).map_err(|e| invalid(&e))?;
+        Ok(SessionPolicy {
+            template: policy.to_string(),
+        })
+    }
+
+    /// Returns true if the policy has placeholders for user attributes.
+    fn is_templated(&self) -> bool {
+        SessionPolicy::PLACEHOLDERS
+            .iter()
+            .any(|placeholder| self.template.contains(placeholder))
+    }
+
+    /// Returns the policy for a token of `user_info`, which is only needed if
+    /// the policy is templated.
+    fn render(&self, user_info: Option<&UserInfo>) -> String {
+        let Some(user_info) = user_info else {
+            return self.template.clone();
+        };
+        let values = [
+            user_info.organization_rid().unwrap_or_default(),
+            &user_info.id,
+            &user_info.username,
+        ];
+        let mut policy = self.template.clone();
+        for (placeholder, value) in SessionPolicy::PLACEHOLDERS.iter().zip(values) {
+            // Placeholders are inside JSON strings, so values are escaped.
+            let escaped = serde_json::Value::from(value).to_string();
+            policy = policy.replace(placeholder, &escaped[1..escaped.len() - 1]);
+        }
+        policy
+    }
+}
+
+/// The second hop of role chaining: credentials from the token exchange
+/// assume a target role with `AssumeRole`.
+pub struct RoleChain {
     endpoint: String,
-    cache: RwLock<std::collections::HashMap<blake3::Hash, Arc<CredentialsCacheValue>>>,
+    region: String,
+    role_arn: String,
 }
 
-impl CredentialsManager {
-    pub fn new(endpoint: &str) -> Self {
-        CredentialsManager {
+impl RoleChain {
+    pub fn new(endpoint: &str, region: &str, role_arn: &str) -> Self {
+        RoleChain {
             endpoint: endpoint.to_string(),
-            cache: RwLock::new(std::collections::HashMap::new()),
+            region: region.to_string(),
+            role_arn: role_arn.to_string(),
         }
     }
 
-    pub async fn get_credentials(&self, token: &str) -> Result<Credentials, CredentialsError> {
-        let hash = blake3::hash(token.as_bytes());
+    /// Assumes the target role with `credentials`, for `duration` or the
+    /// endpoint's default.
+    #[instrument(skip_all)]
+    async fn assume(
+        &self,
+        credentials: &Credentials,
+        duration: Option<Duration>,
+    ) -> Result<Credentials, CredentialsError> {
+        use aws_sigv4::http_request::{sign, SignableBody, SignableRequest, SigningSettings};
+        use aws_sigv4::sign::v4;
+
+        let invalid = |e: &dyn std::fmt::Display| {
+            CredentialsError::Configuration(format!("{}: {}", self.endpoint, e))
+        };
+        let mut url = reqwest::Url::parse(&self.endpoint).map_err(|e| invalid(&e))?;
+        {
+            let mut query = url.query_pairs_mut();
+            query
+                .append_pair("Action", "AssumeRole")
+                .append_pair("Version", "2011-06-15")
+                .append_pair("RoleArn", &self.role_arn)
+                .append_pair("RoleSessionName", "s3proxy");
+            if let Some(duration) = duration {
+                query.append_pair("DurationSeconds", &duration.as_secs().to_string());
+            }
+        }
+
+        let identity = credentials.to_aws().into();
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(&self.region)
+            .name("sts")
+            .settings(SigningSettings::default())
+            .time(SystemTime::now())
+            .build()
+            .map_err(|e| invalid(&e))?;
+        let signable = SignableRequest::new(
+            "POST",
+            url.as_str(),
+            std::iter::empty(),
+            SignableBody::Bytes(&[]),
+        )
+        .map_err(|e| invalid(&e))?;
+        let (instructions, _) = sign(signable, &params.into())
+            .map_err(|e| invalid(&e))?
+            .into_parts();
+        let (headers, _) = instructions.into_parts();
+        let mut request = reqwest::Client::new().post(url);
+        for header in headers {
+            request = request.header(header.name(), header.value());
+        }
+        let res = request.send().await?;
+        if !res.status().is_success() {
+            return Err(CredentialsError::exchange_failed(res).await);
+        }
+
+        let text = res.text().await?;
+        let res: AssumeRoleResponse =
+            quick_xml::de::from_str(&text).map_err(|_| CredentialsError::CredentialsParse())?;
+        Ok(res.assume_role_result.credentials)
+    }
+}
+
+/// What a token is exchanged for besides the provider's endpoint: the role
+/// and the session policy, which may depend on the token's user.
+#[derive(Clone, Default)]
+struct ExchangeTarget {
+    role_arn: Option<String>,
+    policy: Option<String>,
+}
+
+/// Optional behavior of a `TokenExchangeProvider`.
+#[derive(Default)]
+pub struct TokenExchangeOptions {
+    /// Validates tokens locally before they are exchanged.
+    pub validator: Option<JwtValidator>,
+    /// Audience and scopes that tokens must have to be exchanged.
+    pub claims: Option<ClaimRequirements>,
+    /// Selects the role that tokens are exchanged for.
+    pub roles: Option<RoleMap>,
+    /// Scopes down the exchanged credentials.
+    pub session_policy: Option<SessionPolicy>,
+    /// Role assumed with the exchanged credentials.
+    pub chain: Option<RoleChain>,
+    pub session_duration: Option<Duration>,
+    /// Endpoint that the user of a token is looked up at for `roles` and
+    /// `session_policy`.
+    pub user_info_endpoint: String,
+}
+
+/// Exchanges the bearer token of each client for temporary credentials with
+/// `AssumeRoleWithWebIdentity`, caching them until they expire.
+pub struct TokenExchangeProvider {
+    endpoint: String,
+    capacity: usize,
+    options: TokenExchangeOptions,
+    /// How long before their expiration cached credentials are refreshed.
+    refresh_margin: Duration,
+    /// Credentials by the hash of the token, endpoint and target they were
+    /// exchanged with, so that a token never gets credentials of another
+    /// target.
+    cache: RwLock<CredentialsCache>,
+    /// Targets of tokens whose user had to be looked up, by token hash.
+    targets: RwLock<HashMap<blake3::Hash, (ExchangeTarget, Instant)>>,
+    counters: CredentialsCounters,
+}
+
+impl TokenExchangeProvider {
+    pub fn new(endpoint: &str, capacity: usize, mut options: TokenExchangeOptions) -> Self {
+        // Chained sessions can't outlast the AWS limit, whatever is requested.
+        if options.chain.is_some() {
+            options.session_duration = options
+                .session_duration
+                .map(|d| d.min(MAX_CHAINED_SESSION_DURATION));
+        }
+        TokenExchangeProvider {
+            endpoint: endpoint.to_string(),
+            capacity: capacity.max(1),
+            refresh_margin: options.session_duration.unwrap_or(DEFAULT_SESSION_DURATION)
+                / SESSION_REFRESH_DIVISOR,
+            options,
+            cache: RwLock::new(HashMap::new()),
+            targets: RwLock::new(HashMap::new()),
+            counters: CredentialsCounters::default(),
+        }
+    }
+
+    /// Returns true if the target of a token depends on its user.
+    fn needs_user_info(&self) -> bool {
+        self.options.roles.is_some()
+            || self
+                .options
+                .session_policy
+                .as_ref()
+                .is_some_and(SessionPolicy::is_templated)
+    }
+
+    /// Validates a token locally, if configured to, before it is sent
+    /// anywhere.
+    async fn validate(&self, token: &str) -> Result<(), CredentialsError> {
+        if let Some(validator) = &self.options.validator {
+            validator.validate(token).await?;
+        }
+        if let Some(claims) = &self.options.claims {
+            claims.check(token)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the cached target of a token, or the target of all tokens if
+    /// it doesn't depend on their user.
+    fn cached_target(&self, token_hash: &blake3::Hash) -> Option<ExchangeTarget> {
+        if !self.needs_user_info() {
+            return Some(ExchangeTarget {
+                role_arn: None,
+                policy: self
+                    .options
+                    .session_policy
+                    .as_ref()
+                    .map(|policy| policy.render(None)),
+            });
+        }
+        match self.targets.read().unwrap().get(token_hash) {
+            Some((target, resolved)) if resolved.elapsed() < USER_INFO_MAX_AGE => {
+                Some(target.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Looks up the user of a token to resolve its target.
+    async fn resolve_target(
+        &self,
+        token: &str,
+        token_hash: blake3::Hash,
+    ) -> Result<ExchangeTarget, CredentialsError> {
+        let options = &self.options;
+        let user_info = UserInfo::from_token(&options.user_info_endpoint, token).await?;
+        let target = ExchangeTarget {
+            role_arn: options
+                .roles
+                .as_ref()
+                .and_then(|roles| roles.role_arn(&user_info))
+                .map(str::to_string),
+            policy: options
+                .session_policy
+                .as_ref()
+                .map(|policy| policy.render(Some(&user_info))),
+        };
+        let mut targets = self.targets.write().unwrap();
+        if targets.len() >= self.capacity {
+            let oldest = targets
+                .iter()
+                .min_by_key(|(_, (_, resolved))| *resolved)
+                .map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                targets.remove(&oldest);
+            }
+        }
+        targets.insert(token_hash, (target.clone(), Instant::now()));
+        Ok(target)
+    }
+
+    /// Returns the key of the credentials of a token for `target`. Fields are
+    /// length-prefixed so that they can't run into each other.
+    fn cache_key(&self, token: &str, target: &ExchangeTarget) -> blake3::Hash {
+        let chain_role_arn = self
+            .options
+            .chain
+            .as_ref()
+            .map(|chain| chain.role_arn.as_str());
+        let mut hasher = blake3::Hasher::new();
+        for field in [
+            Some(self.endpoint.as_str()),
+            target.role_arn.as_deref(),
+            target.policy.as_deref(),
+            chain_role_arn,
+            Some(token),
+        ] {
+            match field {
+                Some(field) => {
+                    hasher.update(&(field.len() as u64).to_le_bytes());
+                    hasher.update(field.as_bytes());
+                }
+                None => {
+                    hasher.update(&u64::MAX.to_le_bytes());
+                }
+            }
+        }
+        hasher.finalize()
+    }
+
+    async fn fetch_once(
+        &self,
+        token: &str,
+        target: &ExchangeTarget,
+    ) -> Result<Credentials, CredentialsError> {
+        let options = &self.options;
+        let credentials = Credentials::from_token(
+            &self.endpoint,
+            token,
+            target.role_arn.as_deref(),
+            options.session_duration,
+            target.policy.as_deref(),
+        )
+        .await?;
+        match &options.chain {
+            Some(chain) => chain.assume(&credentials, options.session_duration).await,
+            None => Ok(credentials),
+        }
+    }
+
+    /// Exchanges a token, retrying transient failures with backoff.
+    async fn fetch(
+        &self,
+        token: &str,
+        target: &ExchangeTarget,
+    ) -> Result<Credentials, CredentialsError> {
+        let mut attempt = 0;
+        loop {
+            match self.fetch_once(token, target).await {
+                Err(e) if e.is_transient() && attempt + 1 < EXCHANGE_ATTEMPTS => {
+                    let delay = exchange_backoff(attempt);
+                    warn!(attempt, ?delay, "Retrying token exchange: {}", e);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Removes the cache entry of `hash` unless another request has already
+    /// replaced it.
+    fn remove_entry(&self, hash: &blake3::Hash, entry: &Arc<CredentialsCacheValue>) {
+        let mut cache = self.cache.write().unwrap();
+        if cache
+            .get(hash)
+            .is_some_and(|current| Arc::ptr_eq(current, entry))
+        {
+            cache.remove(hash);
+        }
+    }
+
+    /// Makes room for a new entry when the cache is full, first by removing
+    /// expired credentials, then those expiring soonest.
+    fn make_room(&self, cache: &mut CredentialsCache) {
+        if cache.len() < self.capacity {
+            return;
+        }
+        let now = Utc::now();
+        cache.retain(|_, value| {
+            value
+                .expiration()
+                .is_none_or(|expiration| expiration >= now)
+        });
+        if cache.len() < self.capacity {
+            return;
+        }
+        let mut expiring: Vec<(DateTime<Utc>, blake3::Hash)> = cache
+            .iter()
+            .filter_map(|(hash, value)| Some((value.expiration()?, *hash)))
+            .collect();
+        expiring.sort_unstable_by_key(|(expiration, _)| *expiration);
+        for (_, hash) in expiring.iter().take(cache.len() + 1 - self.capacity) {
+            cache.remove(hash);
+        }
+        debug!(entries = cache.len(), "Evicted credentials over capacity");
+    }
+
+    /// Records the outcome and duration of an exchange.
+    fn record_exchange(&self, started: Instant, failed: bool, refreshing: bool) {
+        let counters = &self.counters;
+        let micros = started.elapsed().as_micros() as u64;
+        counters.exchanges.fetch_add(1, Ordering::Relaxed);
+        counters
+            .exchange_micros_total
+            .fetch_add(micros, Ordering::Relaxed);
+        counters
+            .exchange_micros_max
+            .fetch_max(micros, Ordering::Relaxed);
+        if refreshing {
+            counters.refreshes.fetch_add(1, Ordering::Relaxed);
+        }
+        if failed {
+            counters.exchange_failures.fetch_add(1, Ordering::Relaxed);
+            if refreshing {
+                counters.refresh_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    async fn exchange(&self, token: &str) -> Result<Credentials, CredentialsError> {
+        let token_hash = blake3::hash(token.as_bytes());
+        let mut validated = false;
+        let target = match self.cached_target(&token_hash) {
+            Some(target) => target,
+            None => {
+                // Tokens are validated before their user is looked up.
+                self.validate(token).await?;
+                validated = true;
+                self.resolve_target(token, token_hash).await?
+            }
+        };
+        let hash = self.cache_key(token, &target);
+        // Whether the cached credentials of the token are about to expire.
+        let mut refreshing = false;
         loop {
             let item = self.cache.read().unwrap().get(&hash).cloned();
             match item {
                 None => {
                     info!("Cache miss for token");
+                    self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                    if !validated {
+                        self.validate(token).await?;
+                        validated = true;
+                    }
                     let (sender, receiver) = tokio::sync::watch::channel(None);
-                    self.cache
-                        .write()
-                        .unwrap()
-                        .insert(hash.clone(), Arc::new(CredentialsCacheValue(receiver)));
-                    let creds = Credentials::from_token(&self.endpoint, token).await;
+                    let entry = Arc::new(CredentialsCacheValue(receiver));
+                    {
+                        let mut cache = self.cache.write().unwrap();
+                        // Another request may have started the exchange.
+                        if cache.contains_key(&hash) {
+                            continue;
+                        }
+                        self.make_room(&mut cache);
+                        cache.insert(hash, entry.clone());
+                    }
+                    let started = Instant::now();
+                    let creds = self.fetch(token, &target).await;
+                    self.record_exchange(started, creds.is_err(), refreshing);
                     match creds {
                         Ok(creds) => {
-                            sender.send(Some(creds.clone())).unwrap();
+                            sender.send_replace(Some(Ok(creds.clone())));
                             return Ok(creds);
                         }
-                        Err(e) => return Err(e),
+                        // Waiting requests get the error, and failed
+                        // exchanges are retried by the next request.
+                        Err(e) => {
+                            self.remove_entry(&hash, &entry);
+                            let e = Arc::new(e);
+                            sender.send_replace(Some(Err(e.clone())));
+                            return Err(CredentialsError::Shared(e));
+                        }
                     };
                 }
                 Some(item) => {
                     let mut receiver = item.0.clone();
-                    let creds = receiver.wait_for(|c| c.is_some()).await;
+                    let _waiting = item.expiration().is_none().then(|| {
+                        self.counters.waits.fetch_add(1, Ordering::Relaxed);
+                        WaitingGuard::new(&self.counters.waiting)
+                    });
+                    let creds = receiver
+                        .wait_for(|c| c.is_some())
+                        .await
+                        .map(|creds| creds.clone());
                     match creds {
-                        Err(_) => return Err(CredentialsError::CredentialsParse()),
-                        Ok(creds) => match creds.clone() {
-                            Some(creds) if { creds.is_expired() } => {
-                                self.cache.write().unwrap().remove(&hash)
-                            }
-                            Some(creds) => return Ok(creds),
-                            None => panic!("Should not happen"),
-                        },
+                        // The request fetching the credentials was cancelled,
+                        // so fetch them anew.
+                        Err(_) => self.remove_entry(&hash, &item),
+                        Ok(Some(Err(e))) => return Err(CredentialsError::Shared(e)),
+                        Ok(Some(Ok(creds))) if creds.expires_within(self.refresh_margin) => {
+                            refreshing = true;
+                            self.remove_entry(&hash, &item)
+                        }
+                        Ok(Some(Ok(creds))) => {
+                            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                            return Ok(creds);
+                        }
+                        Ok(None) => unreachable!("waited for credentials"),
                     };
                 }
             }
@@ -191,13 +1429,345 @@ impl CredentialsManager {
     }
 }
 
-#[cfg(test)]
-use tokio::sync::Barrier;
+impl CredentialsProvider for TokenExchangeProvider {
+    fn credentials<'a>(
+        &'a self,
+        token: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Credentials, CredentialsError>> {
+        Box::pin(async move {
+            self.exchange(token.ok_or(CredentialsError::TokenMissing())?)
+                .await
+        })
+    }
+
+    fn requires_token(&self) -> bool {
+        true
+    }
+
+    fn sweep(&self) -> usize {
+        self.targets
+            .write()
+            .unwrap()
+            .retain(|_, (_, resolved)| resolved.elapsed() < USER_INFO_MAX_AGE);
+        let now = Utc::now();
+        let mut cache = self.cache.write().unwrap();
+        let before = cache.len();
+        cache.retain(|_, value| {
+            value
+                .expiration()
+                .is_none_or(|expiration| expiration >= now)
+        });
+        before - cache.len()
+    }
+
+    fn stats(&self) -> CredentialsStats {
+        let counters = &self.counters;
+        let exchanges = counters.exchanges.load(Ordering::Relaxed);
+        let exchange_ms_total =
+            counters.exchange_micros_total.load(Ordering::Relaxed) as f64 / 1000.0;
+        CredentialsStats {
+            identities: self.cache.read().unwrap().len() as u64,
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            waits: counters.waits.load(Ordering::Relaxed),
+            waiting: counters.waiting.load(Ordering::Relaxed),
+            exchanges,
+            exchange_failures: counters.exchange_failures.load(Ordering::Relaxed),
+            refreshes: counters.refreshes.load(Ordering::Relaxed),
+            refresh_failures: counters.refresh_failures.load(Ordering::Relaxed),
+            exchange_ms_avg: if exchanges > 0 {
+                exchange_ms_total / exchanges as f64
+            } else {
+                0.0
+            },
+            exchange_ms_max: counters.exchange_micros_max.load(Ordering::Relaxed) as f64 / 1000.0,
+        }
+    }
+}
+
+/// Signs for all clients with the same operator-provided credentials.
+pub struct StaticProvider {
+    credentials: Credentials,
+}
+
+impl StaticProvider {
+    pub fn new(credentials: Credentials) -> Self {
+        StaticProvider { credentials }
+    }
+}
+
+impl CredentialsProvider for StaticProvider {
+    fn credentials<'a>(
+        &'a self,
+        _token: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Credentials, CredentialsError>> {
+        Box::pin(async move { Ok(self.credentials.clone()) })
+    }
+}
+
+/// Signs for all clients with credentials from the AWS default provider chain.
+pub struct ChainProvider {
+    chain: DefaultCredentialsChain,
+    /// The last credentials loaded from the chain.
+    current: tokio::sync::Mutex<Option<Credentials>>,
+}
+
+impl ChainProvider {
+    pub async fn new() -> Self {
+        ChainProvider {
+            chain: DefaultCredentialsChain::builder().build().await,
+            current: tokio::sync::Mutex::new(None),
+        }
+    }
+}
+
+impl CredentialsProvider for ChainProvider {
+    fn credentials<'a>(
+        &'a self,
+        _token: Option<&'a str>,
+    ) -> BoxFuture<'a, Result<Credentials, CredentialsError>> {
+        Box::pin(async move {
+            // Holding the lock while loading makes concurrent requests wait
+            // for a single refresh.
+            let mut current = self.current.lock().await;
+            match &*current {
+                Some(credentials) if !credentials.expires_within(CHAIN_REFRESH_MARGIN) => {
+                    Ok(credentials.clone())
+                }
+                _ => {
+                    info!("Loading credentials from the default chain");
+                    let credentials =
+                        Credentials::from_chain(self.chain.provide_credentials().await?);
+                    *current = Some(credentials.clone());
+                    Ok(credentials)
+                }
+            }
+        })
+    }
+}
+
+pub struct CredentialsManager {
+    provider: Arc<dyn CredentialsProvider>,
+    verifier: Option<SigV4Verifier>,
+    /// Credentials of anonymous requests, which are unsigned if `None`.
+    anonymous: Option<Credentials>,
+    api_keys: Option<ApiKeys>,
+    access_policy: Option<AccessPolicy>,
+    token_sources: Vec<TokenSource>,
+    user_info_endpoint: String,
+    user_info_capacity: usize,
+    sweep_interval: Duration,
+    user_info: RwLock<HashMap<blake3::Hash, (UserInfo, Instant)>>,
+}
+
+impl CredentialsManager {
+    pub fn new(
+        provider: Arc<dyn CredentialsProvider>,
+        verifier: Option<SigV4Verifier>,
+        anonymous: Option<Credentials>,
+        api_keys: Option<ApiKeys>,
+        access_policy: Option<AccessPolicy>,
+        config: &CredentialsConfig,
+    ) -> Self {
+        CredentialsManager {
+            provider,
+            verifier,
+            anonymous,
+            api_keys,
+            access_policy,
+            token_sources: config.token_sources.clone(),
+            user_info_endpoint: config.user_info_endpoint.clone(),
+            user_info_capacity: config.credentials_cache_capacity.max(1),
+            sweep_interval: Duration::from_secs(config.credentials_sweep_interval),
+            user_info: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns whether clients must present a token to be signed for.
+    pub fn requires_token(&self) -> bool {
+        self.provider.requires_token()
+    }
+
+    pub fn sweep_interval(&self) -> Duration {
+        self.sweep_interval
+    }
+
+    /// Returns the token a request authenticates with, or `None` if the
+    /// provider doesn't need one. The signatures of SigV4-signed requests are
+    /// verified if client keys are configured.
+    pub fn authenticate(&self, parts: &Parts) -> Result<Option<String>, CredentialsError> {
+        if let Some(api_keys) = &self.api_keys {
+            if let Some(key) = parts.headers.get(API_KEY_HEADER) {
+                let key = key.to_str().unwrap_or_default();
+                return match api_keys.get(key) {
+                    Some(_) => Ok(Some(key.to_string())),
+                    None => Err(CredentialsError::InvalidToken(
+                        "unknown API key".to_string(),
+                    )),
+                };
+            }
+        }
+        if let Some(verifier) = &self.verifier {
+            if SigV4Verifier::is_signed(parts) {
+                return verifier.verify(parts);
+            }
+        }
+        match Credentials::token_from_request(parts, &self.token_sources) {
+            Ok(token) => Ok(Some(token)),
+            Err(_) if !self.requires_token() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns how the payload of a request that `authenticate` accepted is
+    /// checked against its signature, if it is signed.
+    pub fn payload_check(&self, parts: &Parts) -> Option<PayloadCheck> {
+        let verifier = self.verifier.as_ref()?;
+        if parts.headers.contains_key(API_KEY_HEADER) && self.api_keys.is_some() {
+            return None;
+        }
+        match SigV4Verifier::is_signed(parts) {
+            true => verifier.payload_check(parts),
+            false => None,
+        }
+    }
+
+    /// Removes expired credentials and user info, returning the number of
+    /// removed credentials.
+    pub fn sweep(&self) -> usize {
+        self.user_info
+            .write()
+            .unwrap()
+            .retain(|_, (_, fetched)| fetched.elapsed() < USER_INFO_MAX_AGE);
+        self.provider.sweep()
+    }
+
+    pub fn stats(&self) -> CredentialsStats {
+        self.provider.stats()
+    }
+
+    pub async fn get_user_info(&self, token: &str) -> Result<UserInfo, CredentialsError> {
+        let hash = blake3::hash(token.as_bytes());
+        if let Some((user_info, fetched)) = self.user_info.read().unwrap().get(&hash) {
+            if fetched.elapsed() < USER_INFO_MAX_AGE {
+                return Ok(user_info.clone());
+            }
+        }
+        info!("User info cache miss for token");
+        let user_info = UserInfo::from_token(&self.user_info_endpoint, token).await?;
+        let mut cached = self.user_info.write().unwrap();
+        if cached.len() >= self.user_info_capacity {
+            let oldest = cached
+                .iter()
+                .min_by_key(|(_, (_, fetched))| *fetched)
+                .map(|(hash, _)| *hash);
+            if let Some(oldest) = oldest {
+                cached.remove(&oldest);
+            }
+        }
+        cached.insert(hash, (user_info.clone(), Instant::now()));
+        Ok(user_info)
+    }
+
+    /// Returns the credentials to sign requests of a client presenting
+    /// `token` with.
+    pub async fn get_credentials(
+        &self,
+        token: Option<&str>,
+    ) -> Result<Credentials, CredentialsError> {
+        if let (Some(api_keys), Some(token)) = (&self.api_keys, token) {
+            if let Some(api_key) = api_keys.get(token) {
+                return match &api_key.identity {
+                    Identity::Static(credentials) => Ok(credentials.clone()),
+                    Identity::RefreshToken(state) => {
+                        let access_token = api_keys.access_token(state).await?;
+                        self.provider.credentials(Some(&access_token)).await
+                    }
+                };
+            }
+        }
+        self.provider.credentials(token).await
+    }
+
+    /// Returns true if the client presenting `token` may make a `method`
+    /// request for `path` in `bucket` under the access policy. Anonymous
+    /// requests and API keys have no user attributes.
+    pub async fn authorize(
+        &self,
+        token: Option<&str>,
+        method: &Method,
+        bucket: &str,
+        path: &str,
+    ) -> Result<bool, CredentialsError> {
+        let Some(policy) = &self.access_policy else {
+            return Ok(true);
+        };
+        let user_info = match token {
+            Some(token) if self.api_key_name(token).is_none() => {
+                Some(self.get_user_info(token).await?)
+            }
+            _ => None,
+        };
+        Ok(policy.allows(user_info.as_ref(), method, bucket, path))
+    }
+
+    /// Returns the user presenting `token` and their organization as logged
+    /// with their requests: their id, or `api-key:<name>` for API keys. User
+    /// info that isn't cached is only fetched if `fetch` is set, and failures
+    /// to fetch it are ignored.
+    pub async fn identify(&self, token: &str, fetch: bool) -> Option<UserIdentity> {
+        if let Some(name) = self.api_key_name(token) {
+            return Some(UserIdentity {
+                user: format!("api-key:{}", name),
+                username: None,
+                organization: None,
+            });
+        }
+        let hash = blake3::hash(token.as_bytes());
+        let cached = self
+            .user_info
+            .read()
+            .unwrap()
+            .get(&hash)
+            .filter(|(_, fetched)| fetched.elapsed() < USER_INFO_MAX_AGE)
+            .map(|(user_info, _)| user_info.clone());
+        let user_info = match cached {
+            Some(user_info) => user_info,
+            None if fetch && self.requires_token() => match self.get_user_info(token).await {
+                Ok(user_info) => user_info,
+                Err(e) => {
+                    debug!("Failed to identify user: {}", e);
+                    return None;
+                }
+            },
+            None => return None,
+        };
+        let organization = user_info.organization_rid().map(str::to_string);
+        Some(UserIdentity {
+            user: user_info.id,
+            username: Some(user_info.username),
+            organization,
+        })
+    }
+
+    /// Returns the name of the API key `token` is, if it is one.
+    pub fn api_key_name(&self, token: &str) -> Option<&str> {
+        let api_key = self.api_keys.as_ref()?.get(token)?;
+        Some(&api_key.name)
+    }
+
+    /// Returns the credentials to sign anonymous requests with, or `None` if
+    /// they are sent unsigned.
+    pub fn get_anonymous_credentials(&self) -> Option<Credentials> {
+        self.anonymous.clone()
+    }
+}
 
 // mod tests {
 //     #[tokio::test]
 //     async fn test_credentials_manager_concurrent_get_credentials() {
 //         use super::*;
+//         use tokio::sync::Barrier;
 //         tracing_subscriber::fmt().init();
 
 //         let token = std::env::var("TOKEN").unwrap();
@@ -239,3 +1809,91 @@ use tokio::sync::Barrier;
 //         assert_eq!(cache_read.len(), 1);
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+
+    use super::*;
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[command(flatten)]
+        credentials: CredentialsConfig,
+    }
+
+    /// Parses the configuration of `--auth-mode static` with `args`.
+    fn parse(args: &[&str]) -> CredentialsConfig {
+        let args = ["s3proxy", "--auth-mode", "static"].iter().chain(args);
+        <Cli as clap::Parser>::parse_from(args).credentials
+    }
+
+    /// Starts an STS endpoint that answers every request with `body`.
+    fn sts(body: &'static str) -> String {
+        let service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |_| async move {
+                Ok::<_, Infallible>(Response::new(Body::from(body)))
+            }))
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+        let endpoint = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        endpoint
+    }
+
+    #[tokio::test]
+    async fn malformed_sts_response() {
+        for body in [
+            "",
+            "<AssumeRoleWithWebIdentityResponse><AssumeRoleWithWebIdentityResult>",
+            "<AssumeRoleWithWebIdentityResponse></AssumeRoleWithWebIdentityResponse>",
+        ] {
+            let result = Credentials::from_token(&sts(body), "token", None, None, None).await;
+            assert!(
+                matches!(result, Err(CredentialsError::CredentialsParse())),
+                "{:?}: {:?}",
+                body,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn anonymous_credentials_are_opt_in() {
+        let path = std::env::temp_dir().join(format!(
+            "s3proxy-anonymous-credentials-{}",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "[default]\naws_access_key_id = anonymous\naws_secret_access_key = secret\n",
+        )
+        .unwrap();
+        // The keys of the deployment don't sign anonymous requests.
+        let config = parse(&["--access-key-id", "id", "--secret-access-key", "secret"]);
+        assert!(config.anonymous_credentials().unwrap().is_none());
+        let config = parse(&["--anonymous-credentials-file", path.to_str().unwrap()]);
+        let credentials = config.anonymous_credentials().unwrap().unwrap();
+        assert_eq!(credentials.access_key_id, "anonymous");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn static_credentials_with_one_key() {
+        let error = |config: CredentialsConfig| match config.static_credentials() {
+            Err(CredentialsError::Configuration(message)) => message,
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        };
+        let message = error(parse(&["--access-key-id", "id"]));
+        assert!(
+            message.contains("without --secret-access-key"),
+            "{}",
+            message
+        );
+        let message = error(parse(&["--secret-access-key", "secret"]));
+        assert!(message.contains("without --access-key-id"), "{}", message);
+    }
+}
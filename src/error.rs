@@ -0,0 +1,86 @@
+use hyper::{Body, Response, StatusCode};
+
+use crate::request_id;
+use crate::xml_writer::ErrorResponse;
+
+/// Returns the body of an S3 error, with the id of the request being served.
+pub fn body(code: &str, message: &str, resource: &str) -> String {
+    ErrorResponse {
+        code: code.to_string(),
+        message: message.to_string(),
+        resource: resource.to_string(),
+        request_id: request_id::current(),
+    }
+    .to_xml()
+}
+
+/// Builds an S3 error response for `resource`, the path of the request.
+pub fn response(status: StatusCode, code: &str, message: &str, resource: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/xml")
+        .body(Body::from(body(code, message, resource)))
+        .unwrap()
+}
+
+/// Returns the S3 error code and message of `status`, for errors that come
+/// without a code of their own, like upstream responses to HEAD requests.
+fn code(status: StatusCode) -> (&'static str, &'static str) {
+    match status {
+        StatusCode::BAD_REQUEST => ("InvalidRequest", "Invalid Request"),
+        StatusCode::FORBIDDEN => ("AccessDenied", "Access Denied"),
+        StatusCode::NOT_FOUND => ("NoSuchKey", "The specified key does not exist."),
+        StatusCode::METHOD_NOT_ALLOWED => (
+            "MethodNotAllowed",
+            "The specified method is not allowed against this resource.",
+        ),
+        StatusCode::PRECONDITION_FAILED => (
+            "PreconditionFailed",
+            "At least one of the pre-conditions you specified did not hold.",
+        ),
+        StatusCode::RANGE_NOT_SATISFIABLE => {
+            ("InvalidRange", "The requested range is not satisfiable.")
+        }
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+            ("SlowDown", "Please reduce your request rate.")
+        }
+        StatusCode::NOT_IMPLEMENTED => (
+            "NotImplemented",
+            "A header or method you provided implies functionality that is not implemented.",
+        ),
+        StatusCode::BAD_GATEWAY => ("BadGateway", "The upstream could not be reached."),
+        StatusCode::GATEWAY_TIMEOUT => (
+            "GatewayTimeout",
+            "The request timed out waiting for the upstream.",
+        ),
+        _ if status.is_client_error() => ("InvalidRequest", "Invalid Request"),
+        _ => (
+            "InternalError",
+            "We encountered an internal error. Please try again.",
+        ),
+    }
+}
+
+/// Builds the S3 error response of `status`, or an empty response for
+/// statuses that aren't errors, like 304 Not Modified.
+pub fn from_status(status: StatusCode, resource: &str) -> Response<Body> {
+    if !status.is_client_error() && !status.is_server_error() {
+        return Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .unwrap();
+    }
+    let (code, message) = code(status);
+    response(status, code, message, resource)
+}
+
+/// Builds the response of an upstream request that failed without an
+/// upstream response, like when the upstream couldn't be reached.
+pub fn upstream_failure(e: &reqwest::Error, resource: &str) -> Response<Body> {
+    let status = match e.status() {
+        Some(status) => status,
+        None if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+        None => StatusCode::BAD_GATEWAY,
+    };
+    from_status(status, resource)
+}
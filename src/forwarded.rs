@@ -0,0 +1,277 @@
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+use hyper::HeaderMap;
+use ipnet::IpNet;
+
+/// Peers whose `Forwarded` and `X-Forwarded-For` headers are trusted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustedProxy {
+    /// Peers in a network, or a single address.
+    Net(IpNet),
+    /// Clients of Unix socket listeners, e.g. a sidecar on the same node.
+    Unix,
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unix" {
+            return Ok(TrustedProxy::Unix);
+        }
+        s.parse::<IpNet>()
+            .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+            .map(TrustedProxy::Net)
+            .map_err(|_| format!("expected a CIDR, an IP address or `unix`, got {:?}", s))
+    }
+}
+
+fn is_trusted(trusted: &[TrustedProxy], peer: Option<IpAddr>) -> bool {
+    trusted.iter().any(|proxy| match (proxy, peer) {
+        (TrustedProxy::Net(net), Some(ip)) => net.contains(&ip),
+        (TrustedProxy::Unix, None) => true,
+        _ => false,
+    })
+}
+
+/// Returns the address of the client that sent a request to the peer at
+/// `remote_addr`, which is `None` for Unix socket peers.
+///
+/// The addresses that trusted peers forward, in a `Forwarded` header or, if
+/// there is none, in `X-Forwarded-For`, are followed from the nearest one
+/// as long as they are trusted too. The headers of untrusted peers are
+/// ignored, since clients can send anything in them.
+pub fn client_ip(
+    headers: &HeaderMap,
+    remote_addr: Option<SocketAddr>,
+    trusted: &[TrustedProxy],
+) -> Option<IpAddr> {
+    let mut client = remote_addr.map(|addr| addr.ip());
+    if !is_trusted(trusted, client) {
+        return client;
+    }
+    let values = |name: &str| -> Vec<String> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|hop| hop.trim().to_string())
+            .collect()
+    };
+    let forwarded = values("forwarded");
+    let hops: Vec<Option<IpAddr>> = match forwarded.is_empty() {
+        false => forwarded.iter().map(|hop| forwarded_for(hop)).collect(),
+        true => values("x-forwarded-for")
+            .iter()
+            .map(|hop| parse_ip(hop))
+            .collect(),
+    };
+    for hop in hops.into_iter().rev() {
+        // Unknown and obfuscated addresses end the chain at the last peer
+        // that could be identified.
+        let Some(ip) = hop else {
+            break;
+        };
+        client = Some(ip);
+        if !is_trusted(trusted, client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Returns the address in the `for` parameter of an element of a
+/// `Forwarded` header, like `for=192.0.2.60;proto=https`.
+fn forwarded_for(element: &str) -> Option<IpAddr> {
+    element.split(';').find_map(|pair| {
+        let (name, value) = pair.trim().split_once('=')?;
+        match name.eq_ignore_ascii_case("for") {
+            true => parse_ip(value.trim_matches('"')),
+            false => None,
+        }
+    })
+}
+
+/// Parses an address with an optional port, like `192.0.2.60:8080`,
+/// `[2001:db8::1]:4711` or `2001:db8::1`.
+fn parse_ip(hop: &str) -> Option<IpAddr> {
+    if let Ok(ip) = hop.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = hop.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    hop.parse::<SocketAddr>().ok().map(|addr| addr.ip())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn peer(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 40000))
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    fn trusted() -> Vec<TrustedProxy> {
+        vec!["10.0.0.0/8".parse().unwrap(), "unix".parse().unwrap()]
+    }
+
+    #[test]
+    fn untrusted_peers() {
+        let headers = request_headers(&[
+            ("forwarded", "for=203.0.113.1"),
+            ("x-forwarded-for", "203.0.113.2"),
+        ]);
+        assert_eq!(
+            client_ip(&headers, peer("192.0.2.1"), &trusted()),
+            ip("192.0.2.1")
+        );
+        assert_eq!(client_ip(&headers, peer("10.0.0.1"), &[]), ip("10.0.0.1"));
+        assert_eq!(client_ip(&headers, None, &[]), None);
+    }
+
+    #[test]
+    fn chains_of_hops() {
+        let cases = [
+            // The nearest untrusted hop is the client, whatever it claims.
+            (
+                "x-forwarded-for",
+                "198.51.100.7, 203.0.113.1, 10.0.0.2",
+                "203.0.113.1",
+            ),
+            (
+                "x-forwarded-for",
+                "203.0.113.1,10.0.0.3,10.0.0.2",
+                "203.0.113.1",
+            ),
+            (
+                "forwarded",
+                "for=198.51.100.7, for=203.0.113.1, for=10.0.0.2",
+                "203.0.113.1",
+            ),
+            // Chains of trusted hops end at the first one.
+            ("x-forwarded-for", "10.0.0.3, 10.0.0.2", "10.0.0.3"),
+        ];
+        for (name, value, expected) in cases {
+            let headers = request_headers(&[(name, value)]);
+            assert_eq!(
+                client_ip(&headers, peer("10.0.0.1"), &trusted()),
+                ip(expected),
+                "{}: {}",
+                name,
+                value
+            );
+        }
+        // Elements may be split across several headers.
+        let headers = request_headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            ("x-forwarded-for", "203.0.113.1, 10.0.0.2"),
+        ]);
+        assert_eq!(
+            client_ip(&headers, peer("10.0.0.1"), &trusted()),
+            ip("203.0.113.1")
+        );
+        // Unix socket peers are trusted with `unix`.
+        let headers = request_headers(&[("x-forwarded-for", "203.0.113.1")]);
+        assert_eq!(client_ip(&headers, None, &trusted()), ip("203.0.113.1"));
+    }
+
+    #[test]
+    fn forwarded_takes_precedence() {
+        let headers = request_headers(&[
+            ("forwarded", "for=203.0.113.1"),
+            ("x-forwarded-for", "198.51.100.7"),
+        ]);
+        assert_eq!(
+            client_ip(&headers, peer("10.0.0.1"), &trusted()),
+            ip("203.0.113.1")
+        );
+    }
+
+    #[test]
+    fn malformed_and_obfuscated_hops() {
+        // Hops that can't be identified end the chain at the last one that
+        // could.
+        let cases = [
+            ("x-forwarded-for", "203.0.113.1, not-an-ip", "10.0.0.1"),
+            (
+                "x-forwarded-for",
+                "203.0.113.1, unknown, 10.0.0.2",
+                "10.0.0.2",
+            ),
+            ("x-forwarded-for", "203.0.113.1, , 10.0.0.2", "10.0.0.2"),
+            (
+                "forwarded",
+                "for=203.0.113.1, for=_hidden, for=10.0.0.2",
+                "10.0.0.2",
+            ),
+            ("forwarded", "for=unknown", "10.0.0.1"),
+            ("forwarded", "proto=https;by=10.0.0.1", "10.0.0.1"),
+            ("forwarded", "for=\"[2001:db8::1\"", "10.0.0.1"),
+            ("forwarded", "for", "10.0.0.1"),
+        ];
+        for (name, value, expected) in cases {
+            let headers = request_headers(&[(name, value)]);
+            assert_eq!(
+                client_ip(&headers, peer("10.0.0.1"), &trusted()),
+                ip(expected),
+                "{}: {}",
+                name,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn forwarded_elements() {
+        let cases = [
+            ("for=192.0.2.60", ip("192.0.2.60")),
+            (
+                "for=192.0.2.60;proto=https;by=203.0.113.43",
+                ip("192.0.2.60"),
+            ),
+            ("proto=https; For=\"192.0.2.60:8080\"", ip("192.0.2.60")),
+            ("for=\"[2001:db8:cafe::17]\"", ip("2001:db8:cafe::17")),
+            ("for=\"[2001:db8:cafe::17]:4711\"", ip("2001:db8:cafe::17")),
+            ("for=_hidden", None),
+            ("for=\"_SEVKISEK\"", None),
+            ("by=192.0.2.60", None),
+            ("", None),
+        ];
+        for (element, expected) in cases {
+            assert_eq!(forwarded_for(element), expected, "{}", element);
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        let cases = [
+            ("192.0.2.60", ip("192.0.2.60")),
+            ("192.0.2.60:8080", ip("192.0.2.60")),
+            ("2001:db8::1", ip("2001:db8::1")),
+            ("[2001:db8::1]", ip("2001:db8::1")),
+            ("[2001:db8::1]:4711", ip("2001:db8::1")),
+            ("[192.0.2.60", None),
+            ("192.0.2.256", None),
+            ("example.com", None),
+            ("unknown", None),
+        ];
+        for (hop, expected) in cases {
+            assert_eq!(parse_ip(hop), expected, "{}", hop);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures_util::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::info;
+
+use crate::backend::{Backend, Payload};
+
+/// OAuth scope of the access tokens that requests are authorized with.
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Host of the metadata server of GCE, GKE and Cloud Run, which can be
+/// overridden with `GCE_METADATA_HOST` like in the Google client libraries.
+const METADATA_HOST: &str = "metadata.google.internal";
+
+/// How long before their expiration access tokens are renewed.
+const ACCESS_TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+
+/// Lifetime of the assertions that service account keys sign.
+const ASSERTION_LIFETIME: u64 = 3600;
+
+/// The fields of a service account key file that tokens are obtained with.
+#[derive(Deserialize)]
+struct ServiceAccountKeyFile {
+    client_email: String,
+    private_key: String,
+    private_key_id: Option<String>,
+    token_uri: String,
+}
+
+struct ServiceAccountKey {
+    client_email: String,
+    key_id: Option<String>,
+    token_uri: String,
+    key: jsonwebtoken::EncodingKey,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+/// Google Cloud Storage, accessed through its XML API, which understands the
+/// S3 requests of the proxy. Requests are authorized with the access tokens
+/// of a service account rather than the credentials of clients.
+pub struct GcsBackend {
+    /// The key that tokens are obtained with, or `None` to get them from the
+    /// metadata server.
+    key: Option<ServiceAccountKey>,
+    http_client: reqwest::Client,
+    /// The current access token and when it expires.
+    access_token: Mutex<Option<(String, Instant)>>,
+}
+
+impl GcsBackend {
+    /// Sets up the backend with the service account key in
+    /// `service_account_file`, or with the service account of the instance
+    /// if unset.
+    pub fn new(service_account_file: Option<&Path>) -> Result<Self, String> {
+        let key = match service_account_file {
+            Some(path) => {
+                let invalid = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+                let text = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+                let file: ServiceAccountKeyFile =
+                    serde_json::from_str(&text).map_err(|e| invalid(&e))?;
+                let key = jsonwebtoken::EncodingKey::from_rsa_pem(file.private_key.as_bytes())
+                    .map_err(|e| invalid(&e))?;
+                Some(ServiceAccountKey {
+                    client_email: file.client_email,
+                    key_id: file.private_key_id,
+                    token_uri: file.token_uri,
+                    key,
+                })
+            }
+            None => None,
+        };
+        Ok(GcsBackend {
+            key,
+            http_client: reqwest::Client::new(),
+            access_token: Mutex::new(None),
+        })
+    }
+
+    /// Returns a current access token, renewing it when it is about to
+    /// expire.
+    async fn access_token(&self) -> Result<String, String> {
+        // Holding the lock while renewing makes concurrent requests wait for
+        // a single renewal.
+        let mut access_token = self.access_token.lock().await;
+        if let Some((token, expires)) = &*access_token {
+            if Instant::now() + ACCESS_TOKEN_REFRESH_MARGIN < *expires {
+                return Ok(token.clone());
+            }
+        }
+        info!("Renewing GCS access token");
+        let request = match &self.key {
+            Some(key) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let claims = Claims {
+                    iss: &key.client_email,
+                    scope: SCOPE,
+                    aud: &key.token_uri,
+                    iat: now,
+                    exp: now + ASSERTION_LIFETIME,
+                };
+                let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
+                header.kid = key.key_id.clone();
+                let assertion = jsonwebtoken::encode(&header, &claims, &key.key)
+                    .map_err(|e| format!("failed to sign assertion: {}", e))?;
+                self.http_client.post(&key.token_uri).form(&[
+                    ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                    ("assertion", assertion.as_str()),
+                ])
+            }
+            None => {
+                let host = std::env::var("GCE_METADATA_HOST")
+                    .unwrap_or_else(|_| METADATA_HOST.to_string());
+                self.http_client
+                    .get(format!(
+                        "http://{}/computeMetadata/v1/instance/service-accounts/default/token",
+                        host
+                    ))
+                    .header("metadata-flavor", "Google")
+            }
+        };
+        let text = request
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| format!("failed to get access token: {}", e))?
+            .text()
+            .await
+            .map_err(|e| format!("failed to get access token: {}", e))?;
+        let res: TokenResponse = serde_json::from_str(&text)
+            .map_err(|e| format!("failed to parse access token: {}", e))?;
+        let expires = Instant::now() + Duration::from_secs(res.expires_in.unwrap_or(3600));
+        *access_token = Some((res.access_token.clone(), expires));
+        Ok(res.access_token)
+    }
+}
+
+impl Backend for GcsBackend {
+    fn authorize<'a>(
+        &'a self,
+        request: &'a mut reqwest::Request,
+        _credentials: &'a aws_credential_types::Credentials,
+        _payload: &'a Payload,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let token = self.access_token().await?;
+            let value = format!("Bearer {}", token)
+                .parse()
+                .map_err(|_| "invalid access token".to_string())?;
+            request
+                .headers_mut()
+                .insert(hyper::header::AUTHORIZATION, value);
+            Ok(())
+        })
+    }
+}
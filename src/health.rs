@@ -0,0 +1,44 @@
+use hyper::http::request::Parts;
+use hyper::{Body, Method, Response, StatusCode};
+
+use crate::admin::json_response;
+use crate::s3_handler::S3Handler;
+
+/// Path of the liveness probe, which needs no token.
+pub const HEALTHZ_PATH: &str = "/healthz";
+
+/// Path of the readiness probe, which needs no token.
+pub const READYZ_PATH: &str = "/readyz";
+
+/// Answers health probes, or returns `None` for other requests. Probes are
+/// `GET` or `HEAD` requests without a query string, so that listings and
+/// writes of a bucket of the same name still reach the router.
+pub async fn route_health(parts: &Parts, s3: &S3Handler) -> Option<Response<Body>> {
+    let probe = matches!(parts.method, Method::GET | Method::HEAD) && parts.uri.query().is_none();
+    if !probe {
+        return None;
+    }
+    match parts.uri.path() {
+        HEALTHZ_PATH => Some(json_response(
+            StatusCode::OK,
+            serde_json::json!({
+                "status": "ok",
+                "version": env!("CARGO_PKG_VERSION"),
+                "pid": std::process::id(),
+                "uptime_seconds": s3.uptime().as_secs(),
+            }),
+        )),
+        READYZ_PATH => {
+            let readiness = s3.readiness().await;
+            let status = match readiness.ready {
+                true => StatusCode::OK,
+                false => StatusCode::SERVICE_UNAVAILABLE,
+            };
+            Some(json_response(
+                status,
+                serde_json::to_value(readiness).unwrap(),
+            ))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,104 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use hyper::{Body, Method, Request, Response};
+
+/// A request, as known once it was routed.
+pub struct RequestInfo<'a> {
+    pub request_id: &'a str,
+    pub method: &'a Method,
+    pub path: &'a str,
+    pub client_ip: Option<IpAddr>,
+    pub user: Option<&'a str>,
+    pub bucket: Option<&'a str>,
+    pub key: Option<&'a str>,
+}
+
+/// An authenticated S3 request that the access policy allows.
+pub struct AuthInfo<'a> {
+    pub method: &'a Method,
+    pub bucket: &'a str,
+    /// Key of the object, or the prefix of a listing.
+    pub path: &'a str,
+    /// Whether the request has no token and reads a public bucket.
+    pub anonymous: bool,
+    pub user: Option<&'a str>,
+    pub organization: Option<&'a str>,
+}
+
+/// A block written into the disk cache.
+pub struct CacheFillInfo<'a> {
+    pub tenant: Option<&'a str>,
+    pub bucket: &'a str,
+    pub key: &'a str,
+    pub index: u64,
+    pub bytes: u64,
+}
+
+/// Custom logic that services embedding the proxy plug into the handling of
+/// requests, e.g. to watermark responses or to send audit events elsewhere.
+///
+/// Hooks are called on the request path, so they must not block; slow work
+/// should be spawned. All methods do nothing by default.
+pub trait Hook: Send + Sync {
+    /// Called before a request is routed, with the request as received.
+    /// Returning a response answers the request without routing it.
+    fn on_request(&self, _req: &mut Request<Body>) -> Option<Response<Body>> {
+        None
+    }
+
+    /// Called once an S3 request is authenticated and authorized. Returning
+    /// false denies it with `403 AccessDenied`.
+    fn on_auth(&self, _auth: &AuthInfo<'_>) -> bool {
+        true
+    }
+
+    /// Called with the response of a request before it is sent; its body can
+    /// be replaced.
+    fn on_response(&self, _req: &RequestInfo<'_>, _res: &mut Response<Body>) {}
+
+    /// Called when a block of an object was written into the disk cache.
+    fn on_cache_fill(&self, _fill: &CacheFillInfo<'_>) {}
+}
+
+/// The hooks of the proxy, called in the order they were added.
+#[derive(Clone, Default)]
+pub struct Hooks(Vec<Arc<dyn Hook>>);
+
+impl Hooks {
+    pub fn add(&mut self, hook: impl Hook + 'static) {
+        self.0.push(Arc::new(hook));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the response of the first hook that answers the request.
+    pub fn on_request(&self, req: &mut Request<Body>) -> Option<Response<Body>> {
+        self.0.iter().find_map(|hook| hook.on_request(req))
+    }
+
+    /// Returns whether all hooks allow the request.
+    pub fn on_auth(&self, auth: &AuthInfo<'_>) -> bool {
+        self.0.iter().all(|hook| hook.on_auth(auth))
+    }
+
+    pub fn on_response(&self, req: &RequestInfo<'_>, res: &mut Response<Body>) {
+        for hook in &self.0 {
+            hook.on_response(req, res);
+        }
+    }
+
+    pub fn on_cache_fill(&self, fill: &CacheFillInfo<'_>) {
+        for hook in &self.0 {
+            hook.on_cache_fill(fill);
+        }
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Hooks({})", self.0.len())
+    }
+}
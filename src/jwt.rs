@@ -0,0 +1,423 @@
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::credentials::CredentialsError;
+
+/// Minimum time between refetches of the key set for tokens signed with an
+/// unknown key, so that such tokens can't be used to flood the JWKS endpoint.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The key set of the JWKS endpoint as far as it is known.
+#[derive(Default)]
+struct KeyCache {
+    /// The last fetched key set.
+    keys: Option<JwkSet>,
+    /// When the key set was last requested, whether or not that succeeded.
+    attempted: Option<Instant>,
+}
+
+/// Validates bearer tokens locally against the keys of a JWKS endpoint, so
+/// that invalid tokens are rejected before they are exchanged for
+/// credentials.
+pub struct JwtValidator {
+    jwks_url: String,
+    issuer: Option<String>,
+    audience: Option<String>,
+    refresh_interval: Duration,
+    client: reqwest::Client,
+    keys: RwLock<KeyCache>,
+}
+
+fn invalid(reason: impl std::fmt::Display) -> CredentialsError {
+    CredentialsError::InvalidToken(reason.to_string())
+}
+
+/// Finds the key a token was signed with. Tokens without a key id can only
+/// be checked against a key set holding a single key.
+fn find_key<'a>(keys: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }
+}
+
+/// A claim that may hold one value or a list of them.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl OneOrMany {
+    fn values(&self) -> Vec<&str> {
+        match self {
+            OneOrMany::One(value) => vec![value.as_str()],
+            OneOrMany::Many(values) => values.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    aud: Option<OneOrMany>,
+    /// Space-separated scopes, as in OAuth access tokens.
+    scope: Option<String>,
+    /// Scopes as a list, as issued by some providers.
+    scp: Option<OneOrMany>,
+}
+
+/// Audience and scopes that tokens must have been minted with to be accepted
+/// by the proxy, checked whether or not their signature is validated.
+pub struct ClaimRequirements {
+    /// Audiences of which a token must have at least one.
+    audiences: Vec<String>,
+    /// Scopes that a token must all have.
+    scopes: Vec<String>,
+}
+
+impl ClaimRequirements {
+    pub fn new(audiences: Vec<String>, scopes: Vec<String>) -> Self {
+        ClaimRequirements { audiences, scopes }
+    }
+
+    /// Decodes the claims of a token without checking its signature and
+    /// rejects it if they lack a required audience or scope.
+    pub fn check(&self, token: &str) -> Result<(), CredentialsError> {
+        let rejected = |reason: String| CredentialsError::TokenRejected(reason);
+        let header = decode_header(token).map_err(invalid)?;
+        let mut validation = Validation::new(header.alg);
+        validation.insecure_disable_signature_validation();
+        validation.validate_exp = false;
+        validation.validate_aud = false;
+        validation.required_spec_claims.clear();
+        let claims = decode::<Claims>(token, &DecodingKey::from_secret(&[]), &validation)
+            .map_err(invalid)?
+            .claims;
+
+        let audiences = claims
+            .aud
+            .as_ref()
+            .map(OneOrMany::values)
+            .unwrap_or_default();
+        if !self.audiences.is_empty()
+            && !self
+                .audiences
+                .iter()
+                .any(|audience| audiences.contains(&audience.as_str()))
+        {
+            return Err(rejected(format!("audience {:?} not accepted", audiences)));
+        }
+        let mut scopes: Vec<&str> = claims
+            .scope
+            .as_deref()
+            .map(|scope| scope.split_whitespace().collect())
+            .unwrap_or_default();
+        scopes.extend(
+            claims
+                .scp
+                .as_ref()
+                .map(OneOrMany::values)
+                .unwrap_or_default(),
+        );
+        if let Some(missing) = self
+            .scopes
+            .iter()
+            .find(|scope| !scopes.contains(&scope.as_str()))
+        {
+            return Err(rejected(format!("missing scope {}", missing)));
+        }
+        Ok(())
+    }
+}
+
+impl JwtValidator {
+    pub fn new(
+        jwks_url: &str,
+        issuer: Option<String>,
+        audience: Option<String>,
+        refresh_interval: Duration,
+    ) -> Self {
+        JwtValidator {
+            jwks_url: jwks_url.to_string(),
+            issuer,
+            audience,
+            refresh_interval,
+            client: reqwest::Client::new(),
+            keys: RwLock::new(KeyCache::default()),
+        }
+    }
+
+    async fn fetch_keys(&self) -> Result<JwkSet, CredentialsError> {
+        info!(url = self.jwks_url, "Fetching JWKS");
+        let res = self.client.get(&self.jwks_url).send().await?;
+        let text = res.error_for_status()?.text().await?;
+        serde_json::from_str(&text).map_err(|e| invalid(format!("malformed JWKS: {}", e)))
+    }
+
+    /// Returns the key with id `kid`, refetching the key set when it is older
+    /// than the refresh interval or doesn't hold the key. Refetches are
+    /// limited by the time of the last attempt, so that they are neither
+    /// repeated by concurrent requests nor while the endpoint is failing, in
+    /// which case the keys fetched before are used.
+    async fn key(&self, kid: Option<&str>) -> Result<Jwk, CredentialsError> {
+        if let Some(result) = self.cached_key(&*self.keys.read().await, kid) {
+            return result;
+        }
+        let mut cache = self.keys.write().await;
+        // Another request may have fetched the key set in the meantime.
+        if let Some(result) = self.cached_key(&cache, kid) {
+            return result;
+        }
+        cache.attempted = Some(Instant::now());
+        match self.fetch_keys().await {
+            Ok(keys) => {
+                let jwk = find_key(&keys, kid).cloned();
+                cache.keys = Some(keys);
+                jwk.ok_or_else(|| invalid("unknown signing key"))
+            }
+            Err(e) => match cache.keys.as_ref().and_then(|keys| find_key(keys, kid)) {
+                Some(jwk) => {
+                    warn!(
+                        "Failed to refetch JWKS, using the keys fetched before: {}",
+                        e
+                    );
+                    Ok(jwk.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    /// Returns the key with id `kid` if the key set needn't be refetched for
+    /// it, or the error to reject the token with if it mustn't be yet.
+    fn cached_key(
+        &self,
+        cache: &KeyCache,
+        kid: Option<&str>,
+    ) -> Option<Result<Jwk, CredentialsError>> {
+        let attempted = cache.attempted?.elapsed();
+        match cache.keys.as_ref().and_then(|keys| find_key(keys, kid)) {
+            Some(jwk) if attempted < self.refresh_interval => Some(Ok(jwk.clone())),
+            None if attempted < MIN_REFRESH_INTERVAL => Some(Err(match cache.keys {
+                Some(_) => invalid("unknown signing key"),
+                None => invalid("signing keys unavailable"),
+            })),
+            _ => None,
+        }
+    }
+
+    /// Checks the signature, expiry, issuer and audience of a token.
+    pub async fn validate(&self, token: &str) -> Result<(), CredentialsError> {
+        let header = decode_header(token).map_err(invalid)?;
+        // Keys of a JWKS are public, so symmetric algorithms are never valid.
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(invalid("symmetric signing algorithm"));
+        }
+        let jwk = self.key(header.kid.as_deref()).await?;
+        let key = DecodingKey::from_jwk(&jwk).map_err(invalid)?;
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        decode::<serde_json::Value>(token, &key, &validation).map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => CredentialsError::ExpiredToken(),
+            _ => invalid(e),
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Response, Server};
+
+    use super::*;
+
+    /// A JWKS endpoint without keys that answers with `status` and counts
+    /// its requests.
+    struct Jwks {
+        url: String,
+        status: Arc<AtomicU16>,
+        requests: Arc<AtomicUsize>,
+    }
+
+    fn jwks() -> Jwks {
+        let status = Arc::new(AtomicU16::new(200));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (served_status, counted) = (status.clone(), requests.clone());
+        let service = make_service_fn(move |_| {
+            let (status, requests) = (served_status.clone(), counted.clone());
+            async move {
+                Ok::<_, Infallible>(service_fn(move |_| {
+                    requests.fetch_add(1, Ordering::SeqCst);
+                    let status = status.load(Ordering::SeqCst);
+                    async move {
+                        Response::builder()
+                            .status(status)
+                            .body(Body::from(r#"{"keys":[]}"#))
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+        let url = format!("http://{}/jwks", server.local_addr());
+        tokio::spawn(server);
+        Jwks {
+            url,
+            status,
+            requests,
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_keys_fetch_once() {
+        let jwks = jwks();
+        let validator = Arc::new(JwtValidator::new(
+            &jwks.url,
+            None,
+            None,
+            Duration::from_secs(3600),
+        ));
+        let lookups: Vec<_> = (0..10)
+            .map(|i| {
+                let validator = validator.clone();
+                tokio::spawn(async move { validator.key(Some(&format!("kid-{}", i))).await })
+            })
+            .collect();
+        for lookup in lookups {
+            assert!(lookup.await.unwrap().is_err());
+        }
+        assert_eq!(jwks.requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_fetches_are_limited() {
+        let jwks = jwks();
+        jwks.status.store(500, Ordering::SeqCst);
+        let validator = JwtValidator::new(&jwks.url, None, None, Duration::from_secs(3600));
+        for _ in 0..3 {
+            assert!(validator.key(Some("kid")).await.is_err());
+        }
+        assert_eq!(jwks.requests.load(Ordering::SeqCst), 1);
+    }
+
+    /// Signs `claims` with a key that `ClaimRequirements` doesn't check.
+    fn token(claims: serde_json::Value) -> String {
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap()
+    }
+
+    fn requirements(audiences: &[&str], scopes: &[&str]) -> ClaimRequirements {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+        ClaimRequirements::new(strings(audiences), strings(scopes))
+    }
+
+    /// Checks each token with `claims` against `required`, expecting it to be
+    /// accepted or rejected.
+    fn assert_checks(
+        required: &ClaimRequirements,
+        cases: impl IntoIterator<Item = (serde_json::Value, bool)>,
+    ) {
+        for (claims, accepted) in cases {
+            let result = required.check(&token(claims.clone()));
+            match accepted {
+                true => assert!(result.is_ok(), "{}: {:?}", claims, result),
+                false => assert!(
+                    matches!(result, Err(CredentialsError::TokenRejected(_))),
+                    "{}: {:?}",
+                    claims,
+                    result
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn required_audiences() {
+        let required = requirements(&["s3proxy", "storage"], &[]);
+        let cases = [
+            (serde_json::json!({"aud": "s3proxy"}), true),
+            (serde_json::json!({"aud": "storage"}), true),
+            (serde_json::json!({"aud": ["other", "storage"]}), true),
+            (serde_json::json!({"aud": "other"}), false),
+            (serde_json::json!({"aud": ["other", "S3PROXY"]}), false),
+            (serde_json::json!({"aud": []}), false),
+            (serde_json::json!({}), false),
+        ];
+        assert_checks(&required, cases);
+    }
+
+    #[test]
+    fn required_scopes() {
+        let required = requirements(&[], &["read", "write"]);
+        let cases = [
+            (serde_json::json!({"scope": "read write"}), true),
+            (serde_json::json!({"scope": "  write   other read "}), true),
+            (serde_json::json!({"scp": ["write", "read"]}), true),
+            (serde_json::json!({"scope": "read", "scp": "write"}), true),
+            (serde_json::json!({"scope": "read"}), false),
+            (serde_json::json!({"scope": "read,write"}), false),
+            (serde_json::json!({"scp": "read write"}), false),
+            (serde_json::json!({"aud": "s3proxy"}), false),
+        ];
+        assert_checks(&required, cases);
+        let result = required.check(&token(serde_json::json!({"scope": "read"})));
+        assert!(
+            matches!(&result, Err(CredentialsError::TokenRejected(reason)) if reason == "missing scope write"),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn claims_without_requirements() {
+        let required = requirements(&[], &[]);
+        assert!(required.check(&token(serde_json::json!({}))).is_ok());
+        let required = requirements(&["s3proxy"], &["read"]);
+        let claims = serde_json::json!({"aud": "s3proxy", "scope": "read", "exp": 0});
+        assert!(required.check(&token(claims)).is_ok());
+    }
+
+    #[test]
+    fn malformed_tokens() {
+        let required = requirements(&[], &[]);
+        for token in [
+            "",
+            "not-a-token",
+            "a.b.c",
+            "eyJhbGciOiJIUzI1NiJ9.bm90IGpzb24.c2ln",
+        ] {
+            let result = required.check(token);
+            assert!(
+                matches!(result, Err(CredentialsError::InvalidToken(_))),
+                "{}: {:?}",
+                token,
+                result
+            );
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::credentials::UserInfo;
+
+/// Placeholders in --key-prefix and in the prefixes of access policy rules,
+/// replaced with attributes of the request's user.
+const PLACEHOLDERS: [&str; 3] = ["{{organization_rid}}", "{{user_id}}", "{{username}}"];
+
+/// Returns true if `template` depends on the user of a request.
+pub fn is_templated(template: &str) -> bool {
+    PLACEHOLDERS
+        .iter()
+        .any(|placeholder| template.contains(placeholder))
+}
+
+/// Returns the prefix that `template` gives the keys of `user_info`, which
+/// is `None` for anonymous requests and API keys, or `None` if the user
+/// lacks an attribute the template needs. Attributes containing a `/` count
+/// as missing, so that users can't reach into the namespace of others.
+pub fn render(template: &str, user_info: Option<&UserInfo>) -> Option<String> {
+    let values = [
+        user_info.and_then(UserInfo::organization_rid),
+        user_info.map(|user_info| user_info.id.as_str()),
+        user_info.map(|user_info| user_info.username.as_str()),
+    ];
+    let mut prefix = template.to_string();
+    for (placeholder, value) in PLACEHOLDERS.iter().zip(values) {
+        if !prefix.contains(placeholder) {
+            continue;
+        }
+        match value {
+            Some(value) if !value.is_empty() && !value.contains('/') => {
+                prefix = prefix.replace(placeholder, value);
+            }
+            _ => return None,
+        }
+    }
+    Some(prefix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str, id: &str, organization: Option<&str>) -> UserInfo {
+        let mut user = serde_json::json!({ "username": username, "id": id });
+        if let Some(organization) = organization {
+            user["attributes"] =
+                serde_json::json!({ "multipass:organization-rid": [organization] });
+        }
+        serde_json::from_value(user).unwrap()
+    }
+
+    #[test]
+    fn placeholders() {
+        let alice = user("alice", "id-1", Some("ri.org.1"));
+        let cases = [
+            ("static/", Some("static/")),
+            ("", Some("")),
+            ("users/{{username}}/", Some("users/alice/")),
+            ("{{user_id}}/", Some("id-1/")),
+            (
+                "tenants/{{organization_rid}}/{{user_id}}-{{username}}/",
+                Some("tenants/ri.org.1/id-1-alice/"),
+            ),
+            // Each occurrence is replaced.
+            ("{{username}}/{{username}}/", Some("alice/alice/")),
+            // Unknown placeholders are kept as they are.
+            ("{{email}}/{{username}}/", Some("{{email}}/alice/")),
+        ];
+        for (template, expected) in cases {
+            assert_eq!(
+                render(template, Some(&alice)).as_deref(),
+                expected,
+                "{}",
+                template
+            );
+        }
+    }
+
+    #[test]
+    fn missing_attributes() {
+        // Anonymous requests and API keys have no user.
+        assert_eq!(render("static/", None).as_deref(), Some("static/"));
+        assert_eq!(render("users/{{username}}/", None), None);
+        let without_organization = user("alice", "id-1", None);
+        assert_eq!(
+            render("{{organization_rid}}/", Some(&without_organization)),
+            None
+        );
+        assert_eq!(
+            render("{{username}}/", Some(&without_organization)).as_deref(),
+            Some("alice/")
+        );
+        // Empty attributes would collapse the namespace of the user.
+        assert_eq!(
+            render("users/{{username}}/", Some(&user("", "id-1", None))),
+            None
+        );
+        assert_eq!(
+            render(
+                "{{organization_rid}}/",
+                Some(&user("alice", "id-1", Some("")))
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn attributes_with_slashes() {
+        for user in [
+            user("../bob", "id-1", Some("ri.org.1")),
+            user("alice/x", "id-1", Some("ri.org.1")),
+            user("alice", "id/1", Some("ri.org.1")),
+            user("alice", "id-1", Some("ri.org/1")),
+        ] {
+            assert_eq!(
+                render(
+                    "{{organization_rid}}/{{user_id}}/{{username}}/",
+                    Some(&user)
+                ),
+                None,
+                "{:?}",
+                user
+            );
+        }
+        // Only the attributes that the template needs are checked.
+        let user = user("alice", "id/1", None);
+        assert_eq!(
+            render("users/{{username}}/", Some(&user)).as_deref(),
+            Some("users/alice/")
+        );
+    }
+
+    #[test]
+    fn templated() {
+        assert!(is_templated("users/{{username}}/"));
+        assert!(is_templated("{{organization_rid}}"));
+        assert!(is_templated("a/{{user_id}}"));
+        assert!(!is_templated("static/"));
+        assert!(!is_templated("{{email}}/"));
+        assert!(!is_templated("{username}/"));
+    }
+}
@@ -0,0 +1,296 @@
+//! A caching proxy for S3-compatible object storage.
+//!
+//! The `s3proxy` binary is a thin wrapper around this crate, which other
+//! services can embed instead of running the binary:
+//!
+//! - [`Settings`] holds everything the binary can be configured with and
+//!   sets up the proxy from it: [`Settings::handler`] returns the
+//!   [`S3Handler`] and [`Settings::serve`] serves requests on the listeners.
+//! - [`S3Handler`] performs S3 operations against the upstream, backed by the
+//!   disk cache in [`cache`] and the credentials of [`credentials`].
+//! - [`router::route_request`] routes a single HTTP request, so that
+//!   services with their own routing or authentication in front can hand
+//!   requests to the proxy from their own hyper services.
+//! - [`listener::bind`] and [`listener::serve`] serve requests on addresses
+//!   or on any hyper acceptor.
+//! - [`hooks::Hook`]s added to [`Settings::hooks`] plug custom logic into
+//!   the handling of requests without replacing the router.
+
+use std::sync::Arc;
+
+use tracing::{info, warn};
+
+pub mod access_log;
+pub mod access_policy;
+pub mod admin;
+pub mod api_keys;
+pub mod audit_log;
+mod aws_chunked;
+pub mod backend;
+mod body_channel;
+pub mod cache;
+pub mod cache_io;
+pub mod circuit_breaker;
+mod compression;
+pub mod config_file;
+mod cors;
+pub mod credentials;
+mod error;
+pub mod forwarded;
+pub mod gcs;
+pub mod health;
+pub mod hooks;
+pub mod jwt;
+mod key_prefix;
+pub mod listener;
+pub mod local_fs;
+pub mod metrics;
+pub mod profiling;
+pub mod proxy_protocol;
+mod range;
+mod readahead;
+pub mod request_id;
+pub mod response_headers;
+pub mod router;
+pub mod s3_handler;
+mod sigv4;
+pub mod size_cache;
+pub mod slow_log;
+pub mod telemetry;
+pub mod throttle;
+mod timeout;
+pub mod upstream;
+mod xml_writer;
+
+use crate::access_log::AccessLogConfig;
+use crate::audit_log::AuditLogConfig;
+use crate::backend::BackendKind;
+use crate::cache::{CacheConfig, DiskCache};
+use crate::credentials::{AuthMode, CredentialsConfig, CredentialsManager};
+use crate::hooks::Hooks;
+use crate::listener::ListenerConfig;
+use crate::router::RouterConfig;
+pub use crate::s3_handler::S3Handler;
+use crate::s3_handler::UpstreamConfig;
+use crate::telemetry::TelemetryConfig;
+
+/// Settings of the proxy, as flags of the binary that can also be set in
+/// the environment or a configuration file.
+#[derive(clap::Args, Debug, Clone)]
+pub struct Settings {
+    /// The endpoint to use for S3 requests; required except for the cache commands
+    #[arg(long, short, env)]
+    pub endpoint: Option<String>,
+    #[arg(long, short, default_value = "3000", env)]
+    pub port: u16,
+    #[command(flatten)]
+    pub listener: ListenerConfig,
+    #[command(flatten)]
+    pub credentials: CredentialsConfig,
+    #[command(flatten)]
+    pub cache: CacheConfig,
+    #[command(flatten)]
+    pub router: RouterConfig,
+    #[command(flatten)]
+    pub upstream: UpstreamConfig,
+    #[command(flatten)]
+    pub telemetry: TelemetryConfig,
+    #[command(flatten)]
+    pub access_log: AccessLogConfig,
+    #[command(flatten)]
+    pub audit_log: AuditLogConfig,
+    /// Custom logic called while requests are handled, for services
+    /// embedding the proxy.
+    #[arg(skip)]
+    pub hooks: Hooks,
+}
+
+impl Settings {
+    /// Returns the upstream endpoint, which upstreams on the local
+    /// filesystem don't need.
+    pub fn endpoint(&self) -> Result<&str, String> {
+        match (&self.endpoint, &self.upstream.backend) {
+            (Some(endpoint), _) => Ok(endpoint),
+            (None, BackendKind::Fs(_)) => Ok(local_fs::ENDPOINT),
+            (None, _) => Err("--endpoint is required".to_string()),
+        }
+    }
+
+    /// Checks settings that only make sense together.
+    pub fn check(&self) -> Result<(), String> {
+        let token_only = [
+            (
+                "--cache-tenant-isolation",
+                self.cache.cache_tenant_isolation,
+            ),
+            ("--role-map-file", self.credentials.role_map_file.is_some()),
+            (
+                "--access-policy-file",
+                self.credentials.access_policy_file.is_some(),
+            ),
+            (
+                "--session-policy-file",
+                self.credentials.session_policy_file.is_some(),
+            ),
+            (
+                "--key-prefix",
+                self.router
+                    .key_prefix
+                    .as_deref()
+                    .is_some_and(key_prefix::is_templated),
+            ),
+        ];
+        for (flag, set) in token_only {
+            if set && self.credentials.auth_mode != AuthMode::Token {
+                return Err(format!("{} requires --auth-mode token", flag));
+            }
+        }
+        if !self.cache.cache_io.supported() {
+            return Err(
+                "--cache-io io-uring requires a Linux build with the io-uring feature".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Sets up the credentials manager, loading the files it needs.
+    pub async fn credentials(&self) -> Result<CredentialsManager, String> {
+        let provider = self
+            .credentials
+            .provider(self.endpoint()?)
+            .await
+            .map_err(|e| format!("failed to set up credentials: {}", e))?;
+        let verifier = self
+            .credentials
+            .verifier()
+            .map_err(|e| format!("failed to load client keys: {}", e))?;
+        let anonymous = match self.router.public_buckets.is_empty() {
+            true => None,
+            false => self
+                .credentials
+                .anonymous_credentials()
+                .map_err(|e| format!("failed to set up anonymous credentials: {}", e))?,
+        };
+        let api_keys = self
+            .credentials
+            .api_keys()
+            .map_err(|e| format!("failed to load API keys: {}", e))?;
+        let access_policy = self
+            .credentials
+            .access_policy()
+            .map_err(|e| format!("failed to load access policy: {}", e))?;
+        Ok(CredentialsManager::new(
+            provider,
+            verifier,
+            anonymous,
+            api_keys,
+            access_policy,
+            &self.credentials,
+        ))
+    }
+
+    /// Prepares the disk cache and sets up the handler of S3 requests.
+    pub async fn handler(&self) -> Result<Arc<S3Handler>, String> {
+        self.check()?;
+        let cache = DiskCache::new(self.cache.clone());
+        if !cache.enabled() {
+            info!("Disk cache disabled");
+        } else {
+            match cache.recover().await {
+                Ok(report) => info!(
+                    entries = report.entries,
+                    bytes = report.bytes,
+                    removed_temp = report.removed_temp,
+                    removed_corrupt = report.removed_corrupt,
+                    removed_misplaced = report.removed_misplaced,
+                    "Cache recovered"
+                ),
+                Err(e) => return Err(format!("failed to prepare cache directories: {}", e)),
+            }
+        }
+        let upstreams = self.upstream.upstreams(self.endpoint()?)?;
+        let credentials = self.credentials().await?;
+        Ok(Arc::new(S3Handler::new(
+            upstreams,
+            self.upstream.clone(),
+            credentials,
+            cache,
+            self.hooks.clone(),
+        )))
+    }
+
+    /// Loads everything `serve` would load at startup without touching the
+    /// cache directories or binding any address.
+    pub async fn validate(&self) -> Result<(), String> {
+        let listeners = self.listener.listeners(self.port);
+        self.listener
+            .tls_config(&listeners)
+            .map_err(|e| format!("failed to set up TLS: {}", e))?;
+        self.check()?;
+        self.upstream.upstreams(self.endpoint()?)?;
+        self.credentials().await?;
+        self.router.clone().load_response_headers()?;
+        let access_log_dir = self
+            .access_log
+            .access_log
+            .as_deref()
+            .filter(|path| path.to_str() != Some("-"))
+            .and_then(std::path::Path::parent)
+            .filter(|dir| !dir.as_os_str().is_empty() && !dir.is_dir());
+        if let Some(dir) = access_log_dir {
+            return Err(format!(
+                "failed to open access log: {} is not a directory",
+                dir.display()
+            ));
+        }
+        self.audit_log.check()?;
+        Ok(())
+    }
+
+    /// Sets up the proxy and serves requests on all listeners until one of
+    /// them fails.
+    pub async fn serve(&self) -> Result<(), String> {
+        let listeners = self.listener.listeners(self.port);
+        let tls = self
+            .listener
+            .tls_config(&listeners)
+            .map_err(|e| format!("failed to set up TLS: {}", e))?;
+        let mut router = self.router.clone();
+        router.load_response_headers()?;
+        let s3 = self.handler().await?;
+        match s3.load_size_cache().await {
+            Ok(sizes) => info!(sizes, "Size cache restored"),
+            Err(e) => warn!("Failed to restore size cache: {}", e),
+        }
+        s3.spawn_credentials_sweeper();
+        s3.spawn_cache_scrubber();
+        s3.spawn_size_cache_snapshots();
+        let access_log = self
+            .access_log
+            .open()
+            .map_err(|e| format!("failed to open access log: {}", e))?
+            .map(Arc::new);
+        let audit_log = self.audit_log.open()?.map(Arc::new);
+        let shared = listener::Shared {
+            s3,
+            config: Arc::new(router),
+            access_log,
+            audit_log,
+            connections: self.listener.connection_limit(),
+            bandwidth: self.listener.bandwidth_limit(),
+            connection_bandwidth: self.listener.max_connection_bandwidth,
+            proxy_protocol: self.listener.proxy_protocol,
+            buffers: self.listener.connection_buffers(),
+        };
+        let mut servers = Vec::new();
+        for (listen, scope) in &listeners {
+            let server = listener::bind(listen, *scope, tls.as_ref(), shared.clone())?;
+            info!(%listen, ?scope, "Listening");
+            servers.push(server);
+        }
+        futures_util::future::try_join_all(servers)
+            .await
+            .map(|_| ())
+            .map_err(|e| format!("server error: {}", e))
+    }
+}
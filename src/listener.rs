@@ -0,0 +1,516 @@
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Server;
+use hyper_rustls::acceptor::{TlsAcceptor, TlsStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::sync::PollSemaphore;
+
+use crate::access_log::AccessLog;
+use crate::audit_log::AuditLog;
+use crate::proxy_protocol::{Proxied, ProxyIncoming};
+use crate::router::{self, ListenerScope, RouterConfig};
+use crate::s3_handler::S3Handler;
+use crate::throttle::{RateLimiter, Throttle};
+
+/// An address to accept connections on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    /// `http://<host>:<port>`
+    Http(SocketAddr),
+    /// `https://<host>:<port>`, with the certificate of --tls-cert-file
+    Https(SocketAddr),
+    /// `unix:<path>`
+    Unix(PathBuf),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let addr = |addr: &str| {
+            addr.parse::<SocketAddr>()
+                .map_err(|e| format!("invalid address {:?}: {}", addr, e))
+        };
+        if let Some(rest) = s.strip_prefix("http://") {
+            Ok(Listen::Http(addr(rest)?))
+        } else if let Some(rest) = s.strip_prefix("https://") {
+            Ok(Listen::Https(addr(rest)?))
+        } else if let Some(path) = s.strip_prefix("unix:").filter(|path| !path.is_empty()) {
+            Ok(Listen::Unix(PathBuf::from(path)))
+        } else {
+            Err(format!(
+                "expected http://<host>:<port>, https://<host>:<port> or unix:<path>, got {:?}",
+                s
+            ))
+        }
+    }
+}
+
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listen::Http(addr) => write!(f, "http://{}", addr),
+            Listen::Https(addr) => write!(f, "https://{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct ListenerConfig {
+    /// Unix socket to serve on instead of the TCP port
+    #[arg(long, env, conflicts_with = "listen")]
+    pub bind_unix: Option<PathBuf>,
+    /// Addresses to serve S3 requests on, each http://<host>:<port>, https://<host>:<port> or unix:<path>; replaces --port and --bind-unix
+    #[arg(long, env, value_delimiter = ',')]
+    pub listen: Vec<Listen>,
+    /// Addresses to serve the admin API, health probes and metrics on, in the format of --listen; the admin API is then not served on the other listeners
+    #[arg(long, env, value_delimiter = ',')]
+    pub admin_listen: Vec<Listen>,
+    /// PEM file with the certificate chain of https listeners
+    #[arg(long, env)]
+    pub tls_cert_file: Option<PathBuf>,
+    /// PEM file with the private key of https listeners
+    #[arg(long, env)]
+    pub tls_key_file: Option<PathBuf>,
+    /// Maximum number of open connections on S3 listeners; further connections wait to be accepted until others close
+    #[arg(long, env, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_connections: Option<u32>,
+    /// Maximum bytes per second sent to all clients of S3 listeners together; unlimited if unset
+    #[arg(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_bandwidth: Option<u64>,
+    /// Maximum bytes per second sent to each client connection of S3 listeners; unlimited if unset
+    #[arg(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_connection_bandwidth: Option<u64>,
+    /// Expect a PROXY protocol v1 or v2 header from the load balancer on each connection of TCP S3 listeners, and take client addresses from it; connections without one are closed
+    #[arg(long, env)]
+    pub proxy_protocol: bool,
+    /// Maximum bytes buffered for reading and writing each HTTP/1 client connection [default: about 400 KiB]
+    #[arg(long, env, value_parser = clap::value_parser!(u64).range(8192..))]
+    pub http1_max_buf_size: Option<u64>,
+    /// Initial HTTP/2 flow control window in bytes of each stream of client connections [default: 1 MiB]
+    #[arg(long, env)]
+    pub http2_stream_window_size: Option<u32>,
+    /// Initial HTTP/2 flow control window in bytes of each client connection [default: 1 MiB]
+    #[arg(long, env)]
+    pub http2_connection_window_size: Option<u32>,
+    /// Maximum bytes of response data buffered for each HTTP/2 stream of client connections [default: about 400 KiB]
+    #[arg(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    pub http2_max_send_buf_size: Option<u64>,
+}
+
+/// Buffer sizes of client connections, hyper's defaults where unset.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionBuffers {
+    pub http1_max_buf_size: Option<usize>,
+    pub http2_stream_window_size: Option<u32>,
+    pub http2_connection_window_size: Option<u32>,
+    pub http2_max_send_buf_size: Option<usize>,
+}
+
+impl ListenerConfig {
+    /// Returns the buffer sizes of client connections.
+    pub fn connection_buffers(&self) -> ConnectionBuffers {
+        ConnectionBuffers {
+            http1_max_buf_size: self.http1_max_buf_size.map(|size| size as usize),
+            http2_stream_window_size: self.http2_stream_window_size,
+            http2_connection_window_size: self.http2_connection_window_size,
+            http2_max_send_buf_size: self.http2_max_send_buf_size.map(|size| size as usize),
+        }
+    }
+
+    /// Returns the listeners to start and the requests each serves. Without
+    /// --listen, the proxy serves everything on --bind-unix or `port`.
+    pub fn listeners(&self, port: u16) -> Vec<(Listen, ListenerScope)> {
+        let scope = match self.admin_listen.is_empty() {
+            true => ListenerScope::All,
+            false => ListenerScope::S3,
+        };
+        let listen = match (&self.bind_unix, self.listen.is_empty()) {
+            (_, false) => self.listen.clone(),
+            (Some(path), true) => vec![Listen::Unix(path.clone())],
+            (None, true) => vec![Listen::Http(SocketAddr::from(([0, 0, 0, 0], port)))],
+        };
+        let admin = self
+            .admin_listen
+            .iter()
+            .map(|listen| (listen.clone(), ListenerScope::Admin));
+        listen
+            .into_iter()
+            .map(|listen| (listen, scope))
+            .chain(admin)
+            .collect()
+    }
+
+    /// Returns the permits of open connections, shared by the S3 listeners.
+    pub fn connection_limit(&self) -> Option<Arc<Semaphore>> {
+        self.max_connections
+            .map(|max| Arc::new(Semaphore::new(max as usize)))
+    }
+
+    /// Returns the limiter of the bytes sent, shared by the S3 listeners.
+    pub fn bandwidth_limit(&self) -> Option<Arc<RateLimiter>> {
+        self.max_bandwidth
+            .map(|rate| Arc::new(RateLimiter::new(rate)))
+    }
+
+    /// Loads the certificate and key of https listeners, if there are any.
+    pub fn tls_config(
+        &self,
+        listeners: &[(Listen, ListenerScope)],
+    ) -> Result<Option<Arc<rustls::ServerConfig>>, String> {
+        if !listeners
+            .iter()
+            .any(|(listen, _)| matches!(listen, Listen::Https(_)))
+        {
+            return Ok(None);
+        }
+        let (Some(cert_file), Some(key_file)) = (&self.tls_cert_file, &self.tls_key_file) else {
+            return Err("https listeners require --tls-cert-file and --tls-key-file".to_string());
+        };
+        let open = |path: &Path| {
+            std::fs::File::open(path)
+                .map(std::io::BufReader::new)
+                .map_err(|e| format!("{}: {}", path.display(), e))
+        };
+        let certs = rustls_pemfile::certs(&mut open(cert_file)?)
+            .map_err(|e| format!("{}: {}", cert_file.display(), e))?;
+        if certs.is_empty() {
+            return Err(format!("{}: no certificates found", cert_file.display()));
+        }
+        let mut keys = open(key_file)?;
+        let key = loop {
+            match rustls_pemfile::read_one(&mut keys)
+                .map_err(|e| format!("{}: {}", key_file.display(), e))?
+            {
+                Some(
+                    rustls_pemfile::Item::PKCS8Key(key)
+                    | rustls_pemfile::Item::RSAKey(key)
+                    | rustls_pemfile::Item::ECKey(key),
+                ) => break key,
+                Some(_) => {}
+                None => return Err(format!("{}: no private key found", key_file.display())),
+            }
+        };
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                certs.into_iter().map(rustls::Certificate).collect(),
+                rustls::PrivateKey(key),
+            )
+            .map_err(|e| e.to_string())?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Some(Arc::new(config)))
+    }
+}
+
+/// Connections that requests are served from.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// Returns the address of the client, if it has one.
+    fn remote_addr(&self) -> Option<SocketAddr>;
+}
+
+impl Connection for AddrStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(AddrStream::remote_addr(self))
+    }
+}
+
+impl Connection for TlsStream<AddrStream> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.io().map(AddrStream::remote_addr)
+    }
+}
+
+impl Connection for Proxied {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        Some(Proxied::remote_addr(self))
+    }
+}
+
+impl Connection for TlsStream<Proxied> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.io().map(Proxied::remote_addr)
+    }
+}
+
+impl Connection for UnixStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// A connection holding one of the permits of a connection limit, if any,
+/// until it is closed, and whose writes are throttled to the bandwidth
+/// limits, if any.
+pub struct Limited<C> {
+    conn: C,
+    _permit: Option<OwnedSemaphorePermit>,
+    throttle: Option<Throttle>,
+}
+
+impl<C: Connection> Connection for Limited<C> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr()
+    }
+}
+
+impl<C: AsyncRead + Unpin> AsyncRead for Limited<C> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.conn).poll_read(cx, buf)
+    }
+}
+
+impl<C: AsyncWrite + Unpin> AsyncWrite for Limited<C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let Some(throttle) = &mut this.throttle else {
+            return Pin::new(&mut this.conn).poll_write(cx, buf);
+        };
+        let len = ready!(throttle.poll_reserve(cx, buf.len()));
+        let written = ready!(Pin::new(&mut this.conn).poll_write(cx, &buf[..len]))?;
+        throttle.consume(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.conn).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.conn).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        if self.throttle.is_some() {
+            // Throttled writes go through poll_write one buffer at a time.
+            let buf = bufs.iter().find(|buf| !buf.is_empty());
+            return self.poll_write(cx, buf.map_or(&[], |buf| &**buf));
+        }
+        Pin::new(&mut self.conn).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.throttle.is_none() && self.conn.is_write_vectored()
+    }
+}
+
+/// Accepts connections only while a permit of the connection limit, if
+/// any, is available, leaving further clients in the listen backlog.
+struct LimitedIncoming<A> {
+    incoming: A,
+    permits: Option<PollSemaphore>,
+    permit: Option<OwnedSemaphorePermit>,
+    bandwidth: Option<Arc<RateLimiter>>,
+    connection_bandwidth: Option<u64>,
+}
+
+impl<A: Accept + Unpin> Accept for LimitedIncoming<A> {
+    type Conn = Limited<A::Conn>;
+    type Error = A::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        if let (Some(permits), None) = (&mut this.permits, &this.permit) {
+            match ready!(permits.poll_acquire(cx)) {
+                Some(permit) => this.permit = Some(permit),
+                None => return Poll::Ready(None),
+            }
+        }
+        let accepted = ready!(Pin::new(&mut this.incoming).poll_accept(cx));
+        Poll::Ready(accepted.map(|conn| {
+            conn.map(|conn| {
+                let limiters = this
+                    .connection_bandwidth
+                    .map(|rate| Arc::new(RateLimiter::new(rate)))
+                    .into_iter()
+                    .chain(this.bandwidth.clone())
+                    .collect();
+                Limited {
+                    conn,
+                    _permit: this.permit.take(),
+                    throttle: Throttle::new(limiters),
+                }
+            })
+        }))
+    }
+}
+
+/// What all listeners share.
+#[derive(Clone)]
+pub struct Shared {
+    pub s3: Arc<S3Handler>,
+    pub config: Arc<RouterConfig>,
+    pub access_log: Option<Arc<AccessLog>>,
+    pub audit_log: Option<Arc<AuditLog>>,
+    /// Permits of open connections on S3 listeners.
+    pub connections: Option<Arc<Semaphore>>,
+    /// Limiter of the bytes sent on S3 listeners.
+    pub bandwidth: Option<Arc<RateLimiter>>,
+    /// Bytes per second sent on each connection of S3 listeners.
+    pub connection_bandwidth: Option<u64>,
+    /// Whether connections of TCP S3 listeners start with a PROXY protocol
+    /// header.
+    pub proxy_protocol: bool,
+    /// Buffer sizes of all connections.
+    pub buffers: ConnectionBuffers,
+}
+
+/// Listens on a Unix socket at `path`, replacing the socket of an earlier
+/// run. Other files at `path` are left alone.
+pub fn bind_unix(
+    path: &Path,
+) -> std::io::Result<impl Accept<Conn = UnixStream, Error = std::io::Error>> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(_) => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    Ok(accept::poll_fn(move |cx| {
+        listener
+            .poll_accept(cx)
+            .map(|accepted| Some(accepted.map(|(stream, _)| stream)))
+    }))
+}
+
+/// A bound listener serving requests until it fails.
+pub type Serving = Pin<Box<dyn Future<Output = Result<(), hyper::Error>> + Send>>;
+
+/// Binds `listen` and returns the future that serves the `scope` requests
+/// of its connections. `tls` must be set for https listeners.
+pub fn bind(
+    listen: &Listen,
+    scope: ListenerScope,
+    tls: Option<&Arc<rustls::ServerConfig>>,
+    shared: Shared,
+) -> Result<Serving, String> {
+    let failed = |e: &dyn std::fmt::Display| format!("failed to listen on {}: {}", listen, e);
+    let proxied = shared.proxy_protocol && scope != ListenerScope::Admin;
+    Ok(match listen {
+        Listen::Http(addr) => {
+            let incoming = AddrIncoming::bind(addr).map_err(|e| failed(&e))?;
+            match proxied {
+                true => Box::pin(serve(ProxyIncoming::new(incoming), scope, shared)),
+                false => Box::pin(serve(incoming, scope, shared)),
+            }
+        }
+        Listen::Https(addr) => {
+            let incoming = AddrIncoming::bind(addr).map_err(|e| failed(&e))?;
+            let tls = tls.expect("TLS configuration of https listener").clone();
+            match proxied {
+                true => {
+                    let incoming = TlsAcceptor::builder()
+                        .with_tls_config((*tls).clone())
+                        .with_alpn_protocols(tls.alpn_protocols.clone())
+                        .with_acceptor(ProxyIncoming::new(incoming));
+                    Box::pin(serve(incoming, scope, shared))
+                }
+                false => Box::pin(serve(TlsAcceptor::new(tls, incoming), scope, shared)),
+            }
+        }
+        Listen::Unix(path) => {
+            let incoming = bind_unix(path).map_err(|e| failed(&e))?;
+            Box::pin(serve(incoming, scope, shared))
+        }
+    })
+}
+
+/// Serves the `scope` requests of the connections of `incoming` until it
+/// fails. Admin listeners are exempt from the connection and bandwidth
+/// limits, so that the proxy can still be inspected when it is saturated.
+pub async fn serve<A>(incoming: A, scope: ListenerScope, shared: Shared) -> Result<(), hyper::Error>
+where
+    A: Accept + Unpin,
+    A::Conn: Connection,
+    A::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let incoming = match scope {
+        ListenerScope::Admin => LimitedIncoming {
+            incoming,
+            permits: None,
+            permit: None,
+            bandwidth: None,
+            connection_bandwidth: None,
+        },
+        _ => LimitedIncoming {
+            incoming,
+            permits: shared.connections.clone().map(PollSemaphore::new),
+            permit: None,
+            bandwidth: shared.bandwidth.clone(),
+            connection_bandwidth: shared.connection_bandwidth,
+        },
+    };
+    let Shared {
+        s3,
+        config,
+        access_log,
+        audit_log,
+        buffers,
+        ..
+    } = shared;
+    let make_svc = make_service_fn(|conn: &Limited<A::Conn>| {
+        let remote_addr = conn.remote_addr();
+        let s3 = s3.clone();
+        let config = config.clone();
+        let access_log = access_log.clone();
+        let audit_log = audit_log.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                router::route_request(
+                    req,
+                    remote_addr,
+                    scope,
+                    s3.clone(),
+                    config.clone(),
+                    access_log.clone(),
+                    audit_log.clone(),
+                )
+            }))
+        }
+    });
+    let mut server = Server::builder(incoming)
+        .http2_initial_stream_window_size(buffers.http2_stream_window_size)
+        .http2_initial_connection_window_size(buffers.http2_connection_window_size);
+    if let Some(size) = buffers.http1_max_buf_size {
+        server = server.http1_max_buf_size(size);
+    }
+    if let Some(size) = buffers.http2_max_send_buf_size {
+        server = server.http2_max_send_buf_size(size);
+    }
+    server.serve(make_svc).await
+}
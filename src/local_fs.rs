@@ -0,0 +1,377 @@
+use std::path::{Component, Path, PathBuf};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::future::BoxFuture;
+use futures_util::StreamExt;
+use hyper::{http, Body, StatusCode};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+
+use crate::backend::{Backend, Payload};
+use crate::error;
+use crate::range::ByteRange;
+use crate::xml_writer::{Content, ListBucketResult};
+
+/// Endpoint of upstreams on the local filesystem, which need no --endpoint.
+pub const ENDPOINT: &str = "file:///";
+
+/// Number of keys listed per page unless a request asks for fewer.
+const MAX_KEYS: usize = 1000;
+
+/// A directory tree on the local filesystem served as an upstream, so that
+/// integration tests and local development need no S3 service: the
+/// directories below the root are buckets and the files below them objects.
+/// ETags are derived from the size and modification time of files.
+pub struct LocalFsBackend {
+    root: PathBuf,
+    /// Bytes read at a time from files that are sent.
+    read_buffer_size: usize,
+}
+
+fn internal_error(e: std::io::Error, resource: &str) -> http::Response<Body> {
+    error::response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "InternalError",
+        &e.to_string(),
+        resource,
+    )
+}
+
+/// Returns the ETag of a file, which changes whenever it is written.
+fn etag(metadata: &std::fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    let version = format!("{}:{}", modified.as_nanos(), metadata.len());
+    format!("\"{}\"", &blake3::hash(version.as_bytes()).to_hex()[..32])
+}
+
+fn modified(metadata: &std::fs::Metadata) -> DateTime<Utc> {
+    metadata
+        .modified()
+        .map(Into::into)
+        .unwrap_or_else(|_| Utc::now())
+}
+
+/// Returns the keys of the files below `dir`, with `/` as separator.
+fn walk(dir: &Path, prefix: &str, keys: &mut Vec<(String, std::fs::Metadata)>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let key = format!("{}{}", prefix, name);
+        if metadata.is_dir() {
+            walk(&entry.path(), &format!("{}/", key), keys);
+        } else if metadata.is_file() {
+            keys.push((key, metadata));
+        }
+    }
+}
+
+impl LocalFsBackend {
+    pub fn new(root: &Path, read_buffer_size: usize) -> Result<Self, String> {
+        if !root.is_dir() {
+            return Err(format!("{} is not a directory", root.display()));
+        }
+        Ok(LocalFsBackend {
+            root: root.to_path_buf(),
+            read_buffer_size,
+        })
+    }
+
+    async fn handle(&self, request: reqwest::Request, payload: Payload) -> http::Response<Body> {
+        let resource = percent_encoding::percent_decode_str(request.url().path())
+            .decode_utf8_lossy()
+            .into_owned();
+        let path = resource.trim_start_matches('/');
+        let (bucket, key) = path.split_once('/').unwrap_or((path, ""));
+        // Buckets and keys must not lead out of the root.
+        let within = |path: &str| {
+            Path::new(path)
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        };
+        if !within(bucket) || !within(key) {
+            return error::response(
+                StatusCode::BAD_REQUEST,
+                "InvalidArgument",
+                "Invalid bucket or key",
+                &resource,
+            );
+        }
+        if bucket.is_empty() {
+            // The root, which readiness checks probe.
+            return http::Response::new(Body::empty());
+        }
+        let dir = self.root.join(bucket);
+        if !dir.is_dir() {
+            return error::response(
+                StatusCode::NOT_FOUND,
+                "NoSuchBucket",
+                "The specified bucket does not exist",
+                &resource,
+            );
+        }
+        let method = request.method().clone();
+        match (method, key.is_empty()) {
+            (reqwest::Method::GET, true) => self.list(&dir, &request, &resource).await,
+            (reqwest::Method::HEAD, true) => http::Response::new(Body::empty()),
+            (reqwest::Method::GET | reqwest::Method::HEAD, false) => {
+                self.get(&dir.join(key), &request, &resource).await
+            }
+            (reqwest::Method::PUT, false) => self.put(&dir.join(key), payload, &resource).await,
+            (reqwest::Method::DELETE, false) => match tokio::fs::remove_file(dir.join(key)).await {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => internal_error(e, &resource),
+                _ => http::Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .body(Body::empty())
+                    .unwrap(),
+            },
+            _ => error::response(
+                StatusCode::NOT_IMPLEMENTED,
+                "NotImplemented",
+                "The local filesystem backend does not implement this operation",
+                &resource,
+            ),
+        }
+    }
+
+    /// Answers GetObject and HeadObject, with a single range if asked for.
+    async fn get(
+        &self,
+        file: &Path,
+        request: &reqwest::Request,
+        resource: &str,
+    ) -> http::Response<Body> {
+        let metadata = match tokio::fs::metadata(file).await {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => {
+                return error::response(
+                    StatusCode::NOT_FOUND,
+                    "NoSuchKey",
+                    "The specified key does not exist.",
+                    resource,
+                )
+            }
+        };
+        let etag = etag(&metadata);
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        if header("if-match").is_some_and(|value| value != etag) {
+            return error::response(
+                StatusCode::PRECONDITION_FAILED,
+                "PreconditionFailed",
+                "At least one of the pre-conditions you specified did not hold",
+                resource,
+            );
+        }
+        let builder = http::Response::builder().header("etag", &etag).header(
+            "last-modified",
+            modified(&metadata)
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        );
+        if header("if-none-match") == Some(etag.as_str()) {
+            return builder
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .unwrap();
+        }
+        let size = metadata.len();
+        let range = header("range")
+            .and_then(ByteRange::parse)
+            .and_then(|ranges| ranges.first().copied());
+        let (status, first, last) = match range.map(|range| range.resolve(size)) {
+            None => (StatusCode::OK, 0, size.saturating_sub(1)),
+            Some(Some((first, last))) => (StatusCode::PARTIAL_CONTENT, first, last),
+            Some(None) => {
+                return error::response(
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    "InvalidRange",
+                    "The requested range is not satisfiable",
+                    resource,
+                )
+            }
+        };
+        let length = match size {
+            0 => 0,
+            _ => last - first + 1,
+        };
+        let mut builder = builder
+            .status(status)
+            .header("content-length", length)
+            .header("content-type", "application/octet-stream")
+            .header("accept-ranges", "bytes");
+        if status == StatusCode::PARTIAL_CONTENT {
+            builder = builder.header(
+                "content-range",
+                format!("bytes {}-{}/{}", first, last, size),
+            );
+        }
+        if request.method() == reqwest::Method::HEAD {
+            return builder.body(Body::empty()).unwrap();
+        }
+        let mut reader = match tokio::fs::File::open(file).await {
+            Ok(reader) => reader,
+            Err(e) => return internal_error(e, resource),
+        };
+        if let Err(e) = reader.seek(std::io::SeekFrom::Start(first)).await {
+            return internal_error(e, resource);
+        }
+        let body = Body::wrap_stream(ReaderStream::with_capacity(
+            reader.take(length),
+            self.read_buffer_size,
+        ));
+        builder.body(body).unwrap()
+    }
+
+    /// Answers PutObject, writing the body to a temporary file first so
+    /// that readers never see partial objects.
+    async fn put(&self, file: &Path, payload: Payload, resource: &str) -> http::Response<Body> {
+        let temp = file.with_file_name(format!(
+            ".{}.{}.tmp",
+            file.file_name().unwrap_or_default().to_string_lossy(),
+            std::process::id()
+        ));
+        let written = async {
+            if let Some(parent) = file.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut writer = tokio::fs::File::create(&temp).await?;
+            match payload {
+                Payload::Empty => {}
+                Payload::Bytes(body) => writer.write_all(&body).await?,
+                Payload::Unsigned(mut body) => {
+                    while let Some(chunk) = body.next().await {
+                        writer
+                            .write_all(&chunk.map_err(std::io::Error::other)?)
+                            .await?;
+                    }
+                }
+            }
+            writer.sync_all().await?;
+            tokio::fs::rename(&temp, file).await?;
+            tokio::fs::metadata(file).await
+        };
+        match written.await {
+            Ok(metadata) => http::Response::builder()
+                .header("etag", etag(&metadata))
+                .body(Body::empty())
+                .unwrap(),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&temp).await;
+                internal_error(e, resource)
+            }
+        }
+    }
+
+    /// Answers ListObjectsV2. Continuation tokens are the last key of the
+    /// previous page.
+    async fn list(
+        &self,
+        dir: &Path,
+        request: &reqwest::Request,
+        resource: &str,
+    ) -> http::Response<Body> {
+        let param = |name: &str| {
+            request
+                .url()
+                .query_pairs()
+                .find(|(param, _)| param == name)
+                .map(|(_, value)| value.into_owned())
+                .filter(|value| !value.is_empty())
+        };
+        let prefix = param("prefix").unwrap_or_default();
+        let continuation_token = param("continuation-token");
+        let start_after = param("start-after");
+        let max_keys = param("max-keys")
+            .and_then(|max_keys| max_keys.parse().ok())
+            .map_or(MAX_KEYS, |max_keys: usize| max_keys.min(MAX_KEYS));
+        let after = continuation_token.clone().or(start_after.clone());
+        let dir = dir.to_path_buf();
+        let listed = tokio::task::spawn_blocking(move || {
+            let mut keys = Vec::new();
+            walk(&dir, "", &mut keys);
+            keys.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+            keys
+        })
+        .await;
+        let keys = match listed {
+            Ok(keys) => keys,
+            Err(e) => return internal_error(std::io::Error::from(e), resource),
+        };
+        let mut keys: Vec<_> = keys
+            .into_iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .filter(|(key, _)| after.as_ref().is_none_or(|after| key > after))
+            .collect();
+        let is_truncated = keys.len() > max_keys;
+        keys.truncate(max_keys);
+        let next_continuation_token = match is_truncated {
+            true => keys.last().map(|(key, _)| key.clone()),
+            false => None,
+        };
+        let contents: Vec<Content> = keys
+            .iter()
+            .map(|(key, metadata)| Content {
+                key: key.clone(),
+                last_modified: modified(metadata).to_rfc3339_opts(SecondsFormat::Millis, true),
+                e_tag: etag(metadata),
+                size: metadata.len() as i64,
+                storage_class: "STANDARD".to_string(),
+            })
+            .collect();
+        let result = ListBucketResult {
+            name: resource.trim_matches('/').to_string(),
+            prefix: Some(prefix),
+            delimiter: None,
+            key_count: contents.len() as i32,
+            is_truncated,
+            continuation_token,
+            next_continuation_token,
+            start_after,
+            contents: Some(contents),
+        };
+        let body = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}",
+            quick_xml::se::to_string(&result).unwrap()
+        );
+        http::Response::builder()
+            .header("content-type", "application/xml")
+            .header("content-length", body.len())
+            .body(Body::from(body))
+            .unwrap()
+    }
+}
+
+impl Backend for LocalFsBackend {
+    fn authorize<'a>(
+        &'a self,
+        _request: &'a mut reqwest::Request,
+        _credentials: &'a aws_credential_types::Credentials,
+        _payload: &'a Payload,
+    ) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn send<'a>(
+        &'a self,
+        _client: &'a reqwest::Client,
+        request: reqwest::Request,
+        payload: Payload,
+    ) -> BoxFuture<'a, Result<reqwest::Response, reqwest::Error>> {
+        Box::pin(async move { Ok(self.handle(request, payload).await.into()) })
+    }
+}
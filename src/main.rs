@@ -1,53 +1,223 @@
-use hyper::service::{make_service_fn, service_fn};
-use hyper::Server;
-use std::convert::Infallible;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use tracing::{info, debug};
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::path::PathBuf;
+use tracing::{info, warn};
 
-mod router;
-mod s3_handler;
-mod xml_writer;
-mod credentials;
-
-use crate::s3_handler::S3Handler;
+use s3proxy::cache::DiskCache;
+use s3proxy::size_cache::SizeCache;
+use s3proxy::{config_file, telemetry, S3Handler, Settings};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// The endpoint to use for S3 requests
-    #[arg(long, short, env)]
-    endpoint: String,
-    #[arg(long, short, default_value="3000", env)]
-    port: u16,
+    /// TOML or YAML file with settings named like the flags; flags and environment variables take precedence
+    #[arg(long, env = "S3PROXY_CONFIG")]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    settings: Settings,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Serve S3 requests; the default without a command
+    Serve,
+    /// Download all objects below a prefix into the disk cache and exit
+    Warm(WarmArgs),
+    /// Inspect or purge the disk cache without starting the server
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Check the settings and the files they refer to, and exit
+    ValidateConfig,
+}
 
-    let args = Args::parse();
-    info!("{:?}", args);
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Print the number of cached entries and their size as JSON
+    Stats,
+    /// Remove cached entries of a bucket and key prefix
+    Purge(PurgeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct PurgeArgs {
+    /// Bucket whose entries are removed; entries of all buckets are removed if unset
+    #[arg(long)]
+    bucket: Option<String>,
+    /// Key prefix of the entries to remove
+    #[arg(long, default_value = "")]
+    prefix: String,
+}
+
+#[derive(clap::Args)]
+struct WarmArgs {
+    /// Bucket to warm
+    #[arg(long)]
+    bucket: String,
+    /// Key prefix of the objects to warm
+    #[arg(long, default_value = "")]
+    prefix: String,
+    /// Token exchanged for credentials to read the objects; not needed with --auth-mode static
+    #[arg(long, env)]
+    token: Option<String>,
+    /// Number of objects downloaded concurrently
+    #[arg(long, default_value = "4")]
+    concurrency: usize,
+}
+
+impl std::fmt::Debug for WarmArgs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WarmArgs")
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .field("token", &"<redacted>")
+            .field("concurrency", &self.concurrency)
+            .finish()
+    }
+}
+
+async fn warm(s3: &S3Handler, args: &WarmArgs) {
+    let credentials = match s3.get_credentials(args.token.as_deref()).await {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("failed to get credentials: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let tenant = match s3.get_tenant(args.token.as_deref()).await {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("failed to get user info: {}", e);
+            std::process::exit(1);
+        }
+    };
+    match s3
+        .warm_prefix(
+            &credentials,
+            tenant.as_deref(),
+            &args.bucket,
+            &args.prefix,
+            args.concurrency,
+        )
+        .await
+    {
+        Ok((objects, bytes)) => info!(objects, bytes, "Cache warmed"),
+        Err(e) => {
+            eprintln!("failed to warm cache: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Prints `message` and exits with an error.
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+/// Parses the flags, falling back to the environment and then to the
+/// configuration file, if any. Flags may be given before or after the
+/// command.
+fn parse_args() -> Args {
+    // Arguments capture their environment variables when they are built,
+    // so the command is built again once the file has set them.
+    let command = || Args::command().mut_args(|arg| arg.global(true));
+    let matches = command().ignore_errors(true).get_matches();
+    if let Some(path) = matches.get_one::<PathBuf>("config") {
+        if let Err(e) = config_file::apply(path, &command()) {
+            fail(format!("failed to read configuration file {}", e));
+        }
+    }
+    let matches = command().get_matches();
+    Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit())
+}
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], args.port));
+/// Exits with a usage error unless the upstream endpoint is set, which all
+/// commands but the cache commands need.
+fn require_endpoint(args: &Args) {
+    if args.settings.endpoint().is_err() {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                "the following required arguments were not provided:\n  --endpoint <ENDPOINT>",
+            )
+            .exit()
+    }
+}
+
+/// Checks settings that only make sense together.
+fn check_settings(args: &Args) -> Result<(), String> {
+    args.settings.check()?;
+    if args.settings.cache.no_cache && matches!(args.command, Some(Command::Warm(_))) {
+        return Err("cannot warm the cache with --no-cache".to_string());
+    }
+    Ok(())
+}
 
-    let s3 = Arc::new(S3Handler::new(&args.endpoint));
-    let make_svc = make_service_fn(|_conn| {
-        let s3 = s3.clone();
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                router::route_request(req, s3.clone())
-            }))
+/// Runs a cache command against the cache directories, which a running
+/// proxy may be using too.
+async fn cache_command(args: &Args, command: &CacheCommand) {
+    let cache = DiskCache::new(args.settings.cache.clone());
+    if !cache.enabled() {
+        fail("the disk cache is disabled with --no-cache");
+    }
+    match command {
+        CacheCommand::Stats => match cache.stats().await {
+            Ok(stats) => println!(
+                "{}",
+                serde_json::json!({ "entries": stats.entries, "bytes": stats.bytes })
+            ),
+            Err(e) => fail(format!("failed to read cache directories: {}", e)),
+        },
+        CacheCommand::Purge(purge) => {
+            let removed = match cache.purge(purge.bucket.as_deref(), &purge.prefix).await {
+                Ok(removed) => removed,
+                Err(e) => fail(format!("failed to purge cache: {}", e)),
+            };
+            // Saved object sizes of the purged entries are removed too, so
+            // that they are looked up again after a restart.
+            if let Some((path, _)) = cache.size_snapshot() {
+                let sizes = SizeCache::new(cache.size_cache_capacity(), cache.size_cache_max_age());
+                let bucket = purge.bucket.as_deref();
+                let saved = async {
+                    sizes.load(&path).await?;
+                    sizes.remove(|b, key| {
+                        bucket.is_none_or(|bucket| bucket == b) && key.starts_with(&purge.prefix)
+                    });
+                    sizes.save(&path).await
+                };
+                if let Err(e) = saved.await {
+                    warn!("Failed to update saved object sizes: {}", e);
+                }
+            }
+            println!("{}", serde_json::json!({ "removed": removed }));
         }
-    });
+    }
+}
 
-    let server = Server::bind(&addr).serve(make_svc);
+#[tokio::main]
+async fn main() {
+    let args = parse_args();
+    if let Err(e) = telemetry::init(&args.settings.telemetry) {
+        fail(format!("failed to set up trace export: {}", e));
+    }
+    info!("{:?}", args);
 
-    debug!("Server running on port 3000");
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
+    if !matches!(args.command, Some(Command::Cache(_))) {
+        require_endpoint(&args);
+        check_settings(&args).unwrap_or_else(|e| fail(e));
+    }
+    match &args.command {
+        None | Some(Command::Serve) => args.settings.serve().await.unwrap_or_else(|e| fail(e)),
+        Some(Command::Warm(warm_args)) => {
+            let s3 = args.settings.handler().await.unwrap_or_else(|e| fail(e));
+            warm(&s3, warm_args).await;
+        }
+        Some(Command::Cache(command)) => cache_command(&args, command).await,
+        Some(Command::ValidateConfig) => {
+            args.settings.validate().await.unwrap_or_else(|e| fail(e));
+            println!("Configuration is valid");
+        }
     }
+    telemetry::shutdown();
 }
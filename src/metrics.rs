@@ -0,0 +1,719 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use hyper::body::HttpBody;
+use hyper::http::request::Parts;
+use hyper::{Body, Method, Response, StatusCode};
+
+use crate::admin::ADMIN_PREFIX;
+use crate::cache::BlockUsage;
+use crate::health::{HEALTHZ_PATH, READYZ_PATH};
+use crate::s3_handler::S3Handler;
+
+/// Path of the Prometheus metrics, which need no token.
+pub const METRICS_PATH: &str = "/metrics";
+
+/// Upper bounds in seconds of the buckets of latency histograms.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Returns true for paths of the endpoints of the proxy itself.
+pub fn is_internal(path: &str) -> bool {
+    path.starts_with(ADMIN_PREFIX) || [METRICS_PATH, HEALTHZ_PATH, READYZ_PATH].contains(&path)
+}
+
+/// The S3 operation of a request, used to label its metrics.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Get,
+    Head,
+    List,
+    Put,
+    Delete,
+    Other,
+}
+
+impl Operation {
+    /// Returns the operation of a request. Requests for the endpoints of the
+    /// proxy itself are `Other`.
+    pub fn of(method: &Method, uri: &hyper::Uri) -> Operation {
+        if is_internal(uri.path()) {
+            return Operation::Other;
+        }
+        let list = uri
+            .query()
+            .is_some_and(|query| query.split('&').any(|p| p.starts_with("list-type=")));
+        match *method {
+            Method::GET if list => Operation::List,
+            Method::GET => Operation::Get,
+            Method::HEAD => Operation::Head,
+            Method::PUT => Operation::Put,
+            Method::DELETE => Operation::Delete,
+            _ => Operation::Other,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Get => "get",
+            Operation::Head => "head",
+            Operation::List => "list",
+            Operation::Put => "put",
+            Operation::Delete => "delete",
+            Operation::Other => "other",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket of `LATENCY_BUCKETS`, not cumulative.
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+/// An event of a cache, counted by bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheEvent {
+    Hit,
+    Miss,
+    Eviction,
+    /// A fill that was abandoned before it was committed.
+    FillFailure,
+}
+
+impl CacheEvent {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CacheEvent::Hit => "hit",
+            CacheEvent::Miss => "miss",
+            CacheEvent::Eviction => "eviction",
+            CacheEvent::FillFailure => "fill_failure",
+        }
+    }
+}
+
+/// Events of a cache by bucket.
+#[derive(Debug, Default)]
+pub struct CacheEvents(Mutex<HashMap<(String, CacheEvent), u64>>);
+
+impl CacheEvents {
+    /// Counts an event of `bucket`. Events of entries whose bucket isn't
+    /// known, like those written by older versions, are not counted.
+    pub fn record(&self, bucket: &str, event: CacheEvent) {
+        if bucket.is_empty() {
+            return;
+        }
+        *self
+            .0
+            .lock()
+            .unwrap()
+            .entry((bucket.to_string(), event))
+            .or_default() += 1;
+    }
+
+    fn render(&self, out: &mut String, cache: &str) {
+        let events = self.0.lock().unwrap();
+        let mut events: Vec<_> = events.iter().collect();
+        events.sort_by_key(|((bucket, event), _)| (bucket.as_str(), event.as_str()));
+        for ((bucket, event), count) in events {
+            let _ = writeln!(
+                out,
+                "s3proxy_cache_events_total{{cache=\"{}\",bucket=\"{}\",event=\"{}\"}} {}",
+                cache,
+                bucket,
+                event.as_str(),
+                count
+            );
+        }
+    }
+}
+
+/// The operation of a response and the cache status of its blocks, `hit`,
+/// `miss`, `partial` or `none` if it used no blocks.
+type ResponseLabels = (Operation, &'static str);
+
+#[derive(Default)]
+struct RequestMetrics {
+    /// Requests by method and status.
+    requests: HashMap<(String, u16), u64>,
+    /// Time to response headers by operation.
+    latency: HashMap<Operation, Histogram>,
+    /// Time to the first byte of response bodies.
+    first_byte: HashMap<ResponseLabels, Histogram>,
+    /// Time until response bodies were sent.
+    duration: HashMap<ResponseLabels, Histogram>,
+    /// Bytes of response bodies sent.
+    bytes: HashMap<ResponseLabels, u64>,
+}
+
+/// Counts requests as in flight until it is dropped.
+pub struct InFlightGuard<'a>(&'a AtomicU64);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Metrics of the requests served since startup, exported in the Prometheus
+/// text format.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<RequestMetrics>,
+    in_flight: AtomicU64,
+    /// Upstream responses by endpoint and status class.
+    upstream_responses: Mutex<HashMap<(String, &'static str), u64>>,
+    upstream_retries: AtomicU64,
+    upstream_server_errors: AtomicU64,
+    upstream_connection_errors: AtomicU64,
+    upstream_timeouts: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a request as in flight while the returned guard lives.
+    pub fn start_request(&self) -> InFlightGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(&self.in_flight)
+    }
+
+    /// Counts a request as in flight like `start_request`, unless `limit`
+    /// requests already are.
+    pub fn try_start_request(&self, limit: u32) -> Option<InFlightGuard<'_>> {
+        let previous = self.in_flight.fetch_add(1, Ordering::Relaxed);
+        let guard = InFlightGuard(&self.in_flight);
+        (previous < limit as u64).then_some(guard)
+    }
+
+    pub fn record_request(
+        &self,
+        method: &Method,
+        operation: Operation,
+        status: StatusCode,
+        elapsed: Duration,
+    ) {
+        let mut metrics = self.requests.lock().unwrap();
+        *metrics
+            .requests
+            .entry((method.to_string(), status.as_u16()))
+            .or_default() += 1;
+        metrics
+            .latency
+            .entry(operation)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Records a response body once it has been sent or the client went away.
+    fn record_response(
+        &self,
+        labels: ResponseLabels,
+        first_byte: Duration,
+        elapsed: Duration,
+        bytes: u64,
+    ) {
+        let mut metrics = self.requests.lock().unwrap();
+        metrics
+            .first_byte
+            .entry(labels)
+            .or_default()
+            .observe(first_byte.as_secs_f64());
+        metrics
+            .duration
+            .entry(labels)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+        *metrics.bytes.entry(labels).or_default() += bytes;
+    }
+
+    /// Wraps the body of a response so that the time to its first byte, the
+    /// time until it is sent and its bytes are recorded, by `operation` and
+    /// by whether it was served from the cache.
+    pub fn wrap(
+        self: &Arc<Self>,
+        operation: Operation,
+        start: Instant,
+        res: Response<Body>,
+    ) -> Response<Body> {
+        let usage = res.extensions().get::<Arc<BlockUsage>>().cloned();
+        let (parts, body) = res.into_parts();
+        let body = MeteredBody {
+            body,
+            metrics: self.clone(),
+            operation,
+            usage,
+            start,
+            first_byte: None,
+            bytes: 0,
+        };
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    /// Records the outcome of an upstream request to `endpoint`.
+    pub fn record_upstream(&self, endpoint: &str, res: &Result<reqwest::Response, reqwest::Error>) {
+        if let Ok(res) = res {
+            let class = match res.status().as_u16() {
+                100..=199 => "1xx",
+                200..=299 => "2xx",
+                300..=399 => "3xx",
+                400..=499 => "4xx",
+                _ => "5xx",
+            };
+            *self
+                .upstream_responses
+                .lock()
+                .unwrap()
+                .entry((endpoint.to_string(), class))
+                .or_default() += 1;
+        }
+        match res {
+            Ok(res) if res.status().is_server_error() => {
+                self.upstream_server_errors.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(_) => {}
+            Err(_) => {
+                self.upstream_connection_errors
+                    .fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Counts an upstream request that got no response headers in time.
+    pub fn record_upstream_timeout(&self) {
+        self.upstream_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an upstream request repeating one that failed.
+    pub fn record_upstream_retry(&self) {
+        self.upstream_retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        let metrics = self.requests.lock().unwrap();
+        let mut requests: Vec<_> = metrics.requests.iter().collect();
+        requests.sort();
+        out.push_str("# HELP s3proxy_requests_total Requests by method and status.\n");
+        out.push_str("# TYPE s3proxy_requests_total counter\n");
+        for ((method, status), count) in requests {
+            let _ = writeln!(
+                out,
+                "s3proxy_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                method, status, count
+            );
+        }
+
+        let mut latency: Vec<_> = metrics.latency.iter().collect();
+        latency.sort_by_key(|(operation, _)| operation.as_str());
+        out.push_str(
+            "# HELP s3proxy_request_duration_seconds Time until response headers by operation.\n",
+        );
+        out.push_str("# TYPE s3proxy_request_duration_seconds histogram\n");
+        for (operation, histogram) in latency {
+            let labels = format!("operation=\"{}\"", operation.as_str());
+            histogram.render(out, "s3proxy_request_duration_seconds", &labels);
+        }
+
+        let labels = |(operation, cache): &ResponseLabels| {
+            format!("operation=\"{}\",cache=\"{}\"", operation.as_str(), cache)
+        };
+        let mut first_byte: Vec<_> = metrics.first_byte.iter().collect();
+        first_byte.sort_by_key(|((operation, cache), _)| (operation.as_str(), *cache));
+        out.push_str(
+            "# HELP s3proxy_time_to_first_byte_seconds Time until the first byte of response bodies by operation and cache status.\n",
+        );
+        out.push_str("# TYPE s3proxy_time_to_first_byte_seconds histogram\n");
+        for (key, histogram) in first_byte {
+            histogram.render(out, "s3proxy_time_to_first_byte_seconds", &labels(key));
+        }
+
+        let mut duration: Vec<_> = metrics.duration.iter().collect();
+        duration.sort_by_key(|((operation, cache), _)| (operation.as_str(), *cache));
+        out.push_str(
+            "# HELP s3proxy_response_duration_seconds Time until response bodies were sent by operation and cache status.\n",
+        );
+        out.push_str("# TYPE s3proxy_response_duration_seconds histogram\n");
+        for (key, histogram) in duration {
+            histogram.render(out, "s3proxy_response_duration_seconds", &labels(key));
+        }
+
+        let mut bytes: Vec<_> = metrics.bytes.iter().collect();
+        bytes.sort_by_key(|((operation, cache), _)| (operation.as_str(), *cache));
+        out.push_str(
+            "# HELP s3proxy_response_bytes_total Bytes of response bodies sent by operation and cache status.\n",
+        );
+        out.push_str("# TYPE s3proxy_response_bytes_total counter\n");
+        for (key, bytes) in bytes {
+            let _ = writeln!(
+                out,
+                "s3proxy_response_bytes_total{{{}}} {}",
+                labels(key),
+                bytes
+            );
+        }
+
+        gauge(
+            out,
+            "s3proxy_requests_in_flight",
+            "Requests being served.",
+            self.in_flight.load(Ordering::Relaxed) as f64,
+        );
+        out.push_str("# HELP s3proxy_upstream_errors_total Failed upstream requests by kind.\n");
+        out.push_str("# TYPE s3proxy_upstream_errors_total counter\n");
+        let _ = writeln!(
+            out,
+            "s3proxy_upstream_errors_total{{kind=\"server_error\"}} {}",
+            self.upstream_server_errors.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "s3proxy_upstream_errors_total{{kind=\"connection\"}} {}",
+            self.upstream_connection_errors.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "s3proxy_upstream_errors_total{{kind=\"timeout\"}} {}",
+            self.upstream_timeouts.load(Ordering::Relaxed)
+        );
+
+        let responses = self.upstream_responses.lock().unwrap();
+        let mut responses: Vec<_> = responses.iter().collect();
+        responses.sort();
+        out.push_str(
+            "# HELP s3proxy_upstream_responses_total Upstream responses by endpoint and status class.\n",
+        );
+        out.push_str("# TYPE s3proxy_upstream_responses_total counter\n");
+        for ((endpoint, class), count) in responses {
+            let _ = writeln!(
+                out,
+                "s3proxy_upstream_responses_total{{endpoint=\"{}\",class=\"{}\"}} {}",
+                endpoint, class, count
+            );
+        }
+        counter(
+            out,
+            "s3proxy_upstream_retries_total",
+            "Upstream requests repeating failed ones, like those resuming interrupted cache fills.",
+            self.upstream_retries.load(Ordering::Relaxed),
+        );
+    }
+}
+
+/// A response body that records its metrics when it is dropped.
+struct MeteredBody {
+    body: Body,
+    metrics: Arc<Metrics>,
+    operation: Operation,
+    usage: Option<Arc<BlockUsage>>,
+    start: Instant,
+    /// Time until the first chunk or the end of the body was polled.
+    first_byte: Option<Duration>,
+    bytes: u64,
+}
+
+impl Stream for MeteredBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.body).poll_data(cx);
+        if let Poll::Ready(ready) = &polled {
+            if let Some(Ok(data)) = ready {
+                self.bytes += data.len() as u64;
+            }
+            if self.first_byte.is_none() {
+                self.first_byte = Some(self.start.elapsed());
+            }
+        }
+        polled
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        let cache = self.usage.as_ref().and_then(|usage| usage.status());
+        self.metrics.record_response(
+            (self.operation, cache.unwrap_or("none")),
+            self.first_byte.unwrap_or(elapsed),
+            elapsed,
+            self.bytes,
+        );
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(
+        out,
+        "# HELP {} {}\n# TYPE {} counter\n{} {}",
+        name, help, name, name, value
+    );
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: f64) {
+    let _ = writeln!(
+        out,
+        "# HELP {} {}\n# TYPE {} gauge\n{} {}",
+        name, help, name, name, value
+    );
+}
+
+/// Renders the metrics of the Tokio runtime that the proxy runs on, which
+/// tell executor stalls, e.g. under heavy cache fills, from slow upstreams.
+/// Metrics of the blocking pool and the local queues of workers need a build
+/// with `--cfg tokio_unstable`.
+fn render_runtime(out: &mut String) {
+    let Ok(handle) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    let runtime = handle.metrics();
+    gauge(
+        out,
+        "s3proxy_runtime_workers",
+        "Worker threads of the runtime.",
+        runtime.num_workers() as f64,
+    );
+    gauge(
+        out,
+        "s3proxy_runtime_alive_tasks",
+        "Tasks spawned and not yet completed.",
+        runtime.num_alive_tasks() as f64,
+    );
+    gauge(
+        out,
+        "s3proxy_runtime_global_queue_depth",
+        "Tasks waiting in the global queue of the runtime.",
+        runtime.global_queue_depth() as f64,
+    );
+    out.push_str(
+        "# HELP s3proxy_runtime_worker_busy_seconds_total Time workers spent running tasks.\n",
+    );
+    out.push_str("# TYPE s3proxy_runtime_worker_busy_seconds_total counter\n");
+    for worker in 0..runtime.num_workers() {
+        let _ = writeln!(
+            out,
+            "s3proxy_runtime_worker_busy_seconds_total{{worker=\"{}\"}} {}",
+            worker,
+            runtime.worker_total_busy_duration(worker).as_secs_f64()
+        );
+    }
+    out.push_str(
+        "# HELP s3proxy_runtime_worker_parks_total Times workers ran out of tasks and parked.\n",
+    );
+    out.push_str("# TYPE s3proxy_runtime_worker_parks_total counter\n");
+    for worker in 0..runtime.num_workers() {
+        let _ = writeln!(
+            out,
+            "s3proxy_runtime_worker_parks_total{{worker=\"{}\"}} {}",
+            worker,
+            runtime.worker_park_count(worker)
+        );
+    }
+    #[cfg(tokio_unstable)]
+    {
+        gauge(
+            out,
+            "s3proxy_runtime_blocking_threads",
+            "Threads of the blocking pool, which file I/O runs on.",
+            runtime.num_blocking_threads() as f64,
+        );
+        gauge(
+            out,
+            "s3proxy_runtime_idle_blocking_threads",
+            "Idle threads of the blocking pool.",
+            runtime.num_idle_blocking_threads() as f64,
+        );
+        gauge(
+            out,
+            "s3proxy_runtime_blocking_queue_depth",
+            "Blocking tasks waiting for a thread of the blocking pool.",
+            runtime.blocking_queue_depth() as f64,
+        );
+        out.push_str(
+            "# HELP s3proxy_runtime_worker_local_queue_depth Tasks waiting in the local queues of workers.\n",
+        );
+        out.push_str("# TYPE s3proxy_runtime_worker_local_queue_depth gauge\n");
+        for worker in 0..runtime.num_workers() {
+            let _ = writeln!(
+                out,
+                "s3proxy_runtime_worker_local_queue_depth{{worker=\"{}\"}} {}",
+                worker,
+                runtime.worker_local_queue_depth(worker)
+            );
+        }
+    }
+}
+
+/// Renders the metrics of the proxy: those of requests, the disk cache, the
+/// credentials, the circuit breaker and the runtime.
+fn render(s3: &S3Handler) -> String {
+    let mut out = String::new();
+    s3.metrics().render(&mut out);
+
+    let (hits, misses) = s3.cache_hit_counts();
+    counter(
+        &mut out,
+        "s3proxy_cache_hits_total",
+        "Blocks served from the disk cache.",
+        hits,
+    );
+    counter(
+        &mut out,
+        "s3proxy_cache_misses_total",
+        "Blocks fetched from the upstream.",
+        misses,
+    );
+    let ratio = match hits + misses {
+        0 => 0.0,
+        total => hits as f64 / total as f64,
+    };
+    gauge(
+        &mut out,
+        "s3proxy_cache_hit_ratio",
+        "Share of blocks served from the disk cache since startup.",
+        ratio,
+    );
+    let usage = s3.cache_usage();
+    gauge(
+        &mut out,
+        "s3proxy_cache_entries",
+        "Blocks stored in the disk cache.",
+        usage.entries as f64,
+    );
+    gauge(
+        &mut out,
+        "s3proxy_cache_files",
+        "Files in the cache directories, data and metadata sidecars of blocks.",
+        usage.files as f64,
+    );
+    gauge(
+        &mut out,
+        "s3proxy_cache_bytes",
+        "Bytes of the files in the cache directories.",
+        usage.bytes as f64,
+    );
+
+    out.push_str(
+        "# HELP s3proxy_cache_events_total Hits, misses, evictions and failed fills of the disk cache (blocks) and the size cache by bucket.\n",
+    );
+    out.push_str("# TYPE s3proxy_cache_events_total counter\n");
+    for (cache, events) in s3.cache_events() {
+        events.render(&mut out, cache);
+    }
+
+    let credentials = s3.credentials_stats();
+    gauge(
+        &mut out,
+        "s3proxy_credentials_identities",
+        "Tokens with cached credentials.",
+        credentials.identities as f64,
+    );
+    counter(
+        &mut out,
+        "s3proxy_credentials_exchanges_total",
+        "Token exchanges.",
+        credentials.exchanges,
+    );
+    counter(
+        &mut out,
+        "s3proxy_credentials_exchange_failures_total",
+        "Failed token exchanges.",
+        credentials.exchange_failures,
+    );
+
+    out.push_str(
+        "# HELP s3proxy_upstream_circuit_open Whether requests to an upstream fail fast after consecutive failures.\n",
+    );
+    out.push_str("# TYPE s3proxy_upstream_circuit_open gauge\n");
+    for (endpoint, breaker) in s3.upstreams().circuit_breakers() {
+        let _ = writeln!(
+            out,
+            "s3proxy_upstream_circuit_open{{endpoint=\"{}\"}} {}",
+            endpoint,
+            breaker.is_open() as u8
+        );
+    }
+    out.push_str(
+        "# HELP s3proxy_upstream_circuit_rejections_total Upstream requests failed fast by the circuit breaker.\n",
+    );
+    out.push_str("# TYPE s3proxy_upstream_circuit_rejections_total counter\n");
+    for (endpoint, breaker) in s3.upstreams().circuit_breakers() {
+        let _ = writeln!(
+            out,
+            "s3proxy_upstream_circuit_rejections_total{{endpoint=\"{}\"}} {}",
+            endpoint,
+            breaker.rejected()
+        );
+    }
+    out.push_str(
+        "# HELP s3proxy_upstream_circuit_transitions_total Changes of the state of the circuit breaker of an upstream, by the state changed to.\n",
+    );
+    out.push_str("# TYPE s3proxy_upstream_circuit_transitions_total counter\n");
+    for (endpoint, breaker) in s3.upstreams().circuit_breakers() {
+        for (state, count) in breaker.transitions() {
+            let _ = writeln!(
+                out,
+                "s3proxy_upstream_circuit_transitions_total{{endpoint=\"{}\",to=\"{}\"}} {}",
+                endpoint, state, count
+            );
+        }
+    }
+    render_runtime(&mut out);
+    out
+}
+
+/// Answers `GET /metrics`, or returns `None` for other requests. Requests
+/// with a query string are S3 requests for a bucket of the same name.
+pub fn route_metrics(parts: &Parts, s3: &S3Handler) -> Option<Response<Body>> {
+    if parts.method != Method::GET
+        || parts.uri.query().is_some()
+        || parts.uri.path() != METRICS_PATH
+    {
+        return None;
+    }
+    let body = render(s3);
+    Some(
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "text/plain; version=0.0.4")
+            .header("content-length", body.len())
+            .body(Body::from(body))
+            .unwrap(),
+    )
+}
@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ProfileError {
+    #[error("The proxy was built without the pprof feature")]
+    Unsupported,
+    #[error("Another profile is being captured")]
+    Busy,
+    #[error("Failed to capture profile: {0}")]
+    Failed(String),
+}
+
+/// Held while a profile is captured. The profiler samples the whole process,
+/// so only one profile can be captured at a time.
+#[cfg(feature = "pprof")]
+static CAPTURE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Samples the stacks of all threads `frequency` times per second for
+/// `duration` and renders them as a flame graph in SVG.
+#[cfg(feature = "pprof")]
+pub async fn flamegraph(duration: Duration, frequency: i32) -> Result<Vec<u8>, ProfileError> {
+    let failed = |e: pprof::Error| ProfileError::Failed(e.to_string());
+    let _capture = CAPTURE.try_lock().map_err(|_| ProfileError::Busy)?;
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        // Unwinding through these from the signal handler can deadlock.
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(failed)?;
+    tokio::time::sleep(duration).await;
+    let report = guard.report().build().map_err(failed)?;
+    drop(guard);
+    let mut svg = Vec::new();
+    report.flamegraph(&mut svg).map_err(failed)?;
+    Ok(svg)
+}
+
+#[cfg(not(feature = "pprof"))]
+pub async fn flamegraph(_duration: Duration, _frequency: i32) -> Result<Vec<u8>, ProfileError> {
+    Err(ProfileError::Unsupported)
+}
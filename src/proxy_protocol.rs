@@ -0,0 +1,332 @@
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
+use hyper::server::accept::Accept;
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+use tracing::debug;
+
+/// Time that clients get to send the PROXY protocol header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most connections whose headers are read at once.
+const MAX_PENDING_HEADERS: usize = 64;
+
+/// Signature that PROXY protocol v2 headers start with.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest PROXY protocol v1 header, including the line break.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Longest addresses and TLVs accepted in a v2 header. Load balancers send
+/// far less, but the length field allows up to 64 KiB.
+const V2_MAX_LENGTH: usize = 2048;
+
+/// A connection from a load balancer, with the address of the client that
+/// the load balancer forwards.
+pub struct Proxied {
+    stream: AddrStream,
+    remote_addr: SocketAddr,
+}
+
+impl Proxied {
+    /// Returns the address of the client, or that of the load balancer if
+    /// it didn't forward one, e.g. for its health checks.
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote_addr
+    }
+}
+
+/// Accepts TCP connections that start with a PROXY protocol v1 or v2
+/// header. Headers are read concurrently, so that slow clients don't hold
+/// up others; connections without a valid header are closed.
+pub struct ProxyIncoming {
+    incoming: AddrIncoming,
+    pending: FuturesUnordered<Pin<Box<dyn Future<Output = Option<Proxied>> + Send>>>,
+}
+
+impl ProxyIncoming {
+    pub fn new(incoming: AddrIncoming) -> Self {
+        ProxyIncoming {
+            incoming,
+            pending: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl Accept for ProxyIncoming {
+    type Conn = Proxied;
+    type Error = Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        while this.pending.len() < MAX_PENDING_HEADERS {
+            match Pin::new(&mut this.incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => this.pending.push(Box::pin(async move {
+                    let peer = stream.remote_addr();
+                    match tokio::time::timeout(HEADER_TIMEOUT, read_header(stream)).await {
+                        Ok(Ok(proxied)) => Some(proxied),
+                        Ok(Err(e)) => {
+                            debug!(%peer, "Closing connection: {}", e);
+                            None
+                        }
+                        Err(_) => {
+                            debug!(%peer, "Closing connection: no PROXY protocol header");
+                            None
+                        }
+                    }
+                })),
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) if this.pending.is_empty() => return Poll::Ready(None),
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+        while let Poll::Ready(Some(proxied)) = this.pending.poll_next_unpin(cx) {
+            if let Some(proxied) = proxied {
+                return Poll::Ready(Some(Ok(proxied)));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/// Reads the PROXY protocol header that `stream` starts with.
+async fn read_header(mut stream: AddrStream) -> std::io::Result<Proxied> {
+    let peer = stream.remote_addr();
+    let remote_addr = read_remote_addr(&mut stream).await?;
+    Ok(Proxied {
+        stream,
+        remote_addr: remote_addr.unwrap_or(peer),
+    })
+}
+
+/// Reads a PROXY protocol header, returning the client address it forwards.
+async fn read_remote_addr<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> std::io::Result<Option<SocketAddr>> {
+    // Both versions of headers are at least 12 bytes long.
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("no PROXY protocol header"))
+    }
+}
+
+/// Reads the rest of a v2 header after its signature.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Option<SocketAddr>> {
+    let mut fixed = [0; 4];
+    stream.read_exact(&mut fixed).await?;
+    let [version_command, family, len @ ..] = fixed;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    let len = u16::from_be_bytes(len) as usize;
+    if len > V2_MAX_LENGTH {
+        return Err(invalid("PROXY protocol header too long"));
+    }
+    let mut addresses = vec![0; len];
+    stream.read_exact(&mut addresses).await?;
+    // LOCAL connections are made by the load balancer itself.
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+    let port = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    Ok(match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            Some(SocketAddr::new(IpAddr::V4(ip), port(&addresses[8..])))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            Some(SocketAddr::new(IpAddr::V6(ip), port(&addresses[32..])))
+        }
+        // Unix sockets and unspecified addresses.
+        _ => None,
+    })
+}
+
+/// Reads the rest of a v1 header, a line like
+/// `PROXY TCP4 <source> <destination> <source port> <destination port>`.
+async fn read_v1<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    start: &[u8],
+) -> std::io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    // The header is read byte by byte so that no data after it is consumed.
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("invalid PROXY protocol header"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip = source
+                .parse()
+                .map_err(|_| invalid("invalid source address in PROXY protocol header"))?;
+            let port = port
+                .parse()
+                .map_err(|_| invalid("invalid source port in PROXY protocol header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        _ => Err(invalid("invalid PROXY protocol header")),
+    }
+}
+
+impl AsyncRead for Proxied {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Proxied {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the header that `data` starts with, returning the forwarded
+    /// address and the data after the header.
+    async fn read(data: &[u8]) -> std::io::Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut stream = data;
+        let remote_addr = read_remote_addr(&mut stream).await?;
+        Ok((remote_addr, stream.to_vec()))
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let (addr, _) = read(b"PROXY UNKNOWN\r\n").await.unwrap();
+        assert_eq!(addr, None);
+    }
+
+    #[tokio::test]
+    async fn v2_addresses() {
+        // TCP over IPv4: addresses, ports and a TLV that is skipped.
+        let mut addresses = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        addresses.extend(56324u16.to_be_bytes());
+        addresses.extend(443u16.to_be_bytes());
+        addresses.extend([0x04, 0, 1, 0]);
+        let mut data = v2(1, 0x11, &addresses);
+        data.extend(b"GET /");
+        let (addr, rest) = read(&data).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut addresses = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        addresses.extend([0; 16]);
+        addresses.extend(56324u16.to_be_bytes());
+        addresses.extend(443u16.to_be_bytes());
+        let (addr, _) = read(&v2(1, 0x21, &addresses)).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        // LOCAL connections, e.g. health checks, forward no address.
+        let (addr, _) = read(&v2(0, 0, &[])).await.unwrap();
+        assert_eq!(addr, None);
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let v1 = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n";
+        let v2 = v2(1, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+        for header in [&v1[..], &v2[..]] {
+            for len in [4, 14, header.len() - 1] {
+                let e = read(&header[..len]).await.unwrap_err();
+                assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_headers() {
+        let mut oversized = V2_SIGNATURE.to_vec();
+        oversized.extend([0x21, 0x11, 0xff, 0xff]);
+        let long_v1 = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LENGTH));
+        for data in [
+            &oversized[..],
+            long_v1.as_bytes(),
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 port 443\r\n",
+            b"PROXY TCP4 192.0.2.1\r\n",
+        ] {
+            let result = read(data).await;
+            assert!(
+                matches!(&result, Err(e) if e.kind() == ErrorKind::InvalidData),
+                "{:?}: {:?}",
+                String::from_utf8_lossy(data),
+                result
+            );
+        }
+    }
+}
@@ -0,0 +1,158 @@
+/// A single byte range from a `Range: bytes=...` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `bytes=first-last`
+    Bounded(u64, u64),
+    /// `bytes=first-`
+    From(u64),
+    /// `bytes=-length`
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parses all byte ranges of a `Range` header, ignoring surrounding
+    /// whitespace. Malformed values yield `None`.
+    pub fn parse(value: &str) -> Option<Vec<ByteRange>> {
+        let specs = value.trim().strip_prefix("bytes=")?;
+        specs.split(',').map(ByteRange::parse_spec).collect()
+    }
+
+    fn parse_spec(spec: &str) -> Option<ByteRange> {
+        let (first, last) = spec.trim().split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        match (first.is_empty(), last.is_empty()) {
+            (true, false) => Some(ByteRange::Suffix(last.parse().ok()?)),
+            (false, true) => Some(ByteRange::From(first.parse().ok()?)),
+            (false, false) => {
+                let (first, last) = (first.parse().ok()?, last.parse().ok()?);
+                (first <= last).then_some(ByteRange::Bounded(first, last))
+            }
+            (true, true) => None,
+        }
+    }
+
+    /// Resolves the range against an object of `size` bytes into inclusive
+    /// `(first, last)` offsets, or `None` if it is not satisfiable.
+    pub fn resolve(&self, size: u64) -> Option<(u64, u64)> {
+        if size == 0 {
+            return None;
+        }
+        match *self {
+            ByteRange::Bounded(first, last) if first < size => Some((first, last.min(size - 1))),
+            ByteRange::From(first) if first < size => Some((first, size - 1)),
+            ByteRange::Suffix(length) if length > 0 => {
+                Some((size.saturating_sub(length), size - 1))
+            }
+            _ => None,
+        }
+    }
+
+    /// Resolves ranges against an object of `size` bytes into sorted inclusive
+    /// `(first, last)` offsets, merging overlapping and adjacent ranges so that
+    /// equivalent headers yield the same spans. Unsatisfiable ranges are
+    /// dropped.
+    pub fn coalesce(ranges: &[ByteRange], size: u64) -> Vec<(u64, u64)> {
+        let mut resolved: Vec<(u64, u64)> = ranges
+            .iter()
+            .filter_map(|range| range.resolve(size))
+            .collect();
+        resolved.sort_unstable();
+        let mut spans: Vec<(u64, u64)> = Vec::with_capacity(resolved.len());
+        for (first, last) in resolved {
+            match spans.last_mut() {
+                Some(span) if first <= span.1 + 1 => span.1 = span.1.max(last),
+                _ => spans.push((first, last)),
+            }
+        }
+        spans
+    }
+
+    /// Formats spans returned by `coalesce` as a `Range` header value.
+    pub fn header(spans: &[(u64, u64)]) -> String {
+        let specs: Vec<String> = spans
+            .iter()
+            .map(|(first, last)| format!("{}-{}", first, last))
+            .collect();
+        format!("bytes={}", specs.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            ByteRange::parse("bytes=0-9"),
+            Some(vec![ByteRange::Bounded(0, 9)])
+        );
+        assert_eq!(
+            ByteRange::parse(" bytes=10- , -5 ,3-3"),
+            Some(vec![
+                ByteRange::From(10),
+                ByteRange::Suffix(5),
+                ByteRange::Bounded(3, 3)
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_malformed() {
+        // GET answers these with 416, like unsatisfiable ranges.
+        for value in [
+            "",
+            "bytes=",
+            "bytes=-",
+            "bytes=a-9",
+            "bytes=0-b",
+            "bytes=9-0",
+            "bytes=0-9,",
+            "bytes=0-9,x",
+            "bytes=--5",
+            "bytes=0",
+            "items=0-9",
+        ] {
+            assert_eq!(ByteRange::parse(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn resolve() {
+        assert_eq!(ByteRange::Bounded(0, 9).resolve(100), Some((0, 9)));
+        // The last offset is capped at the end of the object.
+        assert_eq!(ByteRange::Bounded(90, 200).resolve(100), Some((90, 99)));
+        assert_eq!(ByteRange::From(10).resolve(100), Some((10, 99)));
+        assert_eq!(ByteRange::Suffix(5).resolve(100), Some((95, 99)));
+        // Suffixes longer than the object select all of it.
+        assert_eq!(ByteRange::Suffix(500).resolve(100), Some((0, 99)));
+    }
+
+    #[test]
+    fn resolve_unsatisfiable() {
+        // Each of these is answered with 416 Range Not Satisfiable.
+        for (range, size) in [
+            (ByteRange::Bounded(100, 200), 100),
+            (ByteRange::From(100), 100),
+            (ByteRange::From(500), 100),
+            (ByteRange::Suffix(0), 100),
+            (ByteRange::Bounded(0, 9), 0),
+            (ByteRange::From(0), 0),
+            (ByteRange::Suffix(5), 0),
+        ] {
+            assert_eq!(range.resolve(size), None, "{:?} of {}", range, size);
+        }
+    }
+
+    #[test]
+    fn coalesce() {
+        let ranges = ByteRange::parse("bytes=50-59,0-9,10-19,-5,5-7,200-").unwrap();
+        let spans = ByteRange::coalesce(&ranges, 100);
+        assert_eq!(spans, vec![(0, 19), (50, 59), (95, 99)]);
+        assert_eq!(ByteRange::header(&spans), "bytes=0-19,50-59,95-99");
+
+        // Nothing is left when no range is satisfiable.
+        let ranges = ByteRange::parse("bytes=100-,200-300").unwrap();
+        assert!(ByteRange::coalesce(&ranges, 100).is_empty());
+    }
+}
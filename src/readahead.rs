@@ -0,0 +1,37 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Number of objects whose read position is remembered before the tracker
+/// is reset.
+const MAX_TRACKED_OBJECTS: usize = 10_000;
+
+/// Detects sequential range reads of an object, such as columnar readers
+/// scanning a file with consecutive range requests.
+pub struct ReadaheadTracker {
+    positions: Mutex<HashMap<String, u64>>,
+}
+
+impl ReadaheadTracker {
+    pub fn new() -> Self {
+        ReadaheadTracker {
+            positions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a read of bytes `first..=last` of `object` and returns true if it
+    /// starts at, or at most `gap` bytes after, the end of the previous read.
+    pub fn record(&self, object: &str, first: u64, last: u64, gap: u64) -> bool {
+        let mut positions = self.positions.lock().unwrap();
+        if positions.len() >= MAX_TRACKED_OBJECTS && !positions.contains_key(object) {
+            positions.clear();
+        }
+        let previous = positions.insert(object.to_string(), last + 1);
+        matches!(previous, Some(end) if first >= end && first - end <= gap)
+    }
+}
+
+impl Default for ReadaheadTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
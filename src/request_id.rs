@@ -0,0 +1,124 @@
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use tracing::Instrument;
+
+use crate::slow_log::UpstreamTimings;
+
+/// Header that responses carry the id of their request in, as in S3.
+pub const REQUEST_ID_HEADER: &str = "x-amz-request-id";
+
+/// Header that upstream requests carry the id of the request they serve in.
+pub const UPSTREAM_REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Header with which clients agree to pay for requests to requester-pays
+/// buckets, relayed to the upstream requests made for them.
+pub const REQUEST_PAYER_HEADER: &str = "x-amz-request-payer";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+    static REQUESTER_PAYS: bool;
+    static TRACE_CONTEXT: opentelemetry::Context;
+    static UPSTREAM_TIMINGS: Arc<UpstreamTimings>;
+}
+
+/// Returns a new request id: 16 uppercase hex digits, like those of S3,
+/// derived from a per-process random seed and a counter.
+pub fn generate() -> String {
+    static SEED: OnceLock<[u8; 16]> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let seed = SEED.get_or_init(|| {
+        let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        let nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let mut seed = [0; 16];
+        seed[..8].copy_from_slice(&hasher.finish().to_le_bytes());
+        seed[8..].copy_from_slice(&(nanos as u64).to_le_bytes());
+        seed
+    });
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(seed);
+    hasher.update(&NEXT.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+    hasher.finalize().as_bytes()[..8]
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect()
+}
+
+/// Runs `future` as serving the request with id `id`.
+pub fn scope<F: Future>(id: String, future: F) -> impl Future<Output = F::Output> {
+    REQUEST_ID.scope(id, future)
+}
+
+/// Returns the id of the request being served, if any.
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(String::clone).ok()
+}
+
+/// Runs `future` as serving a request whose client sent
+/// `x-amz-request-payer: requester` if `requester_pays` is set.
+pub fn scope_requester_pays<F: Future>(
+    requester_pays: bool,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    REQUESTER_PAYS.scope(requester_pays, future)
+}
+
+/// Returns true if the client of the request being served agreed to pay
+/// for requests to requester-pays buckets.
+pub fn requester_pays() -> bool {
+    REQUESTER_PAYS.try_with(|pays| *pays).unwrap_or_default()
+}
+
+/// Runs `future` as serving a request that is part of the W3C trace of
+/// `context`, as sent by its client.
+pub fn scope_trace_context<F: Future>(
+    context: opentelemetry::Context,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    TRACE_CONTEXT.scope(context, future)
+}
+
+/// Returns the trace context that the client of the request being served
+/// sent, empty if it sent none.
+pub fn trace_context() -> opentelemetry::Context {
+    TRACE_CONTEXT.try_with(Clone::clone).unwrap_or_default()
+}
+
+/// Runs `future` as serving a request whose upstream requests are recorded
+/// in `timings`.
+pub fn scope_upstream_timings<F: Future>(
+    timings: Arc<UpstreamTimings>,
+    future: F,
+) -> impl Future<Output = F::Output> {
+    UPSTREAM_TIMINGS.scope(timings, future)
+}
+
+/// Records an upstream request made for the request being served that got
+/// its response headers after `elapsed`.
+pub fn record_upstream(elapsed: Duration) {
+    let _ = UPSTREAM_TIMINGS.try_with(|timings| timings.record(elapsed));
+}
+
+/// Carries the id of the request being served, whether its client pays for
+/// it, its trace context, the recording of its upstream requests and the
+/// current span over to `future`, for work spawned on behalf of the request.
+pub fn propagate<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let id = current();
+    let timings = UPSTREAM_TIMINGS.try_with(Arc::clone).unwrap_or_default();
+    let future = TRACE_CONTEXT.scope(trace_context(), future.in_current_span());
+    let future = REQUESTER_PAYS.scope(requester_pays(), future);
+    let future = UPSTREAM_TIMINGS.scope(timings, future);
+    async move {
+        match id {
+            Some(id) => REQUEST_ID.scope(id, future).await,
+            None => future.await,
+        }
+    }
+}
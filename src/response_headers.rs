@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use serde::Deserialize;
+
+use crate::router::wildcard_match;
+
+/// Headers of the buckets matching a pattern, overriding the headers of all
+/// responses.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct BucketHeadersFile {
+    /// Bucket name or pattern with `*` wildcards.
+    bucket: String,
+    /// Header values, `null` to not add a header of all responses.
+    headers: HashMap<String, Option<String>>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct ResponseHeadersFile {
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    buckets: Vec<BucketHeadersFile>,
+}
+
+/// Header values of a bucket, `None` for headers of all responses left out.
+type BucketHeaders = Vec<(HeaderName, Option<HeaderValue>)>;
+
+/// Headers that the operator adds to responses, e.g. Strict-Transport-Security
+/// or a Cache-Control for downstream CDNs.
+#[derive(Debug, Default)]
+pub struct ResponseHeaders {
+    /// Headers of all responses.
+    headers: Vec<(HeaderName, HeaderValue)>,
+    /// Bucket patterns and their headers, the first matching one applies.
+    buckets: Vec<(String, BucketHeaders)>,
+}
+
+/// Parses the name and value of a header.
+fn parse(name: &str, value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let name = HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header {:?}", name))?;
+    let value = HeaderValue::from_str(value)
+        .map_err(|_| format!("invalid value of header {}: {:?}", name, value))?;
+    Ok((name, value))
+}
+
+impl ResponseHeaders {
+    /// Reads a JSON object with the `headers` of all responses and the
+    /// `buckets` overriding them.
+    pub fn from_file(path: &Path) -> Result<ResponseHeaders, String> {
+        let invalid = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+        let text = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+        let file: ResponseHeadersFile = serde_json::from_str(&text).map_err(|e| invalid(&e))?;
+        let headers = file
+            .headers
+            .iter()
+            .map(|(name, value)| parse(name, value))
+            .collect::<Result<_, String>>()
+            .map_err(|e| invalid(&e))?;
+        let buckets = file
+            .buckets
+            .into_iter()
+            .map(|bucket| {
+                let headers = bucket
+                    .headers
+                    .iter()
+                    .map(|(name, value)| match value {
+                        Some(value) => parse(name, value).map(|(name, value)| (name, Some(value))),
+                        None => parse(name, "").map(|(name, _)| (name, None)),
+                    })
+                    .collect::<Result<_, String>>()?;
+                Ok((bucket.bucket, headers))
+            })
+            .collect::<Result<_, String>>()
+            .map_err(|e| invalid(&e))?;
+        Ok(ResponseHeaders { headers, buckets })
+    }
+
+    /// Adds the headers of a response for `bucket`, if any, replacing those
+    /// of the same name.
+    pub fn apply(&self, bucket: Option<&str>, headers: &mut HeaderMap) {
+        let overrides = bucket.and_then(|bucket| {
+            self.buckets
+                .iter()
+                .find(|(pattern, _)| wildcard_match(pattern, bucket))
+                .map(|(_, headers)| headers.as_slice())
+        });
+        let overrides = overrides.unwrap_or_default();
+        for (name, value) in &self.headers {
+            if !overrides.iter().any(|(other, _)| other == name) {
+                headers.insert(name, value.clone());
+            }
+        }
+        for (name, value) in overrides {
+            if let Some(value) = value {
+                headers.insert(name, value.clone());
+            }
+        }
+    }
+}
@@ -1,13 +1,161 @@
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use hyper::{header::HeaderValue, Body, Method, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
 use serde::Deserialize;
-use serde_urlencoded;
 
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 
-use crate::credentials::Credentials;
+use crate::access_log::{AccessLog, AccessLogEntry};
+use crate::admin;
+use crate::audit_log::{AuditEntry, AuditLog};
+use crate::compression;
+use crate::cors;
+use crate::credentials::CredentialsError;
+use crate::error;
+use crate::forwarded::{self, TrustedProxy};
+use crate::health;
+use crate::hooks::{AuthInfo, RequestInfo};
+use crate::metrics::{self, Operation};
+use crate::request_id::{self, REQUEST_ID_HEADER, REQUEST_PAYER_HEADER};
+use crate::response_headers::ResponseHeaders;
 use crate::s3_handler::S3Handler;
+use crate::slow_log::{self, SlowLogEntry, UpstreamTimings};
+use crate::telemetry;
+use crate::timeout;
+
+#[derive(clap::Args, Clone)]
+pub struct RouterConfig {
+    /// Bearer token required to access the admin API; the admin API is disabled if unset
+    #[arg(long, env)]
+    pub admin_token: Option<String>,
+    /// Buckets the proxy serves, as names or patterns with `*` wildcards; all buckets are served if empty
+    #[arg(long, env, value_delimiter = ',')]
+    pub allowed_buckets: Vec<String>,
+    /// Buckets the proxy refuses to serve, as names or patterns with `*` wildcards; takes precedence over --allowed-buckets
+    #[arg(long, env, value_delimiter = ',')]
+    pub denied_buckets: Vec<String>,
+    /// Buckets, as names or patterns with `*` wildcards, that clients may read without a token
+    #[arg(long, env, value_delimiter = ',')]
+    pub public_buckets: Vec<String>,
+    /// Maximum number of S3 requests served at once; further requests get 503 SlowDown
+    #[arg(long, env, value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_in_flight_requests: Option<u32>,
+    /// Seconds after which S3 requests still waiting for response headers get 504; unlimited if unset
+    #[arg(long, env, value_parser = clap::value_parser!(u64).range(1..))]
+    pub request_timeout: Option<u64>,
+    /// Seconds after which response bodies that got no data, e.g. from a hung upstream, are aborted (0 disables)
+    #[arg(long, default_value = "60", env)]
+    pub stream_idle_timeout: u64,
+    /// Milliseconds after which requests, including their response bodies, are logged as slow with a breakdown of their timing; disabled if unset
+    #[arg(long, env)]
+    pub slow_request_threshold: Option<u64>,
+    /// Operational endpoints that S3 listeners serve besides the admin listeners of --admin-listen
+    #[arg(long, value_enum, default_value = "all", env)]
+    pub s3_listener_endpoints: S3ListenerEndpoints,
+    /// Proxies whose Forwarded and X-Forwarded-For headers are trusted to name the client, as CIDRs, addresses or `unix` for Unix socket clients
+    #[arg(long, env, value_delimiter = ',')]
+    pub trusted_proxies: Vec<TrustedProxy>,
+    /// Prefix prepended to the keys of requests upstream and stripped from listings, giving each tenant a namespace of its own; {{organization_rid}}, {{user_id}} and {{username}} are replaced with attributes of the token's user
+    #[arg(long, env)]
+    pub key_prefix: Option<String>,
+    /// Reject all S3 requests but GET, HEAD and OPTIONS with 405 MethodNotAllowed
+    #[arg(long, env)]
+    pub read_only: bool,
+    /// Origins, or patterns with `*` wildcards, whose browser scripts may call the proxy; CORS is disabled if empty
+    #[arg(long, env, value_delimiter = ',')]
+    pub cors_allowed_origins: Vec<String>,
+    /// JSON file of headers added to all responses, e.g. Strict-Transport-Security, with overrides for some buckets
+    #[arg(long, env)]
+    pub response_headers_file: Option<PathBuf>,
+    /// The headers of --response-headers-file, set by
+    /// [`RouterConfig::load_response_headers`].
+    #[arg(skip)]
+    pub response_headers: Arc<ResponseHeaders>,
+}
+
+impl RouterConfig {
+    /// Loads the headers of --response-headers-file, if set.
+    pub fn load_response_headers(&mut self) -> Result<(), String> {
+        if let Some(path) = &self.response_headers_file {
+            let headers = ResponseHeaders::from_file(path)
+                .map_err(|e| format!("failed to load response headers: {}", e))?;
+            self.response_headers = Arc::new(headers);
+        }
+        Ok(())
+    }
+
+    /// Returns true if requests for `bucket` may be served.
+    pub fn bucket_allowed(&self, bucket: &str) -> bool {
+        let matches = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| wildcard_match(pattern, bucket))
+        };
+        (self.allowed_buckets.is_empty() || matches(&self.allowed_buckets))
+            && !matches(&self.denied_buckets)
+    }
+
+    /// Returns true if `method` requests for `bucket` may be served without a
+    /// token.
+    pub fn anonymous_allowed(&self, method: &Method, bucket: &str) -> bool {
+        matches!(*method, Method::GET | Method::HEAD)
+            && self
+                .public_buckets
+                .iter()
+                .any(|pattern| wildcard_match(pattern, bucket))
+    }
+}
+
+/// Matches `name` against a pattern in which `*` stands for any run of
+/// characters.
+pub fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard: the pattern must match exactly.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+impl std::fmt::Debug for RouterConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RouterConfig")
+            .field(
+                "admin_token",
+                &self.admin_token.as_ref().map(|_| "<redacted>"),
+            )
+            .field("allowed_buckets", &self.allowed_buckets)
+            .field("denied_buckets", &self.denied_buckets)
+            .field("public_buckets", &self.public_buckets)
+            .field("max_in_flight_requests", &self.max_in_flight_requests)
+            .field("request_timeout", &self.request_timeout)
+            .field("stream_idle_timeout", &self.stream_idle_timeout)
+            .field("slow_request_threshold", &self.slow_request_threshold)
+            .field("s3_listener_endpoints", &self.s3_listener_endpoints)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("key_prefix", &self.key_prefix)
+            .field("read_only", &self.read_only)
+            .field("cors_allowed_origins", &self.cors_allowed_origins)
+            .field("response_headers_file", &self.response_headers_file)
+            .finish()
+    }
+}
 
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
@@ -19,91 +167,581 @@ struct SearchParameters {
     max_keys: Option<i32>,
 }
 
-#[instrument(skip_all, fields(http.method = req.method().to_string(), http.path = req.uri().path_and_query().unwrap().to_string()))]
+/// Returns true if `bucket` is a valid bucket name, including legacy names
+/// with capitals and underscores. An empty name is left to the handlers.
+fn valid_bucket_name(bucket: &str) -> bool {
+    bucket.len() <= 255
+        && bucket != "."
+        && bucket != ".."
+        && bucket
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'.' | b'-' | b'_'))
+}
+
+/// Returns true if the decoded key or prefix has `.` or `..` segments.
+/// Upstream URLs resolve dot segments, so such a key could point outside of
+/// the prefix it was authorized for. Empty segments, as in `a//b`, are valid
+/// keys and left alone.
+fn has_unsafe_segments(path: &str) -> bool {
+    path.split('/').any(|segment| matches!(segment, "." | ".."))
+}
+
+/// Reports a failure to authenticate a request or get its credentials as the
+/// matching S3 error, so that SDKs can tell whether to retry.
+fn credentials_error_response(e: &CredentialsError, resource: &str) -> Response<Body> {
+    let (status, code, message) = e.s3_error();
+    info!(code, "Rejected request: {}", e);
+    error::response(status, code, message, resource)
+}
+
+/// Which requests a listener serves. Health probes and metrics are served
+/// on all listeners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerScope {
+    /// S3 requests and the admin API.
+    All,
+    /// S3 requests, when the admin API has listeners of its own.
+    S3,
+    /// The admin API only.
+    Admin,
+}
+
+/// Operational endpoints that S3 listeners serve when the admin API has
+/// listeners of its own.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum S3ListenerEndpoints {
+    /// Health probes and metrics
+    All,
+    /// Health probes only, e.g. for load balancers probing the data port
+    Health,
+    /// Neither, so that they are only reachable on admin listeners
+    None,
+}
+
+/// What is known about a request for its log line, filled in while it is
+/// routed.
+#[derive(Default)]
+struct RequestLog {
+    /// Whether to look up the user info of the token if it isn't cached.
+    fetch_user: bool,
+    user: Option<String>,
+    username: Option<String>,
+    organization: Option<String>,
+    bucket: Option<String>,
+    key: Option<String>,
+}
+
+#[instrument(skip_all, fields(method = %req.method(), path = req.uri().path(), request_id = tracing::field::Empty, trace_id = tracing::field::Empty, client = tracing::field::Empty, user = tracing::field::Empty, organization = tracing::field::Empty, bucket = tracing::field::Empty, key = tracing::field::Empty, cache = tracing::field::Empty))]
 pub async fn route_request(
+    mut req: Request<Body>,
+    remote_addr: Option<SocketAddr>,
+    scope: ListenerScope,
+    s3: Arc<S3Handler>,
+    config: Arc<RouterConfig>,
+    access_log: Option<Arc<AccessLog>>,
+    audit_log: Option<Arc<AuditLog>>,
+) -> Result<Response<Body>, hyper::Error> {
+    let trace_context = telemetry::continue_trace(req.headers());
+    let request_id = request_id::generate();
+    tracing::Span::current().record("request_id", request_id.as_str());
+    let hooked = s3.hooks().on_request(&mut req);
+    let client_ip = forwarded::client_ip(req.headers(), remote_addr, &config.trusted_proxies);
+    if let Some(ip) = client_ip {
+        tracing::Span::current().record("client", tracing::field::display(ip));
+    }
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let version = req.version();
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let (referer, user_agent) = (header("referer"), header("user-agent"));
+    let accept_encoding = header("accept-encoding");
+    let origin = header("origin");
+    let range = header("range");
+    let requester_pays = header(REQUEST_PAYER_HEADER).as_deref() == Some("requester");
+    let operation = Operation::of(&method, req.uri());
+    let time = chrono::Utc::now();
+    let start = std::time::Instant::now();
+    // Requests for the endpoints of the proxy itself are never turned away,
+    // so that it can still be inspected when it is saturated, nor audited.
+    let internal = metrics::is_internal(&path);
+    let audit_log = audit_log.filter(|_| !internal);
+    let mut log = RequestLog {
+        fetch_user: access_log.is_some() || audit_log.is_some() || !s3.hooks().is_empty(),
+        ..Default::default()
+    };
+    let in_flight = match config.max_in_flight_requests {
+        Some(limit) if !internal => s3.metrics().try_start_request(limit),
+        _ => Some(s3.metrics().start_request()),
+    };
+    let request_timeout = config
+        .request_timeout
+        .filter(|_| !internal)
+        .map(Duration::from_secs);
+    let stream_idle_timeout = config.stream_idle_timeout;
+    let slow_request_threshold = config.slow_request_threshold.map(Duration::from_millis);
+    let upstream_timings = Arc::new(UpstreamTimings::default());
+    let response_headers = config.response_headers.clone();
+    let cors_allowed_origins = config.cors_allowed_origins.clone();
+    // Error responses of the proxy carry the request id in their body too.
+    let routed = async {
+        let res = match (hooked, &in_flight) {
+            (Some(res), _) => res,
+            (None, Some(_)) => {
+                let routed = request_id::scope_requester_pays(
+                    requester_pays,
+                    route(req, scope, s3.clone(), config, &mut log),
+                );
+                match request_timeout {
+                    Some(timeout) => match tokio::time::timeout(timeout, routed).await {
+                        Ok(res) => res?,
+                        Err(_) => {
+                            warn!("No response within {:?}, giving up", timeout);
+                            error::response(
+                                StatusCode::GATEWAY_TIMEOUT,
+                                "GatewayTimeout",
+                                "The request timed out waiting for the upstream.",
+                                &path,
+                            )
+                        }
+                    },
+                    None => routed.await?,
+                }
+            }
+            (None, None) => {
+                info!("Too many requests in flight, rejecting request");
+                error::response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "SlowDown",
+                    "Please reduce your request rate.",
+                    &path,
+                )
+            }
+        };
+        Ok::<_, hyper::Error>(res)
+    };
+    let routed = request_id::scope_trace_context(trace_context, routed);
+    let routed = request_id::scope_upstream_timings(upstream_timings.clone(), routed);
+    let mut res = request_id::scope(request_id.clone(), routed).await?;
+    s3.hooks().on_response(
+        &RequestInfo {
+            request_id: &request_id,
+            method: &method,
+            path: &path,
+            client_ip,
+            user: log.user.as_deref(),
+            bucket: log.bucket.as_deref(),
+            key: log.key.as_deref(),
+        },
+        &mut res,
+    );
+    response_headers.apply(log.bucket.as_deref(), res.headers_mut());
+    cors::apply(&cors_allowed_origins, origin.as_deref(), res.headers_mut());
+    res = compression::compress(res, operation, accept_encoding.as_deref()).await?;
+    if stream_idle_timeout > 0 {
+        res = timeout::idle_timeout(res, Duration::from_secs(stream_idle_timeout));
+    }
+    res.headers_mut().insert(
+        REQUEST_ID_HEADER,
+        HeaderValue::from_str(&request_id).unwrap(),
+    );
+    // Responses to HEAD requests have no body.
+    let content_length = match method {
+        Method::HEAD => 0,
+        _ => res
+            .headers()
+            .get("content-length")
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .unwrap_or_default(),
+    };
+    let elapsed = start.elapsed();
+    s3.metrics()
+        .record_request(&method, operation, res.status(), elapsed);
+    res = s3.metrics().wrap(operation, start, res);
+    res = telemetry::record_cache_status(res);
+    if let Some(threshold) = slow_request_threshold {
+        let entry = SlowLogEntry {
+            method: method.clone(),
+            path: path.clone(),
+            status: res.status(),
+            bucket: log.bucket.clone(),
+            key: log.key.clone(),
+            turnaround: elapsed,
+        };
+        res = slow_log::wrap(entry, threshold, start, upstream_timings, res);
+    }
+    info!(
+        method = %method,
+        path,
+        status = res.status().as_u16(),
+        user = log.user,
+        bucket = log.bucket,
+        key = log.key,
+        took_ms = elapsed.as_micros() as f64 / 1000.0,
+        bytes = content_length,
+        "Request completed"
+    );
+    if let Some(audit_log) = audit_log {
+        let entry = AuditEntry {
+            request_id: request_id.clone(),
+            time,
+            client_ip,
+            method: method.clone(),
+            operation,
+            status: res.status(),
+            user: log.user.clone(),
+            username: log.username.clone(),
+            organization: log.organization.clone(),
+            bucket: log.bucket.clone(),
+            key: log.key.clone(),
+            range,
+        };
+        res = audit_log.wrap(entry, res);
+    }
+    let Some(access_log) = access_log else {
+        return Ok(res);
+    };
+    let entry = AccessLogEntry {
+        request_id,
+        time,
+        client_ip,
+        method,
+        path,
+        version,
+        referer,
+        user_agent,
+        operation,
+        status: res.status(),
+        user: log.user,
+        organization: log.organization,
+        bucket: log.bucket,
+        key: log.key,
+        turnaround: elapsed,
+    };
+    Ok(access_log.wrap(entry, start, res))
+}
+
+async fn route(
     req: Request<Body>,
+    scope: ListenerScope,
     s3: Arc<S3Handler>,
+    config: Arc<RouterConfig>,
+    log: &mut RequestLog,
 ) -> Result<Response<Body>, hyper::Error> {
-    let query = match serde_urlencoded::from_str::<SearchParameters>(
-        req.uri().query().or(Some("")).unwrap(),
-    ) {
+    let (parts, body) = req.into_parts();
+    let not_found = || {
+        error::response(
+            StatusCode::NOT_FOUND,
+            "NotFound",
+            "Not found.",
+            parts.uri.path(),
+        )
+    };
+    if parts.uri.path().starts_with(admin::ADMIN_PREFIX) {
+        if scope == ListenerScope::S3 {
+            return Ok(not_found());
+        }
+        return admin::route_admin(&parts, &s3, config.admin_token.as_deref()).await;
+    }
+    // Endpoints that S3 listeners don't serve are handled as S3 requests for
+    // a bucket of the same name.
+    let (health, metrics) = match (scope, config.s3_listener_endpoints) {
+        (ListenerScope::S3, S3ListenerEndpoints::None) => (false, false),
+        (ListenerScope::S3, S3ListenerEndpoints::Health) => (true, false),
+        _ => (true, true),
+    };
+    if health {
+        if let Some(res) = health::route_health(&parts, &s3).await {
+            return Ok(res);
+        }
+    }
+    if metrics {
+        if let Some(res) = metrics::route_metrics(&parts, &s3) {
+            return Ok(res);
+        }
+    }
+    if scope == ListenerScope::Admin {
+        return Ok(not_found());
+    }
+    if config.read_only && !matches!(parts.method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        info!(method = %parts.method, "Rejected request to read-only proxy");
+        let mut res = error::response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "MethodNotAllowed",
+            "The proxy is read-only",
+            parts.uri.path(),
+        );
+        res.headers_mut().insert(
+            hyper::header::ALLOW,
+            HeaderValue::from_static(cors::allowed_methods(true)),
+        );
+        return Ok(res);
+    }
+    // Preflight requests carry no credentials, and neither do most probes.
+    if parts.method == Method::OPTIONS {
+        return Ok(cors::preflight(
+            &config.cors_allowed_origins,
+            config.read_only,
+            &parts.headers,
+        ));
+    }
+    // The authentication parameters of presigned URLs are handled separately.
+    let search: Vec<&str> = parts
+        .uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.starts_with("X-Amz-"))
+        .collect();
+    let query = match serde_urlencoded::from_str::<SearchParameters>(&search.join("&")) {
         Ok(q) => q,
         Err(e) => {
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::from(format!("Failed to parse query string: {}", e)))
-                .unwrap());
-        }
-    };
-    let parts: Vec<&str> = req.uri().path().splitn(3, '/').collect();
-    let bucket = parts[1];
-    let key = req
-        .uri()
-        .path()
-        .get(bucket.len() + 2..)
-        .or(Some(""))
-        .unwrap();
-
-    // measure the time it takes to handle the request
-    let start = std::time::Instant::now();
+            return Ok(error::response(
+                StatusCode::BAD_REQUEST,
+                "InvalidArgument",
+                &format!("Failed to parse query string: {}", e),
+                parts.uri.path(),
+            ));
+        }
+    };
+    // Keys are decoded here and encoded again for upstream URLs, so that the
+    // cache, policies and logs see the actual keys.
+    let segments: Vec<&str> = parts.uri.path().splitn(3, '/').collect();
+    let decode = |segment: &str| {
+        percent_decode_str(segment)
+            .decode_utf8()
+            .map(Cow::into_owned)
+    };
+    let (bucket, key) = match (
+        decode(segments[1]),
+        decode(segments.get(2).copied().unwrap_or_default()),
+    ) {
+        (Ok(bucket), Ok(key)) => (bucket, key),
+        _ => {
+            return Ok(error::response(
+                StatusCode::BAD_REQUEST,
+                "InvalidURI",
+                "Couldn't parse the specified URI.",
+                parts.uri.path(),
+            ));
+        }
+    };
+    let (bucket, key) = (bucket.as_str(), key.as_str());
+    log.bucket = Some(bucket.to_string()).filter(|bucket| !bucket.is_empty());
+    log.key = Some(key.to_string()).filter(|key| !key.is_empty());
+    let span = tracing::Span::current();
+    span.record("bucket", log.bucket.as_deref());
+    span.record("key", log.key.as_deref());
 
-    let token = match Credentials::token_from_headers(req.headers()) {
-        Ok(t) => t,
-        Err(e) => {
-            return Ok(Response::builder()
-                .status(StatusCode::BAD_REQUEST)
-                .body(Body::from(format!("{}", e)))
-                .unwrap());
+    // Upstream URLs and local paths are built from the decoded names, so
+    // anything that could move them to another bucket or key is rejected
+    // before the allow list is checked.
+    if !valid_bucket_name(bucket) {
+        return Ok(error::response(
+            StatusCode::BAD_REQUEST,
+            "InvalidBucketName",
+            "The specified bucket is not valid.",
+            parts.uri.path(),
+        ));
+    }
+    let prefix = query.prefix.as_deref().unwrap_or_default();
+    if has_unsafe_segments(key) || has_unsafe_segments(prefix) {
+        return Ok(error::response(
+            StatusCode::BAD_REQUEST,
+            "InvalidArgument",
+            "Keys and prefixes with . or .. segments are not supported",
+            parts.uri.path(),
+        ));
+    }
+    // Only ListObjectsV2 is supported. Any other list type would otherwise be
+    // served as an object request after being authorized on the prefix.
+    if query.list_type.is_some_and(|list_type| list_type != 2) {
+        return Ok(error::response(
+            StatusCode::BAD_REQUEST,
+            "InvalidArgument",
+            "Only list-type=2 is supported",
+            parts.uri.path(),
+        ));
+    }
+    let listing = parts.method == Method::GET && query.list_type == Some(2);
+    if !config.bucket_allowed(bucket) {
+        info!(bucket, "Denied access to bucket");
+        return Ok(error::response(
+            StatusCode::FORBIDDEN,
+            "AccessDenied",
+            "Access Denied",
+            parts.uri.path(),
+        ));
+    }
+
+    let (token, anonymous) = match s3.authenticate(&parts) {
+        Ok(t) => (t, false),
+        Err(CredentialsError::TokenMissing())
+            if config.anonymous_allowed(&parts.method, bucket) =>
+        {
+            (None, true)
         }
+        Err(e) => return Ok(credentials_error_response(&e, parts.uri.path())),
     };
 
-    let credentials = match s3.get_credentials(&token).await {
+    let token = token.as_deref();
+    let credentials = match anonymous {
+        true => Ok(s3.get_anonymous_credentials()),
+        false => s3.get_credentials(token).await,
+    };
+    let credentials = match credentials {
         Ok(c) => c,
-        Err(_) => {
-            return Ok(Response::builder()
-                .status(StatusCode::UNAUTHORIZED)
-                .body(Body::from("Unauthorized\n"))
-                .unwrap());
+        Err(e) => return Ok(credentials_error_response(&e, parts.uri.path())),
+    };
+
+    // Anonymous requests share the cache of public buckets.
+    let tenant = match anonymous {
+        true => Ok(None),
+        false => s3.get_tenant(token).await,
+    };
+    let tenant = match tenant {
+        Ok(t) => t,
+        Err(e) => return Ok(credentials_error_response(&e, parts.uri.path())),
+    };
+    let tenant = tenant.as_deref();
+
+    let path = match listing {
+        true => prefix,
+        false => key,
+    };
+    let authorized = s3.authorize(token, &parts.method, bucket, path).await;
+    let identity = match token {
+        Some(token) => s3.identify(token, log.fetch_user).await,
+        None => None,
+    };
+    if let Some(identity) = identity {
+        span.record("user", identity.user.as_str());
+        span.record("organization", identity.organization.as_deref());
+        log.user = Some(identity.user);
+        log.username = identity.username;
+        log.organization = identity.organization;
+    }
+    match authorized {
+        Ok(true) => {}
+        Ok(false) => {
+            info!(bucket, path, "Denied access by policy");
+            return Ok(error::response(
+                StatusCode::FORBIDDEN,
+                "AccessDenied",
+                "Access Denied",
+                parts.uri.path(),
+            ));
         }
+        Err(e) => return Ok(credentials_error_response(&e, parts.uri.path())),
+    }
+    let auth = AuthInfo {
+        method: &parts.method,
+        bucket,
+        path,
+        anonymous,
+        user: log.user.as_deref(),
+        organization: log.organization.as_deref(),
     };
+    if !s3.hooks().on_auth(&auth) {
+        info!(bucket, path, "Denied access by hook");
+        return Ok(error::response(
+            StatusCode::FORBIDDEN,
+            "AccessDenied",
+            "Access Denied",
+            parts.uri.path(),
+        ));
+    }
 
-    let res = match (req.method(), req.uri().path(), query.list_type) {
-        (&Method::GET, _, Some(2)) => {
-            let prefix = query.prefix.unwrap_or_default();
+    let key_prefix = match &config.key_prefix {
+        Some(template) => match s3.get_key_prefix(template, token).await {
+            Ok(Some(prefix)) => prefix,
+            Ok(None) => {
+                info!(bucket, path, "Denied access without key prefix");
+                return Ok(error::response(
+                    StatusCode::FORBIDDEN,
+                    "AccessDenied",
+                    "Access Denied",
+                    parts.uri.path(),
+                ));
+            }
+            Err(e) => return Ok(credentials_error_response(&e, parts.uri.path())),
+        },
+        None => String::new(),
+    };
+    let key = &format!("{}{}", key_prefix, key);
+
+    let res = match (&parts.method, parts.uri.path(), listing) {
+        (&Method::GET, _, true) => {
             s3.list_objects(
                 &credentials,
+                tenant,
                 bucket,
-                &prefix,
+                &key_prefix,
+                path,
                 query.continuation_token,
                 query.start_after,
                 query.max_keys,
             )
             .await
         }
+        (&Method::GET | &Method::HEAD, _, _) if S3Handler::uses_sse_c(&parts.headers) => {
+            s3.get_sse_c_object(&credentials, &parts.method, bucket, key, &parts.headers)
+                .await
+        }
         (&Method::GET, _, _) => {
-            let range: Option<&HeaderValue> = req.headers().get("range");
-            s3.get_object(&credentials, bucket, key, range).await
-        }
-        (&Method::HEAD, _, _) => s3.head_object(&credentials, bucket, key).await,
-        // Handle other routes and methods accordingly.
-        _ => Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(Body::from("Not found.\n"))
-            .unwrap()),
-    };
-    let cl_zero = &HeaderValue::from_static("0");
-    let cl = res
-        .as_ref()
-        .unwrap()
-        .headers()
-        .get("content-length")
-        .unwrap_or(cl_zero);
-    let elapsed = start.elapsed();
-    info!(
-        status = res.as_ref().unwrap().status().as_u16(),
-        took_ms = elapsed.as_micros() as f64 / 1000.0,
-        content_length = cl.to_str().unwrap(),
-    );
-
+            let range: Option<&HeaderValue> = parts.headers.get("range");
+            s3.get_object(&credentials, tenant, bucket, key, range)
+                .await
+        }
+        (&Method::HEAD, _, _) => s3.head_object(&credentials, tenant, bucket, key).await,
+        (&Method::PUT, _, _) => {
+            let payload_check = s3.payload_check(&parts);
+            s3.put_object(
+                &credentials,
+                tenant,
+                bucket,
+                key,
+                &parts.headers,
+                body,
+                payload_check,
+            )
+            .await
+        }
+        (&Method::DELETE, _, _) => s3.delete_object(&credentials, bucket, key).await,
+        _ => Ok(error::from_status(
+            StatusCode::NOT_IMPLEMENTED,
+            parts.uri.path(),
+        )),
+    };
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_names() {
+        for bucket in ["", "b", "my-bucket", "my.bucket", "Legacy_Bucket"] {
+            assert!(valid_bucket_name(bucket), "{bucket}");
+        }
+        for bucket in [".", "..", "a/b", "a/../b", "a?b", "a%2Fb", "a b"] {
+            assert!(!valid_bucket_name(bucket), "{bucket}");
+        }
+        assert!(!valid_bucket_name(&"a".repeat(256)));
+    }
+
+    #[test]
+    fn unsafe_segments() {
+        for path in ["", "key", "a/b/c", "folder/", "a.b/..c/d..", "a//b", "/a"] {
+            assert!(!has_unsafe_segments(path), "{path}");
+        }
+        for path in [".", "..", "a/../b", "orgA/../orgB/", "./a", "a/."] {
+            assert!(has_unsafe_segments(path), "{path}");
+        }
+    }
+}
@@ -1,281 +1,2431 @@
-use futures_util::TryFutureExt;
+use bytes::{Bytes, BytesMut};
+use futures_util::stream::{self, BoxStream};
+use futures_util::{StreamExt, TryFutureExt, TryStreamExt};
+use hyper::header::HeaderMap;
 use hyper::{http, StatusCode};
 use hyper::{Body, Response};
-use sha2::{Digest, Sha256};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::RwLock;
-use std::time::{SystemTime, Duration};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-use tokio::try_join;
-use tokio_util::io::ReaderStream;
-use tracing::{info, instrument};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::join;
+use tracing::{debug, info, instrument, warn};
 
-use crate::credentials::{CredentialsError, CredentialsManager};
-use crate::xml_writer::ListBucketResult;
+use crate::aws_chunked;
+use crate::backend::{BackendKind, Backends, Payload};
+use crate::body_channel;
+use crate::cache::{
+    BlockUsage, CacheEntry, CacheMetadata, CacheStats, DiskCache, DiskUsage, FillSlot, UploadStage,
+};
+use crate::cache_io::CacheFile;
+use crate::credentials::{CredentialsError, CredentialsManager, CredentialsStats, UserIdentity};
+use crate::error;
+use crate::hooks::{CacheFillInfo, Hooks};
+use crate::key_prefix;
+use crate::metrics::{CacheEvents, Metrics};
+use crate::range::ByteRange;
+use crate::readahead::ReadaheadTracker;
+use crate::request_id::{self, REQUEST_PAYER_HEADER, UPSTREAM_REQUEST_ID_HEADER};
+use crate::sigv4::{self, PayloadCheck};
+use crate::size_cache::SizeCache;
+use crate::telemetry;
+use crate::timeout::IdleTimeout;
+use crate::upstream::{Upstream, Upstreams};
+use crate::xml_writer::{ListBucketResult, ListingRewrite};
+
+/// How long the result of a readiness check is reused, so that frequent
+/// probes don't load the upstream.
+const READINESS_TTL: Duration = Duration::from_secs(5);
+
+/// Time the upstream has to answer the request of a readiness check.
+const READINESS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Number of upstream requests made to fill a cache block when the response
+/// stream is interrupted.
+const FILL_ATTEMPTS: u32 = 3;
+
+/// Uploads up to this many bytes are buffered, so that they can be signed
+/// with their hash; larger ones are streamed through.
+const MAX_BUFFERED_UPLOAD: usize = 16 * 1024 * 1024;
+
+/// Client request headers that are relayed upstream on PutObject.
+const PUT_FORWARDED_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-disposition",
+    "content-encoding",
+    "content-language",
+    "content-md5",
+    "content-type",
+    "expires",
+    "x-amz-storage-class",
+    "x-amz-tagging",
+];
+
+/// HTTP versions that upstream connections may use.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamHttpVersion {
+    /// HTTP/1.1 only
+    Http1,
+    /// HTTP/2 if the endpoint offers it over TLS (ALPN), otherwise HTTP/1.1
+    Auto,
+    /// HTTP/2 only, also over plain HTTP (prior knowledge)
+    Http2,
+}
+
+/// Prefix of the headers with which clients send the keys of objects
+/// encrypted with SSE-C, and with which the upstream confirms them.
+const SSE_C_HEADER_PREFIX: &str = "x-amz-server-side-encryption-customer-";
+
+/// Client request headers relayed upstream with GETs and HEADs of SSE-C
+/// encrypted objects.
+const SSE_C_FORWARDED_HEADERS: &[&str] = &[
+    "range",
+    "if-match",
+    "if-modified-since",
+    "if-none-match",
+    "if-unmodified-since",
+];
+
+/// Headers of standard object metadata that `standard` relays besides those
+/// starting with `x-amz-`.
+const STANDARD_RESPONSE_HEADERS: &[&str] = &[
+    "cache-control",
+    "content-disposition",
+    "content-encoding",
+    "content-language",
+    "expires",
+];
+
+/// Upstream response headers never relayed: those recorded separately, those
+/// the proxy sets itself and hop-by-hop headers.
+const UNRELAYED_RESPONSE_HEADERS: &[&str] = &[
+    "accept-ranges",
+    "connection",
+    "content-length",
+    "content-md5",
+    "content-range",
+    "content-type",
+    "date",
+    "etag",
+    "keep-alive",
+    "last-modified",
+    "proxy-authenticate",
+    "server",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "x-amz-id-2",
+    "x-amz-request-id",
+    UPSTREAM_REQUEST_ID_HEADER,
+];
+
+/// Upstream response headers relayed by GetObject and HeadObject.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderPassthrough {
+    /// Content-Type, ETag and Last-Modified only, and the SSE-C headers of encrypted objects
+    Minimal,
+    /// Also standard object metadata (Cache-Control, Content-Disposition, Content-Encoding, Content-Language, Expires) and x-amz-* headers like user metadata and the version id
+    Standard,
+    /// All headers but hop-by-hop headers and those the proxy sets itself
+    All,
+}
+
+impl HeaderPassthrough {
+    /// Returns the headers of `headers` to relay besides Content-Type, ETag
+    /// and Last-Modified.
+    fn select(self, headers: &HeaderMap) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .filter(|(name, _)| {
+                let name = name.as_str();
+                if UNRELAYED_RESPONSE_HEADERS.contains(&name) {
+                    return false;
+                }
+                match self {
+                    HeaderPassthrough::Minimal => name.starts_with(SSE_C_HEADER_PREFIX),
+                    HeaderPassthrough::Standard => {
+                        STANDARD_RESPONSE_HEADERS.contains(&name) || name.starts_with("x-amz-")
+                    }
+                    HeaderPassthrough::All => true,
+                }
+            })
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect()
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct UpstreamConfig {
+    /// Stream all uploads upstream signed with UNSIGNED-PAYLOAD instead of buffering them to hash the payload; uploads are then not written to the cache
+    #[arg(long, env)]
+    pub unsigned_payload: bool,
+    /// HTTP versions used for upstream connections
+    #[arg(long, value_enum, default_value = "auto", env)]
+    pub upstream_http_version: UpstreamHttpVersion,
+    /// Upstream response headers that GetObject and HeadObject relay to clients
+    #[arg(long, value_enum, default_value = "standard", env)]
+    pub response_header_passthrough: HeaderPassthrough,
+    /// Maximum number of idle upstream connections kept open; unlimited if unset
+    #[arg(long, env)]
+    pub upstream_pool_max_idle: Option<usize>,
+    /// Seconds after which idle upstream connections are closed (0 keeps them open)
+    #[arg(long, default_value = "90", env)]
+    pub upstream_pool_idle_timeout: u64,
+    /// Interval in seconds of TCP keepalive probes on upstream connections (0 disables them)
+    #[arg(long, default_value = "60", env)]
+    pub upstream_tcp_keepalive: u64,
+    /// Send `x-amz-request-payer: requester` with all upstream requests, so that requester-pays buckets can be read; otherwise it is only sent for clients that send it
+    #[arg(long, env)]
+    pub requester_pays: bool,
+    /// Seconds to wait for upstream connections to be established (0 waits as long as the OS does)
+    #[arg(long, default_value = "10", env)]
+    pub upstream_connect_timeout: u64,
+    /// Seconds to wait for the response headers of upstream requests without a body, which then get 504 (0 waits forever)
+    #[arg(long, default_value = "30", env)]
+    pub upstream_response_timeout: u64,
+    /// Seconds after which upstream response bodies that got no data are given up on; interrupted block fills are resumed (0 waits forever)
+    #[arg(long, default_value = "30", env)]
+    pub upstream_body_idle_timeout: u64,
+    /// Consecutive failed requests to an upstream after which its requests fail fast for a cooldown (0 disables the circuit breaker)
+    #[arg(long, default_value = "10", env)]
+    pub upstream_breaker_threshold: u32,
+    /// Seconds that upstream requests fail fast for once the circuit breaker opened
+    #[arg(long, default_value = "30", env)]
+    pub upstream_breaker_cooldown: u64,
+    /// Storage service at --endpoint: `s3`, `gcs`, or `fs:<dir>` to serve a local directory instead of an endpoint
+    #[arg(long, default_value = "s3", env)]
+    pub backend: BackendKind,
+    /// Region that requests to --endpoint are signed for
+    #[arg(long, default_value = "foundry", env)]
+    pub upstream_region: String,
+    /// Service account key file that requests to GCS are authorized with; the service account of the instance is used if unset
+    #[arg(long, env)]
+    pub gcs_service_account_file: Option<PathBuf>,
+    /// JSON file of routes sending the requests for some buckets or key prefixes to other upstreams, each with its own region and keys
+    #[arg(long, env)]
+    pub upstream_routes_file: Option<PathBuf>,
+    /// Initial HTTP/2 flow control window in bytes of each stream of upstream connections [default: 2 MiB]
+    #[arg(long, env)]
+    pub upstream_http2_stream_window_size: Option<u32>,
+    /// Initial HTTP/2 flow control window in bytes of each upstream connection [default: 5 MiB]
+    #[arg(long, env)]
+    pub upstream_http2_connection_window_size: Option<u32>,
+    /// Bytes read at a time from cached blocks and from the files of the fs backend when they are sent
+    #[arg(long, default_value = "262144", env, value_parser = clap::value_parser!(u64).range(1..))]
+    pub read_buffer_size: u64,
+    /// Chunks of a response body queued while the client connection is busy sending earlier ones
+    #[arg(long, default_value = "1", env, value_parser = clap::value_parser!(u64).range(1..))]
+    pub response_channel_capacity: u64,
+}
+
+/// Returns `secs` as a duration, or `None` for 0.
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+impl UpstreamConfig {
+    /// Sets up the upstream at `endpoint` and those of the routes file, with
+    /// their backends.
+    pub fn upstreams(&self, endpoint: &str) -> Result<Upstreams, String> {
+        let mut backends = Backends::new(
+            self.backend.clone(),
+            &self.upstream_region,
+            self.gcs_service_account_file.clone(),
+            self.read_buffer_size as usize,
+        );
+        Upstreams::new(
+            endpoint,
+            &mut backends,
+            self.upstream_routes_file.as_deref(),
+            self.upstream_breaker_threshold,
+            Duration::from_secs(self.upstream_breaker_cooldown),
+        )
+        .map_err(|e| format!("failed to set up upstreams: {}", e))
+    }
+
+    /// Builds the client that upstream requests are made with.
+    fn client(&self) -> reqwest::Client {
+        let mut builder = reqwest::Client::builder()
+            .pool_idle_timeout(seconds(self.upstream_pool_idle_timeout))
+            .tcp_keepalive(seconds(self.upstream_tcp_keepalive))
+            .http2_initial_stream_window_size(self.upstream_http2_stream_window_size)
+            .http2_initial_connection_window_size(self.upstream_http2_connection_window_size);
+        if let Some(timeout) = seconds(self.upstream_connect_timeout) {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(max_idle) = self.upstream_pool_max_idle {
+            builder = builder.pool_max_idle_per_host(max_idle);
+        }
+        builder = match self.upstream_http_version {
+            UpstreamHttpVersion::Http1 => builder.http1_only(),
+            UpstreamHttpVersion::Auto => builder,
+            UpstreamHttpVersion::Http2 => builder.http2_prior_knowledge(),
+        };
+        builder.build().unwrap()
+    }
+}
+
+/// Result of a readiness check: whether the upstream is reachable and the
+/// cache writable, with the error of each failed check.
+#[derive(Serialize, Debug, Clone)]
+pub struct Readiness {
+    pub ready: bool,
+    pub upstream: String,
+    pub cache: String,
+}
+
+/// Size and metadata of an object, needed before a response can be
+/// assembled from cache blocks.
+#[derive(Clone)]
+struct ObjectInfo {
+    size: u64,
+    metadata: CacheMetadata,
+    /// How long past their max age cached blocks of the object are served,
+    /// while the object is revalidated in the background.
+    max_staleness: Duration,
+}
+
+/// The payload of an upload, kept to write it to the cache once the upstream
+/// accepted it.
+enum UploadCopy {
+    Buffered(Bytes),
+    Staged(UploadStage),
+}
+
+impl UploadCopy {
+    fn len(&self) -> u64 {
+        match self {
+            UploadCopy::Buffered(body) => body.len() as u64,
+            UploadCopy::Staged(stage) => stage.written(),
+        }
+    }
+
+    async fn read(&mut self, offset: u64, len: u64) -> std::io::Result<Bytes> {
+        match self {
+            UploadCopy::Buffered(body) => Ok(body.slice(offset as usize..(offset + len) as usize)),
+            UploadCopy::Staged(stage) => stage.read(offset, len as usize).await,
+        }
+    }
+}
+
+/// Sends the payload of a streamed upload, starting with the already read
+/// `head`, to the body of the upstream request as it arrives, while staging
+/// a copy for the cache if given one. Returns the length of the payload and
+/// the stage, which is dropped if it can't be written or the upstream stops
+/// reading. Fails, aborting the upstream request, if the payload can't be
+/// read or decoded.
+async fn pump_upload(
+    head: Bytes,
+    payload: BoxStream<'static, std::io::Result<Bytes>>,
+    mut sender: hyper::body::Sender,
+    mut stage: Option<UploadStage>,
+) -> std::io::Result<(u64, Option<UploadStage>)> {
+    let head = (!head.is_empty()).then_some(Ok(head));
+    let mut payload = stream::iter(head).chain(payload);
+    let mut length = 0;
+    // Each chunk is only sent once the next one arrived, so that the upstream
+    // never gets the whole payload if it fails a check at its end.
+    let mut held = None;
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                sender.abort();
+                return Err(e);
+            }
+        };
+        length += chunk.len() as u64;
+        if let Some(staged) = &mut stage {
+            if let Err(e) = staged.write(&chunk).await {
+                warn!("Failed to stage upload for the cache: {}", e);
+                stage = None;
+            }
+        }
+        let Some(chunk) = held.replace(chunk) else {
+            continue;
+        };
+        // The upstream may respond before it read the whole body, e.g. to
+        // reject the request.
+        if sender.send_data(chunk).await.is_err() {
+            return Ok((length, None));
+        }
+    }
+    if let Some(chunk) = held {
+        if sender.send_data(chunk).await.is_err() {
+            return Ok((length, None));
+        }
+    }
+    Ok((length, stage))
+}
 
 pub struct S3Handler {
-    // config: Builder,
+    config: UpstreamConfig,
     credentials: CredentialsManager,
-    size_cache: RwLock<std::collections::HashMap<String, i64>>,
+    size_cache: SizeCache,
+    cache: Arc<DiskCache>,
+    readahead: ReadaheadTracker,
+    /// Cache names of the first blocks of objects being revalidated.
+    revalidating: Mutex<HashSet<String>>,
     http_client: reqwest::Client,
-    endpoint: String,
+    upstreams: Upstreams,
+    started: Instant,
+    /// The last readiness check and when it was made.
+    readiness: tokio::sync::Mutex<Option<(Readiness, Instant)>>,
+    metrics: Arc<Metrics>,
+    hooks: Hooks,
 }
 
 impl S3Handler {
-    pub fn new(endpoint: &str) -> Self {
-        let client = reqwest::Client::builder().http1_only().tcp_keepalive(Some(Duration::from_secs(60))).build().unwrap();
-
-        let size_cache = std::collections::HashMap::new();
+    pub fn new(
+        upstreams: Upstreams,
+        config: UpstreamConfig,
+        credentials: CredentialsManager,
+        cache: DiskCache,
+        hooks: Hooks,
+    ) -> Self {
+        let client = config.client();
+        let size_cache = SizeCache::new(cache.size_cache_capacity(), cache.size_cache_max_age());
         S3Handler {
-            // config: s3config,
-            size_cache: RwLock::new(size_cache),
-            credentials: CredentialsManager::new(&endpoint),
+            config,
+            size_cache,
+            cache: Arc::new(cache),
+            readahead: ReadaheadTracker::new(),
+            revalidating: Mutex::new(HashSet::new()),
+            credentials,
             http_client: client,
-            endpoint: endpoint.to_string(),
+            upstreams,
+            started: Instant::now(),
+            readiness: tokio::sync::Mutex::new(None),
+            metrics: Arc::default(),
+            hooks,
         }
     }
 
-    pub(crate) fn handle_sdk_error(e: reqwest::Error) -> Result<Response<Body>, hyper::Error> {
-        Ok(Response::builder()
-            .status(e.status().unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
-            .body(Body::from(""))
-            .unwrap())
+    /// Returns the token a request authenticates with, if any.
+    pub fn authenticate(
+        &self,
+        parts: &http::request::Parts,
+    ) -> Result<Option<String>, CredentialsError> {
+        self.credentials.authenticate(parts)
+    }
+
+    /// Returns how the payload of an authenticated request is checked
+    /// against its signature, if the proxy verified one.
+    pub fn payload_check(&self, parts: &http::request::Parts) -> Option<PayloadCheck> {
+        self.credentials.payload_check(parts)
     }
 
     pub async fn get_credentials(
         &self,
-        token: &str,
+        token: Option<&str>,
     ) -> Result<aws_credential_types::Credentials, CredentialsError> {
-        let credentials = self.credentials.get_credentials(&token).await?;
+        let credentials = self.credentials.get_credentials(token).await?;
         Ok(aws_credential_types::Credentials::new(
             credentials.access_key_id,
             credentials.secret_access_key,
-            Some(credentials.session_token),
+            credentials.session_token,
             None,
             "PLTR",
         ))
     }
 
+    /// Returns the credentials of anonymous requests for public buckets.
+    /// Requests with an empty access key id are sent unsigned.
+    pub fn get_anonymous_credentials(&self) -> aws_credential_types::Credentials {
+        match self.credentials.get_anonymous_credentials() {
+            Some(credentials) => aws_credential_types::Credentials::new(
+                credentials.access_key_id,
+                credentials.secret_access_key,
+                credentials.session_token,
+                None,
+                "PLTR",
+            ),
+            None => aws_credential_types::Credentials::new("", "", None, None, "anonymous"),
+        }
+    }
+
+    /// Returns the organization RID the cache is scoped to for `token`, or
+    /// `None` without tenant isolation. Users without an organization are
+    /// scoped to their own id.
+    pub async fn get_tenant(
+        &self,
+        token: Option<&str>,
+    ) -> Result<Option<String>, CredentialsError> {
+        if !self.cache.tenant_isolation() {
+            return Ok(None);
+        }
+        let token = token.ok_or(CredentialsError::TokenMissing())?;
+        // API keys have no user; each is a tenant of its own.
+        if let Some(name) = self.credentials.api_key_name(token) {
+            return Ok(Some(format!("api-key:{}", name)));
+        }
+        let user_info = self.credentials.get_user_info(token).await?;
+        let tenant = user_info.organization_rid().unwrap_or(&user_info.id);
+        Ok(Some(tenant.to_string()))
+    }
+
+    /// Returns the prefix that the --key-prefix `template` gives the keys of
+    /// the client presenting `token`, or `None` if the template needs an
+    /// attribute that the client lacks.
+    pub async fn get_key_prefix(
+        &self,
+        template: &str,
+        token: Option<&str>,
+    ) -> Result<Option<String>, CredentialsError> {
+        let user_info = match token {
+            Some(token)
+                if key_prefix::is_templated(template)
+                    && self.credentials.api_key_name(token).is_none() =>
+            {
+                Some(self.credentials.get_user_info(token).await?)
+            }
+            _ => None,
+        };
+        Ok(key_prefix::render(template, user_info.as_ref()))
+    }
+
+    /// Returns true if the client presenting `token` may access `path`, the
+    /// key or listed prefix of a request, under the access policy.
+    pub async fn authorize(
+        &self,
+        token: Option<&str>,
+        method: &http::Method,
+        bucket: &str,
+        path: &str,
+    ) -> Result<bool, CredentialsError> {
+        self.credentials
+            .authorize(token, method, bucket, path)
+            .await
+    }
+
+    /// Returns the user presenting `token` and their organization, if known,
+    /// for logging.
+    pub async fn identify(&self, token: &str, fetch: bool) -> Option<UserIdentity> {
+        self.credentials.identify(token, fetch).await
+    }
+
     async fn request(
         &self,
+        upstream: &Upstream,
         method: reqwest::Method,
         credentials: &aws_credential_types::Credentials,
         uri: &str,
         headers: Option<Vec<(&str, &str)>>,
+        payload: Payload,
     ) -> Result<reqwest::Response, reqwest::Error> {
-        use aws_sigv4::http_request::{SignableBody, SignableRequest, SigningSettings};
-        use aws_sigv4::sign::v4;
         use http::{HeaderName, HeaderValue};
 
-        let signing_settings = SigningSettings::default();
-        let creds = credentials.clone().into();
-
-        let signer = v4::SigningParams::builder()
-            .identity(&creds)
-            .region("foundry")
-            .name("s3")
-            .settings(signing_settings)
-            .time(SystemTime::now())
-            .build()
-            .unwrap();
-        let signable_request = SignableRequest::new(
-            method.as_str(),
-            uri,
-            headers.clone().unwrap_or_default().into_iter(),
-            SignableBody::Bytes(&[]),
-        )
-        .expect("signable request");
-        let signed =
-            aws_sigv4::http_request::sign(signable_request, &signer.into()).expect("sign request");
-        let (x, _) = signed.into_parts();
-        let (signed_headers, _) = x.into_parts();
-        let mut request = reqwest::Request::new(method, reqwest::Url::parse(uri).unwrap());
+        let mut request = reqwest::Request::new(method.clone(), reqwest::Url::parse(uri).unwrap());
         let request_headers = request.headers_mut();
-        for header in headers.clone().unwrap_or_default().into_iter() {
+        for header in headers.unwrap_or_default() {
             request_headers.insert(
                 HeaderName::from_str(header.0).unwrap(),
                 HeaderValue::from_str(header.1).unwrap(),
             );
         }
-        for header in signed_headers.into_iter() {
-            request_headers.insert(
-                header.name(),
-                HeaderValue::from_str(header.value()).unwrap(),
+        // Added before signing, as S3 requires the header to be signed.
+        if self.config.requester_pays || request_id::requester_pays() {
+            request_headers.insert(REQUEST_PAYER_HEADER, HeaderValue::from_static("requester"));
+        }
+        // Upstreams with keys of their own are always sent requests signed
+        // with them.
+        let credentials = upstream.credentials().unwrap_or(credentials);
+        let authorized = upstream
+            .backend()
+            .authorize(&mut request, credentials, &payload)
+            .await;
+        if let Err(e) = authorized {
+            warn!("Failed to authorize upstream request: {}", e);
+            return Ok(error::response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailable",
+                "The upstream request could not be authorized; retry later.",
+                "",
+            )
+            .into());
+        }
+        self.execute(upstream, request, payload).await
+    }
+
+    /// Sends an upstream request, tagged with the id of the request it serves.
+    ///
+    /// Requests without a body that get no response headers within the
+    /// response timeout get a 504 response instead. Requests with a body are
+    /// exempt, as the upstream only answers once it has received the body.
+    ///
+    /// While the circuit breaker of the upstream is open, requests get a 503
+    /// response without reaching it, so that stale cache entries can be
+    /// served.
+    async fn execute(
+        &self,
+        upstream: &Upstream,
+        mut request: reqwest::Request,
+        payload: Payload,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        if let Some(id) = request_id::current() {
+            request.headers_mut().insert(
+                UPSTREAM_REQUEST_ID_HEADER,
+                http::HeaderValue::from_str(&id).unwrap(),
             );
         }
-        self.http_client.execute(request).await
+        telemetry::inject_trace(request.headers_mut());
+        let breaker = upstream.circuit_breaker();
+        if !breaker.allow() {
+            return Ok(error::response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "ServiceUnavailable",
+                "The upstream is failing; retry later.",
+                "",
+            )
+            .into());
+        }
+        let timeout = seconds(self.config.upstream_response_timeout)
+            .filter(|_| matches!(payload, Payload::Empty));
+        let sent = Instant::now();
+        let send = upstream.backend().send(&self.http_client, request, payload);
+        let res = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, send).await {
+                Ok(res) => res,
+                Err(_) => {
+                    warn!("Upstream sent no response headers in {:?}", timeout);
+                    request_id::record_upstream(sent.elapsed());
+                    self.metrics.record_upstream_timeout();
+                    breaker.record(false);
+                    return Ok(error::response(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        "GatewayTimeout",
+                        "The upstream did not respond in time.",
+                        "",
+                    )
+                    .into());
+                }
+            },
+            None => send.await,
+        };
+        request_id::record_upstream(sent.elapsed());
+        self.metrics.record_upstream(upstream.endpoint(), &res);
+        breaker.record(matches!(&res, Ok(res) if !res.status().is_server_error()));
+        res
     }
 
-    #[instrument(skip(self, credentials))]
-    pub async fn head_object(
+    /// Returns the body of an upstream response, which fails once it got no
+    /// data for the body idle timeout.
+    fn body_stream(
+        &self,
+        resp: reqwest::Response,
+    ) -> IdleTimeout<impl futures_util::Stream<Item = reqwest::Result<Bytes>>> {
+        IdleTimeout::new(
+            resp.bytes_stream(),
+            seconds(self.config.upstream_body_idle_timeout),
+        )
+    }
+
+    /// Returns size and metadata of an object from the first cached block.
+    async fn cached_object_info(
+        &self,
+        tenant: Option<&str>,
+        bucket: &str,
+        key: &str,
+    ) -> Option<ObjectInfo> {
+        let (_, metadata) = self
+            .cache
+            .head(&DiskCache::block_filename(tenant, bucket, key, 0))
+            .await?;
+        Some(ObjectInfo {
+            size: metadata.object_size?,
+            metadata,
+            max_staleness: Duration::ZERO,
+        })
+    }
+
+    /// Like `cached_object_info`, but also uses a first block that expired
+    /// less than `grace` ago.
+    async fn stale_object_info(
         &self,
+        tenant: Option<&str>,
+        bucket: &str,
+        key: &str,
+        grace: Option<Duration>,
+    ) -> Option<ObjectInfo> {
+        let (_, metadata) = self
+            .cache
+            .head_stale(&DiskCache::block_filename(tenant, bucket, key, 0), grace?)
+            .await?;
+        Some(ObjectInfo {
+            size: metadata.object_size?,
+            metadata,
+            max_staleness: Duration::ZERO,
+        })
+    }
+
+    /// Returns size and metadata of an object whose first cached block expired
+    /// within the stale-while-revalidate window, and revalidates the object in
+    /// the background.
+    async fn revalidating_object_info(
+        self: &Arc<Self>,
         credentials: &aws_credential_types::Credentials,
+        tenant: Option<&str>,
         bucket: &str,
         key: &str,
-    ) -> Result<Response<Body>, hyper::Error> {
+    ) -> Option<ObjectInfo> {
+        let grace = self.cache.stale_while_revalidate();
+        let mut info = self.stale_object_info(tenant, bucket, key, grace).await?;
+        info.max_staleness = grace?;
+
+        let first_block = DiskCache::block_filename(tenant, bucket, key, 0);
+        if !self
+            .revalidating
+            .lock()
+            .unwrap()
+            .insert(first_block.clone())
         {
-            let size_cache = self.size_cache.read().unwrap();
-            match size_cache.get(key) {
-                Some(size) => {
-                    return Ok(Response::builder()
-                        .status(200)
-                        .header("content-length", size.to_string())
-                        .body(Body::from(""))
-                        .unwrap())
-                }
-                None => {}
+            return Some(info);
+        }
+        let handler = self.clone();
+        let credentials = credentials.clone();
+        let tenant = tenant.map(str::to_string);
+        let (bucket, key) = (bucket.to_string(), key.to_string());
+        let stale = info.clone();
+        tokio::spawn(request_id::propagate(async move {
+            if let Err(e) = handler
+                .revalidate_object(&credentials, tenant.as_deref(), &bucket, &key, &stale)
+                .await
+            {
+                warn!(bucket, key, "Failed to revalidate object: {}", e);
+            }
+            handler.revalidating.lock().unwrap().remove(&first_block);
+        }));
+        Some(info)
+    }
+
+    /// Checks an expired cached object against upstream. The cached blocks of
+    /// an unchanged object are kept for another max age, those of a changed
+    /// object are refetched.
+    async fn revalidate_object(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        tenant: Option<&str>,
+        bucket: &str,
+        key: &str,
+        stale: &ObjectInfo,
+    ) -> std::io::Result<()> {
+        let info = self
+            .fetch_object_info(credentials, tenant, bucket, key)
+            .await
+            .map_err(|resp| {
+                std::io::Error::other(format!("upstream returned {}", resp.status()))
+            })?;
+        let block_size = self.cache.block_size();
+        let unchanged = info.size == stale.size
+            && info.metadata.etag.is_some()
+            && info.metadata.etag == stale.metadata.etag;
+        if unchanged {
+            debug!(bucket, key, "Revalidated unchanged object");
+            for index in 0..stale.size.div_ceil(block_size).max(1) {
+                self.cache
+                    .touch(&DiskCache::block_filename(tenant, bucket, key, index))
+                    .await?;
             }
+            return Ok(());
         }
-        let uri = format!("{}{}/{}", self.endpoint, bucket, key,);
-        let resp = self
-            .request(reqwest::Method::HEAD, credentials, &uri, None)
-            .await;
-        match resp {
-            Ok(obj) => {
-                info!("Got object: {:?}", obj.headers());
-                let cl = obj
-                    .headers()
-                    .get("content-length")
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .parse::<i64>()
-                    .unwrap();
-                self.size_cache.write().unwrap().insert(key.to_string(), cl);
-                Ok(Response::builder()
-                    .status(200)
-                    .header("content-length", cl)
-                    .body(Body::from(""))
-                    .unwrap())
+
+        debug!(bucket, key, "Refetching changed object");
+        for index in 0..info.size.div_ceil(block_size) {
+            let fname = DiskCache::block_filename(tenant, bucket, key, index);
+            if self
+                .cache
+                .head_stale(&fname, stale.max_staleness)
+                .await
+                .is_none()
+            {
+                continue;
             }
-            Err(e) => S3Handler::handle_sdk_error(e),
+            let block_start = index * block_size;
+            let block_end = (block_start + block_size).min(info.size);
+            self.stream_block(
+                credentials,
+                bucket,
+                key,
+                &info,
+                index,
+                block_start..block_end,
+                0..0,
+                None,
+                None,
+            )
+            .await?;
         }
+        Ok(())
     }
 
-    fn hash_filename(bucket: &str, key: &str, range: &str) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(format!("{}/{}/{}", bucket, key, range));
-        let result = hasher.finalize();
-        format!("{:x}", result)
+    /// Fetches size and metadata of an object with a HEAD request. Failures are
+    /// returned as the response to relay to the client.
+    async fn fetch_object_info(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        tenant: Option<&str>,
+        bucket: &str,
+        key: &str,
+    ) -> Result<ObjectInfo, Response<Body>> {
+        let upstream = self.upstreams.route(bucket, key);
+        let uri = upstream.object_url(bucket, key);
+        let obj = self
+            .request(
+                upstream,
+                reqwest::Method::HEAD,
+                credentials,
+                &uri,
+                None,
+                Payload::Empty,
+            )
+            .await
+            .map_err(|e| error::upstream_failure(&e, &format!("/{}/{}", bucket, key)))?;
+        // Responses to HEAD requests have no body to relay an error from.
+        if !obj.status().is_success() {
+            return Err(error::from_status(
+                obj.status(),
+                &format!("/{}/{}", bucket, key),
+            ));
+        }
+        debug!("Got object: {:?}", obj.headers());
+        let Some(cl) = obj
+            .headers()
+            .get("content-length")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+        else {
+            warn!(
+                bucket,
+                key, "Upstream HEAD response without a valid Content-Length"
+            );
+            return Err(error::from_status(
+                StatusCode::BAD_GATEWAY,
+                &format!("/{}/{}", bucket, key),
+            ));
+        };
+        let metadata = CacheMetadata {
+            tenant: tenant.map(str::to_string),
+            headers: self
+                .config
+                .response_header_passthrough
+                .select(obj.headers()),
+            ..CacheMetadata::from_headers(obj.headers())
+        };
+        let headers = metadata.response_headers();
+        self.size_cache
+            .insert(tenant, bucket, key, cl as i64, Some(headers));
+        Ok(ObjectInfo {
+            size: cl,
+            metadata,
+            max_staleness: Duration::ZERO,
+        })
     }
 
-    #[instrument(skip(self, credentials))]
-    pub async fn get_object(
+    /// Revalidates a cached object with a GET of its first block that is
+    /// conditional on the cached ETag. Unchanged objects are served from the
+    /// cache; for changed and uncached ones, the first block is cached from
+    /// the response. Failures are returned as the response to relay to the
+    /// client.
+    async fn conditional_object_info(
         &self,
         credentials: &aws_credential_types::Credentials,
+        tenant: Option<&str>,
         bucket: &str,
         key: &str,
-        range: Option<&http::HeaderValue>,
-    ) -> Result<Response<Body>, hyper::Error> {
-        let fname = S3Handler::hash_filename(
-            bucket,
-            key,
-            range.map(|r| r.to_str().unwrap()).unwrap_or_default(),
-        );
+    ) -> Result<ObjectInfo, Response<Body>> {
+        let cached = self.cached_object_info(tenant, bucket, key).await;
+        let etag = cached.as_ref().and_then(|info| info.metadata.etag.clone());
+        let block_size = self.cache.block_size();
+        let range = format!("bytes=0-{}", block_size - 1);
+        let mut headers = vec![("range", range.as_str())];
+        if let Some(etag) = etag.as_deref() {
+            headers.push(("if-none-match", etag));
+        }
+        let upstream = self.upstreams.route(bucket, key);
+        let uri = upstream.object_url(bucket, key);
+        let resp = self
+            .request(
+                upstream,
+                reqwest::Method::GET,
+                credentials,
+                &uri,
+                Some(headers),
+                Payload::Empty,
+            )
+            .await
+            .map_err(|e| error::upstream_failure(&e, &format!("/{}/{}", bucket, key)))?;
+        match (resp.status(), cached) {
+            (StatusCode::NOT_MODIFIED, Some(info)) if etag.is_some() => {
+                debug!(bucket, key, "Revalidated unchanged object");
+                return Ok(info);
+            }
+            // Empty objects have no first byte to return.
+            (StatusCode::RANGE_NOT_SATISFIABLE, _) => {
+                return self
+                    .fetch_object_info(credentials, tenant, bucket, key)
+                    .await;
+            }
+            (status, _) if !status.is_success() => {
+                return Err(error::from_status(status, &format!("/{}/{}", bucket, key)));
+            }
+            _ => {}
+        }
 
-        if let Ok(f) = tokio::fs::metadata(format!("data/{}", fname)).await {
-            let file = File::open(format!("data/{}", fname)).await.unwrap();
-            let stream = ReaderStream::with_capacity(file, 16_384);
-            let body = Body::wrap_stream(stream);
-            return Ok(Response::builder()
-                .status(200)
-                .header("content-length", f.len())
-                .body(body)
+        // The size is that of the whole object, which upstreams that ignore
+        // the range return in full.
+        let size = match resp.status() {
+            StatusCode::PARTIAL_CONTENT => resp
+                .headers()
+                .get("content-range")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.rsplit_once('/'))
+                .and_then(|(_, size)| size.parse::<u64>().ok()),
+            _ => resp.content_length(),
+        };
+        let Some(size) = size else {
+            warn!(bucket, key, "Upstream GET response without a valid size");
+            return Err(error::from_status(
+                StatusCode::BAD_GATEWAY,
+                &format!("/{}/{}", bucket, key),
+            ));
+        };
+        let metadata = CacheMetadata {
+            tenant: tenant.map(str::to_string),
+            headers: self
+                .config
+                .response_header_passthrough
+                .select(resp.headers()),
+            ..CacheMetadata::from_headers(resp.headers())
+        };
+        let headers = metadata.response_headers();
+        self.size_cache
+            .insert(tenant, bucket, key, size as i64, Some(headers));
+        let info = ObjectInfo {
+            size,
+            metadata,
+            max_staleness: Duration::ZERO,
+        };
+
+        // Blocks without an ETag couldn't be told apart from those of a later
+        // version.
+        if !self.cache.is_cacheable(size) || info.metadata.etag.is_none() {
+            return Ok(info);
+        }
+        let block_len = block_size.min(size);
+        let block = match resp.bytes().await {
+            Ok(block) if block.len() as u64 >= block_len => block.slice(..block_len as usize),
+            Ok(_) => {
+                warn!(bucket, key, "Upstream returned a short first block");
+                return Ok(info);
+            }
+            Err(e) => {
+                warn!(bucket, key, "Failed to read first block: {}", e);
+                return Ok(info);
+            }
+        };
+        debug!(bucket, key, "Caching first block of changed object");
+        let name = DiskCache::block_filename(tenant, bucket, key, 0);
+        let metadata = CacheMetadata {
+            bucket: Some(bucket.to_string()),
+            key: Some(key.to_string()),
+            object_size: Some(size),
+            ..info.metadata.clone()
+        };
+        match self.cache.insert(&name, metadata, &block).await {
+            Ok(true) => {
+                self.hooks.on_cache_fill(&CacheFillInfo {
+                    tenant,
+                    bucket,
+                    key,
+                    index: 0,
+                    bytes: block_len,
+                });
+                if let Some(tenant) = tenant {
+                    self.cache.enforce_quota(tenant);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => warn!(bucket, key, "Failed to cache first block: {}", e),
+        }
+        Ok(info)
+    }
+
+    #[instrument(skip(self, credentials), fields(request_id = request_id::current()))]
+    pub async fn head_object(
+        self: &Arc<Self>,
+        credentials: &aws_credential_types::Credentials,
+        tenant: Option<&str>,
+        bucket: &str,
+        key: &str,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let cached = match self.cached_object_info(tenant, bucket, key).await {
+            Some(info) => Some(info),
+            None => {
+                self.revalidating_object_info(credentials, tenant, bucket, key)
+                    .await
+            }
+        };
+        if let Some(info) = cached {
+            return Ok(info
+                .metadata
+                .apply(Response::builder().status(200))
+                .header("content-length", info.size)
+                .body(Body::from(""))
+                .unwrap());
+        }
+        // Sizes learned from listings and uploads lack the headers of the
+        // object, which only `minimal` does without.
+        let with_headers = self.config.response_header_passthrough != HeaderPassthrough::Minimal;
+        if let Some((size, headers)) = self.size_cache.get(tenant, bucket, key, with_headers) {
+            let mut builder = Response::builder().status(200);
+            for (name, value) in headers.into_iter().flatten() {
+                builder = builder.header(name, value);
+            }
+            return Ok(builder
+                .header("content-length", size.to_string())
+                .body(Body::from(""))
                 .unwrap());
         }
+        match self
+            .fetch_object_info(credentials, tenant, bucket, key)
+            .await
+        {
+            Ok(info) => Ok(info
+                .metadata
+                .apply(Response::builder().status(200))
+                .header("content-length", info.size)
+                .body(Body::from(""))
+                .unwrap()),
+            Err(resp) => Ok(resp),
+        }
+    }
 
-        let (sender, body) = hyper::Body::channel();
+    /// Removes disk cache and size cache entries matching a bucket and key prefix.
+    pub async fn purge_cache(&self, bucket: Option<&str>, prefix: &str) -> std::io::Result<usize> {
+        self.size_cache
+            .remove(|b, key| bucket.is_none_or(|bucket| bucket == b) && key.starts_with(prefix));
+        self.cache.purge(bucket, prefix).await
+    }
 
-        let uri = format!("{}{}/{}", self.endpoint, bucket, key,);
-        let headers = range.map(|r| vec![("range", r.to_str().unwrap())]);
-        let resp = match self
-            .request(reqwest::Method::GET, credentials, &uri, headers)
+    pub async fn cache_stats(&self) -> std::io::Result<CacheStats> {
+        self.cache.stats().await
+    }
+
+    /// Time since the handler was created at startup.
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Checks that the upstream answers and the cache is writable, reusing
+    /// the result of a check made within `READINESS_TTL`. Any response but a
+    /// server error counts as the upstream being reachable.
+    pub async fn readiness(&self) -> Readiness {
+        // Holding the lock while checking makes concurrent probes wait for a
+        // single check.
+        let mut last = self.readiness.lock().await;
+        if let Some((readiness, checked)) = &*last {
+            if checked.elapsed() < READINESS_TTL {
+                return readiness.clone();
+            }
+        }
+        let primary = self.upstreams.primary();
+        let mut request = reqwest::Request::new(
+            reqwest::Method::HEAD,
+            reqwest::Url::parse(primary.endpoint()).unwrap(),
+        );
+        *request.timeout_mut() = Some(READINESS_TIMEOUT);
+        let upstream = match primary
+            .backend()
+            .send(&self.http_client, request, Payload::Empty)
             .await
         {
-            Ok(resp) => resp,
-            Err(e) => return S3Handler::handle_sdk_error(e),
+            Ok(res) if res.status().is_server_error() => format!("status {}", res.status()),
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let cache = match self.cache.check_writable().await {
+            Ok(()) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let readiness = Readiness {
+            ready: upstream == "ok" && cache == "ok",
+            upstream,
+            cache,
         };
+        if !readiness.ready {
+            warn!(?readiness, "Readiness check failed");
+        }
+        *last = Some((readiness.clone(), Instant::now()));
+        readiness
+    }
 
-        use futures_util::StreamExt;
-        let cl = resp
-            .headers()
-            .get("content-length")
-            .unwrap()
-            .to_str()
-            .unwrap()
-            .to_string();
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    pub fn upstreams(&self) -> &Upstreams {
+        &self.upstreams
+    }
+
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
 
-        let mut obj_body = resp.bytes_stream();
+    /// Returns the numbers of disk cache hits and misses since startup.
+    pub fn cache_hit_counts(&self) -> (u64, u64) {
+        self.cache.hit_counts()
+    }
+
+    /// Returns the entries, files and bytes of the disk cache.
+    pub fn cache_usage(&self) -> DiskUsage {
+        self.cache.usage()
+    }
+
+    /// Returns the events by bucket of the disk cache and the size cache.
+    pub fn cache_events(&self) -> [(&'static str, &CacheEvents); 2] {
+        [
+            ("disk", self.cache.events()),
+            ("size", self.size_cache.events()),
+        ]
+    }
+
+    pub fn credentials_stats(&self) -> CredentialsStats {
+        self.credentials.stats()
+    }
+
+    /// Restores the size cache from its last snapshot, returning the number of
+    /// restored sizes.
+    pub async fn load_size_cache(&self) -> std::io::Result<usize> {
+        match self.cache.size_snapshot() {
+            Some((path, _)) => self.size_cache.load(&path).await,
+            None => Ok(0),
+        }
+    }
 
-        let mut file = File::create(format!("data/.{}", fname)).await.unwrap();
+    /// Periodically saves the size cache if snapshots are enabled.
+    pub fn spawn_size_cache_snapshots(self: &Arc<Self>) {
+        let Some((path, interval)) = self.cache.size_snapshot() else {
+            return;
+        };
+        let handler = self.clone();
         tokio::spawn(async move {
-            let mut sender = sender;
-            while let Some(buf) = obj_body.next().await {
-                let bytes = buf.unwrap();
-
-                try_join!(
-                    sender
-                        .send_data(bytes.clone())
-                        .map_err(|_| std::io::Error::new(
-                            std::io::ErrorKind::Other,
-                            "failed to send data"
-                        )),
-                    file.write(&bytes),
-                )
-                .unwrap();
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match handler.size_cache.save(&path).await {
+                    Ok(sizes) => debug!(sizes, "Saved size cache"),
+                    Err(e) => warn!("Failed to save size cache: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Periodically removes expired credentials from memory.
+    pub fn spawn_credentials_sweeper(self: &Arc<Self>) {
+        let interval = self.credentials.sweep_interval();
+        if interval.is_zero() {
+            return;
+        }
+        let handler = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let removed = handler.credentials.sweep();
+                debug!(removed, "Swept expired credentials");
             }
+        });
+    }
 
-            tokio::fs::rename(format!("data/.{}", fname), format!("data/{}", fname))
-                .await
-                .unwrap();
+    /// Periodically verifies all cached blocks if a verification interval is
+    /// configured.
+    pub fn spawn_cache_scrubber(self: &Arc<Self>) {
+        let Some(interval) = self.cache.verify_interval() else {
+            return;
+        };
+        let handler = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                match handler.cache.scrub().await {
+                    Ok(removed) => info!(removed, "Verified cache"),
+                    Err(e) => warn!("Failed to verify cache: {}", e),
+                }
+            }
         });
+    }
 
-        Ok(Response::builder()
-            .status(200)
-            .header("content-length", cl)
-            .body(body)
-            .unwrap())
+    /// Returns true if a request carries the key of an object encrypted with
+    /// SSE-C. Such objects are never cached, as the cache would serve them
+    /// to clients without the key.
+    pub fn uses_sse_c(headers: &HeaderMap) -> bool {
+        headers
+            .keys()
+            .any(|name| name.as_str().starts_with(SSE_C_HEADER_PREFIX))
     }
 
-    #[instrument(skip(self, credentials))]
-    pub async fn list_objects(
+    /// Serves a GET or HEAD of an object encrypted with SSE-C straight from
+    /// the upstream, relaying the key and the range and conditions of the
+    /// client.
+    #[instrument(skip(self, credentials, headers), fields(request_id = request_id::current()))]
+    pub async fn get_sse_c_object(
         &self,
         credentials: &aws_credential_types::Credentials,
+        method: &http::Method,
         bucket: &str,
-        prefix: &str,
-        continuation_token: Option<String>,
-        start_after: Option<String>,
-        max_keys: Option<i32>,
+        key: &str,
+        headers: &HeaderMap,
     ) -> Result<Response<Body>, hyper::Error> {
-        let uri = format!(
-            "{}{}?list-type=2&prefix={}&continuation-token={}&start-after={}&max-keys={}",
-            self.endpoint,
-            bucket,
-            prefix,
-            continuation_token.unwrap_or_default(),
-            start_after.unwrap_or_default(),
-            max_keys.map(|k| k.to_string()).unwrap_or_default(),
-        );
-        let resp = self
-            .request(reqwest::Method::GET, credentials, &uri, None)
-            .await;
-        if let Err(err) = resp {
-            return S3Handler::handle_sdk_error(err);
-        }
-
-        let status = resp.as_ref().unwrap().status();
-        let body = resp.unwrap().text().await.unwrap();
-
-        if status.is_success() {
-            let result = ListBucketResult::from_str(body.as_str()).unwrap();
+        let forwarded = headers
+            .iter()
+            .filter(|(name, _)| {
+                SSE_C_FORWARDED_HEADERS.contains(&name.as_str())
+                    || name.as_str().starts_with(SSE_C_HEADER_PREFIX)
+            })
+            .filter_map(|(name, value)| Some((name.as_str(), value.to_str().ok()?)))
+            .collect();
+        self.proxy_object(credentials, method.clone(), bucket, key, forwarded)
+            .await
+    }
 
-            let mut size_cache = self.size_cache.write().unwrap();
-            result.contents.unwrap_or_default().iter().for_each(|obj| {
-                size_cache.insert(obj.key.clone(), obj.size);
-            });
+    /// Streams upstream responses without caching them, for GETs of several
+    /// disjoint byte ranges, which cannot be served from cache blocks, and
+    /// of objects encrypted with SSE-C.
+    async fn proxy_object(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        method: reqwest::Method,
+        bucket: &str,
+        key: &str,
+        headers: Vec<(&str, &str)>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let upstream = self.upstreams.route(bucket, key);
+        let uri = upstream.object_url(bucket, key);
+        let resp = match self
+            .request(
+                upstream,
+                method,
+                credentials,
+                &uri,
+                Some(headers),
+                Payload::Empty,
+            )
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) => return Ok(error::upstream_failure(&e, &format!("/{}/{}", bucket, key))),
+        };
+        let metadata = CacheMetadata {
+            headers: self
+                .config
+                .response_header_passthrough
+                .select(resp.headers()),
+            ..CacheMetadata::from_headers(resp.headers())
+        };
+        let mut builder = metadata.apply(Response::builder().status(resp.status()));
+        for name in ["content-length", "content-range"] {
+            if let Some(value) = resp.headers().get(name) {
+                builder = builder.header(name, value.as_bytes());
+            }
+        }
+        Ok(builder
+            .body(Body::wrap_stream(self.body_stream(resp)))
+            .unwrap())
+    }
+
+    #[instrument(skip(self, credentials), fields(request_id = request_id::current()))]
+    pub async fn get_object(
+        self: &Arc<Self>,
+        credentials: &aws_credential_types::Credentials,
+        tenant: Option<&str>,
+        bucket: &str,
+        key: &str,
+        range: Option<&http::HeaderValue>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let cached = match self.cache.revalidate() {
+            true => None,
+            false => match self.cached_object_info(tenant, bucket, key).await {
+                Some(info) => Some(info),
+                None => {
+                    self.revalidating_object_info(credentials, tenant, bucket, key)
+                        .await
+                }
+            },
+        };
+        let fetched = match (cached, self.cache.revalidate()) {
+            (Some(info), _) => Ok(info),
+            (None, true) => {
+                self.conditional_object_info(credentials, tenant, bucket, key)
+                    .await
+            }
+            (None, false) => {
+                self.fetch_object_info(credentials, tenant, bucket, key)
+                    .await
+            }
+        };
+        let mut stale = false;
+        let info = match fetched {
+            Ok(info) => info,
+            Err(resp) if resp.status().is_server_error() => {
+                match self
+                    .stale_object_info(tenant, bucket, key, self.cache.stale_if_error())
+                    .await
+                {
+                    Some(info) => {
+                        warn!(bucket, key, status = %resp.status(), "Serving stale cached object");
+                        stale = true;
+                        info
+                    }
+                    None => return Ok(resp),
+                }
+            }
+            Err(resp) => return Ok(resp),
+        };
+
+        let (first, last, status) = match range {
+            None if info.size == 0 => {
+                return Ok(info
+                    .metadata
+                    .apply(Response::builder().status(200))
+                    .header("content-length", 0)
+                    .body(Body::from(""))
+                    .unwrap());
+            }
+            None => (0, info.size - 1, StatusCode::OK),
+            Some(value) => {
+                // Malformed ranges are rejected rather than forwarded, and
+                // equivalent ones are normalized to the same spans.
+                let ranges = value.to_str().ok().and_then(ByteRange::parse);
+                let spans = ByteRange::coalesce(&ranges.unwrap_or_default(), info.size);
+                match spans[..] {
+                    [] => {
+                        let mut res = error::from_status(
+                            StatusCode::RANGE_NOT_SATISFIABLE,
+                            &format!("/{}/{}", bucket, key),
+                        );
+                        res.headers_mut().insert(
+                            "content-range",
+                            format!("bytes */{}", info.size).parse().unwrap(),
+                        );
+                        return Ok(res);
+                    }
+                    [(first, last)] => (first, last, StatusCode::PARTIAL_CONTENT),
+                    _ => {
+                        let range = ByteRange::header(&spans);
+                        return self
+                            .proxy_object(
+                                credentials,
+                                reqwest::Method::GET,
+                                bucket,
+                                key,
+                                vec![("range", &range)],
+                            )
+                            .await;
+                    }
+                }
+            }
+        };
+
+        let mut builder = info
+            .metadata
+            .apply(Response::builder().status(status))
+            .header("accept-ranges", "bytes")
+            .header("content-length", last - first + 1);
+        if stale {
+            builder = builder.header("warning", "110 - \"Response is Stale\"");
+        }
+        if status == StatusCode::PARTIAL_CONTENT {
+            builder = builder.header(
+                "content-range",
+                format!("bytes {}-{}/{}", first, last, info.size),
+            );
+        }
+
+        let readahead = self.cache.readahead();
+        if readahead > 0
+            && status == StatusCode::PARTIAL_CONTENT
+            && self.cache.is_cacheable(info.size)
+        {
+            let object = format!("{}/{}", bucket, key);
+            if self
+                .readahead
+                .record(&object, first, last, self.cache.block_size())
+            {
+                self.prefetch_blocks(credentials, bucket, key, &info, last, readahead);
+            }
+        }
+
+        let usage = Arc::new(BlockUsage::default());
+        // Small cached objects are sent in one write, without a task
+        // streaming them.
+        if self.cache.is_small(info.size) {
+            if let Some(data) = self
+                .read_small_cached(bucket, key, &info, first, last)
+                .await
+            {
+                self.cache.record_hit(bucket);
+                usage.record(true);
+                return Ok(builder.extension(usage).body(Body::from(data)).unwrap());
+            }
+        }
+
+        let (mut sender, body) =
+            body_channel::channel(self.config.response_channel_capacity as usize);
+        let handler = self.clone();
+        let credentials = credentials.clone();
+        let (bucket, key) = (bucket.to_string(), key.to_string());
+        let block_usage = usage.clone();
+        tokio::spawn(request_id::propagate(async move {
+            if let Err(e) = handler
+                .stream_blocks(
+                    &credentials,
+                    &bucket,
+                    &key,
+                    &info,
+                    first,
+                    last,
+                    &mut sender,
+                    &block_usage,
+                )
+                .await
+            {
+                warn!(bucket, key, "Failed to stream object: {}", e);
+                sender.abort();
+            }
+        }));
+
+        Ok(builder.extension(usage).body(body).unwrap())
+    }
+
+    /// Sends bytes `first..=last` of an object, block by block. Objects outside
+    /// the cached size range are streamed straight from upstream.
+    #[allow(clippy::too_many_arguments)]
+    async fn stream_blocks(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        bucket: &str,
+        key: &str,
+        info: &ObjectInfo,
+        first: u64,
+        last: u64,
+        sender: &mut body_channel::Sender,
+        usage: &BlockUsage,
+    ) -> std::io::Result<()> {
+        if !self.cache.is_cacheable(info.size) {
+            debug!(
+                bucket,
+                key,
+                size = info.size,
+                "Streaming uncacheable object"
+            );
+            let etag = info.metadata.etag.as_deref();
+            return self
+                .stream_uncached(credentials, bucket, key, etag, first..last + 1, sender)
+                .await;
+        }
+        let block_size = self.cache.block_size();
+        for index in first / block_size..=last / block_size {
+            let block_start = index * block_size;
+            let block_end = (block_start + block_size).min(info.size);
+            let slice =
+                first.max(block_start) - block_start..(last + 1).min(block_end) - block_start;
+            self.stream_block(
+                credentials,
+                bucket,
+                key,
+                info,
+                index,
+                block_start..block_end,
+                slice,
+                Some(sender),
+                Some(usage),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Fetches the `count` blocks following `last` into the cache in the
+    /// background, one at a time.
+    fn prefetch_blocks(
+        self: &Arc<Self>,
+        credentials: &aws_credential_types::Credentials,
+        bucket: &str,
+        key: &str,
+        info: &ObjectInfo,
+        last: u64,
+        count: u64,
+    ) {
+        let block_size = self.cache.block_size();
+        let next = last / block_size + 1;
+        let blocks: Vec<u64> = (next..next + count)
+            .take_while(|index| index * block_size < info.size)
+            .collect();
+        if blocks.is_empty() {
+            return;
+        }
+        let handler = self.clone();
+        let credentials = credentials.clone();
+        let (bucket, key) = (bucket.to_string(), key.to_string());
+        let info = info.clone();
+        tokio::spawn(request_id::propagate(async move {
+            for index in blocks {
+                let block_start = index * block_size;
+                let block_end = (block_start + block_size).min(info.size);
+                debug!(bucket, key, index, "Prefetching block");
+                if let Err(e) = handler
+                    .stream_block(
+                        &credentials,
+                        &bucket,
+                        &key,
+                        &info,
+                        index,
+                        block_start..block_end,
+                        0..0,
+                        None,
+                        None,
+                    )
+                    .await
+                {
+                    warn!(bucket, key, index, "Failed to prefetch block: {}", e);
+                    break;
+                }
+            }
+        }));
+    }
+
+    /// Opens the cached block `fname` of an object. Blocks of a different
+    /// object version or with a truncated file count as missing, so that they
+    /// are refetched.
+    async fn cached_block(
+        &self,
+        fname: &str,
+        info: &ObjectInfo,
+        block_len: u64,
+    ) -> Option<CacheEntry> {
+        let expected_etag = info.metadata.etag.as_deref();
+        self.cache
+            .get_stale(fname, info.max_staleness)
+            .await
+            .filter(|entry| {
+                entry.len == block_len
+                    && (expected_etag.is_none() || entry.metadata.etag.as_deref() == expected_etag)
+            })
+    }
+
+    /// Reads bytes `first..=last` of a small object from its cached block
+    /// into memory, or returns `None` if the block isn't cached or can't be
+    /// read. Reading the whole slice at once rather than mapping the file
+    /// keeps eviction from truncating it while it is sent.
+    async fn read_small_cached(
+        &self,
+        bucket: &str,
+        key: &str,
+        info: &ObjectInfo,
+        first: u64,
+        last: u64,
+    ) -> Option<Bytes> {
+        let tenant = info.metadata.tenant.as_deref();
+        let fname = DiskCache::block_filename(tenant, bucket, key, 0);
+        let entry = self.cached_block(&fname, info, info.size).await?;
+        let read = match entry.metadata.is_compressed() {
+            true => entry
+                .decompress()
+                .await
+                .map(|data| data.slice(first as usize..last as usize + 1)),
+            false => entry.file.read_at(first, (last - first + 1) as usize).await,
+        };
+        match read {
+            Ok(data) => Some(data),
+            Err(e) => {
+                warn!(bucket, key, "Failed to read cached object: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Sends `slice` of the block covering `block` bytes of the object, from
+    /// the cache if a block with a matching ETag is present, otherwise by
+    /// fetching the whole block upstream and filling the cache. Without a
+    /// sender the block is only brought into the cache. Blocks that are sent
+    /// are counted in `usage`.
+    #[allow(clippy::too_many_arguments)]
+    async fn stream_block(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        bucket: &str,
+        key: &str,
+        info: &ObjectInfo,
+        index: u64,
+        block: std::ops::Range<u64>,
+        slice: std::ops::Range<u64>,
+        mut sender: Option<&mut body_channel::Sender>,
+        usage: Option<&BlockUsage>,
+    ) -> std::io::Result<()> {
+        let tenant = info.metadata.tenant.as_deref();
+        let fname = DiskCache::block_filename(tenant, bucket, key, index);
+        let expected_etag = info.metadata.etag.as_deref();
+
+        let guard = loop {
+            let cached = self
+                .cached_block(&fname, info, block.end - block.start)
+                .await;
+            match (cached, sender.as_deref_mut()) {
+                (Some(_), None) => return Ok(()),
+                (Some(entry), Some(sender)) => {
+                    self.cache.record_hit(bucket);
+                    if let Some(usage) = usage {
+                        usage.record(true);
+                    }
+                    return self.send_entry(entry, slice, sender).await;
+                }
+                (None, _) => {}
+            }
+            // Without room for new entries the block is streamed uncached.
+            if !self.cache.has_space(&fname) {
+                let Some(sender) = sender else {
+                    return Ok(());
+                };
+                self.cache.record_miss(bucket);
+                if let Some(usage) = usage {
+                    usage.record(false);
+                }
+                let range = block.start + slice.start..block.start + slice.end;
+                return self
+                    .stream_uncached(credentials, bucket, key, expected_etag, range, sender)
+                    .await;
+            }
+            match self.cache.claim(&fname, bucket, key) {
+                FillSlot::Leader(guard) => break guard,
+                // Prefetches leave blocks that are already being filled alone.
+                FillSlot::Follower(_) if sender.is_none() => return Ok(()),
+                FillSlot::Follower(mut receiver) => {
+                    debug!("Waiting for in-flight cache fill");
+                    // Retry the lookup whether the fill succeeded or not.
+                    let _ = receiver.wait_for(|filled| *filled).await;
+                }
+            }
+        };
+        // Beyond the limit of concurrent fills, misses wait for a fill to
+        // finish and prefetches are skipped.
+        let _permit = match sender {
+            Some(_) => self.cache.acquire_fill().await,
+            None => match self.cache.try_acquire_fill() {
+                Some(permit) => permit,
+                None => return Ok(()),
+            },
+        };
+        if sender.is_some() {
+            self.cache.record_miss(bucket);
+            if let Some(usage) = usage {
+                usage.record(false);
+            }
+        }
+
+        // Resume a fill interrupted earlier if it belongs to the same object
+        // version, sending the part of the slice that is already on disk.
+        let block_len = block.end - block.start;
+        let mut fill = self.cache.open_fill(bucket, guard).await?;
+        let resumable = fill.written() > 0
+            && fill.written() < block_len
+            && fill.metadata().etag.is_some()
+            && (expected_etag.is_none() || fill.metadata().etag.as_deref() == expected_etag);
+        if !resumable {
+            fill.reset().await?;
+        } else {
+            debug!(
+                bucket,
+                key,
+                index,
+                offset = fill.written(),
+                "Resuming cache fill"
+            );
+            if let Some(sender) = sender.as_deref_mut() {
+                if slice.start < fill.written() {
+                    let written = slice.start..slice.end.min(fill.written());
+                    self.send_file_slice(fill.file(), written, sender).await?;
+                }
+            }
+        }
+
+        use futures_util::StreamExt;
+        let upstream = self.upstreams.route(bucket, key);
+        let uri = upstream.object_url(bucket, key);
+        let mut attempts = 0;
+        'fetch: while fill.written() < block_len {
+            let range = format!("bytes={}-{}", block.start + fill.written(), block.end - 1);
+            let mut headers = vec![("range", range.as_str())];
+            let if_match = fill.metadata().etag.clone();
+            if let Some(etag) = if_match.as_deref() {
+                headers.push(("if-match", etag));
+            }
+            let resp = self
+                .request(
+                    upstream,
+                    reqwest::Method::GET,
+                    credentials,
+                    &uri,
+                    Some(headers),
+                    Payload::Empty,
+                )
+                .await;
+            let upstream_failed = match &resp {
+                Ok(resp) => resp.status().is_server_error(),
+                Err(_) => true,
+            };
+            // Serve the rest of the slice from an expired copy of the block if
+            // stale-if-error allows it.
+            if let Some(grace) = self.cache.stale_if_error().filter(|_| upstream_failed) {
+                let stale = self.cache.get_stale(&fname, grace).await.filter(|entry| {
+                    entry.len == block_len
+                        && (expected_etag.is_none()
+                            || entry.metadata.etag.as_deref() == expected_etag)
+                });
+                if let (Some(entry), Some(sender)) = (stale, sender.as_deref_mut()) {
+                    warn!(bucket, key, index, "Serving stale cached block");
+                    let sent = slice.start.max(fill.written()).min(slice.end);
+                    return self.send_entry(entry, sent..slice.end, sender).await;
+                }
+            }
+            let resp = resp.map_err(std::io::Error::other)?;
+            if resp.status() == StatusCode::PRECONDITION_FAILED {
+                return Err(std::io::Error::other("object changed while reading"));
+            }
+            if !resp.status().is_success() {
+                return Err(std::io::Error::other(format!(
+                    "upstream returned {}",
+                    resp.status()
+                )));
+            }
+
+            if fill.written() == 0 {
+                let metadata = CacheMetadata {
+                    bucket: Some(bucket.to_string()),
+                    key: Some(key.to_string()),
+                    tenant: tenant.map(str::to_string),
+                    object_size: Some(info.size),
+                    headers: self
+                        .config
+                        .response_header_passthrough
+                        .select(resp.headers()),
+                    ..CacheMetadata::from_headers(resp.headers())
+                };
+                if expected_etag.is_some() && metadata.etag.as_deref() != expected_etag {
+                    return Err(std::io::Error::other("object changed while reading"));
+                }
+                fill.set_metadata(metadata).await?;
+            }
+
+            let mut obj_body = self.body_stream(resp);
+            while let Some(buf) = obj_body.next().await {
+                let bytes = match buf {
+                    Ok(bytes) => bytes,
+                    Err(e) if attempts + 1 < FILL_ATTEMPTS => {
+                        attempts += 1;
+                        self.metrics.record_upstream_retry();
+                        warn!(
+                            bucket,
+                            key,
+                            index,
+                            offset = fill.written(),
+                            "Resuming interrupted fill: {}",
+                            e
+                        );
+                        continue 'fetch;
+                    }
+                    Err(e) => return Err(std::io::Error::other(e)),
+                };
+                let chunk = fill.written()..fill.written() + bytes.len() as u64;
+                let from = slice.start.max(chunk.start);
+                let to = slice.end.min(chunk.end);
+                let part = match from < to {
+                    true => bytes.slice((from - chunk.start) as usize..(to - chunk.start) as usize),
+                    false => Bytes::new(),
+                };
+
+                let (sent, written) = join!(
+                    async {
+                        match sender.as_deref_mut() {
+                            Some(sender) if !part.is_empty() => {
+                                sender
+                                    .send_data(part)
+                                    .map_err(|_| std::io::Error::other("failed to send data"))
+                                    .await
+                            }
+                            _ => Ok(()),
+                        }
+                    },
+                    fill.write(&bytes),
+                );
+                sent?;
+                // A failed cache write, e.g. on a full disk, doesn't fail the
+                // transfer: the rest of the slice is streamed uncached.
+                if let Err(e) = written {
+                    warn!(bucket, key, index, "Failed to write cache block: {}", e);
+                    self.cache.reclaim_space(&fname);
+                    if let Err(e) = fill.reset().await {
+                        warn!(bucket, key, index, "Failed to discard cache block: {}", e);
+                    }
+                    let Some(sender) = sender else {
+                        return Ok(());
+                    };
+                    let range = block.start + slice.start.max(chunk.end)..block.start + slice.end;
+                    return self
+                        .stream_uncached(credentials, bucket, key, expected_etag, range, sender)
+                        .await;
+                }
+            }
+            break;
+        }
+
+        if fill.written() != block_len {
+            return Err(std::io::Error::other("upstream returned a short block"));
+        }
+        if !fill.commit().await? {
+            debug!("Discarded cache fill of a replaced object");
+            return Ok(());
+        }
+        self.hooks.on_cache_fill(&CacheFillInfo {
+            tenant,
+            bucket,
+            key,
+            index,
+            bytes: block_len,
+        });
+        if let Some(tenant) = tenant {
+            self.cache.enforce_quota(tenant);
+        }
+        Ok(())
+    }
+
+    /// Sends `slice` of a cached block.
+    async fn send_entry(
+        &self,
+        entry: CacheEntry,
+        slice: std::ops::Range<u64>,
+        sender: &mut body_channel::Sender,
+    ) -> std::io::Result<()> {
+        if entry.metadata.is_compressed() {
+            let data = entry.decompress().await?;
+            return sender
+                .send_data(data.slice(slice.start as usize..slice.end as usize))
+                .await
+                .map_err(|_| std::io::Error::other("failed to send data"));
+        }
+        self.send_file_slice(&entry.file, slice, sender).await
+    }
+
+    /// Sends bytes `range` of an object straight from upstream without caching
+    /// them.
+    async fn stream_uncached(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        bucket: &str,
+        key: &str,
+        etag: Option<&str>,
+        range: std::ops::Range<u64>,
+        sender: &mut body_channel::Sender,
+    ) -> std::io::Result<()> {
+        use futures_util::StreamExt;
+
+        if range.is_empty() {
+            return Ok(());
+        }
+        let upstream = self.upstreams.route(bucket, key);
+        let uri = upstream.object_url(bucket, key);
+        let range = format!("bytes={}-{}", range.start, range.end - 1);
+        let mut headers = vec![("range", range.as_str())];
+        if let Some(etag) = etag {
+            headers.push(("if-match", etag));
+        }
+        let resp = self
+            .request(
+                upstream,
+                reqwest::Method::GET,
+                credentials,
+                &uri,
+                Some(headers),
+                Payload::Empty,
+            )
+            .await
+            .map_err(std::io::Error::other)?;
+        if resp.status() == StatusCode::PRECONDITION_FAILED {
+            return Err(std::io::Error::other("object changed while reading"));
+        }
+        if !resp.status().is_success() {
+            return Err(std::io::Error::other(format!(
+                "upstream returned {}",
+                resp.status()
+            )));
+        }
+        let mut body = self.body_stream(resp);
+        while let Some(buf) = body.next().await {
+            sender
+                .send_data(buf.map_err(std::io::Error::other)?)
+                .await
+                .map_err(|_| std::io::Error::other("failed to send data"))?;
+        }
+        Ok(())
+    }
+
+    /// Sends `slice` of a cached file in chunks of the read buffer size,
+    /// reading each chunk while the previous one is sent. Large reads take
+    /// fewer trips to the blocking pool and are written to the socket in
+    /// fewer, larger writes.
+    async fn send_file_slice(
+        &self,
+        file: &CacheFile,
+        slice: std::ops::Range<u64>,
+        sender: &mut body_channel::Sender,
+    ) -> std::io::Result<()> {
+        let read = |offset: u64| {
+            let len = (slice.end - offset).min(self.config.read_buffer_size) as usize;
+            file.read_at(offset, len)
+        };
+        let mut offset = slice.start;
+        let mut next = (offset < slice.end).then(|| read(offset));
+        while let Some(chunk) = next.take() {
+            let buf = chunk.await?;
+            offset += buf.len() as u64;
+            if offset < slice.end {
+                next = Some(read(offset));
+            }
+            sender
+                .send_data(buf)
+                .await
+                .map_err(|_| std::io::Error::other("failed to send data"))?;
+        }
+        Ok(())
+    }
+
+    /// Lists the objects below `prefix` with ListObjectsV2, for a client
+    /// whose keys are those below `key_prefix` upstream.
+    #[instrument(skip(self, credentials), fields(request_id = request_id::current()))]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn list_objects(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        tenant: Option<&str>,
+        bucket: &str,
+        key_prefix: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+        start_after: Option<String>,
+        max_keys: Option<i32>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let rewrite = ListingRewrite::new(key_prefix);
+        let prefix = rewrite.upstream_key(prefix);
+        let continuation_token = continuation_token.map(|token| rewrite.upstream_token(&token));
+        let start_after = start_after.map(|start_after| rewrite.upstream_key(&start_after));
+        let upstream = self.upstreams.route(bucket, &prefix);
+        let uri = upstream.bucket_url(
+            bucket,
+            &[
+                ("list-type", "2"),
+                ("prefix", &prefix),
+                (
+                    "continuation-token",
+                    &continuation_token.unwrap_or_default(),
+                ),
+                ("start-after", &start_after.unwrap_or_default()),
+                (
+                    "max-keys",
+                    &max_keys.map(|k| k.to_string()).unwrap_or_default(),
+                ),
+            ],
+        );
+        let resp = self
+            .request(
+                upstream,
+                reqwest::Method::GET,
+                credentials,
+                &uri,
+                None,
+                Payload::Empty,
+            )
+            .await;
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => return Ok(error::upstream_failure(&e, &format!("/{}", bucket))),
+        };
+
+        let status = resp.status();
+        let mut body = match resp.text().await {
+            Ok(body) => body,
+            Err(e) => return Ok(error::upstream_failure(&e, &format!("/{}", bucket))),
+        };
+
+        if status.is_success() {
+            let invalid = || {
+                warn!(bucket, "Failed to parse upstream listing");
+                error::response(
+                    StatusCode::BAD_GATEWAY,
+                    "BadGateway",
+                    "The upstream sent an invalid listing.",
+                    &format!("/{}", bucket),
+                )
+            };
+            let Ok(result) = ListBucketResult::from_str(body.as_str()) else {
+                return Ok(invalid());
+            };
+
+            // The size cache holds the sizes of upstream keys.
+            let contents = result.contents.unwrap_or_default();
+            self.size_cache.extend(
+                tenant,
+                bucket,
+                contents.iter().map(|obj| (obj.key.as_str(), obj.size)),
+            );
+            body = match rewrite.apply(&body) {
+                Ok(body) => body,
+                Err(_) => return Ok(invalid()),
+            };
+        }
+
+        Ok(Response::builder()
+            .status(status)
+            .header("content-length", body.len())
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    /// Fetches one page of a ListObjectsV2 listing.
+    async fn list_page(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> std::io::Result<ListBucketResult> {
+        let upstream = self.upstreams.route(bucket, prefix);
+        let uri = upstream.bucket_url(
+            bucket,
+            &[
+                ("list-type", "2"),
+                ("prefix", prefix),
+                (
+                    "continuation-token",
+                    &continuation_token.unwrap_or_default(),
+                ),
+            ],
+        );
+        let resp = self
+            .request(
+                upstream,
+                reqwest::Method::GET,
+                credentials,
+                &uri,
+                None,
+                Payload::Empty,
+            )
+            .await
+            .map_err(std::io::Error::other)?;
+        if !resp.status().is_success() {
+            return Err(std::io::Error::other(format!(
+                "upstream returned {}",
+                resp.status()
+            )));
+        }
+        let body = resp.text().await.map_err(std::io::Error::other)?;
+        ListBucketResult::from_str(&body).map_err(std::io::Error::other)
+    }
+
+    /// Downloads every object below `prefix` into the disk cache, fetching up
+    /// to `concurrency` objects at a time. Returns the number of objects and
+    /// bytes warmed.
+    pub async fn warm_prefix(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        tenant: Option<&str>,
+        bucket: &str,
+        prefix: &str,
+        concurrency: usize,
+    ) -> std::io::Result<(usize, u64)> {
+        use futures_util::StreamExt;
+
+        let mut keys = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let page = self
+                .list_page(credentials, bucket, prefix, continuation_token)
+                .await?;
+            keys.extend(page.contents.unwrap_or_default().into_iter().map(|c| c.key));
+            match page.next_continuation_token {
+                Some(token) if page.is_truncated => continuation_token = Some(token),
+                _ => break,
+            }
+        }
+
+        let results: Vec<std::io::Result<u64>> = futures_util::stream::iter(keys)
+            .map(|key| async move {
+                let info = self
+                    .fetch_object_info(credentials, tenant, bucket, &key)
+                    .await
+                    .map_err(|resp| {
+                        std::io::Error::other(format!("HEAD {} returned {}", key, resp.status()))
+                    })?;
+                if !self.cache.is_cacheable(info.size) {
+                    debug!(bucket, key, size = info.size, "Skipping uncacheable object");
+                    return Ok(0);
+                }
+                let block_size = self.cache.block_size();
+                for index in 0..info.size.div_ceil(block_size) {
+                    let block_start = index * block_size;
+                    let block_end = (block_start + block_size).min(info.size);
+                    self.stream_block(
+                        credentials,
+                        bucket,
+                        &key,
+                        &info,
+                        index,
+                        block_start..block_end,
+                        0..0,
+                        None,
+                        None,
+                    )
+                    .await?;
+                }
+                info!(bucket, key, size = info.size, "Warmed object");
+                Ok(info.size)
+            })
+            .buffer_unordered(concurrency.max(1))
+            .collect()
+            .await;
+
+        let mut bytes = 0;
+        for result in &results {
+            match result {
+                Ok(size) => bytes += size,
+                Err(e) => warn!(bucket, "Failed to warm object: {}", e),
+            }
+        }
+        let warmed = results.iter().filter(|r| r.is_ok()).count();
+        if warmed < results.len() {
+            return Err(std::io::Error::other(format!(
+                "{} of {} objects failed to warm",
+                results.len() - warmed,
+                results.len()
+            )));
+        }
+        Ok((warmed, bytes))
+    }
+
+    /// Collects the client headers to forward upstream for a PutObject. When the
+    /// body is `aws-chunked`, the encoding is removed since the proxy re-signs
+    /// the decoded payload.
+    fn put_headers(headers: &HeaderMap, chunked: bool) -> Vec<(String, String)> {
+        headers
+            .iter()
+            .filter(|(name, _)| {
+                PUT_FORWARDED_HEADERS.contains(&name.as_str())
+                    || name.as_str().starts_with("x-amz-meta-")
+                    || name.as_str().starts_with(SSE_C_HEADER_PREFIX)
+            })
+            .filter_map(|(name, value)| {
+                let value = value.to_str().ok()?;
+                if chunked && name == "content-encoding" {
+                    return aws_chunked::strip_content_encoding(value)
+                        .map(|v| (name.to_string(), v));
+                }
+                Some((name.to_string(), value.to_string()))
+            })
+            .collect()
+    }
+
+    /// Writes an uploaded object into the cache block by block, so that it can
+    /// be read back without going upstream.
+    async fn cache_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        metadata: CacheMetadata,
+        mut copy: UploadCopy,
+    ) -> std::io::Result<()> {
+        let size = copy.len();
+        if !self.cache.is_cacheable(size) {
+            return Ok(());
+        }
+        let tenant = metadata.tenant.as_deref();
+        let block_size = self.cache.block_size();
+        for (index, offset) in (0..size).step_by(block_size as usize).enumerate() {
+            let block = copy.read(offset, block_size.min(size - offset)).await?;
+            let name = DiskCache::block_filename(tenant, bucket, key, index as u64);
+            if self.cache.insert(&name, metadata.clone(), &block).await? {
+                self.hooks.on_cache_fill(&CacheFillInfo {
+                    tenant,
+                    bucket,
+                    key,
+                    index: index as u64,
+                    bytes: block.len() as u64,
+                });
+            }
+        }
+        if let Some(tenant) = tenant {
+            self.cache.enforce_quota(tenant);
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, credentials, headers, body, payload_check), fields(request_id = request_id::current()))]
+    pub async fn put_object(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        tenant: Option<&str>,
+        bucket: &str,
+        key: &str,
+        headers: &HeaderMap,
+        body: Body,
+        payload_check: Option<PayloadCheck>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let resource = format!("/{}/{}", bucket, key);
+        let chunked = aws_chunked::is_aws_chunked(headers);
+        let mut put_headers = S3Handler::put_headers(headers, chunked);
+        let length = aws_chunked::payload_length(headers, chunked);
+        let sse_c = S3Handler::uses_sse_c(headers);
+        let mut payload = match (chunked, payload_check) {
+            (true, Some(PayloadCheck::Chunks(signatures))) => {
+                aws_chunked::decode_stream(body, Some(signatures)).boxed()
+            }
+            (true, _) => aws_chunked::decode_stream(body, None).boxed(),
+            (false, Some(PayloadCheck::Sha256(expected))) => {
+                sigv4::check_sha256(body.map_err(std::io::Error::other).boxed(), expected)
+            }
+            (false, _) => body.map_err(std::io::Error::other).boxed(),
+        };
+
+        // Small uploads are buffered to be signed with their hash. Larger
+        // ones, those of unknown length that turn out to be larger, and all
+        // with --unsigned-payload are streamed through.
+        let mut head = BytesMut::new();
+        let mut complete = false;
+        // Empty ones are always buffered: the upstream could take them before
+        // their payload is checked.
+        let buffered = length == Some(0)
+            || !self.config.unsigned_payload
+                && length.is_none_or(|length| length <= MAX_BUFFERED_UPLOAD as u64);
+        while buffered && head.len() <= MAX_BUFFERED_UPLOAD {
+            match payload.next().await {
+                Some(Ok(chunk)) => head.extend_from_slice(&chunk),
+                Some(Err(e)) => return Ok(S3Handler::invalid_upload(&e, &resource)),
+                None => {
+                    complete = true;
+                    break;
+                }
+            }
+        }
+
+        let upstream = self.upstreams.route(bucket, key);
+        let uri = upstream.object_url(bucket, key);
+        let (resp, size, copy) = if complete {
+            let body = head.freeze();
+            let put_headers = put_headers
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            let resp = self
+                .request(
+                    upstream,
+                    reqwest::Method::PUT,
+                    credentials,
+                    &uri,
+                    Some(put_headers),
+                    Payload::Bytes(body.clone()),
+                )
+                .await;
+            (resp, body.len() as u64, Some(UploadCopy::Buffered(body)))
+        } else {
+            // Without a length the upstream request is sent chunked, which
+            // not every upstream accepts.
+            if let Some(length) = length {
+                put_headers.push(("content-length".to_string(), length.to_string()));
+            }
+            // A copy is staged on disk for the cache, unless the upload can't
+            // be cached anyway.
+            let stage = match !sse_c && length.is_none_or(|length| self.cache.is_cacheable(length))
+            {
+                true => match self.cache.stage_upload(bucket, key).await {
+                    Ok(stage) => Some(stage),
+                    Err(e) => {
+                        warn!(bucket, key, "Failed to stage upload for the cache: {}", e);
+                        None
+                    }
+                },
+                false => None,
+            };
+            let put_headers = put_headers
+                .iter()
+                .map(|(k, v)| (k.as_str(), v.as_str()))
+                .collect();
+            let (sender, body) = Body::channel();
+            let (pumped, resp) = join!(
+                pump_upload(head.freeze(), payload, sender, stage),
+                self.request(
+                    upstream,
+                    reqwest::Method::PUT,
+                    credentials,
+                    &uri,
+                    Some(put_headers),
+                    Payload::Unsigned(body),
+                )
+            );
+            match pumped {
+                Ok((size, stage)) => (resp, size, stage.map(UploadCopy::Staged)),
+                Err(e) => return Ok(S3Handler::invalid_upload(&e, &resource)),
+            }
+        };
+        let resp = match resp {
+            Ok(resp) => resp,
+            Err(e) => return Ok(error::upstream_failure(&e, &resource)),
+        };
+
+        let status = resp.status();
+        let size = Some(size);
+        if status.is_success() {
+            // The object changed for every tenant.
+            self.size_cache.remove(|b, k| b == bucket && k == key);
+            if let Some(size) = size.filter(|_| !sse_c) {
+                self.size_cache
+                    .insert(tenant, bucket, key, size as i64, None);
+            }
+        }
+        // Blocks of an earlier version must not be served in place of the
+        // new one, whether it is written to the cache below or not, and for
+        // no tenant.
+        if status.is_success() {
+            if let Err(e) = self.cache.remove_object(bucket, key).await {
+                warn!(
+                    bucket,
+                    key, "Failed to remove overwritten object from cache: {}", e
+                );
+            }
+        }
+        // Without an ETag the cached blocks couldn't be told apart from those
+        // of a later version, so the upload is only cached with one.
+        let copy =
+            copy.filter(|_| status.is_success() && resp.headers().contains_key("etag") && !sse_c);
+        if let Some(copy) = copy {
+            // GETs of the object return the metadata it was uploaded with,
+            // along with that of the upload response like its version id.
+            let mut object_headers: HeaderMap = headers
+                .iter()
+                .filter(|(name, _)| {
+                    STANDARD_RESPONSE_HEADERS.contains(&name.as_str())
+                        || name.as_str().starts_with("x-amz-meta-")
+                })
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect();
+            object_headers.extend(resp.headers().clone());
+            let metadata = CacheMetadata {
+                bucket: Some(bucket.to_string()),
+                key: Some(key.to_string()),
+                tenant: tenant.map(str::to_string),
+                object_size: Some(copy.len()),
+                content_type: headers
+                    .get("content-type")
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string),
+                headers: self
+                    .config
+                    .response_header_passthrough
+                    .select(&object_headers),
+                ..CacheMetadata::from_headers(resp.headers())
+            };
+            if let Err(e) = self.cache_upload(bucket, key, metadata, copy).await {
+                warn!(bucket, key, "Failed to cache uploaded object: {}", e);
+            }
+        }
+        let mut builder = Response::builder().status(status);
+        for (name, value) in resp.headers() {
+            if name == "etag" || name.as_str().starts_with(SSE_C_HEADER_PREFIX) {
+                builder = builder.header(name, value);
+            }
+        }
+        let body = resp.bytes().await.unwrap_or_default();
+        if !status.is_success() && body.is_empty() {
+            return Ok(error::from_status(status, &resource));
+        }
+        Ok(builder
+            .header("content-length", body.len())
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    /// Reports an upload body that couldn't be read or decoded, or that
+    /// failed the check against its signature.
+    fn invalid_upload(e: &std::io::Error, resource: &str) -> Response<Body> {
+        info!("Failed to read upload: {}", e);
+        let inner = e.get_ref();
+        if inner.is_some_and(|inner| inner.is::<sigv4::Sha256Mismatch>()) {
+            return error::response(
+                StatusCode::BAD_REQUEST,
+                "XAmzContentSHA256Mismatch",
+                &e.to_string(),
+                resource,
+            );
+        }
+        let chunked = inner.and_then(|inner| inner.downcast_ref::<aws_chunked::AwsChunkedError>());
+        if let Some(aws_chunked::AwsChunkedError::SignatureMismatch()) = chunked {
+            return error::response(
+                StatusCode::FORBIDDEN,
+                "SignatureDoesNotMatch",
+                "The request signature we calculated does not match the signature you provided.",
+                resource,
+            );
         }
+        error::response(
+            StatusCode::BAD_REQUEST,
+            "InvalidRequest",
+            &e.to_string(),
+            resource,
+        )
+    }
 
+    /// Deletes an object upstream and drops its cached size and blocks.
+    #[instrument(skip(self, credentials), fields(request_id = request_id::current()))]
+    pub async fn delete_object(
+        &self,
+        credentials: &aws_credential_types::Credentials,
+        bucket: &str,
+        key: &str,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let upstream = self.upstreams.route(bucket, key);
+        let uri = upstream.object_url(bucket, key);
+        let resp = match self
+            .request(
+                upstream,
+                reqwest::Method::DELETE,
+                credentials,
+                &uri,
+                None,
+                Payload::Empty,
+            )
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) => return Ok(error::upstream_failure(&e, &format!("/{}/{}", bucket, key))),
+        };
+
+        let status = resp.status();
+        if status.is_success() {
+            self.size_cache.remove(|b, k| b == bucket && k == key);
+            if let Err(e) = self.cache.remove_object(bucket, key).await {
+                warn!(
+                    bucket,
+                    key, "Failed to remove deleted object from cache: {}", e
+                );
+            }
+        }
+        let body = resp.bytes().await.unwrap_or_default();
+        if !status.is_success() && body.is_empty() {
+            return Ok(error::from_status(status, &format!("/{}/{}", bucket, key)));
+        }
         Ok(Response::builder()
             .status(status)
             .header("content-length", body.len())
@@ -283,3 +2433,260 @@ impl S3Handler {
             .unwrap())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+
+    use clap::Parser;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Method, Request, Server};
+
+    use super::*;
+    use crate::Settings;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        settings: Settings,
+    }
+
+    /// Starts an upstream that keeps objects in memory and, like some S3
+    /// compatible services, returns no ETag for uploads.
+    fn upstream_without_put_etag() -> String {
+        upstream().0
+    }
+
+    /// Like `upstream_without_put_etag`, also returning the method and path
+    /// of each request it gets, followed by `if-none-match` for conditional
+    /// ones.
+    fn upstream() -> (String, Arc<Mutex<Vec<String>>>) {
+        let objects = Arc::new(Mutex::new(HashMap::<String, Bytes>::new()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+        let service = make_service_fn(move |_| {
+            let objects = objects.clone();
+            let log = log.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let objects = objects.clone();
+                    let log = log.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let conditional = match req.headers().contains_key("if-none-match") {
+                            true => " if-none-match",
+                            false => "",
+                        };
+                        log.lock().unwrap().push(format!(
+                            "{} {}{}",
+                            req.method(),
+                            path,
+                            conditional
+                        ));
+                        let response = Response::builder();
+                        if req.method() == Method::PUT {
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                            objects.lock().unwrap().insert(path, body);
+                            return response.body(Body::empty());
+                        }
+                        let Some(object) = objects.lock().unwrap().get(&path).cloned() else {
+                            return response.status(404).body(Body::empty());
+                        };
+                        let etag = format!("\"{}\"", blake3::hash(&object).to_hex());
+                        if req
+                            .headers()
+                            .get("if-none-match")
+                            .is_some_and(|v| v == &etag)
+                        {
+                            return response
+                                .status(304)
+                                .header("etag", etag)
+                                .body(Body::empty());
+                        }
+                        let response = response.header("etag", etag);
+                        let range = req
+                            .headers()
+                            .get("range")
+                            .and_then(|range| range.to_str().ok())
+                            .and_then(|range| range.strip_prefix("bytes="))
+                            .and_then(|range| range.split_once('-'))
+                            .map(|(start, end)| {
+                                let start: usize = start.parse().unwrap();
+                                let end = end
+                                    .parse()
+                                    .map_or(object.len(), |end: usize| (end + 1).min(object.len()));
+                                (start, end)
+                            });
+                        let (status, data) = match range {
+                            Some((start, end)) => (206, object.slice(start..end)),
+                            None => (200, object.clone()),
+                        };
+                        let response = response
+                            .status(status)
+                            .header("content-length", data.len())
+                            .header(
+                                "content-range",
+                                format!(
+                                    "bytes {}-{}/{}",
+                                    range.map_or(0, |r| r.0),
+                                    range.map_or(object.len(), |r| r.1) - 1,
+                                    object.len()
+                                ),
+                            );
+                        match req.method() == Method::HEAD {
+                            true => response.body(Body::empty()),
+                            false => response.body(Body::from(data)),
+                        }
+                    }
+                }))
+            }
+        });
+        let server = Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+        let endpoint = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        (endpoint, requests)
+    }
+
+    /// Sets up a handler for `endpoint` with a fresh cache directory, which
+    /// is returned for the test to remove.
+    async fn handler(endpoint: &str, name: &str) -> (Arc<S3Handler>, PathBuf) {
+        handler_with(endpoint, name, &[]).await
+    }
+
+    /// Like `handler`, with further command line arguments.
+    async fn handler_with(endpoint: &str, name: &str, args: &[&str]) -> (Arc<S3Handler>, PathBuf) {
+        let cache_dir =
+            std::env::temp_dir().join(format!("s3proxy-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&cache_dir);
+        let cli = Cli::parse_from(
+            [
+                "s3proxy",
+                "--endpoint",
+                endpoint,
+                "--cache-dir",
+                cache_dir.to_str().unwrap(),
+                "--cache-min-free-space",
+                "0",
+            ]
+            .iter()
+            .chain(args),
+        );
+        (cli.settings.handler().await.unwrap(), cache_dir)
+    }
+
+    #[tokio::test]
+    async fn put_without_etag_replaces_cached_object() {
+        let (s3, cache_dir) = handler(&upstream_without_put_etag(), "put-without-etag").await;
+        let credentials =
+            aws_credential_types::Credentials::new("id", "secret", None, None, "test");
+        for body in ["first version", "second version"] {
+            let response = s3
+                .put_object(
+                    &credentials,
+                    None,
+                    "b",
+                    "k",
+                    &HeaderMap::new(),
+                    Body::from(body),
+                    None,
+                )
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            // The first read fills the cache, which the next upload must
+            // invalidate.
+            for _ in 0..2 {
+                let response = s3
+                    .get_object(&credentials, None, "b", "k", None)
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), StatusCode::OK);
+                let data = hyper::body::to_bytes(response.into_body()).await.unwrap();
+                assert_eq!(data, body);
+            }
+        }
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn put_discards_fill_in_progress() {
+        let (s3, cache_dir) = handler(&upstream_without_put_etag(), "put-during-fill").await;
+        let credentials =
+            aws_credential_types::Credentials::new("id", "secret", None, None, "test");
+        // A read of the first version is filling the cache when the second
+        // version is uploaded.
+        let name = DiskCache::block_filename(None, "b", "k", 0);
+        let FillSlot::Leader(guard) = s3.cache.claim(&name, "b", "k") else {
+            panic!("no fill should be in progress");
+        };
+        let mut fill = s3.cache.open_fill("b", guard).await.unwrap();
+        fill.set_metadata(CacheMetadata {
+            bucket: Some("b".to_string()),
+            key: Some("k".to_string()),
+            etag: Some("\"first\"".to_string()),
+            object_size: Some(13),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        fill.write(b"first version").await.unwrap();
+        let response = s3
+            .put_object(
+                &credentials,
+                None,
+                "b",
+                "k",
+                &HeaderMap::new(),
+                Body::from("second version"),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!fill.commit().await.unwrap());
+        let response = s3
+            .get_object(&credentials, None, "b", "k", None)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let data = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(data, "second version");
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn revalidate_with_conditional_get() {
+        let (endpoint, requests) = upstream();
+        let (s3, cache_dir) = handler_with(&endpoint, "revalidate", &["--cache-revalidate"]).await;
+        let credentials =
+            aws_credential_types::Credentials::new("id", "secret", None, None, "test");
+        let get = || async {
+            let response = s3
+                .get_object(&credentials, None, "b", "k", None)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            hyper::body::to_bytes(response.into_body()).await.unwrap()
+        };
+        // Objects changed behind the proxy are refetched, unchanged ones are
+        // served from the cache, without any HEAD requests.
+        let conditional = "GET /b/k if-none-match";
+        for (body, first) in [
+            ("first version", "GET /b/k"),
+            ("second version", conditional),
+        ] {
+            reqwest::Client::new()
+                .put(format!("{}b/k", endpoint))
+                .body(body)
+                .send()
+                .await
+                .unwrap();
+            requests.lock().unwrap().clear();
+            assert_eq!(get().await, body);
+            assert_eq!(get().await, body);
+            assert_eq!(*requests.lock().unwrap(), [first, conditional]);
+        }
+        std::fs::remove_dir_all(cache_dir).unwrap();
+    }
+}
@@ -0,0 +1,277 @@
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::{CacheEvent, CacheEvents};
+
+/// Entries are keyed by tenant, bucket and object key, so that sizes are only
+/// served to the tenant that fetched them.
+type SizeKey = (Option<String>, String, String);
+
+/// Relayed response headers of an object, as name and value.
+pub type Headers = Vec<(String, String)>;
+
+/// A cached size and the Unix time in seconds at which it was fetched, with
+/// the headers of the object if it was learned from a HEAD response.
+type Entry = (i64, u64, Option<Headers>);
+
+/// An object size as stored in snapshots.
+#[derive(Serialize, Deserialize)]
+struct SizeEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
+    bucket: String,
+    key: String,
+    size: i64,
+    /// Unix time in seconds at which the size was fetched.
+    fetched: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    headers: Option<Headers>,
+}
+
+/// Object sizes learned from HEAD and list responses, so that HEAD requests
+/// can be answered without going upstream. Sizes learned from HEAD responses
+/// are stored with the headers that HEAD relays. Holds at most `capacity` sizes,
+/// dropping the least recently used ones; a capacity of zero disables it.
+pub struct SizeCache {
+    entries: Option<Mutex<LruCache<SizeKey, Entry>>>,
+    max_age: Duration,
+    events: CacheEvents,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn size_key(tenant: Option<&str>, bucket: &str, key: &str) -> SizeKey {
+    (
+        tenant.map(str::to_string),
+        bucket.to_string(),
+        key.to_string(),
+    )
+}
+
+impl SizeCache {
+    pub fn new(capacity: usize, max_age: Duration) -> Self {
+        SizeCache {
+            entries: NonZeroUsize::new(capacity)
+                .map(|capacity| Mutex::new(LruCache::new(capacity))),
+            max_age,
+            events: CacheEvents::default(),
+        }
+    }
+
+    /// Returns the hits, misses and evictions by bucket since startup.
+    pub fn events(&self) -> &CacheEvents {
+        &self.events
+    }
+
+    /// Adds an entry, counting the least recently used entry that it
+    /// replaces, if any, as evicted.
+    fn put(&self, entries: &mut LruCache<SizeKey, Entry>, size_key: SizeKey, entry: Entry) {
+        if let Some((evicted, _)) = entries.push(size_key.clone(), entry) {
+            if evicted != size_key {
+                self.events.record(&evicted.1, CacheEvent::Eviction);
+            }
+        }
+    }
+
+    fn is_expired(&self, fetched: u64) -> bool {
+        now().saturating_sub(fetched) > self.max_age.as_secs()
+    }
+
+    /// Returns the size of an object and its headers, if they were stored.
+    /// With `with_headers`, sizes stored without headers count as a miss.
+    pub fn get(
+        &self,
+        tenant: Option<&str>,
+        bucket: &str,
+        key: &str,
+        with_headers: bool,
+    ) -> Option<(i64, Option<Headers>)> {
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        let size_key = size_key(tenant, bucket, key);
+        let Some((size, fetched, headers)) = entries.get(&size_key) else {
+            self.events.record(bucket, CacheEvent::Miss);
+            return None;
+        };
+        if self.is_expired(*fetched) {
+            entries.pop(&size_key);
+            self.events.record(bucket, CacheEvent::Eviction);
+            self.events.record(bucket, CacheEvent::Miss);
+            return None;
+        }
+        if with_headers && headers.is_none() {
+            self.events.record(bucket, CacheEvent::Miss);
+            return None;
+        }
+        self.events.record(bucket, CacheEvent::Hit);
+        Some((*size, headers.clone()))
+    }
+
+    /// Stores the size of an object, and its headers if known.
+    pub fn insert(
+        &self,
+        tenant: Option<&str>,
+        bucket: &str,
+        key: &str,
+        size: i64,
+        headers: Option<Headers>,
+    ) {
+        if let Some(entries) = &self.entries {
+            let mut entries = entries.lock().unwrap();
+            let entry = (size, now(), headers);
+            self.put(&mut entries, size_key(tenant, bucket, key), entry);
+        }
+    }
+
+    /// Inserts the sizes of several objects of a bucket at once.
+    pub fn extend<'a>(
+        &self,
+        tenant: Option<&str>,
+        bucket: &str,
+        sizes: impl Iterator<Item = (&'a str, i64)>,
+    ) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        let fetched = now();
+        let mut entries = entries.lock().unwrap();
+        for (key, size) in sizes {
+            self.put(
+                &mut entries,
+                size_key(tenant, bucket, key),
+                (size, fetched, None),
+            );
+        }
+    }
+
+    /// Removes the entries of all tenants whose bucket and key match
+    /// `predicate`.
+    pub fn remove(&self, predicate: impl Fn(&str, &str) -> bool) {
+        let Some(entries) = &self.entries else {
+            return;
+        };
+        let mut entries = entries.lock().unwrap();
+        let matching: Vec<SizeKey> = entries
+            .iter()
+            .filter(|((_, bucket, key), _)| predicate(bucket, key))
+            .map(|(size_key, _)| size_key.clone())
+            .collect();
+        for size_key in matching {
+            entries.pop(&size_key);
+            self.events.record(&size_key.1, CacheEvent::Eviction);
+        }
+    }
+
+    /// Writes all unexpired entries to `path`, returning their number.
+    pub async fn save(&self, path: &Path) -> std::io::Result<usize> {
+        let Some(entries) = &self.entries else {
+            return Ok(0);
+        };
+        // Least recently used first, so that loading restores the order.
+        let snapshot: Vec<SizeEntry> = entries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|(_, (_, fetched, _))| !self.is_expired(*fetched))
+            .map(
+                |((tenant, bucket, key), (size, fetched, headers))| SizeEntry {
+                    tenant: tenant.clone(),
+                    bucket: bucket.clone(),
+                    key: key.clone(),
+                    size: *size,
+                    fetched: *fetched,
+                    headers: headers.clone(),
+                },
+            )
+            .collect();
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        tokio::fs::write(&temp_path, serde_json::to_vec(&snapshot)?).await?;
+        tokio::fs::rename(&temp_path, path).await?;
+        Ok(snapshot.len())
+    }
+
+    /// Loads the unexpired entries of a snapshot written by `save`, returning
+    /// their number. A missing snapshot loads nothing.
+    pub async fn load(&self, path: &Path) -> std::io::Result<usize> {
+        let Some(entries) = &self.entries else {
+            return Ok(0);
+        };
+        let data = match tokio::fs::read(path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        // Snapshots of older versions lack the bucket and are ignored.
+        let Ok(snapshot) = serde_json::from_slice::<Vec<SizeEntry>>(&data) else {
+            return Ok(0);
+        };
+        let mut entries = entries.lock().unwrap();
+        let mut loaded = 0;
+        for entry in snapshot {
+            if self.is_expired(entry.fetched) {
+                continue;
+            }
+            entries.put(
+                (entry.tenant, entry.bucket, entry.key),
+                (entry.size, entry.fetched, entry.headers),
+            );
+            loaded += 1;
+        }
+        Ok(loaded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers() -> Headers {
+        vec![
+            ("etag".to_string(), "\"abc\"".to_string()),
+            ("x-amz-meta-owner".to_string(), "me".to_string()),
+        ]
+    }
+
+    #[test]
+    fn sizes_without_headers() {
+        let sizes = SizeCache::new(10, Duration::from_secs(60));
+        sizes.insert(None, "b", "head", 1, Some(headers()));
+        sizes.extend(None, "b", [("listed", 2)].into_iter());
+        assert_eq!(
+            sizes.get(None, "b", "head", true),
+            Some((1, Some(headers())))
+        );
+        assert_eq!(sizes.get(None, "b", "listed", false), Some((2, None)));
+        assert_eq!(sizes.get(None, "b", "listed", true), None);
+        assert_eq!(sizes.get(Some("t"), "b", "head", false), None);
+    }
+
+    #[tokio::test]
+    async fn snapshot_keeps_headers() {
+        let path = std::env::temp_dir().join(format!("s3proxy-sizes-{}.json", std::process::id()));
+        let sizes = SizeCache::new(10, Duration::from_secs(60));
+        sizes.insert(None, "b", "head", 1, Some(headers()));
+        sizes.insert(None, "b", "put", 2, None);
+        assert_eq!(sizes.save(&path).await.unwrap(), 2);
+
+        let loaded = SizeCache::new(10, Duration::from_secs(60));
+        assert_eq!(loaded.load(&path).await.unwrap(), 2);
+        assert_eq!(
+            loaded.get(None, "b", "head", true),
+            Some((1, Some(headers())))
+        );
+        assert_eq!(loaded.get(None, "b", "put", false), Some((2, None)));
+        std::fs::remove_file(path).unwrap();
+    }
+}
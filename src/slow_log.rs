@@ -0,0 +1,129 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Response, StatusCode};
+use tracing::{warn, Span};
+
+use crate::cache::BlockUsage;
+
+/// Upstream requests made on behalf of one request, including those of the
+/// tasks it spawned, and the time until their response headers.
+#[derive(Debug, Default)]
+pub struct UpstreamTimings {
+    requests: AtomicU64,
+    micros_total: AtomicU64,
+    micros_max: AtomicU64,
+}
+
+impl UpstreamTimings {
+    pub fn record(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.micros_total.fetch_add(micros, Ordering::Relaxed);
+        self.micros_max.fetch_max(micros, Ordering::Relaxed);
+    }
+}
+
+/// What is known about a request when its response headers are sent.
+pub struct SlowLogEntry {
+    pub method: Method,
+    pub path: String,
+    pub status: StatusCode,
+    pub bucket: Option<String>,
+    pub key: Option<String>,
+    /// Time until the response headers were sent.
+    pub turnaround: Duration,
+}
+
+/// Wraps the body of a response so that the request is logged as slow once
+/// the body is done, if it took `threshold` or longer.
+pub fn wrap(
+    entry: SlowLogEntry,
+    threshold: Duration,
+    start: Instant,
+    upstream: Arc<UpstreamTimings>,
+    res: Response<Body>,
+) -> Response<Body> {
+    let usage = res.extensions().get::<Arc<BlockUsage>>().cloned();
+    let (parts, body) = res.into_parts();
+    let body = SlowLogBody {
+        body,
+        entry,
+        threshold,
+        start,
+        first_byte: None,
+        bytes: 0,
+        usage,
+        upstream,
+        span: Span::current(),
+    };
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// A response body that logs its request when it is dropped, if it was
+/// slow.
+struct SlowLogBody {
+    body: Body,
+    entry: SlowLogEntry,
+    threshold: Duration,
+    start: Instant,
+    /// Time until the first chunk or the end of the body was polled.
+    first_byte: Option<Duration>,
+    bytes: u64,
+    usage: Option<Arc<BlockUsage>>,
+    upstream: Arc<UpstreamTimings>,
+    span: Span,
+}
+
+impl Stream for SlowLogBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.body).poll_data(cx);
+        if let Poll::Ready(ready) = &polled {
+            if let Some(Ok(data)) = ready {
+                self.bytes += data.len() as u64;
+            }
+            if self.first_byte.is_none() {
+                self.first_byte = Some(self.start.elapsed());
+            }
+        }
+        polled
+    }
+}
+
+impl Drop for SlowLogBody {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        if elapsed < self.threshold {
+            return;
+        }
+        let ms = |micros: u64| micros as f64 / 1000.0;
+        let entry = &self.entry;
+        let upstream = &self.upstream;
+        self.span.in_scope(|| {
+            warn!(
+                method = %entry.method,
+                path = entry.path,
+                status = entry.status.as_u16(),
+                bucket = entry.bucket,
+                key = entry.key,
+                bytes = self.bytes,
+                cache = self.usage.as_ref().and_then(|usage| usage.status()),
+                took_ms = ms(elapsed.as_micros() as u64),
+                headers_ms = ms(entry.turnaround.as_micros() as u64),
+                first_byte_ms = ms(self.first_byte.unwrap_or(elapsed).as_micros() as u64),
+                upstream_requests = upstream.requests.load(Ordering::Relaxed),
+                upstream_ms = ms(upstream.micros_total.load(Ordering::Relaxed)),
+                upstream_max_ms = ms(upstream.micros_max.load(Ordering::Relaxed)),
+                "Slow request"
+            )
+        });
+    }
+}
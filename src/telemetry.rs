@@ -0,0 +1,192 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::{Body, HeaderMap, Response};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{TraceContextExt, TraceError};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::Config;
+use opentelemetry_sdk::Resource;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::cache::BlockUsage;
+use crate::request_id;
+
+/// Formats of log lines.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line, with the fields of events at the top level
+    Json,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct TelemetryConfig {
+    /// Format of log lines
+    #[arg(long, value_enum, default_value = "text", env)]
+    pub log_format: LogFormat,
+    /// OTLP/HTTP endpoint to export traces to, e.g. http://collector:4318; traces are not exported if unset
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+    /// Service name of the exported traces
+    #[arg(long, default_value = "s3proxy", env = "OTEL_SERVICE_NAME")]
+    pub otlp_service_name: String,
+}
+
+/// Installs the global subscriber, which logs events filtered by `RUST_LOG`
+/// and, with an OTLP endpoint, exports spans of level `INFO` and above.
+pub fn init(config: &TelemetryConfig) -> Result<(), TraceError> {
+    // Trace contexts are relayed upstream even if no traces are exported.
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let otlp = match &config.otlp_endpoint {
+        Some(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .http()
+                        .with_endpoint(endpoint),
+                )
+                .with_trace_config(Config::default().with_resource(Resource::new([
+                    KeyValue::new("service.name", config.otlp_service_name.clone()),
+                    KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+                ])))
+                .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+            Some(
+                tracing_opentelemetry::layer()
+                    .with_tracer(tracer)
+                    .with_filter(LevelFilter::INFO),
+            )
+        }
+        None => None,
+    };
+    let fmt = match config.log_format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(true)
+            .boxed(),
+    };
+    let registry = tracing_subscriber::registry()
+        .with(fmt.with_filter(EnvFilter::from_default_env()))
+        .with(otlp);
+    // Listens on TOKIO_CONSOLE_BIND, 127.0.0.1:6669 by default.
+    #[cfg(feature = "console-subscriber")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.init();
+    Ok(())
+}
+
+/// Exports the spans that haven't been exported yet.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key)?.to_str().ok()
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Makes the W3C trace context of a request, if any, the parent of the
+/// current span, so that it joins the client's trace, and records its trace
+/// id on the span. Returns the context for `inject_trace`.
+pub fn continue_trace(headers: &HeaderMap) -> opentelemetry::Context {
+    let context = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    });
+    let span = tracing::Span::current();
+    let span_context = context.span().span_context().clone();
+    if span_context.is_valid() {
+        span.record("trace_id", tracing::field::display(span_context.trace_id()));
+    }
+    span.set_parent(context.clone());
+    context
+}
+
+/// Adds the W3C trace context headers to an upstream request, so that the
+/// upstream joins the trace: with the current span as parent if traces are
+/// exported, otherwise as the client sent them. Like the request id, they
+/// are added after signing and are left out of the signature.
+pub fn inject_trace(headers: &mut HeaderMap) {
+    let mut context = tracing::Span::current().context();
+    if !context.span().span_context().is_valid() {
+        context = request_id::trace_context();
+    }
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut HeaderInjector(headers))
+    });
+}
+
+/// Records the cache status of a `GET` response served from blocks, `hit`,
+/// `miss` or `partial`, as the `cache` field of the current span once its
+/// body is done. The span stays open until then.
+pub fn record_cache_status(res: Response<Body>) -> Response<Body> {
+    let Some(usage) = res.extensions().get::<Arc<BlockUsage>>().cloned() else {
+        return res;
+    };
+    let (parts, body) = res.into_parts();
+    let body = CacheStatusBody {
+        body,
+        usage,
+        span: tracing::Span::current(),
+    };
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// A response body that records the cache status of its blocks on a span
+/// when it is dropped.
+struct CacheStatusBody {
+    body: Body,
+    usage: Arc<BlockUsage>,
+    span: tracing::Span,
+}
+
+impl Stream for CacheStatusBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+}
+
+impl Drop for CacheStatusBody {
+    fn drop(&mut self) {
+        if let Some(status) = self.usage.status() {
+            self.span.record("cache", status);
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::time::Sleep;
+
+/// Most bytes that a write waits for, so that slow rates still send data in
+/// reasonably large writes without starving other writers for long.
+const MAX_WAIT_BYTES: f64 = 16_384.0;
+
+/// A token bucket limiting the bytes per second written by the connections
+/// sharing it. It holds up to a second worth of bytes, so that idle
+/// connections can burst briefly.
+pub struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        RateLimiter {
+            rate: bytes_per_second as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_second as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes up to `want` bytes from the bucket, or returns how long to wait
+    /// until enough are available.
+    fn take(&self, want: usize) -> Result<usize, Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refilled).min(self.rate);
+        bucket.updated = now;
+        let needed = (want as f64).min(self.rate).clamp(1.0, MAX_WAIT_BYTES);
+        if bucket.tokens < needed {
+            return Err(Duration::from_secs_f64(
+                (needed - bucket.tokens) / self.rate,
+            ));
+        }
+        let taken = (bucket.tokens as usize).min(want);
+        bucket.tokens -= taken as f64;
+        Ok(taken)
+    }
+
+    /// Returns bytes that were taken but not written.
+    fn refund(&self, bytes: usize) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.tokens = (bucket.tokens + bytes as f64).min(self.rate);
+    }
+}
+
+/// The throttling of the writes of a connection by its own limiter and the
+/// ones it shares with other connections.
+pub struct Throttle {
+    limiters: Vec<Arc<RateLimiter>>,
+    /// Bytes taken from all limiters that haven't been written yet.
+    reserved: usize,
+    wait: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    /// Returns the throttle of a connection, or `None` if no limit applies.
+    pub fn new(limiters: Vec<Arc<RateLimiter>>) -> Option<Self> {
+        (!limiters.is_empty()).then_some(Throttle {
+            limiters,
+            reserved: 0,
+            wait: None,
+        })
+    }
+
+    /// Polls for permission to write up to `len` bytes, returning how many
+    /// may be written. Written bytes must be reported with `consume`.
+    pub fn poll_reserve(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<usize> {
+        while self.reserved == 0 && len > 0 {
+            if let Some(wait) = &mut self.wait {
+                std::task::ready!(wait.as_mut().poll(cx));
+                self.wait = None;
+            }
+            match self.take(len) {
+                Ok(taken) => self.reserved = taken,
+                Err(wait) => self.wait = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+        Poll::Ready(self.reserved.min(len))
+    }
+
+    /// Takes the same number of bytes, up to `want`, from every limiter.
+    fn take(&self, want: usize) -> Result<usize, Duration> {
+        let mut taken = Vec::with_capacity(self.limiters.len());
+        for limiter in &self.limiters {
+            let want = taken.last().copied().unwrap_or(want);
+            match limiter.take(want) {
+                Ok(bytes) => taken.push(bytes),
+                Err(wait) => {
+                    for (limiter, bytes) in self.limiters.iter().zip(taken) {
+                        limiter.refund(bytes);
+                    }
+                    return Err(wait);
+                }
+            }
+        }
+        let granted = taken.last().copied().unwrap_or(want);
+        for (limiter, bytes) in self.limiters.iter().zip(taken) {
+            limiter.refund(bytes - granted);
+        }
+        Ok(granted)
+    }
+
+    /// Records that `written` of the reserved bytes were written.
+    pub fn consume(&mut self, written: usize) {
+        self.reserved -= written.min(self.reserved);
+    }
+}
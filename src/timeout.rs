@@ -0,0 +1,83 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::{Stream, TryStreamExt};
+use hyper::{Body, Response};
+use tokio::time::Sleep;
+use tracing::{warn, Span};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Aborts the body of a response once it has waited `timeout` for data,
+/// e.g. from a hung upstream.
+pub fn idle_timeout(res: Response<Body>, timeout: Duration) -> Response<Body> {
+    let span = Span::current();
+    let (parts, body) = res.into_parts();
+    let body = IdleTimeout::new(body, Some(timeout)).inspect_err(move |e| {
+        if e.is::<Elapsed>() {
+            span.in_scope(|| warn!("Aborting response body: {}", e));
+        }
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+/// Error of a stream that got no data in time.
+#[derive(Debug)]
+pub struct Elapsed(Duration);
+
+impl std::fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no data in {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// A stream of bytes that fails once it has waited `timeout` for its next
+/// chunk. Time spent by the consumer between chunks, e.g. while a slow
+/// client reads them, doesn't count.
+pub struct IdleTimeout<S> {
+    stream: S,
+    timeout: Option<Duration>,
+    /// Fires when the stream has been waiting for data for `timeout`.
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> IdleTimeout<S> {
+    /// Wraps `stream`, which never times out if `timeout` is `None`.
+    pub fn new(stream: S, timeout: Option<Duration>) -> Self {
+        IdleTimeout {
+            stream,
+            timeout,
+            idle: None,
+        }
+    }
+}
+
+impl<S, E> Stream for IdleTimeout<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    E: Into<BoxError>,
+{
+    type Item = Result<Bytes, BoxError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(polled) = Pin::new(&mut self.stream).poll_next(cx) {
+            self.idle = None;
+            return Poll::Ready(polled.map(|data| data.map_err(Into::into)));
+        }
+        let Some(timeout) = self.timeout else {
+            return Poll::Pending;
+        };
+        let idle = self
+            .idle
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+        match idle.as_mut().poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Some(Err(Elapsed(timeout).into()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
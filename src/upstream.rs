@@ -0,0 +1,200 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use percent_encoding::{utf8_percent_encode, AsciiSet};
+use serde::Deserialize;
+
+use crate::backend::{Backend, BackendKind, Backends};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::router::wildcard_match;
+use crate::sigv4::SIGV4_ENCODE;
+
+/// Characters percent-encoded in the keys of upstream URLs: all but the
+/// unreserved ones and `/`, so that the URL is its own canonical URI.
+const KEY_ENCODE: &AsciiSet = &SIGV4_ENCODE.remove(b'/');
+
+/// An upstream endpoint that S3 requests are sent to.
+pub struct Upstream {
+    /// Base URL that bucket names are appended to, ending in `/`.
+    endpoint: String,
+    /// The storage service behind the endpoint.
+    backend: Arc<dyn Backend>,
+    /// Keys that requests are signed with instead of the credentials of the
+    /// client, if any.
+    credentials: Option<aws_credential_types::Credentials>,
+    /// The circuit breaker of the endpoint, shared by its routes.
+    breaker: Arc<CircuitBreaker>,
+}
+
+impl Upstream {
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    pub fn backend(&self) -> &dyn Backend {
+        &*self.backend
+    }
+
+    pub fn credentials(&self) -> Option<&aws_credential_types::Credentials> {
+        self.credentials.as_ref()
+    }
+
+    pub fn circuit_breaker(&self) -> &CircuitBreaker {
+        &self.breaker
+    }
+
+    /// Returns the URL of `key` in `bucket`.
+    pub fn object_url(&self, bucket: &str, key: &str) -> String {
+        format!(
+            "{}{}/{}",
+            self.endpoint,
+            bucket,
+            utf8_percent_encode(key, KEY_ENCODE)
+        )
+    }
+
+    /// Returns the URL of `bucket` with the query parameters `params`,
+    /// encoded as in canonical query strings, e.g. spaces as `%20`.
+    pub fn bucket_url(&self, bucket: &str, params: &[(&str, &str)]) -> String {
+        let query: Vec<String> = params
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "{}={}",
+                    utf8_percent_encode(name, SIGV4_ENCODE),
+                    utf8_percent_encode(value, SIGV4_ENCODE)
+                )
+            })
+            .collect();
+        format!("{}{}?{}", self.endpoint, bucket, query.join("&"))
+    }
+}
+
+/// Sends the requests for the keys below a prefix of the buckets matching a
+/// pattern to an upstream of its own.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Route {
+    /// Bucket name or pattern with `*` wildcards.
+    bucket: String,
+    #[serde(default)]
+    prefix: String,
+    endpoint: String,
+    /// Storage service at the endpoint; --backend if unset.
+    backend: Option<BackendKind>,
+    /// Signing region of S3 endpoints; --upstream-region if unset.
+    region: Option<String>,
+    access_key_id: Option<String>,
+    secret_access_key: Option<String>,
+    session_token: Option<String>,
+}
+
+/// The upstreams of the proxy: --endpoint, and those that routes send
+/// requests for some buckets or prefixes to.
+pub struct Upstreams {
+    primary: Upstream,
+    /// Routes in the order of the file, the first matching one applies.
+    routes: Vec<(String, String, Upstream)>,
+    /// The circuit breakers of the endpoints, starting with --endpoint.
+    breakers: Vec<(String, Arc<CircuitBreaker>)>,
+}
+
+impl Upstreams {
+    /// Sets up the upstream at `endpoint` and those of the routes in
+    /// `routes_file`, a JSON array of routes, if any.
+    pub fn new(
+        endpoint: &str,
+        backends: &mut Backends,
+        routes_file: Option<&Path>,
+        breaker_threshold: u32,
+        breaker_cooldown: Duration,
+    ) -> Result<Self, String> {
+        let mut breakers: Vec<(String, Arc<CircuitBreaker>)> = Vec::new();
+        let mut upstream = |endpoint: &str, backend, credentials| {
+            let endpoint = match endpoint.ends_with('/') {
+                true => endpoint.to_string(),
+                false => format!("{}/", endpoint),
+            };
+            let breaker = match breakers.iter().find(|(other, _)| *other == endpoint) {
+                Some((_, breaker)) => breaker.clone(),
+                None => {
+                    let breaker =
+                        Arc::new(CircuitBreaker::new(breaker_threshold, breaker_cooldown));
+                    breakers.push((endpoint.clone(), breaker.clone()));
+                    breaker
+                }
+            };
+            Upstream {
+                endpoint,
+                backend,
+                credentials,
+                breaker,
+            }
+        };
+        let primary = upstream(endpoint, backends.get(None, None)?, None);
+        let routes: Vec<Route> = match routes_file {
+            Some(path) => {
+                let invalid = |e: &dyn std::fmt::Display| format!("{}: {}", path.display(), e);
+                let text = std::fs::read_to_string(path).map_err(|e| invalid(&e))?;
+                serde_json::from_str(&text).map_err(|e| invalid(&e))?
+            }
+            None => Vec::new(),
+        };
+        let routes = routes
+            .into_iter()
+            .map(|route| {
+                let credentials = match (route.access_key_id, route.secret_access_key) {
+                    (Some(access_key_id), Some(secret_access_key)) => {
+                        Some(aws_credential_types::Credentials::new(
+                            access_key_id,
+                            secret_access_key,
+                            route.session_token,
+                            None,
+                            "route",
+                        ))
+                    }
+                    (None, None) => None,
+                    _ => {
+                        return Err(format!(
+                            "route of {} to {} needs both access_key_id and secret_access_key",
+                            route.bucket, route.endpoint
+                        ))
+                    }
+                };
+                let backend = backends.get(route.backend.as_ref(), route.region.as_deref())?;
+                let upstream = upstream(&route.endpoint, backend, credentials);
+                Ok((route.bucket, route.prefix, upstream))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Upstreams {
+            primary,
+            routes,
+            breakers,
+        })
+    }
+
+    /// Returns the upstream of `path`, the key or, for listings, the prefix
+    /// of a request for `bucket`.
+    pub fn route(&self, bucket: &str, path: &str) -> &Upstream {
+        self.routes
+            .iter()
+            .find(|(pattern, prefix, _)| {
+                wildcard_match(pattern, bucket) && path.starts_with(prefix)
+            })
+            .map_or(&self.primary, |(_, _, upstream)| upstream)
+    }
+
+    /// Returns the upstream of --endpoint.
+    pub fn primary(&self) -> &Upstream {
+        &self.primary
+    }
+
+    /// Returns the endpoints and their circuit breakers, starting with
+    /// --endpoint.
+    pub fn circuit_breakers(&self) -> impl Iterator<Item = (&str, &CircuitBreaker)> {
+        self.breakers
+            .iter()
+            .map(|(endpoint, breaker)| (endpoint.as_str(), &**breaker))
+    }
+}
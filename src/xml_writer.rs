@@ -1,4 +1,4 @@
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
@@ -27,8 +27,113 @@ pub struct ListBucketResult {
     pub contents: Option<Vec<Content>>,
 }
 
+/// An S3 error response body.
+#[derive(Serialize)]
+#[serde(rename = "Error", rename_all = "PascalCase")]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+    pub resource: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ErrorResponse {
+    pub fn to_xml(&self) -> String {
+        let body = quick_xml::se::to_string(self).unwrap();
+        format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n{}", body)
+    }
+}
+
 impl ListBucketResult {
     pub fn from_str(s: &str) -> Result<ListBucketResult, quick_xml::de::DeError> {
         quick_xml::de::from_str(s)
     }
 }
+
+/// Marks the continuation tokens of clients that were upstream tokens
+/// starting with the key prefix, which is cut off them. Upstream tokens are
+/// base64 or, on the local filesystem, keys, which start with the prefix.
+const PREFIXED_TOKEN_MARKER: char = '~';
+
+/// Rewrites ListObjectsV2 requests into the keys of the upstream and their
+/// responses back into the view of the client, whose keys lack a prefix.
+pub struct ListingRewrite<'a> {
+    key_prefix: &'a str,
+}
+
+impl<'a> ListingRewrite<'a> {
+    pub fn new(key_prefix: &'a str) -> Self {
+        ListingRewrite { key_prefix }
+    }
+
+    /// Returns true if clients see the listings of the upstream as they are.
+    pub fn is_identity(&self) -> bool {
+        self.key_prefix.is_empty()
+    }
+
+    /// Returns the upstream key or prefix of one of the client.
+    pub fn upstream_key(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
+    }
+
+    /// Returns the upstream continuation token of one of the client.
+    pub fn upstream_token(&self, token: &str) -> String {
+        match token.strip_prefix(PREFIXED_TOKEN_MARKER) {
+            Some(rest) if !self.is_identity() => self.upstream_key(rest),
+            _ => token.to_string(),
+        }
+    }
+
+    /// Returns the continuation token of the client of an upstream one.
+    fn client_token(&self, token: &str) -> String {
+        match token.strip_prefix(self.key_prefix) {
+            Some(rest) if !self.is_identity() => format!("{}{}", PREFIXED_TOKEN_MARKER, rest),
+            _ => token.to_string(),
+        }
+    }
+
+    /// Rewrites the keys, prefixes, including those of `CommonPrefixes`, and
+    /// continuation tokens of a ListObjectsV2 response, leaving everything
+    /// else as it is.
+    pub fn apply(&self, xml: &str) -> Result<String, quick_xml::Error> {
+        use quick_xml::events::{BytesText, Event};
+
+        if self.is_identity() {
+            return Ok(xml.to_string());
+        }
+        let mut reader = quick_xml::Reader::from_str(xml);
+        let mut writer = quick_xml::Writer::new(Vec::new());
+        let mut element = Vec::new();
+        loop {
+            match reader.read_event()? {
+                Event::Eof => break,
+                Event::Start(start) => {
+                    element = start.name().as_ref().to_vec();
+                    writer.write_event(Event::Start(start))?;
+                }
+                Event::Text(text) if matches!(&element[..], b"Key" | b"Prefix" | b"StartAfter") => {
+                    let value = text.unescape()?;
+                    let value = value.strip_prefix(self.key_prefix).unwrap_or(&value);
+                    writer.write_event(Event::Text(BytesText::new(value)))?;
+                }
+                Event::Text(text)
+                    if matches!(
+                        &element[..],
+                        b"ContinuationToken" | b"NextContinuationToken"
+                    ) =>
+                {
+                    let value = self.client_token(&text.unescape()?);
+                    writer.write_event(Event::Text(BytesText::new(&value)))?;
+                }
+                event => {
+                    if let Event::End(_) = event {
+                        element.clear();
+                    }
+                    writer.write_event(event)?;
+                }
+            }
+        }
+        Ok(String::from_utf8_lossy(&writer.into_inner()).into_owned())
+    }
+}